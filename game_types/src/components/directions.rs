@@ -1,5 +1,7 @@
+use std::f32::consts::TAU;
+
 use bevy::{
-    math::IVec2,
+    math::{IVec2, Vec2},
     prelude::{Component, Resource},
 };
 
@@ -30,6 +32,44 @@ pub const DIRECTIONS: [IVec2; 8] =
         IVec2::new(-1, 1),  // North-West
     ];
 
+/// Axial offsets to the six neighbours of a hexagonal cell, counter-clockwise starting from the east.
+pub const HEX_DIRECTIONS: [IVec2; 6] =
+    [
+        IVec2::new(1, 0),   // East
+        IVec2::new(0, 1),   // North-East
+        IVec2::new(-1, 1),  // North-West
+        IVec2::new(-1, 0),  // West
+        IVec2::new(0, -1),  // South-West
+        IVec2::new(1, -1),  // South-East
+    ];
+
+pub const CARDINAL_DIRECTIONS: [IVec2; 4] =
+    [
+        IVec2::new(0, 1),   // North
+        IVec2::new(1, 0),   // East
+        IVec2::new(0, -1),  // South
+        IVec2::new(-1, 0),  // West
+    ];
+
+/// Which cells are considered to be neighbours of a cell.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub enum Connectivity {
+    // Only cells sharing an edge
+    #[default]
+    Four,
+    // Cells sharing an edge or a corner
+    Eight,
+}
+
+impl Connectivity {
+    pub fn offsets(&self) -> &'static [IVec2] {
+        match self {
+            Connectivity::Four => &CARDINAL_DIRECTIONS,
+            Connectivity::Eight => &DIRECTIONS,
+        }
+    }
+}
+
 pub struct DirectionIntoIter(Direction);
 
 impl Iterator for DirectionIntoIter {
@@ -94,6 +134,31 @@ impl Direction {
     }
 }
 
+impl Direction {
+    /// The one of the eight directions closest to the vector, none for a zero vector.
+    pub fn from_vector(vector: Vec2) -> Option<Self> {
+        if vector == Vec2::ZERO {
+            return None;
+        }
+        // Clockwise from the north, like the order of the variants
+        let angle = vector.x.atan2(vector.y).rem_euclid(TAU);
+        Direction::North.into_iter().nth((angle / (TAU / 8.0)).round() as usize % 8)
+    }
+
+    pub fn opposite(&self) -> Self {
+        match self {
+            Self::North => Self::South,
+            Self::NorthEast => Self::SouthWest,
+            Self::East => Self::West,
+            Self::SouthEast => Self::NorthWest,
+            Self::South => Self::North,
+            Self::SouthWest => Self::NorthEast,
+            Self::West => Self::East,
+            Self::NorthWest => Self::SouthEast,
+        }
+    }
+}
+
 impl Into<IVec2> for Direction {
     fn into(self) -> IVec2 {
         self.as_vector()
@@ -2,8 +2,10 @@ use bevy::{
     math::Vec2,
     prelude::{Component, Resource}
 };
+use std::collections::HashMap;
+
 use ndarray::Array2;
-use crate::components::grid_components::definitions::CellIndex2d;
+use crate::components::grid_components::definitions::{CellIndex2d, LayeredCellIndex};
 
 
 #[derive(Component)]
@@ -14,9 +16,74 @@ pub struct FlowField {
     pub field: Array2<Vec2>,
 }
 
-#[derive(Default)]
-pub struct ExplosionParameters
-{
-    pub impact_center_cell_index: CellIndex2d,
-    pub impact_radius: f32,
-}
\ No newline at end of file
+/// Flow field of a `HexGrid2D`, stored in the offset layout of the map. Vectors are in world space.
+#[derive(Resource, Clone)]
+pub struct HexFlowField {
+    pub field: Array2<Vec2>,
+}
+
+/// Flow fields of every layer of a `LayeredGrid`. Cells from which the cheapest way continues through a portal
+/// have no flow, the portal to take is recorded for them instead.
+#[derive(Resource, Default)]
+pub struct LayeredFlowField {
+    pub fields: Vec<FlowField>,
+    pub portals_to_take: HashMap<LayeredCellIndex, usize>,
+}
+
+/// Cells the integrated flow field leads to. Changing them makes the field to be integrated again.
+#[derive(Resource, Default, Clone)]
+pub struct FlowFieldGoals {
+    pub cells: Vec<CellIndex2d>,
+}
+
+/// Which way a vortex turns the flow around its center.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum VortexDirection {
+    Clockwise,
+    CounterClockwise,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FlowBrushKind {
+    // Pushes away from the center, like an explosion
+    Repulsor,
+    // Pulls towards the center, like a sink
+    Attractor,
+    Vortex(VortexDirection),
+    // Same direction everywhere within the radius, in world space
+    Wind(Vec2),
+    // Directions changing smoothly from cell to cell, `scale` is the size of a noise feature in world units
+    Noise { scale: f32, seed: u32 },
+}
+
+/// How the strength of a brush fades from its center towards the radius.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub enum Falloff {
+    #[default]
+    Linear,
+    Smoothstep,
+    // Sharp peak in the center, fading out to zero at the radius
+    InverseSquare,
+}
+
+/// Edit of the flow field around a world position. The radius is in world units, an infinite one covers
+/// the whole field.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FlowBrush {
+    pub kind: FlowBrushKind,
+    pub center: Vec2,
+    pub radius: f32,
+    pub falloff: Falloff,
+    pub strength: f32,
+    // Seconds until the brush is gone, its strength decays over them. Brushes without one stay until removed
+    pub duration: Option<f32>,
+    pub(crate) age: f32,
+}
+
+/// Brushes stacked on top of the flow field. The field as it was before the brushes is kept as the base,
+/// so that the brushes could be re-applied as they decay and the field restored once they are all gone.
+#[derive(Resource, Default)]
+pub struct FlowBrushes {
+    pub(crate) brushes: Vec<FlowBrush>,
+    pub(crate) base_field: Option<Array2<Vec2>>,
+}
@@ -0,0 +1,218 @@
+use std::{
+    collections::HashSet,
+    ops::{Index, IndexMut},
+};
+
+use bevy::math::{IVec2, UVec2, Vec2};
+use bracket_pathfinding::prelude::SmallVec;
+use ndarray::Array2;
+
+use crate::{
+    components::directions::Connectivity,
+    function_libs::flow_field::{DIAGONAL_STEP_COST, STRAIGHT_STEP_COST},
+};
+
+use super::nav_grid_traits::{calculate_octile_cost, NavGrid, NavGridCosts, NavGridNeighbors};
+use super::definitions::{
+    CellIndex2d,
+    ChunkCoordinate,
+    ChunkedGridRelatedData,
+    Grid2D,
+    GridCellData,
+    Occupation,
+};
+
+impl ChunkedGridRelatedData {
+    /// Cells of chunks that are not loaded are reported as occupied, so nothing gets routed through them.
+    pub fn new(chunk_size: UVec2) -> Self {
+        ChunkedGridRelatedData::with_unloaded_cell(chunk_size, GridCellData {
+            occupation_state: Occupation::Occupied,
+            ..Default::default()
+        })
+    }
+
+    pub fn with_unloaded_cell(chunk_size: UVec2, unloaded_cell: GridCellData) -> Self {
+        assert!(chunk_size.x > 0 && chunk_size.y > 0, "Chunk should have at least one cell");
+        ChunkedGridRelatedData {
+            chunk_size,
+            chunks: Default::default(),
+            unloaded_cell,
+        }
+    }
+
+    #[inline]
+    pub fn get_chunk_size(&self) -> UVec2 {
+        self.chunk_size
+    }
+
+    /// Splits a signed cell coordinate into the chunk it belongs to and its index inside of that chunk.
+    #[inline]
+    pub fn split_cell_coordinate(&self, cell: IVec2) -> (ChunkCoordinate, CellIndex2d) {
+        let chunk_size = self.chunk_size.as_ivec2();
+        let chunk = IVec2::new(cell.x.div_euclid(chunk_size.x), cell.y.div_euclid(chunk_size.y));
+        let local = CellIndex2d::new(cell.x.rem_euclid(chunk_size.x), cell.y.rem_euclid(chunk_size.y));
+        (chunk, local)
+    }
+
+    #[inline]
+    pub fn chunk_of(&self, cell: IVec2) -> ChunkCoordinate {
+        self.split_cell_coordinate(cell).0
+    }
+
+    /// Signed cell coordinate of the world position. Unlike `Grid2D::calculate_cell_index_from_position`
+    /// it is not clamped to the grid bounds.
+    #[inline]
+    pub fn calculate_cell_from_position(grid_parameters: &Grid2D, position: Vec2) -> IVec2 {
+        ((position - grid_parameters.shape_rect.min) / grid_parameters.cell_size).floor().as_ivec2()
+    }
+
+    /// Chunks within a square of `radius` chunks around the chunk of the given cell.
+    pub fn calculate_chunks_around(&self, cell: IVec2, radius: u32) -> impl Iterator<Item=ChunkCoordinate> {
+        let center = self.chunk_of(cell);
+        let radius = radius as i32;
+        (-radius..=radius).flat_map(move |y| (-radius..=radius).map(move |x| center + IVec2::new(x, y)))
+    }
+
+    #[inline]
+    pub fn is_chunk_loaded(&self, chunk: ChunkCoordinate) -> bool {
+        self.chunks.contains_key(&chunk)
+    }
+
+    pub fn iter_loaded_chunks(&self) -> impl Iterator<Item=&ChunkCoordinate> {
+        self.chunks.keys()
+    }
+
+    /// Creates a chunk filled with default cells. Returns `false` if it was loaded already.
+    pub fn load_chunk(&mut self, chunk: ChunkCoordinate) -> bool {
+        if self.is_chunk_loaded(chunk) {
+            return false;
+        }
+        let shape = (self.chunk_size.x as usize, self.chunk_size.y as usize);
+        self.chunks.insert(chunk, Array2::default(shape));
+        true
+    }
+
+    /// Puts previously saved data back, replacing the chunk if it was loaded.
+    pub fn insert_chunk(&mut self, chunk: ChunkCoordinate, data: Array2<GridCellData>) {
+        assert_eq!(data.dim(), (self.chunk_size.x as usize, self.chunk_size.y as usize),
+                   "Chunk data does not match the chunk size");
+        self.chunks.insert(chunk, data);
+    }
+
+    pub fn unload_chunk(&mut self, chunk: ChunkCoordinate) -> Option<Array2<GridCellData>> {
+        self.chunks.remove(&chunk)
+    }
+
+    /// Loads every chunk from `required` that is missing and unloads every loaded chunk that is not in it.
+    /// Returns coordinates of loaded chunks together with data of unloaded ones.
+    pub fn stream_chunks(&mut self, required: &HashSet<ChunkCoordinate>)
+                         -> (Vec<ChunkCoordinate>, Vec<(ChunkCoordinate, Array2<GridCellData>)>) {
+        let chunks_to_unload: Vec<ChunkCoordinate> = self.chunks.keys()
+            .filter(|chunk| !required.contains(chunk))
+            .copied()
+            .collect();
+        let unloaded = chunks_to_unload.into_iter()
+            .filter_map(|chunk| self.unload_chunk(chunk).map(|data| (chunk, data)))
+            .collect();
+
+        let loaded = required.iter()
+            .copied()
+            .filter(|&chunk| self.load_chunk(chunk))
+            .collect();
+
+        (loaded, unloaded)
+    }
+
+    pub fn get_data_at(&self, cell: IVec2) -> &GridCellData {
+        let (chunk, local) = self.split_cell_coordinate(cell);
+        match self.chunks.get(&chunk) {
+            Some(chunk_data) => &chunk_data[&local],
+            None => &self.unloaded_cell,
+        }
+    }
+
+    /// Loads the chunk with default cells if it was not loaded yet.
+    pub fn get_data_at_mut(&mut self, cell: IVec2) -> &mut GridCellData {
+        let (chunk, local) = self.split_cell_coordinate(cell);
+        self.load_chunk(chunk);
+        let chunk_data = self.chunks.get_mut(&chunk).expect("Chunk was just loaded");
+        &mut chunk_data[&local]
+    }
+}
+
+impl Index<IVec2> for ChunkedGridRelatedData {
+    type Output = GridCellData;
+
+    fn index(&self, index: IVec2) -> &Self::Output {
+        self.get_data_at(index)
+    }
+}
+
+impl IndexMut<IVec2> for ChunkedGridRelatedData {
+    fn index_mut(&mut self, index: IVec2) -> &mut Self::Output {
+        self.get_data_at_mut(index)
+    }
+}
+
+impl Index<CellIndex2d> for ChunkedGridRelatedData {
+    type Output = GridCellData;
+
+    fn index(&self, index: CellIndex2d) -> &Self::Output {
+        self.get_data_at(index.into())
+    }
+}
+
+impl IndexMut<CellIndex2d> for ChunkedGridRelatedData {
+    fn index_mut(&mut self, index: CellIndex2d) -> &mut Self::Output {
+        self.get_data_at_mut(index.into())
+    }
+}
+
+/// Only loaded chunks are navigable, so paths never leave the streamed area.
+impl NavGrid for ChunkedGridRelatedData {
+    type Cell = IVec2;
+
+    #[inline]
+    fn contains(&self, cell: IVec2) -> bool {
+        self.is_chunk_loaded(self.chunk_of(cell))
+    }
+}
+
+impl NavGridNeighbors for ChunkedGridRelatedData {
+    fn neighbors(&self, cell: IVec2) -> SmallVec<[IVec2; 8]> {
+        let is_free = |cell: IVec2| self.contains(cell) && self.is_passable(cell);
+
+        let mut neighbors = SmallVec::new();
+        for offset in Connectivity::Eight.offsets() {
+            let neighbor = cell + *offset;
+            if !self.contains(neighbor) {
+                continue;
+            }
+            // Diagonal steps are not allowed to cut corners of occupied cells
+            let is_diagonal = offset.x != 0 && offset.y != 0;
+            if is_diagonal && !(is_free(cell + IVec2::new(offset.x, 0)) && is_free(cell + IVec2::new(0, offset.y))) {
+                continue;
+            }
+            neighbors.push(neighbor);
+        }
+        neighbors
+    }
+}
+
+impl NavGridCosts for ChunkedGridRelatedData {
+    #[inline]
+    fn is_passable(&self, cell: IVec2) -> bool {
+        self.get_data_at(cell).occupation_state == Occupation::Free
+    }
+
+    #[inline]
+    fn step_cost(&self, from: IVec2, to: IVec2) -> u32 {
+        let step_cost = if from.x != to.x && from.y != to.y { DIAGONAL_STEP_COST } else { STRAIGHT_STEP_COST };
+        (step_cost as f32 * (1.0 + self.get_data_at(to).detraction_factor)).round() as u32
+    }
+
+    #[inline]
+    fn estimate_cost(&self, from: IVec2, to: IVec2) -> u32 {
+        calculate_octile_cost(to - from)
+    }
+}
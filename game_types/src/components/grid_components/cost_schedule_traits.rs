@@ -0,0 +1,204 @@
+use bevy::math::URect;
+
+use crate::function_libs::grid_calculations::{remap_cell_after_resize, remap_inclusive_rect_after_resize};
+
+use super::definitions::{
+    AppliedSchedule,
+    CellIndex2d,
+    CostSchedule,
+    GridRelatedData,
+    GridResized,
+    ScheduleCurve,
+    ScheduledArea,
+    ScheduledEffect,
+    ScheduleStateChanged,
+};
+
+impl ScheduleCurve {
+    pub fn sample(&self, time: f32) -> f32 {
+        match self {
+            ScheduleCurve::Periodic { period, phase, active_duration } => {
+                if *period <= 0.0 {
+                    return 0.0;
+                }
+                let time_in_period = (time - phase).rem_euclid(*period);
+                if time_in_period < *active_duration { 1.0 } else { 0.0 }
+            }
+            ScheduleCurve::Keyframes { period, keys } => {
+                if keys.is_empty() || *period <= 0.0 {
+                    return 0.0;
+                }
+                sample_keyframes(keys, *period, time.rem_euclid(*period)).clamp(0.0, 1.0)
+            }
+        }
+    }
+}
+
+// Keys before the first and after the last one are interpolated across the period boundary
+fn sample_keyframes(keys: &[(f32, f32)], period: f32, time_in_period: f32) -> f32 {
+    let next = keys.partition_point(|(key_time, _)| *key_time <= time_in_period);
+    let (previous_time, previous_value) = if next == 0 {
+        let (key_time, value) = keys[keys.len() - 1];
+        (key_time - period, value)
+    } else {
+        keys[next - 1]
+    };
+    let (next_time, next_value) = if next == keys.len() {
+        let (key_time, value) = keys[0];
+        (key_time + period, value)
+    } else {
+        keys[next]
+    };
+
+    let span = next_time - previous_time;
+    if span <= 0.0 {
+        return previous_value;
+    }
+    previous_value + (next_value - previous_value) * (time_in_period - previous_time) / span
+}
+
+impl ScheduledArea {
+    #[inline]
+    pub fn contains(&self, cell: CellIndex2d) -> bool {
+        self.area.contains(cell.into())
+    }
+
+    #[inline]
+    pub fn is_closed_at(&self, time: f32) -> bool {
+        self.effect == ScheduledEffect::Occupation && self.curve.sample(time) >= 0.5
+    }
+
+    #[inline]
+    pub fn calculate_extra_cost_at(&self, time: f32) -> u32 {
+        match self.effect {
+            ScheduledEffect::ExtraCost(max_cost) => (max_cost as f32 * self.curve.sample(time)).round() as u32,
+            ScheduledEffect::Occupation => 0,
+        }
+    }
+}
+
+impl CostSchedule {
+    /// Registers the scheduled area and returns its index. The area should be inside of the grid.
+    /// It starts affecting the grid data with the next `apply_at`.
+    pub fn add_area(&mut self, scheduled_area: ScheduledArea) -> usize {
+        self.areas.push(scheduled_area);
+        self.applied.push(AppliedSchedule::default());
+        self.areas.len() - 1
+    }
+
+    #[inline]
+    pub fn get_area(&self, scheduled_area: usize) -> &ScheduledArea {
+        &self.areas[scheduled_area]
+    }
+
+    /// Areas still inside of the grid, see `resize_to`.
+    pub fn iter_areas(&self) -> impl Iterator<Item=&ScheduledArea> {
+        self.areas.iter().zip(self.applied.iter())
+            .filter(|(_, applied)| !applied.is_cut_off)
+            .map(|(scheduled_area, _)| scheduled_area)
+    }
+
+    /// Whether some scheduled area occupies the cell at the given time.
+    pub fn is_closed_at(&self, cell: CellIndex2d, time: f32) -> bool {
+        self.iter_areas().any(|scheduled_area| scheduled_area.contains(cell) && scheduled_area.is_closed_at(time))
+    }
+
+    /// Extra cost of stepping into the cell at the given time, summed over all scheduled areas.
+    pub fn calculate_extra_cost_at(&self, cell: CellIndex2d, time: f32) -> u32 {
+        self.iter_areas()
+            .filter(|scheduled_area| scheduled_area.contains(cell))
+            .map(|scheduled_area| scheduled_area.calculate_extra_cost_at(time))
+            .sum()
+    }
+
+    /// Whether the schedule closes the cell, which would be passable without it. Moving obstacles standing on
+    /// the cell are not taken into account, there is no telling where they would be later on.
+    pub fn is_closed_by_schedule(&self, cell: CellIndex2d) -> bool {
+        self.areas.iter().zip(self.applied.iter())
+            .any(|(scheduled_area, applied)| {
+                // Closed cells are gathered in the order of the data, so they stay sorted
+                scheduled_area.contains(cell) && applied.closed_cells.binary_search(&cell).is_ok()
+            })
+    }
+
+    /// Part of the `GridCellData::scheduled_cost` of the cell written by the schedule.
+    pub fn calculate_applied_extra_cost(&self, cell: CellIndex2d) -> u32 {
+        self.areas.iter().zip(self.applied.iter())
+            .filter(|(scheduled_area, _)| scheduled_area.contains(cell))
+            .map(|(_, applied)| applied.extra_cost)
+            .sum()
+    }
+
+    /// Writes effects of the scheduled areas at the given time into the grid data and reports the areas
+    /// whose effect has changed since the previous call.
+    pub fn apply_at(&mut self, time: f32, grid_data: &mut GridRelatedData) -> Vec<ScheduleStateChanged> {
+        let mut state_changes = Vec::new();
+        for (index, (scheduled_area, applied)) in self.areas.iter().zip(self.applied.iter_mut()).enumerate() {
+            if applied.is_cut_off {
+                continue;
+            }
+            let is_closed = scheduled_area.is_closed_at(time);
+            let extra_cost = scheduled_area.calculate_extra_cost_at(time);
+            let occupation_changed = is_closed != applied.is_closed;
+            let cost_changed = extra_cost != applied.extra_cost;
+
+            if occupation_changed {
+                if is_closed {
+                    applied.closed_cells = close_open_cells(grid_data, scheduled_area.area);
+                } else {
+                    for cell in applied.closed_cells.drain(..) {
+                        grid_data.open_at(&cell);
+                    }
+                }
+                applied.is_closed = is_closed;
+            }
+            if cost_changed {
+                for cell_data in grid_data.get_segment_mut_view_of(scheduled_area.area).iter_mut() {
+                    cell_data.scheduled_cost = cell_data.scheduled_cost.saturating_sub(applied.extra_cost) + extra_cost;
+                }
+                applied.extra_cost = extra_cost;
+            }
+
+            if occupation_changed || cost_changed {
+                state_changes.push(ScheduleStateChanged {
+                    scheduled_area: index,
+                    area: scheduled_area.area,
+                    occupation_changed,
+                });
+            }
+        }
+        state_changes
+    }
+
+    /// Moves the areas along with the grid data after the grid was resized, clipping them to the grid.
+    /// Effects written into the cells move with the data, so only the cells that are left get opened later.
+    /// Areas cut off completely stop affecting the grid, but keep their index.
+    pub fn resize_to(&mut self, grid_resized: &GridResized) {
+        for (scheduled_area, applied) in self.areas.iter_mut().zip(self.applied.iter_mut()) {
+            if applied.is_cut_off {
+                continue;
+            }
+            applied.closed_cells = applied.closed_cells.iter()
+                .filter_map(|cell| remap_cell_after_resize(*cell, grid_resized))
+                .collect();
+            match remap_inclusive_rect_after_resize(scheduled_area.area, grid_resized) {
+                Some(area) => scheduled_area.area = area,
+                None => *applied = AppliedSchedule { is_cut_off: true, ..Default::default() },
+            }
+        }
+    }
+}
+
+// Closes the cells of the area that are not blocked by themselves and returns them, so that only they would
+// be opened later. Closures are counted together with the ones of moving obstacles, see `close_at`.
+fn close_open_cells(grid_data: &mut GridRelatedData, area: URect) -> Vec<CellIndex2d> {
+    let closed_cells: Vec<CellIndex2d> = grid_data.get_segment_view_of(area)
+        .indexed_iter()
+        .filter(|(_, cell_data)| !cell_data.is_blocked_by_itself())
+        .map(|((x, y), _)| CellIndex2d::new(area.min.x + x as u32, area.min.y + y as u32))
+        .collect();
+    for cell in closed_cells.iter() {
+        grid_data.close_at(cell);
+    }
+    closed_cells
+}
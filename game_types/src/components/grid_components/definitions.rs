@@ -1,6 +1,8 @@
 use std::collections::HashMap;
 use bevy::{
     math::{
+        BVec2,
+        IRect,
         IVec2,
         Rect,
         Vec2,
@@ -8,6 +10,8 @@ use bevy::{
     prelude::{
         Color,
         Component,
+        Entity,
+        Event,
         Resource,
         URect,
         UVec2,
@@ -15,7 +19,11 @@ use bevy::{
 };
 use derive_more::{Add, AddAssign, AsRef, Constructor, Display, From, Into, Rem, Sub};
 use ndarray::Array2;
-use crate::components::directions::Direction;
+use serde::{Deserialize, Serialize};
+use crate::components::{
+    directions::Direction,
+    movement_components::{AgentClassMask, SurfaceTopology},
+};
 
 pub type CellIndex1d = u32;
 
@@ -31,8 +39,18 @@ impl Default for Occupation {
     }
 }
 
+/// Which cells a shape stamped into the grid covers.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum StampMode {
+    // Cells which centers are inside of the shape
+    #[default]
+    CellCenter,
+    // Cells overlapping the shape at all, so that nothing the shape touches would stay passable
+    Conservative,
+}
+
 #[derive(Clone, Copy, Default, Eq, Hash, Ord, PartialEq, PartialOrd,
-Add, AddAssign, Sub, Rem, From, Into)]
+Add, AddAssign, Sub, Rem, From, Into, Serialize, Deserialize)]
 pub struct CellIndex2d {
     pub x: CellIndex1d,
     pub y: CellIndex1d,
@@ -46,6 +64,8 @@ pub struct GridSegment {
     // Offset from parent grid to segment grid
     pub(super) offset: IVec2,
     pub(super) bounds: URect,
+    // Axes along which the segment continues on the opposite edge of the parent grid
+    pub(super) wrap: BVec2,
 }
 
 impl GridSegment {
@@ -79,6 +99,13 @@ pub struct GridCellData {
     pub color: Color,
     pub occupation_state: Occupation,
     pub detraction_factor: f32,
+    // Added to the cost of stepping into the cell by the active schedules, in step cost units
+    pub scheduled_cost: u32,
+    // Dynamic sources keeping the cell occupied, like closed scheduled areas and moving obstacles standing
+    // on it, see `GridRelatedData::close_at`
+    pub(crate) closures: u16,
+    // Whether the cell is free by itself, so it gets freed again once all closures are gone
+    pub(crate) free_under_closures: bool,
 }
 
 #[derive(Resource, Clone)]
@@ -91,6 +118,257 @@ pub struct GridRelatedData {
     pub(super) data: Array2<GridCellData>,
 }
 
+/// Axial coordinate of a hexagonal cell. Axes are at 60 degrees, so `q + r + s == 0` with the implied third one.
+#[derive(Clone, Copy, Default, Eq, Hash, Ord, PartialEq, PartialOrd, Debug, Constructor, Display)]
+#[display("({}, {})", q, r)]
+pub struct HexCellIndex {
+    pub q: i32,
+    pub r: i32,
+}
+
+/// Rectangular map of pointy-top hexagons. Cells are addressed with axial coordinates, while data is stored
+/// in "odd-r" offset layout, where odd rows are shifted right by half of a cell.
+#[derive(Resource, Clone)]
+pub struct HexGrid2D {
+    pub column_number: u32,
+    pub row_number: u32,
+    // Distance from the center of a hexagon to any of its corners
+    pub cell_radius: f32,
+    // World position of the center of the offset cell (0, 0)
+    pub origin: Vec2,
+}
+
+/// Square grid together with its data, seen as a navigation grid. Occupied cells are impassable,
+/// entering a cell costs more the higher its detraction factor is.
+#[derive(Clone, Copy, Constructor)]
+pub struct SquareNavGrid<'a> {
+    pub grid: &'a Grid2D,
+    pub data: &'a GridRelatedData,
+    pub topology: SurfaceTopology,
+}
+
+/// Adds passability on top of a grid that only describes the cells layout, like `HexGrid2D`.
+#[derive(Clone, Copy, Constructor)]
+pub struct PassabilityOverlay<'a, G, F> {
+    pub grid: &'a G,
+    pub is_passable: F,
+}
+
+#[derive(Clone, Copy, Default, Eq, Hash, Ord, PartialEq, PartialOrd, Debug, Constructor, Display,
+Serialize, Deserialize)]
+#[display("{}@{}", cell, layer)]
+pub struct LayeredCellIndex {
+    pub layer: u32,
+    pub cell: CellIndex2d,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub enum PortalKind {
+    Stairs,
+    Elevator,
+    Teleporter,
+}
+
+/// Connection between cells of different layers (or distant cells of the same one).
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct PortalLink {
+    pub from: LayeredCellIndex,
+    pub to: LayeredCellIndex,
+    pub kind: PortalKind,
+    // In step cost units, see `NavGridCosts`
+    pub cost: u32,
+    pub bidirectional: bool,
+}
+
+pub struct GridLayer {
+    pub grid: Grid2D,
+    pub data: GridRelatedData,
+}
+
+/// Floors of a level stacked on top of each other and connected with portals.
+/// Layers don't have to be of the same size.
+#[derive(Resource, Default)]
+pub struct LayeredGrid {
+    pub(super) layers: Vec<GridLayer>,
+    // Index of the first cell of every layer in the flat storage of layered fields
+    pub(super) layer_offsets: Vec<usize>,
+    pub(super) portals: Vec<PortalLink>,
+    // Portals that could be entered from the cell, in both directions for bidirectional ones
+    pub(super) portals_by_cell: HashMap<LayeredCellIndex, Vec<usize>>,
+    // Cheapest sum of portal costs leading from one layer to another, indexed by both layers. Rebuilt whenever
+    // portals or layers are added, see `estimate_cost`.
+    pub(super) layer_portal_costs: Vec<Vec<Option<u32>>>,
+    // Areas around the portal entrances and exits on every layer
+    pub(super) portal_areas: Vec<PortalAreas>,
+}
+
+#[derive(Clone, Copy, Default)]
+pub(super) struct PortalAreas {
+    pub(super) entrances: Option<URect>,
+    pub(super) exits: Option<URect>,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub enum OffMeshLinkKind {
+    Jump,
+    Ladder,
+    Door,
+}
+
+/// Designer placed connection between two cells that are not necessarily adjacent.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct OffMeshLink {
+    pub from: CellIndex2d,
+    pub to: CellIndex2d,
+    pub kind: OffMeshLinkKind,
+    // In step cost units, see `NavGridCosts`
+    pub cost: u32,
+    pub bidirectional: bool,
+    // Agent classes allowed to use the link
+    pub agent_classes: AgentClassMask,
+    pub enabled: bool,
+}
+
+#[derive(Resource, Default)]
+pub struct OffMeshLinks {
+    pub(super) links: Vec<OffMeshLink>,
+    // Links that could be entered from the cell, in both directions for bidirectional ones
+    pub(super) links_by_cell: HashMap<CellIndex2d, Vec<usize>>,
+}
+
+/// Navigation grid extended with off-mesh links usable by the given agent classes.
+#[derive(Clone, Copy)]
+pub struct LinkedNavGrid<'a, G> {
+    pub(super) grid: &'a G,
+    pub(super) links: &'a OffMeshLinks,
+    pub(super) agent_classes: AgentClassMask,
+    // Whether some link is cheaper than walking between its ends, which makes the base estimate inadmissible
+    pub(super) has_shortcuts: bool,
+}
+
+/// Value in the 0..=1 range changing over time, in seconds.
+#[derive(Clone, PartialEq, Debug)]
+pub enum ScheduleCurve {
+    /// Equals 1 for `active_duration` seconds of every period, starting `phase` seconds into it, otherwise 0.
+    Periodic { period: f32, phase: f32, active_duration: f32 },
+    /// Linearly interpolated `(time, value)` keys sorted by time, repeated every period.
+    Keyframes { period: f32, keys: Vec<(f32, f32)> },
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum ScheduledEffect {
+    /// Cells are occupied while the curve is at least one half.
+    Occupation,
+    /// Cost of stepping into the cells is increased by up to the given amount, scaled by the curve.
+    ExtraCost(u32),
+}
+
+/// Patrol lane, timed gate or hazard zone that only affects the area from time to time.
+#[derive(Clone, Debug, Constructor)]
+pub struct ScheduledArea {
+    pub area: URect,
+    pub curve: ScheduleCurve,
+    pub effect: ScheduledEffect,
+}
+
+/// Effects of a scheduled area currently written into `GridRelatedData`.
+#[derive(Clone, Default, Debug)]
+pub(super) struct AppliedSchedule {
+    pub(super) is_closed: bool,
+    // Cells occupied by the schedule, cells that were occupied already are left untouched
+    pub(super) closed_cells: Vec<CellIndex2d>,
+    pub(super) extra_cost: u32,
+    // The area is outside of the grid since it was resized
+    pub(super) is_cut_off: bool,
+}
+
+#[derive(Resource, Default)]
+pub struct CostSchedule {
+    pub(super) areas: Vec<ScheduledArea>,
+    pub(super) applied: Vec<AppliedSchedule>,
+}
+
+/// Sent whenever a scheduled area starts affecting its cells differently, so paths and flow fields
+/// crossing it could be recalculated.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct ScheduleStateChanged {
+    pub scheduled_area: usize,
+    pub area: URect,
+    pub occupation_changed: bool,
+}
+
+pub type ChunkCoordinate = IVec2;
+
+/// Grid data split into fixed-size chunks, so that only chunks around streaming anchors have to be kept
+/// in memory. Cells are addressed with signed coordinates, where (0, 0) is the first cell of `Grid2D`.
+#[derive(Resource)]
+pub struct ChunkedGridRelatedData {
+    pub(super) chunk_size: UVec2,
+    pub(super) chunks: HashMap<ChunkCoordinate, Array2<GridCellData>>,
+    // Returned for cells of chunks that are not loaded
+    pub(super) unloaded_cell: GridCellData,
+}
+
+/// Chunks within `radius_in_chunks` around the entity are kept loaded.
+#[derive(Component, Clone, Copy)]
+pub struct ChunkStreamingAnchor {
+    pub radius_in_chunks: u32,
+}
+
+#[derive(Event, Clone, Copy)]
+pub struct ChunkLoaded {
+    pub chunk: ChunkCoordinate,
+}
+
+/// Carries the data of the unloaded chunk, so it could be persisted.
+#[derive(Event, Clone)]
+pub struct ChunkUnloaded {
+    pub chunk: ChunkCoordinate,
+    pub data: Array2<GridCellData>,
+}
+
+/// Exact euclidean distance (in cells) from every cell to the closest occupied cell.
+/// Values are capped at `max_distance`, which also bounds how far an edit has to be propagated.
+#[derive(Resource, Clone)]
+pub struct ClearanceLayer {
+    pub(crate) distances: Array2<f32>,
+    pub(crate) max_distance: f32,
+    pub(crate) dirty_area: Option<URect>,
+}
+
+/// Asks for the grid to be resized. Existing cells stay attached to the `anchor` side of the grid.
+#[derive(Event, Clone, Copy)]
+pub struct ResizeGrid {
+    pub column_number: u32,
+    pub row_number: u32,
+    pub anchor: Direction,
+}
+
+/// Sent after the grid was resized, so layers that are sized after the grid could be re-allocated.
+#[derive(Event, Clone, Copy)]
+pub struct GridResized {
+    pub previous_size: UVec2,
+    pub new_size: UVec2,
+    // Where the previous cell (0, 0) ended up in the resized grid
+    pub offset: IVec2,
+}
+
+/// Sent whenever the occupation of cells inside `area` was changed.
+#[derive(Event, Clone, Copy)]
+pub struct OccupationChanged {
+    pub area: URect,
+}
+
+/// Cells covered by footprints of moving obstacles, closed on top of the occupation in `GridRelatedData`.
+/// Closures are counted in the grid data together with the ones of scheduled areas, so a cell is freed only
+/// once neither an obstacle nor a closed area is left on it.
+#[derive(Resource, Clone)]
+pub struct DynamicOccupancy {
+    // Number of footprints covering the cell
+    pub(crate) stamps: Array2<u16>,
+    pub(crate) footprints: HashMap<Entity, Vec<CellIndex2d>>,
+}
+
 
 #[derive(Clone, Copy, Default, Component, AsRef, Constructor, From, Into)]
 pub struct CellIndex {
@@ -2,7 +2,7 @@ use std::cmp::min;
 use std::collections::HashMap;
 
 use bevy::{
-    math::{IVec2, Rect, URect, UVec2, Vec2},
+    math::{IRect, IVec2, Rect, URect, UVec2, Vec2},
 };
 use colored::{Color, ColoredString, Colorize};
 use ndarray::{Array2, ArrayView2};
@@ -11,12 +11,13 @@ use crate::{
     components::{
         directions::Direction,
         grid_components::{
-            definitions::{CellIndex1d, CellIndex2d, Grid2D, GridSegment},
+            definitions::{CellIndex1d, CellIndex2d, Grid2D, GridResized, GridSegment},
             grid_related_iterators::CoordinateIterator,
         },
         movement_components::{
             Coordinate,
             SurfaceCoordinate,
+            SurfaceTopology,
         },
     },
     function_libs::grid_calculations::{
@@ -42,6 +43,7 @@ impl Grid2D {
     }
 
     pub fn new(column_number: u32, row_number: u32, cell_size: Vec2) -> Self {
+        assert!(column_number > 0 && row_number > 0, "Grid should have at least one cell");
         let grid_size = Vec2::new(column_number as f32 * cell_size.x,
                                   row_number as f32 * cell_size.y);
 
@@ -66,6 +68,32 @@ impl Grid2D {
         return grid;
     }
 
+    /// Resizes the grid, keeping existing cells attached to the `anchor` side (or corner) of it.
+    /// Existing cells keep their world positions, so the grid grows or shrinks around them.
+    pub fn resize(&mut self, column_number: u32, row_number: u32, anchor: Direction) -> GridResized {
+        let previous_size = UVec2::new(self.column_number, self.row_number);
+        let new_size = UVec2::new(column_number, row_number);
+        let anchor_vector = anchor.as_vector();
+        let offset = IVec2::new(
+            grid_calculations::calculate_anchored_offset(previous_size.x, new_size.x, anchor_vector.x),
+            grid_calculations::calculate_anchored_offset(previous_size.y, new_size.y, anchor_vector.y),
+        );
+
+        let shape_min = self.shape_rect.min - offset.as_vec2() * self.cell_size;
+        let mut resized_grid = Grid2D::new(column_number, row_number, self.cell_size);
+        resized_grid.cells_spacing = self.cells_spacing;
+        resized_grid.shape_rect = Rect::from_corners(shape_min, shape_min + resized_grid.grid_size);
+        *self = resized_grid;
+
+        GridResized { previous_size, new_size, offset }
+    }
+
+    /// Adds `cells` columns and/or rows on the given side of the grid.
+    pub fn extend(&mut self, direction: Direction, cells: u32) -> GridResized {
+        let growth = direction.as_vector().abs().as_uvec2() * cells;
+        self.resize(self.column_number + growth.x, self.row_number + growth.y, direction.opposite())
+    }
+
     pub fn calculate_surface_coordinates_for_1d(&self, cell_indexes: &Vec<usize>) -> Vec<SurfaceCoordinate> {
         cell_indexes
             .iter()
@@ -95,7 +123,7 @@ impl Grid2D {
     pub fn form_segment_from(&self, origin: CellIndex2d, in_direction: IVec2, num_cells: u32) -> GridSegment {
         let segment_grid = URect {
             min: origin.into(),
-            max: UVec2::from(origin + in_direction * (num_cells as i32 - 1)),
+            max: UVec2::from(origin.saturating_offset(in_direction * (num_cells as i32 - 1))),
         };
         let parent_grid = self.indexes_rect;
         GridSegment::new(parent_grid, segment_grid)
@@ -105,8 +133,8 @@ impl Grid2D {
     pub fn calculate_cell_index_in_direction_from(&self, origin: CellIndex2d, in_direction: IVec2,
                                                   num_cells: u32) -> CellIndex2d
     {
-        let new_index = origin + (in_direction * num_cells as i32);
-        new_index
+        let new_index = origin.saturating_offset(in_direction * num_cells as i32);
+        self.form_grid_bound_cell_index(new_index.x, new_index.y)
     }
 
     /// Calculates a clamped rectangle area from a given center point and size.
@@ -167,10 +195,14 @@ impl Grid2D {
         rect
     }
 
+    /// Calculates an area of the given size around the cell. Along bounded axes of the topology the area is
+    /// clamped to the grid, along wrapped ones it may start below zero or end after the last cell.
     #[inline]
-    pub fn calculate_square_area_wrapped_from(&self, from: CellIndex2d, area_size: UVec2) -> URect {
-        let rect = URect::from_center_size(from.into(), area_size);
-        self.clamp_rect_to_grid_bounds(rect)
+    pub fn calculate_square_area_wrapped_from(&self, from: CellIndex2d, area_size: UVec2,
+                                              topology: SurfaceTopology) -> IRect {
+        let (min_x, max_x) = calculate_wrapped_span(from.x, area_size.x, self.column_number, topology.wraps_x());
+        let (min_y, max_y) = calculate_wrapped_span(from.y, area_size.y, self.row_number, topology.wraps_y());
+        IRect::from_corners(IVec2::new(min_x, min_y), IVec2::new(max_x, max_y))
     }
 
     /// Calculate a line from a given point in a specified direction for a certain number of cells.
@@ -193,6 +225,15 @@ impl Grid2D {
         URect::from_corners(point.into(), UVec2::new(new_x, new_y))
     }
 
+    /// Grows the area by `cells` in every direction, keeping it inside of the grid bounds.
+    #[inline]
+    pub fn inflate_area_clamped(&self, area: URect, cells: u32) -> URect {
+        let min = UVec2::new(area.min.x.saturating_sub(cells), area.min.y.saturating_sub(cells));
+        let max = UVec2::new(area.max.x.saturating_add(cells).min(self.max_column_index),
+                             area.max.y.saturating_add(cells).min(self.max_row_index));
+        URect::from_corners(min, max)
+    }
+
     #[inline]
     pub fn clamp_rect_to_grid_bounds(&self, rect: URect) -> URect {
         let min_x = rect.min.x.clamp(0, self.max_column_index);
@@ -216,8 +257,8 @@ impl Grid2D {
     }
 
     fn get_indexes(&self) -> Array2<CellIndex2d> {
-        let mut arr = Array2::from_elem((self.row_number as usize,
-                                         self.column_number as usize), CellIndex2d { x: 0, y: 0 });
+        let mut arr = Array2::from_elem((self.column_number as usize,
+                                         self.row_number as usize), CellIndex2d { x: 0, y: 0 });
 
         for ((col, row), cell_index_2d) in arr.indexed_iter_mut() {
             *cell_index_2d = CellIndex2d {
@@ -351,6 +392,19 @@ impl Grid2D {
     }
 }
 
+// Inclusive span of `size` cells around the center. A wrapped span never covers more cells than the grid has.
+fn calculate_wrapped_span(center: u32, size: u32, cells_number: u32, wraps: bool) -> (i32, i32) {
+    let half_size = (size / 2) as i32;
+    let center = center as i32;
+    if wraps {
+        let span = (half_size * 2).min(cells_number as i32 - 1);
+        let min = center - half_size.min(span / 2);
+        (min, min + span)
+    } else {
+        ((center - half_size).max(0), (center + half_size).min(cells_number as i32 - 1))
+    }
+}
+
 fn determine_cell_type(grid_segments: &HashMap<Direction, URect>, cell_index2d: &CellIndex2d) -> ColoredString
 {
     let number = cell_index2d.to_string();
@@ -1,6 +1,10 @@
 use bevy::math::{IVec2, URect};
 
-use crate::components::grid_components::definitions::CellIndex2d;
+use crate::components::{
+    directions::Connectivity,
+    grid_components::definitions::{CellIndex2d, Grid2D},
+    movement_components::SurfaceTopology,
+};
 
 pub struct AreaLineIterator {
     bounds: URect,
@@ -22,13 +26,14 @@ impl Iterator for AreaLineIterator {
     type Item = CellIndex2d;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if !self.bounds.contains((self.current + self.direction).into())
+        let next_cell = self.current.checked_offset(self.direction)?;
+        if !self.bounds.contains(next_cell.into())
         {
             return None;
         }
 
         let result = Some(self.current);
-        self.current += self.direction;
+        self.current = next_cell;
 
         result
     }
@@ -62,16 +67,16 @@ impl Iterator for AreaFullIterator {
             return None;
         }
 
-        if offset_y != IVec2::ZERO && self.bounds.contains((self.current + offset_y).into()) {
-            self.current += offset_y;
+        if let Some(next_cell) = self.step_within_bounds(offset_y) {
+            self.current = next_cell;
             /*   println!("prev: {prev}; offset is: {offset_y}; result is: {}", self.current)*/
-        } else if offset_x != IVec2::ZERO && self.bounds.contains((self.current + offset_x).into()) {
+        } else if let Some(next_cell) = self.step_within_bounds(offset_x) {
+            self.current.x = next_cell.x;
             if self.direction.y > 0 {
                 self.current.y = self.bounds.min.y;
             } else {
                 self.current.y = self.bounds.max.y;
             }
-            self.current += offset_x;
             /*  println!("prev: {prev}; offset is: {offset_x}; result is: {}", self.current)*/
         } else {
             return None;// Finished iterating when the offsets are out of bounds
@@ -81,6 +86,49 @@ impl Iterator for AreaFullIterator {
     }
 }
 
+impl AreaFullIterator {
+    #[inline]
+    fn step_within_bounds(&self, offset: IVec2) -> Option<CellIndex2d> {
+        if offset == IVec2::ZERO {
+            return None;
+        }
+        self.current.checked_offset(offset).filter(|next_cell| self.bounds.contains((*next_cell).into()))
+    }
+}
+
+/// Iterates over neighbours of a cell that are on the grid surface.
+/// Along wrapped axes of the topology neighbours continue on the opposite edge of the grid.
+pub struct NeighborsIterator<'a> {
+    grid: &'a Grid2D,
+    center: CellIndex2d,
+    offsets: std::slice::Iter<'static, IVec2>,
+    topology: SurfaceTopology,
+}
+
+impl<'a> NeighborsIterator<'a> {
+    pub fn new(center: CellIndex2d, grid: &'a Grid2D, connectivity: Connectivity, topology: SurfaceTopology) -> Self {
+        NeighborsIterator {
+            grid,
+            center,
+            offsets: connectivity.offsets().iter(),
+            topology,
+        }
+    }
+}
+
+impl Iterator for NeighborsIterator<'_> {
+    type Item = CellIndex2d;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for offset in self.offsets.by_ref() {
+            if let Some(neighbor) = self.topology.offset_cell_in(self.center, *offset, self.grid) {
+                return Some(neighbor);
+            }
+        }
+        None
+    }
+}
+
 pub struct CoordinateIterator {
     inner: std::vec::IntoIter<CellIndex2d>,
 }
@@ -10,7 +10,7 @@ use std::{
 };
 
 use bevy::{
-    math::URect,
+    math::{BVec2, IRect, URect},
     prelude::{
         IVec2,
         UVec2,
@@ -20,13 +20,19 @@ use bevy::{
 };
 use colored::{ColoredString, Colorize};
 use derive_more::AddAssign;
-use ndarray::{Array2, ArrayView2, ArrayViewMut2, IndexLonger, Ix2, NdIndex};
+use ndarray::{Array2, ArrayView2, ArrayViewMut2, CowArray, IndexLonger, Ix2, NdIndex};
 use num_traits::AsPrimitive;
 use rand::Rng;
 
 use crate::{
     components::{
-        grid_components::definitions::Occupation,
+        directions::Connectivity,
+        grid_components::{
+            definitions::Occupation,
+            grid_related_iterators::NeighborsIterator,
+            nav_grid_traits::NavGridStorage,
+        },
+        movement_components::SurfaceTopology,
         pathfinding_components::PathfindingMap,
     },
     function_libs::grid_calculations::{
@@ -37,7 +43,8 @@ use crate::{
 use crate::function_libs::grid_calculations;
 use crate::function_libs::grid_calculations::normalize_rect;
 
-use super::definitions::{CellIndex1d, CellIndex2d, Grid2D, GridCellData, GridRelatedData, GridSegment};
+use super::definitions::{CellIndex1d, CellIndex2d, ClearanceLayer, Grid2D, GridCellData, GridRelatedData,
+                         GridResized, GridSegment, ObstaclesParameters};
 
 impl From<UVec2> for CellIndex2d {
     fn from(vec: UVec2) -> Self {
@@ -63,14 +70,33 @@ impl From<CellIndex2d> for UVec2 {
     }
 }
 
+/// Storage indexes of the `NavGridStorage` layouts, see `GridSegment::global_to_local_cell`.
+impl From<(usize, usize)> for CellIndex2d {
+    fn from(storage_index: (usize, usize)) -> Self {
+        CellIndex2d::new(storage_index.0, storage_index.1)
+    }
+}
+
+impl From<CellIndex2d> for (usize, usize) {
+    fn from(value: CellIndex2d) -> Self {
+        (value.x as usize, value.y as usize)
+    }
+}
+
+impl From<CellIndex2d> for IVec2 {
+    fn from(value: CellIndex2d) -> Self {
+        IVec2 { x: value.x as i32, y: value.y as i32 }
+    }
+}
+
+/// Offsets are expected to stay within `u32`, which is asserted in debug builds, and are not checked against
+/// the grid at all. Use [`CellIndex2d::checked_offset_in`] when leaving the grid has to be detected.
 impl Add<IVec2> for CellIndex2d {
     type Output = CellIndex2d;
 
     fn add(self, rhs: IVec2) -> Self::Output {
-        CellIndex2d {
-            x: (self.x as i32 + rhs.x) as u32,
-            y: (self.y as i32 + rhs.y) as u32,
-        }
+        debug_assert!(self.checked_offset(rhs).is_some(), "Offset {rhs} moves {self} out of the index range");
+        self.saturating_offset(rhs)
     }
 }
 
@@ -78,18 +104,14 @@ impl Sub<IVec2> for CellIndex2d {
     type Output = CellIndex2d;
 
     fn sub(self, rhs: IVec2) -> Self::Output {
-        CellIndex2d {
-            x: (self.x as i32 - rhs.x) as u32,
-            y: (self.y as i32 - rhs.y) as u32,
-        }
+        self + -rhs
     }
 }
 
 
 impl AddAssign<IVec2> for CellIndex2d {
     fn add_assign(&mut self, rhs: IVec2) {
-        self.x = (self.x as i32 + rhs.x) as u32;
-        self.y = (self.y as i32 + rhs.y) as u32;
+        *self = *self + rhs;
     }
 }
 
@@ -188,11 +210,59 @@ impl CellIndex2d {
 
     pub fn normalize(&self) -> Self {
         let length = ((self.x.pow(2) + self.y.pow(2)) as f32).sqrt() as u32;
+        if length == 0 {
+            return Self::ZERO;
+        }
         Self {
             x: self.x / length,
             y: self.y / length,
         }
     }
+
+    /// Offsets the index, returning `None` if any of the coordinates would go out of the `u32` range.
+    #[inline]
+    pub fn checked_offset(&self, offset: IVec2) -> Option<Self> {
+        let x = u32::try_from(self.x as i64 + offset.x as i64).ok()?;
+        let y = u32::try_from(self.y as i64 + offset.y as i64).ok()?;
+        Some(CellIndex2d { x, y })
+    }
+
+    /// Offsets the index, returning `None` if the result is outside of the grid.
+    #[inline]
+    pub fn checked_offset_in(&self, offset: IVec2, grid: &Grid2D) -> Option<Self> {
+        self.checked_offset(offset).filter(|cell_index| grid.is_cell_index_in_grid_bounds(*cell_index))
+    }
+
+    /// Offsets the index, stopping at zero and at `u32::MAX`.
+    #[inline]
+    pub fn saturating_offset(&self, offset: IVec2) -> Self {
+        CellIndex2d {
+            x: (self.x as i64 + offset.x as i64).clamp(0, u32::MAX as i64) as u32,
+            y: (self.y as i64 + offset.y as i64).clamp(0, u32::MAX as i64) as u32,
+        }
+    }
+
+    /// Offsets the index, wrapping around the grid edges.
+    #[inline]
+    pub fn wrapping_offset_in(&self, offset: IVec2, grid: &Grid2D) -> Self {
+        CellIndex2d {
+            x: (self.x as i64 + offset.x as i64).rem_euclid(grid.column_number as i64) as u32,
+            y: (self.y as i64 + offset.y as i64).rem_euclid(grid.row_number as i64) as u32,
+        }
+    }
+
+    /// Iterates over neighbouring cells that are inside of the grid.
+    #[inline]
+    pub fn neighbors<'a>(&self, grid: &'a Grid2D, connectivity: Connectivity) -> NeighborsIterator<'a> {
+        self.neighbors_on(grid, connectivity, SurfaceTopology::Bounded)
+    }
+
+    /// Iterates over neighbouring cells, wrapping around the grid edges the way the topology does.
+    #[inline]
+    pub fn neighbors_on<'a>(&self, grid: &'a Grid2D, connectivity: Connectivity,
+                            topology: SurfaceTopology) -> NeighborsIterator<'a> {
+        NeighborsIterator::new(*self, grid, connectivity, topology)
+    }
 }
 
 unsafe impl NdIndex<Ix2> for &CellIndex2d {
@@ -209,6 +279,14 @@ unsafe impl NdIndex<Ix2> for &CellIndex2d {
     }
 }
 
+impl GridCellData {
+    /// Whether the cell is occupied by itself, rather than only closed by dynamic sources.
+    #[inline]
+    pub fn is_blocked_by_itself(&self) -> bool {
+        self.occupation_state == Occupation::Occupied && !(self.closures > 0 && self.free_under_closures)
+    }
+}
+
 impl GridRelatedData {
     pub fn new(grid_parameters: &Grid2D) -> Self {
         GridRelatedData {
@@ -217,12 +295,42 @@ impl GridRelatedData {
         }
     }
 
+    /// Re-allocates the data after the grid was resized. Existing cells keep their data.
+    pub fn resize_to(&mut self, grid_resized: &GridResized) {
+        let new_shape = (grid_resized.new_size.x as usize, grid_resized.new_size.y as usize);
+        self.data = grid_calculations::resize_2d_array(&self.data, new_shape, grid_resized.offset,
+                                                       |_| GridCellData::default());
+    }
+
     pub fn create_pathfinding_map_on(&self, target_grid: &Grid2D, inclusive_rect: URect) -> PathfindingMap {
         let slice = self.get_segment_view_of(inclusive_rect);
-        PathfindingMap::new(target_grid.form_segment_for(inclusive_rect), slice, inclusive_rect,
+        PathfindingMap::new(target_grid.form_segment_for(inclusive_rect), CowArray::from(slice), inclusive_rect,
                             normalize_rect(inclusive_rect))
     }
 
+    /// Creates a pathfinding map on an area that may cross the grid edges along wrapped axes of the topology.
+    /// Areas that stay inside of the grid are viewed in place, others are gathered into a copy.
+    pub fn create_wrapped_pathfinding_map_on(&self, target_grid: &Grid2D, inclusive_area: IRect,
+                                             topology: SurfaceTopology) -> PathfindingMap {
+        let grid_area = IRect::from_corners(IVec2::ZERO, target_grid.indexes_rect.max.as_ivec2());
+        if grid_area.contains(inclusive_area.min) && grid_area.contains(inclusive_area.max) {
+            let inclusive_rect = URect::from_corners(inclusive_area.min.as_uvec2(), inclusive_area.max.as_uvec2());
+            return self.create_pathfinding_map_on(target_grid, inclusive_rect);
+        }
+
+        let segment = GridSegment::new_wrapped(target_grid.indexes_rect, inclusive_area, topology.wrap_flags());
+        let shape = (inclusive_area.width() as usize + 1, inclusive_area.height() as usize + 1);
+        let gathered_data = Array2::from_shape_fn(shape, |(x, y)| {
+            let global_index = segment.local_to_global_index(CellIndex2d::new(x, y));
+            self.get_data_at(&global_index).clone()
+        });
+
+        // Global rect of the same size, starting from where the wrapped area starts
+        let area_start: UVec2 = segment.local_to_global_index(CellIndex2d::ZERO).into();
+        let area = URect::from_corners(area_start, area_start + segment.bounds.max);
+        PathfindingMap::new(segment, CowArray::from(gathered_data), area, segment.bounds)
+    }
+
     pub fn has_obstacle_in(&self, area: URect) -> bool {
         let mut has = false;
         for cell_data in self.get_segment_view_of(area) {
@@ -235,6 +343,70 @@ impl GridRelatedData {
         return has;
     }
 
+    #[inline]
+    pub fn is_occupied_at(&self, cell_index2d: &CellIndex2d) -> bool {
+        self.get_data_at(cell_index2d).occupation_state == Occupation::Occupied
+    }
+
+    /// Changes the occupation of the cell and reports whether it was actually different before.
+    /// Closed cells stay occupied, only what they are left as once opened changes.
+    pub fn set_occupation_at(&mut self, cell_index2d: &CellIndex2d, occupation: Occupation) -> bool {
+        let cell_data = self.get_data_at_mut(cell_index2d);
+        if cell_data.closures > 0 {
+            cell_data.free_under_closures = occupation == Occupation::Free;
+            return false;
+        }
+        if cell_data.occupation_state == occupation {
+            return false;
+        }
+        cell_data.occupation_state = occupation;
+        true
+    }
+
+    /// Occupies the cell on behalf of a dynamic source, like a scheduled area or a moving obstacle. Closures
+    /// of all sources are counted together, so the cell stays occupied until each of them is opened again.
+    /// Returns whether the cell got occupied.
+    pub fn close_at(&mut self, cell_index2d: &CellIndex2d) -> bool {
+        let cell_data = self.get_data_at_mut(cell_index2d);
+        cell_data.closures += 1;
+        if cell_data.closures > 1 {
+            return false;
+        }
+        cell_data.free_under_closures = cell_data.occupation_state == Occupation::Free;
+        cell_data.occupation_state = Occupation::Occupied;
+        cell_data.free_under_closures
+    }
+
+    /// Takes back a closure made with `close_at`. Returns whether it was the last one and the cell got freed.
+    pub fn open_at(&mut self, cell_index2d: &CellIndex2d) -> bool {
+        let cell_data = self.get_data_at_mut(cell_index2d);
+        debug_assert!(cell_data.closures > 0, "Cell {cell_index2d} is opened more often than it was closed");
+        cell_data.closures = cell_data.closures.saturating_sub(1);
+        if cell_data.closures > 0 || !cell_data.free_under_closures {
+            return false;
+        }
+        cell_data.free_under_closures = false;
+        cell_data.occupation_state = Occupation::Free;
+        true
+    }
+
+    /// Whether the cell is occupied by itself, rather than only closed by dynamic sources.
+    #[inline]
+    pub fn is_blocked_by_itself_at(&self, cell_index2d: &CellIndex2d) -> bool {
+        self.get_data_at(cell_index2d).is_blocked_by_itself()
+    }
+
+    /// Rewrites detraction factors inside of the area from the clearance layer. Cells that are further
+    /// than `influence_radius` from any obstacle are not detracted at all.
+    pub fn apply_detraction_from_clearance(&mut self, clearance_layer: &ClearanceLayer, area: URect,
+                                           influence_radius: f32) {
+        let mut segment_view = self.get_segment_mut_view_of(area);
+        for ((x, y), cell_data) in segment_view.indexed_iter_mut() {
+            let cell_index2d = CellIndex2d::new(area.min.x + x as u32, area.min.y + y as u32);
+            cell_data.detraction_factor = clearance_layer.detraction_factor_at(&cell_index2d, influence_radius);
+        }
+    }
+
     pub fn set_color_for_index(&mut self, cell_index2d: &CellIndex2d, color: Color) {
         self.data[cell_index2d].color = color
     }
@@ -270,14 +442,14 @@ impl GridRelatedData {
     pub fn fill_with_random_obstacle_pattern(&mut self, grid_parameters: &Grid2D) {
         const BORDER_RANGE: usize = 5;
         let mut rng = rand::thread_rng();
-        let shape = (grid_parameters.row_number as usize,
-                     grid_parameters.column_number as usize);
+        let shape = (grid_parameters.column_number as usize,
+                     grid_parameters.row_number as usize);
         self.data = Array2::from_shape_fn(shape, |idx| {
-            let (row, column) = idx;
+            let (column, row) = idx;
             let occupation = if row < BORDER_RANGE
                 || column < BORDER_RANGE
-                || row >= shape.0 - BORDER_RANGE
-                || column >= shape.1 - BORDER_RANGE
+                || column >= shape.0 - BORDER_RANGE
+                || row >= shape.1 - BORDER_RANGE
             {
                 Occupation::Free
             } else if rng.gen_bool(0.75) {
@@ -290,6 +462,7 @@ impl GridRelatedData {
                 color: Color::WHITE, // Adjust color corresponding to Occupation
                 occupation_state: occupation,
                 detraction_factor: 0.0,
+                ..Default::default()
             }
         });
     }
@@ -357,6 +530,14 @@ impl IndexMut<CellIndex2d> for GridRelatedData {
     }
 }
 
+impl ObstaclesParameters {
+    /// Radius (in cells) around obstacles in which cells receive a detraction factor.
+    #[inline]
+    pub fn influence_radius(&self) -> f32 {
+        (self.influence_area.max_element() / 2) as f32
+    }
+}
+
 impl GridSegment {
     pub fn new(parent: URect, child: URect) -> Self {
         let offset = IVec2 {
@@ -370,22 +551,46 @@ impl GridSegment {
             parent_grid: parent,
             offset,
             bounds,
+            wrap: BVec2::FALSE,
+        }
+    }
+
+    /// Segment that may start before or end after the parent grid along the `wrap` axes,
+    /// continuing on the opposite edge of the parent grid.
+    pub fn new_wrapped(parent: URect, child: IRect, wrap: BVec2) -> Self {
+        let bounds = URect::from_corners(UVec2::ZERO, child.size().as_uvec2());
+
+        Self {
+            parent_grid: parent,
+            offset: child.min - parent.min.as_ivec2(),
+            bounds,
+            wrap,
         }
     }
 
     // Transform from global index to local index
     pub fn global_to_local_index(&self, global_index: CellIndex2d) -> CellIndex2d {
         CellIndex2d {
-            x: (global_index.x as i32 - self.offset.x) as CellIndex1d,
-            y: (global_index.y as i32 - self.offset.y) as CellIndex1d,
+            x: self.wrap_axis(global_index.x as i32 - self.offset.x, self.parent_grid.width(), self.wrap.x),
+            y: self.wrap_axis(global_index.y as i32 - self.offset.y, self.parent_grid.height(), self.wrap.y),
         }
     }
 
     // Transform from local index to global index
     pub fn local_to_global_index(&self, local_index: CellIndex2d) -> CellIndex2d {
         CellIndex2d {
-            x: (local_index.x as i32 + self.offset.x) as CellIndex1d,
-            y: (local_index.y as i32 + self.offset.y) as CellIndex1d,
+            x: self.wrap_axis(local_index.x as i32 + self.offset.x, self.parent_grid.width(), self.wrap.x),
+            y: self.wrap_axis(local_index.y as i32 + self.offset.y, self.parent_grid.height(), self.wrap.y),
+        }
+    }
+
+    // Parent grid is an inclusive rect, so it spans one cell more than its width
+    #[inline]
+    fn wrap_axis(&self, value: i32, parent_width: u32, wraps: bool) -> CellIndex1d {
+        if wraps {
+            value.rem_euclid(parent_width as i32 + 1) as CellIndex1d
+        } else {
+            value as CellIndex1d
         }
     }
 
@@ -401,10 +606,27 @@ impl GridSegment {
         length: u32,
     ) -> URect {
         let starting_cell_index = self.global_to_local_index(start_cell);
-        let ending_cell_index = starting_cell_index + (direction * (length - 1) as i32);
+        let ending_cell_index = starting_cell_index.saturating_offset(direction * (length - 1) as i32);
         URect::from_corners(starting_cell_index.into(), ending_cell_index.into())
     }
 
+    /// Converts a cell of any layout the segment was cut from, going through the storage indexes of the layout.
+    /// Local cells are the cells of the same layout which storage starts at the segment corner.
+    pub fn global_to_local_cell<G: NavGridStorage>(&self, layout: &G, global_cell: G::Cell) -> G::Cell {
+        let local_index = self.global_to_local_index(layout.storage_index(global_cell).into());
+        layout.cell_at_storage(local_index.into())
+    }
+
+    pub fn local_to_global_cell<G: NavGridStorage>(&self, layout: &G, local_cell: G::Cell) -> G::Cell {
+        let global_index = self.local_to_global_index(layout.storage_index(local_cell).into());
+        layout.cell_at_storage(global_index.into())
+    }
+
+    #[inline]
+    pub fn contains_global_cell<G: NavGridStorage>(&self, layout: &G, global_cell: G::Cell) -> bool {
+        layout.contains(global_cell) && self.contains_global(layout.storage_index(global_cell).into())
+    }
+
     // contains_global
     #[inline]
     pub fn contains_global(&self, cell_index2d: CellIndex2d) -> bool {
@@ -0,0 +1,109 @@
+use bevy::math::{IVec2, UVec2, Vec2};
+
+use crate::components::directions::HEX_DIRECTIONS;
+
+use super::definitions::{HexCellIndex, HexGrid2D};
+
+const SQRT_3: f32 = 1.732_050_8;
+
+impl HexCellIndex {
+    #[inline]
+    pub fn offset(&self, offset: IVec2) -> HexCellIndex {
+        HexCellIndex::new(self.q + offset.x, self.r + offset.y)
+    }
+
+    /// Amount of steps between two hexagons.
+    #[inline]
+    pub fn distance(&self, other: &HexCellIndex) -> u32 {
+        let dq = self.q - other.q;
+        let dr = self.r - other.r;
+        (dq.unsigned_abs() + dr.unsigned_abs() + (dq + dr).unsigned_abs()) / 2
+    }
+
+    pub fn iter_neighbors(self) -> impl Iterator<Item=HexCellIndex> {
+        HEX_DIRECTIONS.iter().map(move |direction| self.offset(*direction))
+    }
+}
+
+impl From<HexCellIndex> for IVec2 {
+    fn from(value: HexCellIndex) -> Self {
+        IVec2::new(value.q, value.r)
+    }
+}
+
+impl HexGrid2D {
+    /// Creates a map centered around the world origin.
+    pub fn new(column_number: u32, row_number: u32, cell_radius: f32) -> Self {
+        assert!(column_number > 0 && row_number > 0, "Hex grid should have at least one cell");
+        let cell_width = SQRT_3 * cell_radius;
+        // Odd rows stick out by half of a cell, rows overlap by a quarter of a cell height
+        let map_size = Vec2::new(cell_width * (column_number as f32 + 0.5),
+                                 cell_radius * (1.5 * (row_number - 1) as f32 + 2.0));
+        let origin = -map_size / 2.0 + Vec2::new(cell_width / 2.0, cell_radius);
+
+        HexGrid2D { column_number, row_number, cell_radius, origin }
+    }
+
+    #[inline]
+    pub fn axial_to_offset(cell_index: HexCellIndex) -> IVec2 {
+        IVec2::new(cell_index.q + (cell_index.r - (cell_index.r & 1)) / 2, cell_index.r)
+    }
+
+    #[inline]
+    pub fn offset_to_axial(offset: IVec2) -> HexCellIndex {
+        HexCellIndex::new(offset.x - (offset.y - (offset.y & 1)) / 2, offset.y)
+    }
+
+    #[inline]
+    pub fn contains(&self, cell_index: HexCellIndex) -> bool {
+        let offset = HexGrid2D::axial_to_offset(cell_index);
+        offset.x >= 0 && offset.y >= 0 && offset.x < self.column_number as i32 && offset.y < self.row_number as i32
+    }
+
+    #[inline]
+    pub fn get_size(&self) -> UVec2 {
+        UVec2::new(self.column_number, self.row_number)
+    }
+
+    /// Iterates over all cells row by row, the same order `Grid2D::iter_coordinates` uses.
+    pub fn iter_cells(&self) -> impl Iterator<Item=HexCellIndex> + '_ {
+        (0..self.row_number as i32).flat_map(move |row| {
+            (0..self.column_number as i32).map(move |column| HexGrid2D::offset_to_axial(IVec2::new(column, row)))
+        })
+    }
+
+    pub fn iter_neighbors_of(&self, cell_index: HexCellIndex) -> impl Iterator<Item=HexCellIndex> + '_ {
+        cell_index.iter_neighbors().filter(move |neighbor| self.contains(*neighbor))
+    }
+
+    #[inline]
+    pub fn calculate_cell_position(&self, cell_index: HexCellIndex) -> Vec2 {
+        let x = self.cell_radius * SQRT_3 * (cell_index.q as f32 + cell_index.r as f32 / 2.0);
+        let y = self.cell_radius * 1.5 * cell_index.r as f32;
+        self.origin + Vec2::new(x, y)
+    }
+
+    /// Hexagon under the world position. It is not clamped, so it could be outside of the map.
+    pub fn calculate_cell_index_from_position(&self, position: Vec2) -> HexCellIndex {
+        let local = (position - self.origin) / self.cell_radius;
+        let q = SQRT_3 / 3.0 * local.x - local.y / 3.0;
+        let r = 2.0 / 3.0 * local.y;
+        round_axial(q, r)
+    }
+}
+
+// Rounds fractional axial coordinates to the closest hexagon, fixing the axis with the largest rounding error
+// so that the implied cube coordinates still sum up to zero
+fn round_axial(q: f32, r: f32) -> HexCellIndex {
+    let s = -q - r;
+    let (mut rounded_q, mut rounded_r, rounded_s) = (q.round(), r.round(), s.round());
+    let (q_error, r_error, s_error) = ((rounded_q - q).abs(), (rounded_r - r).abs(), (rounded_s - s).abs());
+
+    if q_error > r_error && q_error > s_error {
+        rounded_q = -rounded_r - rounded_s;
+    } else if r_error > s_error {
+        rounded_r = -rounded_q - rounded_s;
+    }
+
+    HexCellIndex::new(rounded_q as i32, rounded_r as i32)
+}
@@ -0,0 +1,260 @@
+use bevy::math::{IVec2, URect, UVec2};
+use bracket_pathfinding::prelude::SmallVec;
+
+use crate::{
+    components::movement_components::SurfaceTopology,
+    function_libs::grid_calculations::extend_inclusive_rect,
+};
+
+use super::{
+    definitions::{
+        CellIndex2d,
+        Grid2D,
+        GridLayer,
+        GridRelatedData,
+        LayeredCellIndex,
+        LayeredGrid,
+        PortalAreas,
+        PortalLink,
+        SquareNavGrid,
+    },
+    nav_grid_traits::{calculate_octile_cost, NavGrid, NavGridCosts, NavGridNeighbors, NavGridStorage},
+};
+
+impl LayeredGrid {
+    pub fn new() -> Self {
+        LayeredGrid::default()
+    }
+
+    /// Puts the layer on top of the existing ones and returns its index.
+    pub fn add_layer(&mut self, grid: Grid2D, data: GridRelatedData) -> u32 {
+        let next_offset = self.calculate_cells_number();
+        self.layer_offsets.push(next_offset);
+        self.layers.push(GridLayer { grid, data });
+        self.rebuild_portal_estimates();
+        (self.layers.len() - 1) as u32
+    }
+
+    #[inline]
+    pub fn get_layers_number(&self) -> u32 {
+        self.layers.len() as u32
+    }
+
+    #[inline]
+    pub fn get_layer(&self, layer: u32) -> &GridLayer {
+        &self.layers[layer as usize]
+    }
+
+    #[inline]
+    pub fn get_layer_mut(&mut self, layer: u32) -> &mut GridLayer {
+        &mut self.layers[layer as usize]
+    }
+
+    /// Amount of cells in all layers together.
+    pub fn calculate_cells_number(&self) -> usize {
+        self.layers.iter()
+            .map(|layer| (layer.grid.column_number * layer.grid.row_number) as usize)
+            .sum()
+    }
+
+    /// Registers the portal and returns its index. Both ends should be inside of the layers.
+    pub fn add_portal(&mut self, portal: PortalLink) -> usize {
+        let portal_index = self.push_portal(portal);
+        self.rebuild_portal_estimates();
+        portal_index
+    }
+
+    /// Replaces all portals, e.g. with ones deserialized from the level description.
+    pub fn set_portals(&mut self, portals: Vec<PortalLink>) {
+        self.portals.clear();
+        self.portals_by_cell.clear();
+        for portal in portals {
+            self.push_portal(portal);
+        }
+        self.rebuild_portal_estimates();
+    }
+
+    #[inline]
+    pub fn get_portals(&self) -> &[PortalLink] {
+        &self.portals
+    }
+
+    /// Portals that could be entered from the cell, together with the cell they lead to.
+    pub fn iter_portals_from(&self, cell: LayeredCellIndex) -> impl Iterator<Item=(usize, LayeredCellIndex)> + '_ {
+        self.portals_by_cell.get(&cell)
+            .into_iter()
+            .flatten()
+            .map(move |&portal_index| {
+                let portal = &self.portals[portal_index];
+                let destination = if portal.from == cell { portal.to } else { portal.from };
+                (portal_index, destination)
+            })
+    }
+
+    /// Index of the cheapest portal leading from one cell to another, if there is any.
+    pub fn find_portal_between(&self, from: LayeredCellIndex, to: LayeredCellIndex) -> Option<usize> {
+        self.iter_portals_from(from)
+            .filter(|(_, destination)| *destination == to)
+            .map(|(portal_index, _)| portal_index)
+            .min_by_key(|portal_index| self.portals[*portal_index].cost)
+    }
+
+    #[inline]
+    pub fn is_occupied_at(&self, cell: LayeredCellIndex) -> bool {
+        self.get_layer(cell.layer).data.is_occupied_at(&cell.cell)
+    }
+
+    /// Portals in the directions they could be walked, as entrance, exit and cost.
+    fn iter_portal_passages(&self) -> impl Iterator<Item=(LayeredCellIndex, LayeredCellIndex, u32)> + '_ {
+        self.portals.iter().flat_map(|portal| {
+            let backwards = portal.bidirectional.then_some((portal.to, portal.from, portal.cost));
+            std::iter::once((portal.from, portal.to, portal.cost)).chain(backwards)
+        })
+    }
+
+    // Lower bound of paths going through at least one portal, `None` if no portal could connect the cells
+    fn estimate_portal_cost(&self, from: LayeredCellIndex, to: LayeredCellIndex) -> Option<u32> {
+        let portals_cost = (*self.layer_portal_costs.get(from.layer as usize)?.get(to.layer as usize)?)?;
+        let entrance_cost = calculate_octile_cost_to_area(from.cell, self.portal_areas[from.layer as usize].entrances?);
+        let exit_cost = calculate_octile_cost_to_area(to.cell, self.portal_areas[to.layer as usize].exits?);
+        Some(entrance_cost + portals_cost + exit_cost)
+    }
+
+    fn push_portal(&mut self, portal: PortalLink) -> usize {
+        assert!(self.contains(portal.from) && self.contains(portal.to),
+                "Portal {} -> {} leads outside of the layers", portal.from, portal.to);
+        let portal_index = self.portals.len();
+        self.portals.push(portal);
+        self.index_portal(portal_index);
+        portal_index
+    }
+
+    // Cheapest portal chains between every two layers, found like Floyd-Warshall does, and the areas the chains
+    // could start and end in. There are only few layers, so it is rebuilt from scratch.
+    fn rebuild_portal_estimates(&mut self) {
+        let layers_number = self.layers.len();
+        let mut costs: Vec<Vec<Option<u32>>> = vec![vec![None; layers_number]; layers_number];
+        let mut areas = vec![PortalAreas::default(); layers_number];
+        let passages: Vec<_> = self.iter_portal_passages().collect();
+        for (entrance, exit, cost) in passages {
+            let cheapest = &mut costs[entrance.layer as usize][exit.layer as usize];
+            *cheapest = Some(cheapest.map_or(cost, |cheapest| cheapest.min(cost)));
+            let entrances = &mut areas[entrance.layer as usize].entrances;
+            *entrances = Some(extend_inclusive_rect(*entrances, entrance.cell));
+            let exits = &mut areas[exit.layer as usize].exits;
+            *exits = Some(extend_inclusive_rect(*exits, exit.cell));
+        }
+        for via in 0..layers_number {
+            for from in 0..layers_number {
+                for to in 0..layers_number {
+                    if let (Some(first), Some(second)) = (costs[from][via], costs[via][to]) {
+                        let chain = first + second;
+                        costs[from][to] = Some(costs[from][to].map_or(chain, |cheapest| cheapest.min(chain)));
+                    }
+                }
+            }
+        }
+        self.layer_portal_costs = costs;
+        self.portal_areas = areas;
+    }
+
+    #[inline]
+    fn layer_nav_grid(&self, layer: u32) -> SquareNavGrid {
+        let layer = self.get_layer(layer);
+        SquareNavGrid::new(&layer.grid, &layer.data, SurfaceTopology::Bounded)
+    }
+
+    fn index_portal(&mut self, portal_index: usize) {
+        let portal = self.portals[portal_index];
+        self.portals_by_cell.entry(portal.from).or_default().push(portal_index);
+        if portal.bidirectional {
+            self.portals_by_cell.entry(portal.to).or_default().push(portal_index);
+        }
+    }
+}
+
+impl NavGrid for LayeredGrid {
+    type Cell = LayeredCellIndex;
+
+    #[inline]
+    fn contains(&self, cell: LayeredCellIndex) -> bool {
+        cell.layer < self.get_layers_number()
+            && self.get_layer(cell.layer).grid.is_cell_index_in_grid_bounds(cell.cell)
+    }
+}
+
+/// Neighbours within the same layer, plus destinations of the portals starting in the cell.
+impl NavGridNeighbors for LayeredGrid {
+    fn neighbors(&self, cell: LayeredCellIndex) -> SmallVec<[LayeredCellIndex; 8]> {
+        let mut neighbors: SmallVec<[LayeredCellIndex; 8]> = self.layer_nav_grid(cell.layer)
+            .neighbors(cell.cell)
+            .into_iter()
+            .map(|neighbor| LayeredCellIndex::new(cell.layer, neighbor))
+            .collect();
+        neighbors.extend(self.iter_portals_from(cell).map(|(_, destination)| destination));
+        neighbors
+    }
+}
+
+impl NavGridCosts for LayeredGrid {
+    #[inline]
+    fn is_passable(&self, cell: LayeredCellIndex) -> bool {
+        !self.is_occupied_at(cell)
+    }
+
+    fn step_cost(&self, from: LayeredCellIndex, to: LayeredCellIndex) -> u32 {
+        if let Some(portal_index) = self.find_portal_between(from, to) {
+            return self.portals[portal_index].cost;
+        }
+        self.layer_nav_grid(from.layer).step_cost(from.cell, to.cell)
+    }
+
+    // Any path through portals walks to an entrance on the starting layer, pays at least the cheapest chain of
+    // portals between the layers and walks from an exit on the goal layer, so the cheapest of each part bounds
+    // it from below
+    fn estimate_cost(&self, from: LayeredCellIndex, to: LayeredCellIndex) -> u32 {
+        let direct_cost = (from.layer == to.layer).then(|| calculate_layer_octile_cost(from, to));
+        let portal_cost = self.estimate_portal_cost(from, to);
+        match (direct_cost, portal_cost) {
+            (Some(direct_cost), Some(portal_cost)) => direct_cost.min(portal_cost),
+            (Some(cost), None) | (None, Some(cost)) => cost,
+            // The goal is unreachable, nothing to estimate
+            (None, None) => 0,
+        }
+    }
+}
+
+// Octile distance between cells of the same layer
+#[inline]
+fn calculate_layer_octile_cost(from: LayeredCellIndex, to: LayeredCellIndex) -> u32 {
+    calculate_octile_cost(IVec2::from(to.cell) - IVec2::from(from.cell))
+}
+
+// Octile distance from the cell to the closest cell of the area
+#[inline]
+fn calculate_octile_cost_to_area(cell: CellIndex2d, area: URect) -> u32 {
+    let closest = UVec2::from(cell).clamp(area.min, area.max);
+    calculate_octile_cost(closest.as_ivec2() - IVec2::from(cell))
+}
+
+/// Layers are stored one after another in a single column.
+impl NavGridStorage for LayeredGrid {
+    #[inline]
+    fn storage_shape(&self) -> (usize, usize) {
+        (self.calculate_cells_number(), 1)
+    }
+
+    #[inline]
+    fn storage_index(&self, cell: LayeredCellIndex) -> (usize, usize) {
+        let layer_grid = &self.get_layer(cell.layer).grid;
+        let index_in_layer = layer_grid.calc_cell_index_1d_at(cell.cell) as usize;
+        (self.layer_offsets[cell.layer as usize] + index_in_layer, 0)
+    }
+
+    fn cell_at_storage(&self, storage_index: (usize, usize)) -> LayeredCellIndex {
+        let layer = self.layer_offsets.partition_point(|offset| *offset <= storage_index.0) - 1;
+        let index_in_layer = storage_index.0 - self.layer_offsets[layer];
+        let cell: CellIndex2d = self.layers[layer].grid.calc_cell_index_2d_at(index_in_layer as u32);
+        LayeredCellIndex::new(layer as u32, cell)
+    }
+}
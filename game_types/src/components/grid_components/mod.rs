@@ -2,3 +2,9 @@ pub mod definitions;
 pub mod grid_related_traits;
 pub mod grid_related_iterators;
 pub mod grid_2d_traits;
+pub mod chunked_grid_traits;
+pub mod hex_grid_traits;
+pub mod nav_grid_traits;
+pub mod layered_grid_traits;
+pub mod off_mesh_links_traits;
+pub mod cost_schedule_traits;
\ No newline at end of file
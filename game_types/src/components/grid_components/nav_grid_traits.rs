@@ -0,0 +1,386 @@
+use std::{fmt::Debug, hash::Hash};
+
+use bevy::math::{IVec2, URect, Vec2};
+use bracket_pathfinding::prelude::SmallVec;
+
+use crate::{
+    components::directions::Connectivity,
+    function_libs::flow_field::{DIAGONAL_STEP_COST, STRAIGHT_STEP_COST},
+};
+
+use super::{
+    definitions::{CellIndex2d, Grid2D, HexCellIndex, HexGrid2D, PassabilityOverlay, SquareNavGrid},
+    grid_related_iterators::{AreaFullIterator, AreaLineIterator, CoordinateIterator},
+};
+
+/// Cell indexing and bounds. Base of the traits navigation algorithms are generic over, so they could run on
+/// any storage: square, hexagonal or chunked grids, pathfinding maps, etc.
+pub trait NavGrid {
+    type Cell: Copy + Eq + Hash + Debug;
+
+    fn contains(&self, cell: Self::Cell) -> bool;
+}
+
+pub trait NavGridNeighbors: NavGrid {
+    /// Cells within the bounds a single step away. Passability of the cells themselves is not checked.
+    fn neighbors(&self, cell: Self::Cell) -> SmallVec<[Self::Cell; 8]>;
+}
+
+/// Costs of all grids are in the same units: a straight step on an even ground costs `STRAIGHT_STEP_COST`.
+pub trait NavGridCosts: NavGrid {
+    fn is_passable(&self, cell: Self::Cell) -> bool;
+    fn step_cost(&self, from: Self::Cell, to: Self::Cell) -> u32;
+    /// Never overestimates the cost of the cheapest path, so it could be used as an A* heuristic.
+    fn estimate_cost(&self, from: Self::Cell, to: Self::Cell) -> u32;
+}
+
+pub trait NavGridWorld: NavGrid {
+    fn calculate_cell_position(&self, cell: Self::Cell) -> Vec2;
+    fn calculate_cell_from_position(&self, position: Vec2) -> Self::Cell;
+
+    /// World direction of a step between neighbouring cells.
+    fn calculate_step_direction(&self, from: Self::Cell, to: Self::Cell) -> Vec2 {
+        (self.calculate_cell_position(to) - self.calculate_cell_position(from)).normalize_or_zero()
+    }
+}
+
+/// Grids that could keep per-cell data in a dense array, like integration and flow fields.
+/// Areas are rects of storage indexes and iteration directions step along the storage axes.
+pub trait NavGridStorage: NavGrid {
+    fn storage_shape(&self) -> (usize, usize);
+    fn storage_index(&self, cell: Self::Cell) -> (usize, usize);
+    fn cell_at_storage(&self, storage_index: (usize, usize)) -> Self::Cell;
+
+    fn iter_cells_in_area(&self, area: URect) -> impl Iterator<Item=Self::Cell> + '_ {
+        CoordinateIterator::new(area.min.x, area.max.x, area.min.y, area.max.y)
+            .map(|storage_cell| self.cell_at_storage(storage_cell.into()))
+    }
+
+    fn iter_cells_in_line(&self, start: Self::Cell, direction: IVec2,
+                          area: URect) -> impl Iterator<Item=Self::Cell> + '_ {
+        AreaLineIterator::iter_area_in_line_from(self.storage_index(start).into(), direction, area)
+            .map(|storage_cell| self.cell_at_storage(storage_cell.into()))
+    }
+
+    fn iter_cells_in_area_from(&self, start: Self::Cell, direction: IVec2,
+                               area: URect) -> impl Iterator<Item=Self::Cell> + '_ {
+        AreaFullIterator::iter_area_fully_from(self.storage_index(start).into(), direction, area)
+            .map(|storage_cell| self.cell_at_storage(storage_cell.into()))
+    }
+}
+
+// Octile distance in step cost units
+#[inline]
+pub fn calculate_octile_cost(offset: IVec2) -> u32 {
+    let x_distance = offset.x.unsigned_abs();
+    let y_distance = offset.y.unsigned_abs();
+    let diagonal_steps = x_distance.min(y_distance);
+    let straight_steps = x_distance.max(y_distance) - diagonal_steps;
+    diagonal_steps * DIAGONAL_STEP_COST + straight_steps * STRAIGHT_STEP_COST
+}
+
+#[inline]
+fn calculate_square_step_cost(from: CellIndex2d, to: CellIndex2d) -> u32 {
+    if from.x != to.x && from.y != to.y { DIAGONAL_STEP_COST } else { STRAIGHT_STEP_COST }
+}
+
+impl NavGrid for Grid2D {
+    type Cell = CellIndex2d;
+
+    #[inline]
+    fn contains(&self, cell: CellIndex2d) -> bool {
+        self.is_cell_index_in_grid_bounds(cell)
+    }
+}
+
+impl NavGridNeighbors for Grid2D {
+    fn neighbors(&self, cell: CellIndex2d) -> SmallVec<[CellIndex2d; 8]> {
+        cell.neighbors(self, Connectivity::Eight).collect()
+    }
+}
+
+/// Bare layout: every cell is passable and costs only depend on the step length.
+impl NavGridCosts for Grid2D {
+    #[inline]
+    fn is_passable(&self, _cell: CellIndex2d) -> bool {
+        true
+    }
+
+    #[inline]
+    fn step_cost(&self, from: CellIndex2d, to: CellIndex2d) -> u32 {
+        calculate_square_step_cost(from, to)
+    }
+
+    #[inline]
+    fn estimate_cost(&self, from: CellIndex2d, to: CellIndex2d) -> u32 {
+        calculate_octile_cost(IVec2::from(to) - IVec2::from(from))
+    }
+}
+
+impl NavGridWorld for Grid2D {
+    #[inline]
+    fn calculate_cell_position(&self, cell: CellIndex2d) -> Vec2 {
+        Grid2D::calculate_cell_position(self, cell)
+    }
+
+    #[inline]
+    fn calculate_cell_from_position(&self, position: Vec2) -> CellIndex2d {
+        self.calculate_cell_index_from_position(position)
+    }
+}
+
+impl NavGridStorage for Grid2D {
+    #[inline]
+    fn storage_shape(&self) -> (usize, usize) {
+        (self.column_number as usize, self.row_number as usize)
+    }
+
+    #[inline]
+    fn storage_index(&self, cell: CellIndex2d) -> (usize, usize) {
+        (cell.x as usize, cell.y as usize)
+    }
+
+    #[inline]
+    fn cell_at_storage(&self, storage_index: (usize, usize)) -> CellIndex2d {
+        CellIndex2d::new(storage_index.0, storage_index.1)
+    }
+}
+
+impl NavGrid for SquareNavGrid<'_> {
+    type Cell = CellIndex2d;
+
+    #[inline]
+    fn contains(&self, cell: CellIndex2d) -> bool {
+        self.grid.is_cell_index_in_grid_bounds(cell)
+    }
+}
+
+/// Wraps around along the axes the topology wraps. Diagonal steps are not allowed to cut corners of
+/// occupied cells.
+impl NavGridNeighbors for SquareNavGrid<'_> {
+    fn neighbors(&self, cell: CellIndex2d) -> SmallVec<[CellIndex2d; 8]> {
+        let is_free = |offset: IVec2| {
+            self.topology.offset_cell_in(cell, offset, self.grid)
+                .filter(|neighbor| !self.data.is_occupied_at(neighbor))
+        };
+
+        let mut neighbors = SmallVec::new();
+        for offset in Connectivity::Eight.offsets() {
+            let Some(neighbor) = self.topology.offset_cell_in(cell, *offset, self.grid) else {
+                continue;
+            };
+            let is_diagonal = offset.x != 0 && offset.y != 0;
+            if is_diagonal && (is_free(IVec2::new(offset.x, 0)).is_none() || is_free(IVec2::new(0, offset.y)).is_none()) {
+                continue;
+            }
+            neighbors.push(neighbor);
+        }
+        neighbors
+    }
+}
+
+impl NavGridCosts for SquareNavGrid<'_> {
+    #[inline]
+    fn is_passable(&self, cell: CellIndex2d) -> bool {
+        !self.data.is_occupied_at(&cell)
+    }
+
+    #[inline]
+    fn step_cost(&self, from: CellIndex2d, to: CellIndex2d) -> u32 {
+        let step_cost = match self.calculate_wrapped_offset(from, to) {
+            offset if offset.x != 0 && offset.y != 0 => DIAGONAL_STEP_COST,
+            _ => STRAIGHT_STEP_COST,
+        };
+        let cell_data = self.data.get_data_at(&to);
+        (step_cost as f32 * (1.0 + cell_data.detraction_factor)).round() as u32 + cell_data.scheduled_cost
+    }
+
+    #[inline]
+    fn estimate_cost(&self, from: CellIndex2d, to: CellIndex2d) -> u32 {
+        calculate_octile_cost(self.calculate_wrapped_offset(from, to))
+    }
+}
+
+impl NavGridWorld for SquareNavGrid<'_> {
+    #[inline]
+    fn calculate_cell_position(&self, cell: CellIndex2d) -> Vec2 {
+        self.grid.calculate_cell_position(cell)
+    }
+
+    #[inline]
+    fn calculate_cell_from_position(&self, position: Vec2) -> CellIndex2d {
+        self.grid.calculate_cell_index_from_position(position)
+    }
+
+    // Steps over the wrapped edges point away from the grid, not across all of it
+    #[inline]
+    fn calculate_step_direction(&self, from: CellIndex2d, to: CellIndex2d) -> Vec2 {
+        (self.calculate_wrapped_offset(from, to).as_vec2() * self.grid.cell_size).normalize_or_zero()
+    }
+}
+
+impl NavGridStorage for SquareNavGrid<'_> {
+    #[inline]
+    fn storage_shape(&self) -> (usize, usize) {
+        self.grid.storage_shape()
+    }
+
+    #[inline]
+    fn storage_index(&self, cell: CellIndex2d) -> (usize, usize) {
+        self.grid.storage_index(cell)
+    }
+
+    #[inline]
+    fn cell_at_storage(&self, storage_index: (usize, usize)) -> CellIndex2d {
+        self.grid.cell_at_storage(storage_index)
+    }
+}
+
+impl SquareNavGrid<'_> {
+    /// Shortest offset between two cells, going over the wrapped edges if that is shorter.
+    pub fn calculate_wrapped_offset(&self, from: CellIndex2d, to: CellIndex2d) -> IVec2 {
+        let offset = IVec2::from(to) - IVec2::from(from);
+        IVec2::new(shorten_wrapped_axis(offset.x, self.grid.column_number, self.topology.wraps_x()),
+                   shorten_wrapped_axis(offset.y, self.grid.row_number, self.topology.wraps_y()))
+    }
+}
+
+#[inline]
+fn shorten_wrapped_axis(offset: i32, cells_number: u32, wraps: bool) -> i32 {
+    let cells_number = cells_number as i32;
+    if !wraps || offset.abs() * 2 <= cells_number {
+        offset
+    } else {
+        offset - offset.signum() * cells_number
+    }
+}
+
+impl NavGrid for HexGrid2D {
+    type Cell = HexCellIndex;
+
+    #[inline]
+    fn contains(&self, cell: HexCellIndex) -> bool {
+        HexGrid2D::contains(self, cell)
+    }
+}
+
+impl NavGridNeighbors for HexGrid2D {
+    fn neighbors(&self, cell: HexCellIndex) -> SmallVec<[HexCellIndex; 8]> {
+        self.iter_neighbors_of(cell).collect()
+    }
+}
+
+/// Bare layout: every cell is passable and all six steps have the same length.
+impl NavGridCosts for HexGrid2D {
+    #[inline]
+    fn is_passable(&self, _cell: HexCellIndex) -> bool {
+        true
+    }
+
+    #[inline]
+    fn step_cost(&self, _from: HexCellIndex, _to: HexCellIndex) -> u32 {
+        STRAIGHT_STEP_COST
+    }
+
+    #[inline]
+    fn estimate_cost(&self, from: HexCellIndex, to: HexCellIndex) -> u32 {
+        from.distance(&to) * STRAIGHT_STEP_COST
+    }
+}
+
+impl NavGridWorld for HexGrid2D {
+    #[inline]
+    fn calculate_cell_position(&self, cell: HexCellIndex) -> Vec2 {
+        HexGrid2D::calculate_cell_position(self, cell)
+    }
+
+    #[inline]
+    fn calculate_cell_from_position(&self, position: Vec2) -> HexCellIndex {
+        self.calculate_cell_index_from_position(position)
+    }
+}
+
+impl NavGridStorage for HexGrid2D {
+    #[inline]
+    fn storage_shape(&self) -> (usize, usize) {
+        (self.column_number as usize, self.row_number as usize)
+    }
+
+    #[inline]
+    fn storage_index(&self, cell: HexCellIndex) -> (usize, usize) {
+        let offset = HexGrid2D::axial_to_offset(cell);
+        (offset.x as usize, offset.y as usize)
+    }
+
+    #[inline]
+    fn cell_at_storage(&self, storage_index: (usize, usize)) -> HexCellIndex {
+        HexGrid2D::offset_to_axial(IVec2::new(storage_index.0 as i32, storage_index.1 as i32))
+    }
+}
+
+impl<G: NavGrid, F: Fn(G::Cell) -> bool> NavGrid for PassabilityOverlay<'_, G, F> {
+    type Cell = G::Cell;
+
+    #[inline]
+    fn contains(&self, cell: G::Cell) -> bool {
+        self.grid.contains(cell)
+    }
+}
+
+impl<G: NavGridNeighbors, F: Fn(G::Cell) -> bool> NavGridNeighbors for PassabilityOverlay<'_, G, F> {
+    #[inline]
+    fn neighbors(&self, cell: G::Cell) -> SmallVec<[G::Cell; 8]> {
+        self.grid.neighbors(cell)
+    }
+}
+
+impl<G: NavGridCosts, F: Fn(G::Cell) -> bool> NavGridCosts for PassabilityOverlay<'_, G, F> {
+    #[inline]
+    fn is_passable(&self, cell: G::Cell) -> bool {
+        self.grid.is_passable(cell) && (self.is_passable)(cell)
+    }
+
+    #[inline]
+    fn step_cost(&self, from: G::Cell, to: G::Cell) -> u32 {
+        self.grid.step_cost(from, to)
+    }
+
+    #[inline]
+    fn estimate_cost(&self, from: G::Cell, to: G::Cell) -> u32 {
+        self.grid.estimate_cost(from, to)
+    }
+}
+
+impl<G: NavGridWorld, F: Fn(G::Cell) -> bool> NavGridWorld for PassabilityOverlay<'_, G, F> {
+    #[inline]
+    fn calculate_cell_position(&self, cell: G::Cell) -> Vec2 {
+        self.grid.calculate_cell_position(cell)
+    }
+
+    #[inline]
+    fn calculate_cell_from_position(&self, position: Vec2) -> G::Cell {
+        self.grid.calculate_cell_from_position(position)
+    }
+
+    #[inline]
+    fn calculate_step_direction(&self, from: G::Cell, to: G::Cell) -> Vec2 {
+        self.grid.calculate_step_direction(from, to)
+    }
+}
+
+impl<G: NavGridStorage, F: Fn(G::Cell) -> bool> NavGridStorage for PassabilityOverlay<'_, G, F> {
+    #[inline]
+    fn storage_shape(&self) -> (usize, usize) {
+        self.grid.storage_shape()
+    }
+
+    #[inline]
+    fn storage_index(&self, cell: G::Cell) -> (usize, usize) {
+        self.grid.storage_index(cell)
+    }
+
+    #[inline]
+    fn cell_at_storage(&self, storage_index: (usize, usize)) -> G::Cell {
+        self.grid.cell_at_storage(storage_index)
+    }
+}
@@ -0,0 +1,245 @@
+use bevy::math::{IVec2, Vec2};
+use bracket_pathfinding::prelude::SmallVec;
+
+use crate::{
+    components::movement_components::{AgentClassMask, ManeuverLinkSegment},
+    function_libs::grid_calculations::remap_cell_after_resize,
+};
+
+use super::{
+    definitions::{CellIndex2d, Grid2D, GridResized, LinkedNavGrid, OffMeshLink, OffMeshLinks, SquareNavGrid},
+    nav_grid_traits::{calculate_octile_cost, NavGrid, NavGridCosts, NavGridNeighbors, NavGridStorage, NavGridWorld},
+};
+
+/// Grids whose cells could be translated to the cells of `Grid2D`, where off-mesh links are registered.
+pub trait NavGridLinkSpace: NavGrid<Cell=CellIndex2d> {
+    fn to_grid_cell(&self, cell: CellIndex2d) -> CellIndex2d;
+    /// `None` if the grid cell is not covered by this grid.
+    fn from_grid_cell(&self, grid_cell: CellIndex2d) -> Option<CellIndex2d>;
+}
+
+impl OffMeshLink {
+    /// Whether every agent class of the mask is allowed to use the link.
+    #[inline]
+    pub fn allows(&self, agent_classes: AgentClassMask) -> bool {
+        self.enabled && self.agent_classes & agent_classes == agent_classes
+    }
+}
+
+impl OffMeshLinks {
+    /// Registers the link and returns its index.
+    pub fn add_link(&mut self, link: OffMeshLink) -> usize {
+        let link_index = self.links.len();
+        self.links.push(link);
+        self.index_link(link_index);
+        link_index
+    }
+
+    /// Moves the link ends along with the grid after it was resized. Links with an end that was cut off are
+    /// disabled and could not be entered from anywhere anymore, but keep their index.
+    pub fn resize_to(&mut self, grid_resized: &GridResized) {
+        self.links_by_cell.clear();
+        for link_index in 0..self.links.len() {
+            let link = &mut self.links[link_index];
+            match (remap_cell_after_resize(link.from, grid_resized), remap_cell_after_resize(link.to, grid_resized)) {
+                (Some(from), Some(to)) => {
+                    (link.from, link.to) = (from, to);
+                    self.index_link(link_index);
+                }
+                _ => link.enabled = false,
+            }
+        }
+    }
+
+    #[inline]
+    pub fn get_link(&self, link_index: usize) -> &OffMeshLink {
+        &self.links[link_index]
+    }
+
+    #[inline]
+    pub fn set_enabled(&mut self, link_index: usize, enabled: bool) {
+        self.links[link_index].enabled = enabled;
+    }
+
+    pub fn iter_links(&self) -> impl Iterator<Item=&OffMeshLink> {
+        self.links.iter()
+    }
+
+    /// Usable links that could be entered from the cell, together with the cell they lead to.
+    pub fn iter_links_from(&self, cell: CellIndex2d, agent_classes: AgentClassMask)
+                           -> impl Iterator<Item=(usize, CellIndex2d)> + '_ {
+        self.links_by_cell.get(&cell)
+            .into_iter()
+            .flatten()
+            .filter(move |&&link_index| self.links[link_index].allows(agent_classes))
+            .map(move |&link_index| {
+                let link = &self.links[link_index];
+                let destination = if link.from == cell { link.to } else { link.from };
+                (link_index, destination)
+            })
+    }
+
+    /// Index of the cheapest usable link leading from one cell to another, if there is any.
+    pub fn find_link_between(&self, from: CellIndex2d, to: CellIndex2d, agent_classes: AgentClassMask)
+                             -> Option<usize> {
+        self.iter_links_from(from, agent_classes)
+            .filter(|(_, destination)| *destination == to)
+            .map(|(link_index, _)| link_index)
+            .min_by_key(|link_index| self.links[*link_index].cost)
+    }
+
+    /// Steps of the path going over links. Steps between adjacent cells count only if the link there is not
+    /// more expensive than walking, since otherwise the agent would have just walked.
+    pub fn find_links_along(&self, path: &[CellIndex2d], agent_classes: AgentClassMask) -> Vec<ManeuverLinkSegment> {
+        path.windows(2)
+            .enumerate()
+            .filter_map(|(waypoint, step)| {
+                let link_index = self.find_link_between(step[0], step[1], agent_classes)?;
+                let offset = IVec2::from(step[1]) - IVec2::from(step[0]);
+                let is_adjacent = offset.abs().max_element() == 1;
+                (!is_adjacent || self.links[link_index].cost <= calculate_octile_cost(offset))
+                    .then_some(ManeuverLinkSegment { start_waypoint: waypoint, link: link_index })
+            })
+            .collect()
+    }
+
+    fn index_link(&mut self, link_index: usize) {
+        let link = self.links[link_index];
+        self.links_by_cell.entry(link.from).or_default().push(link_index);
+        if link.bidirectional {
+            self.links_by_cell.entry(link.to).or_default().push(link_index);
+        }
+    }
+}
+
+impl<'a, G: NavGridLinkSpace + NavGridCosts> LinkedNavGrid<'a, G> {
+    pub fn new(grid: &'a G, links: &'a OffMeshLinks, agent_classes: AgentClassMask) -> Self {
+        let has_shortcuts = links.iter_links()
+            .filter(|link| link.allows(agent_classes))
+            .any(|link| match (grid.from_grid_cell(link.from), grid.from_grid_cell(link.to)) {
+                (Some(from), Some(to)) => link.cost < grid.estimate_cost(from, to),
+                _ => false,
+            });
+        LinkedNavGrid { grid, links, agent_classes, has_shortcuts }
+    }
+
+    /// Whether some usable link is cheaper than walking between its ends, so walking estimates overestimate.
+    #[inline]
+    pub fn has_shortcuts(&self) -> bool {
+        self.has_shortcuts
+    }
+
+    /// Destinations of the usable links starting in the cell, that are covered by the grid.
+    fn iter_link_destinations(&self, cell: CellIndex2d) -> impl Iterator<Item=(usize, CellIndex2d)> + '_ {
+        self.links.iter_links_from(self.grid.to_grid_cell(cell), self.agent_classes)
+            .filter_map(|(link_index, destination)| {
+                self.grid.from_grid_cell(destination).map(|destination| (link_index, destination))
+            })
+    }
+
+    fn find_link_between(&self, from: CellIndex2d, to: CellIndex2d) -> Option<usize> {
+        self.links.find_link_between(self.grid.to_grid_cell(from), self.grid.to_grid_cell(to), self.agent_classes)
+    }
+}
+
+impl<G: NavGridLinkSpace + NavGridCosts> NavGrid for LinkedNavGrid<'_, G> {
+    type Cell = CellIndex2d;
+
+    #[inline]
+    fn contains(&self, cell: CellIndex2d) -> bool {
+        self.grid.contains(cell)
+    }
+}
+
+impl<G: NavGridLinkSpace + NavGridCosts + NavGridNeighbors> NavGridNeighbors for LinkedNavGrid<'_, G> {
+    fn neighbors(&self, cell: CellIndex2d) -> SmallVec<[CellIndex2d; 8]> {
+        let mut neighbors = self.grid.neighbors(cell);
+        for (_, destination) in self.iter_link_destinations(cell) {
+            if !neighbors.contains(&destination) {
+                neighbors.push(destination);
+            }
+        }
+        neighbors
+    }
+}
+
+impl<G: NavGridLinkSpace + NavGridCosts + NavGridNeighbors> NavGridCosts for LinkedNavGrid<'_, G> {
+    #[inline]
+    fn is_passable(&self, cell: CellIndex2d) -> bool {
+        self.grid.is_passable(cell)
+    }
+
+    // Walking to an adjacent cell may still be cheaper than a link between the same cells
+    fn step_cost(&self, from: CellIndex2d, to: CellIndex2d) -> u32 {
+        let link_cost = self.find_link_between(from, to).map(|link_index| self.links.get_link(link_index).cost);
+        let is_adjacent = self.grid.neighbors(from).contains(&to);
+        match (link_cost, is_adjacent) {
+            (Some(link_cost), true) => link_cost.min(self.grid.step_cost(from, to)),
+            (Some(link_cost), false) => link_cost,
+            (None, _) => self.grid.step_cost(from, to),
+        }
+    }
+
+    #[inline]
+    fn estimate_cost(&self, from: CellIndex2d, to: CellIndex2d) -> u32 {
+        if self.has_shortcuts { 0 } else { self.grid.estimate_cost(from, to) }
+    }
+}
+
+impl<G: NavGridLinkSpace + NavGridCosts + NavGridWorld> NavGridWorld for LinkedNavGrid<'_, G> {
+    #[inline]
+    fn calculate_cell_position(&self, cell: CellIndex2d) -> Vec2 {
+        self.grid.calculate_cell_position(cell)
+    }
+
+    #[inline]
+    fn calculate_cell_from_position(&self, position: Vec2) -> CellIndex2d {
+        self.grid.calculate_cell_from_position(position)
+    }
+
+    #[inline]
+    fn calculate_step_direction(&self, from: CellIndex2d, to: CellIndex2d) -> Vec2 {
+        self.grid.calculate_step_direction(from, to)
+    }
+}
+
+impl<G: NavGridLinkSpace + NavGridCosts + NavGridStorage> NavGridStorage for LinkedNavGrid<'_, G> {
+    #[inline]
+    fn storage_shape(&self) -> (usize, usize) {
+        self.grid.storage_shape()
+    }
+
+    #[inline]
+    fn storage_index(&self, cell: CellIndex2d) -> (usize, usize) {
+        self.grid.storage_index(cell)
+    }
+
+    #[inline]
+    fn cell_at_storage(&self, storage_index: (usize, usize)) -> CellIndex2d {
+        self.grid.cell_at_storage(storage_index)
+    }
+}
+
+impl NavGridLinkSpace for Grid2D {
+    #[inline]
+    fn to_grid_cell(&self, cell: CellIndex2d) -> CellIndex2d {
+        cell
+    }
+
+    #[inline]
+    fn from_grid_cell(&self, grid_cell: CellIndex2d) -> Option<CellIndex2d> {
+        self.is_cell_index_in_grid_bounds(grid_cell).then_some(grid_cell)
+    }
+}
+
+impl NavGridLinkSpace for SquareNavGrid<'_> {
+    #[inline]
+    fn to_grid_cell(&self, cell: CellIndex2d) -> CellIndex2d {
+        cell
+    }
+
+    #[inline]
+    fn from_grid_cell(&self, grid_cell: CellIndex2d) -> Option<CellIndex2d> {
+        self.grid.from_grid_cell(grid_cell)
+    }
+}
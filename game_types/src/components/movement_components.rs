@@ -1,5 +1,6 @@
-use bevy::prelude::{Component, Vec2};
-use crate::components::pathfinding_components::Pathfinder;
+use bevy::prelude::{Component, Entity, Event, Resource, UVec2, Vec2, Vec3};
+use derive_more::Constructor;
+use crate::components::{grid_components::definitions::CellIndex2d, pathfinding_components::Pathfinder};
 
 pub type Coordinate = f32;
 
@@ -9,6 +10,16 @@ pub struct MoveTag;
 #[derive(Component, Clone, Default)]
 pub struct ObstacleTag;
 
+/// Shape an `ObstacleTag` entity covers on the grid, in world units around its transform. Rectangles and
+/// polygons turn with the transform.
+#[derive(Component, Clone, Debug)]
+pub enum ObstacleFootprint {
+    Circle { radius: f32 },
+    Rect { half_size: Vec2 },
+    // Vertices in order, either clockwise or counter-clockwise
+    Polygon { vertices: Vec<Vec2> },
+}
+
 #[derive(Component, Clone, Default)]
 pub struct PerformManeuver;
 
@@ -25,14 +36,426 @@ impl From<SurfaceCoordinate> for Vec2 {
     }
 }
 
+impl From<Vec2> for SurfaceCoordinate {
+    fn from(value: Vec2) -> Self {
+        SurfaceCoordinate { latitude: value.x, longitude: value.y }
+    }
+}
+
+/// How the surface behaves at its edges. Coordinates, neighbours, pathfinding areas and flow field
+/// integration either wrap around or stop at the edges along each axis.
+#[derive(Resource, Clone, Copy, Default, Eq, PartialEq, Debug)]
+pub enum SurfaceTopology {
+    #[default]
+    Bounded,
+    Torus,
+    // Wraps around along the x axis only
+    CylinderX,
+    // Wraps around along the y axis only
+    CylinderY,
+    // Grid laid over a planet in an equirectangular way: the x axis goes around the equator and wraps,
+    // the y axis goes from the south to the north pole. Poles are not connected across.
+    Sphere,
+}
+
+/// Planet the grid is projected on when the topology is `SurfaceTopology::Sphere`.
+#[derive(Resource, Constructor, Clone, Copy, Debug)]
+pub struct SphericalSurface {
+    pub radius: f32,
+    pub center: Vec3,
+}
+
 pub struct CoordinateBounds {
     pub min: Coordinate,
     pub max: Coordinate,
 }
 
-#[derive(Component, Default)]
+#[derive(Component)]
 pub struct Maneuver {
     pub path_points: Vec<SurfaceCoordinate>,
     pub progress: f32,
     pub last_destination: Pathfinder,
-}
\ No newline at end of file
+    // Sorted by the waypoint they start at
+    pub link_segments: Vec<ManeuverLinkSegment>,
+    // Links started since the last `take_started_links`, in either direction
+    pub(crate) started_links: Vec<usize>,
+    pub curve_kind: CurveKind,
+    // Curve length from the start up to evenly spaced progress samples, see `advance_by_distance`
+    pub(crate) arc_lengths: Vec<f32>,
+    pub playback_mode: PlaybackMode,
+    // Multiplies the speed the maneuver is played with
+    pub time_scale: f32,
+    pub(crate) paused: bool,
+    // Going from the end towards the start, for the ping-pong and reverse playback
+    pub(crate) backwards: bool,
+    pub(crate) started: bool,
+    // Waypoints passed since the last `take_passed_waypoints`, in the order they were passed
+    pub(crate) passed_waypoints: Vec<usize>,
+    // Seconds the maneuver stands at each waypoint, e.g. waiting for reserved cells to clear. Empty if it
+    // does not stop anywhere.
+    pub(crate) dwell_times: Vec<f32>,
+    // Seconds left to stand at the waypoint the maneuver has reached
+    pub(crate) dwell_remaining: f32,
+}
+
+impl Default for Maneuver {
+    fn default() -> Self {
+        Maneuver::new(Vec::new())
+    }
+}
+
+/// Keeps checking the part of the maneuver ahead of the agent and plans around cells that got blocked.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct PathValidation {
+    // Seconds between the checks
+    pub interval: f32,
+    // Waypoints ahead that are checked. Detours are searched within as many cells around the agent.
+    pub look_ahead: u32,
+    // Cells with at least that many other agents count as blocked
+    pub crowd_limit: u32,
+    pub(crate) since_last_check: f32,
+}
+
+impl Default for PathValidation {
+    fn default() -> Self {
+        PathValidation { interval: 0.25, look_ahead: 8, crowd_limit: 2, since_last_check: 0.0 }
+    }
+}
+
+/// How far an agent that stopped making progress has escalated.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub enum StuckStage {
+    #[default]
+    Moving,
+    // The whole way to the goal is planned again
+    Replanning,
+    // Stands still, waiting for the way to clear
+    Waiting,
+    // Heads for a free cell near the goal instead
+    Rerouting,
+    // Nothing helped, the maneuver is stopped
+    GivenUp,
+}
+
+/// Watches an agent performing a maneuver and escalates while it does not get anywhere.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct StuckDetector {
+    // Moving less than that away from where the agent was last seen making progress does not count,
+    // in surface coordinate units
+    pub progress_distance: f32,
+    // Seconds without progress before escalating to the next stage
+    pub patience: f32,
+    // Seconds the agent waits before looking for another goal
+    pub wait_duration: f32,
+    pub(crate) stage: StuckStage,
+    pub(crate) anchor: Vec2,
+    pub(crate) stalled_for: f32,
+}
+
+impl Default for StuckDetector {
+    fn default() -> Self {
+        StuckDetector {
+            progress_distance: 0.01,
+            patience: 1.5,
+            wait_duration: 2.0,
+            stage: StuckStage::Moving,
+            anchor: Vec2::ZERO,
+            stalled_for: 0.0,
+        }
+    }
+}
+
+/// How far ahead an agent checks its way for obstacles and how large the area is it searches a detour in.
+/// Faster agents look further ahead, so they start going around earlier.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct AvoidanceSettings {
+    // Cells in front that are checked even when standing still
+    pub min_look_ahead: u32,
+    // Cells in front are added for the distance covered in that many seconds at the current speed
+    pub look_ahead_time: f32,
+    pub max_look_ahead: u32,
+    // Size of the area around the agent a detour is searched in, in cells
+    pub search_window: UVec2,
+}
+
+impl Default for AvoidanceSettings {
+    fn default() -> Self {
+        AvoidanceSettings { min_look_ahead: 2, look_ahead_time: 0.5, max_look_ahead: 8, search_window: UVec2::new(8, 8) }
+    }
+}
+
+/// Axis the lanes of a lawnmower pattern go along.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub enum SweepAxis {
+    #[default]
+    Vertical,
+    Horizontal,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub enum SpiralDirection {
+    Inwards,
+    #[default]
+    Outwards,
+}
+
+/// How the maneuver is played once it reaches an end of its path.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub enum PlaybackMode {
+    // From the start to the end, then done
+    #[default]
+    Once,
+    // Jumps back to the start after the end and goes on
+    Loop,
+    // Turns around at either end and goes on
+    PingPong,
+    // From the end to the start, then done
+    Reverse,
+}
+
+/// How the maneuver curve is laid through its path points.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub enum CurveKind {
+    // Centripetal Catmull-Rom going through every point
+    #[default]
+    CatmullRom,
+    // Cubic Bezier sections: every third point is passed through, the two in between are its handles
+    BezierChain,
+    // Uniform cubic B-spline, the points only pull the curve. Passes through the first and the last one.
+    BSpline,
+    // Goes through every point with the given tangent, per section. Missing tangents are estimated
+    // from the neighbouring points.
+    Hermite { tangents: Vec<Vec2> },
+    // Shortest paths of turns and straights for vehicles that can not turn tighter than the radius,
+    // in surface coordinate units. Headings at the points follow the path. With `reverse_sections` a whole
+    // section could also be driven backwards, whichever is shorter. Gears do not change within a section.
+    Dubins { turn_radius: f32, reverse_sections: bool },
+}
+
+/// Point of a maneuver curve together with the unit direction the curve goes in there.
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub struct CurveSample {
+    pub position: Vec2,
+    pub tangent: Vec2,
+    // The vehicle drives backwards here, facing against the tangent
+    pub backwards: bool,
+}
+
+/// Part of the maneuver going over an off-mesh link, starting at the given waypoint.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct ManeuverLinkSegment {
+    pub start_waypoint: usize,
+    pub link: usize,
+}
+
+/// Bit mask of agent classes, e.g. infantry or vehicles, used to filter off-mesh links.
+pub type AgentClassMask = u32;
+
+pub const ALL_AGENT_CLASSES: AgentClassMask = AgentClassMask::MAX;
+
+#[derive(Component, Clone, Copy, Debug)]
+pub struct AgentClass {
+    pub mask: AgentClassMask,
+}
+
+/// Sent when an agent starts going over an off-mesh link, so the matching animation could be played.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct OffMeshLinkTraversalStarted {
+    pub entity: Entity,
+    pub link: usize,
+}
+
+#[derive(Event, Clone, Copy, Debug)]
+pub struct ManeuverStarted {
+    pub entity: Entity,
+}
+
+/// Sent every time the agent performing a maneuver passes one of its path points.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct WaypointPassed {
+    pub entity: Entity,
+    pub waypoint: usize,
+}
+
+/// Sent when a maneuver played once or in reverse reaches its end. Looping ones never finish.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct ManeuverFinished {
+    pub entity: Entity,
+}
+
+#[derive(Clone, Copy, Default, Eq, PartialEq, Hash, Debug)]
+pub enum FormationShape {
+    // Side by side with the leader
+    #[default]
+    Line,
+    // Diagonal ranks behind the leader on both sides
+    Wedge,
+    // One behind another
+    Column,
+    // Rows of equal width behind the leader
+    Box,
+}
+
+/// Squad led by the entity the component is on. Slots are laid out around the leader relative to
+/// the direction it moves in, either along the flow field or its maneuver.
+#[derive(Component, Clone, Debug)]
+pub struct Formation {
+    pub shape: FormationShape,
+    // Distance between neighbouring slots, in cells
+    pub spacing: f32,
+    pub members: Vec<Entity>,
+    // Cells of the slots, indexed by the slot
+    pub(crate) slot_cells: Vec<CellIndex2d>,
+    // Last direction the leader moved in, kept while it stands still
+    pub(crate) heading: Vec2,
+    // Shape the slots were last handed out for
+    pub(crate) assigned_shape: FormationShape,
+}
+
+/// Member of the formation of the leader, following the slot with the given index. Whenever slots are handed
+/// out again, the way to the slot is planned together with the other members, so they would not collide.
+#[derive(Component, Clone, Debug)]
+pub struct FormationMember {
+    pub leader: Entity,
+    pub slot: usize,
+    // One cell per step on the way to the slot, repeated cells are waits
+    pub(crate) route: Vec<CellIndex2d>,
+    // Steps of the route taken so far, the fraction is the part of the way to the next cell
+    pub(crate) route_progress: f32,
+}
+
+/// Inertia of the agent: instead of following the desired direction right away it accelerates, turns and
+/// brakes within the limits. Speeds are in surface coordinate units per second, the turn rate in radians
+/// per second. Takes precedence over `MovementSpeed`.
+#[derive(Component, Clone, Copy, Default, Debug)]
+pub struct Kinematics {
+    pub velocity: Vec2,
+    pub max_speed: f32,
+    pub max_acceleration: f32,
+    pub max_turn_rate: f32,
+}
+
+/// Direction the agent faces, in radians counterclockwise from the x axis. It follows the movement
+/// direction smoothly instead of snapping to it.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Heading {
+    pub angle: f32,
+    // How fast the remaining angle shrinks, per second. Higher is snappier.
+    pub turn_speed: f32,
+}
+
+impl Default for Heading {
+    fn default() -> Self {
+        Heading { angle: 0.0, turn_speed: 8.0 }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ZOrderPolicy {
+    // All agents share the same depth
+    Flat,
+    // Agents lower on the grid are drawn over the ones above them, within the range above the agents layer
+    SortByRow { range: f32 },
+}
+
+/// Depths sprites are drawn at.
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct ZLayering {
+    pub cells: f32,
+    pub flow_arrows: f32,
+    pub agents: f32,
+    pub policy: ZOrderPolicy,
+}
+
+impl Default for ZLayering {
+    fn default() -> Self {
+        ZLayering { cells: 0.0, flow_arrows: 1.0, agents: 10.0, policy: ZOrderPolicy::Flat }
+    }
+}
+
+/// Velocity the agent moved with during the last frame, in surface coordinate units per second.
+#[derive(Component, Clone, Copy, Default, Debug)]
+pub struct Velocity {
+    pub value: Vec2,
+}
+
+/// Sum of the velocities the steering behaviours of the agent ask for, recalculated every frame.
+/// Agents with it are moved by `steering_movement_system` instead of the flow field alone.
+#[derive(Component, Clone, Copy, Default, Debug)]
+pub struct DesiredVelocity {
+    pub value: Vec2,
+}
+
+/// How much of the flow field direction and of the steering behaviours the agent follows.
+#[derive(Component, Clone, Copy, Debug, Constructor)]
+pub struct SteeringBlend {
+    pub flow_weight: f32,
+    pub steering_weight: f32,
+}
+
+impl Default for SteeringBlend {
+    fn default() -> Self {
+        SteeringBlend { flow_weight: 1.0, steering_weight: 1.0 }
+    }
+}
+
+// Steering behaviours. Positions and distances are in surface coordinate units, see `SurfaceCoordinate`.
+
+#[derive(Component, Clone, Copy, Debug, Constructor)]
+pub struct Seek {
+    pub target: Vec2,
+}
+
+#[derive(Component, Clone, Copy, Debug, Constructor)]
+pub struct Flee {
+    pub threat: Vec2,
+    // Threats further away are ignored
+    pub panic_distance: f32,
+}
+
+/// Seeks the target, slowing down within `slowing_distance` so it stops there.
+#[derive(Component, Clone, Copy, Debug, Constructor)]
+pub struct Arrive {
+    pub target: Vec2,
+    pub slowing_distance: f32,
+}
+
+/// Seeks the place the target entity is heading to.
+#[derive(Component, Clone, Copy, Debug, Constructor)]
+pub struct Pursue {
+    pub target: Entity,
+}
+
+/// Flees from the place the threat entity is heading to.
+#[derive(Component, Clone, Copy, Debug, Constructor)]
+pub struct Evade {
+    pub threat: Entity,
+    pub panic_distance: f32,
+}
+
+/// Random but smooth movement: the agent seeks a point moving along a circle projected in front of it.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Wander {
+    pub radius: f32,
+    pub distance: f32,
+    // Largest change of the point angle per second, in radians
+    pub jitter: f32,
+    pub(crate) angle: f32,
+}
+
+/// Arrives at the offset from the leader entity, rotated the way the leader moves. The y axis of the offset
+/// points forward.
+#[derive(Component, Clone, Copy, Debug, Constructor)]
+pub struct FollowLeader {
+    pub leader: Entity,
+    pub offset: Vec2,
+    pub slowing_distance: f32,
+}
+
+/// Moves behind the closest obstacle, as seen from the threat entity.
+#[derive(Component, Clone, Copy, Debug, Constructor)]
+pub struct Hide {
+    pub threat: Entity,
+    // Obstacles further away are not considered, in cells
+    pub search_radius: u32,
+}
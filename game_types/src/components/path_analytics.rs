@@ -1,6 +1,5 @@
 use bracket_pathfinding::prelude::{a_star_search, BaseMap, NavigationPath, SmallVec};
 use colored::{ColoredString, Colorize};
-use pathfinding::prelude::astar;
 
 use crate::{
     components::{
@@ -10,18 +9,26 @@ use crate::{
             Grid2D,
             GridCellData,
             GridRelatedData,
+            LinkedNavGrid,
             Occupation,
+            OffMeshLinks,
         },
-        movement_components::SurfaceCoordinate,
+        movement_components::{AgentClassMask, SurfaceCoordinate},
         pathfinding_components::{Pathfinder, PathfindingMap},
     },
-    function_libs::grid_calculations::{
-        self
-        ,
+    function_libs::{
+        flow_field::STRAIGHT_STEP_COST,
+        grid_calculations::{
+            self
+            ,
+        },
+        navigation,
     },
 };
-use crate::components::directions::Direction;
+use crate::components::directions::{Connectivity, Direction};
 use crate::components::grid_components::grid_related_iterators::AreaLineIterator;
+use crate::components::grid_components::nav_grid_traits::{NavGrid, NavGridCosts, NavGridNeighbors, NavGridStorage};
+use crate::components::grid_components::off_mesh_links_traits::NavGridLinkSpace;
 
 impl<'a> PathfindingMap<'a> {
     #[deprecated]
@@ -43,6 +50,31 @@ impl<'a> PathfindingMap<'a> {
         return Some(self.convert_normalized_2d_to_global_points(&path_points));
     }
 
+    /// Same as `calculate_path_coordinates_global`, but also going over the off-mesh links usable by
+    /// the agent classes. Both ends of a link should be inside of the map area for it to be used.
+    pub fn calculate_path_coordinates_global_with_links(&self, pathfinder: Pathfinder, links: &OffMeshLinks,
+                                                        agent_classes: AgentClassMask)
+                                                        -> Option<Vec<CellIndex2d>> {
+        let linked_map = LinkedNavGrid::new(self, links, agent_classes);
+        let (path_points, _) = navigation::find_path_on(&linked_map, pathfinder.start, pathfinder.end)?;
+        Some(self.convert_normalized_2d_to_global_points(&path_points))
+    }
+
+    /// Same as `calculate_path_coordinates_global_with_links`, but with a custom heuristic taking global cells,
+    /// e.g. the great circle one on a spherical surface. The heuristic is in step cost units and should never
+    /// overestimate walking, it is dropped while some usable link is a shortcut.
+    pub fn calculate_path_coordinates_global_with(&self, pathfinder: Pathfinder, links: &OffMeshLinks,
+                                                  agent_classes: AgentClassMask,
+                                                  heuristic: impl Fn(CellIndex2d, CellIndex2d) -> u32)
+                                                  -> Option<Vec<CellIndex2d>> {
+        let linked_map = LinkedNavGrid::new(self, links, agent_classes);
+        let goal = self.grid_segment.local_to_global_index(pathfinder.end);
+        let (path_points, _) = navigation::find_path_on_with(&linked_map, pathfinder.start, pathfinder.end, |p| {
+            if linked_map.has_shortcuts() { 0 } else { heuristic(self.grid_segment.local_to_global_index(p), goal) }
+        })?;
+        Some(self.convert_normalized_2d_to_global_points(&path_points))
+    }
+
     #[inline]
     fn calculate_path_local(&self, pathfinder: Pathfinder) -> NavigationPath {
         //log what is going to happen, printing out the area as well
@@ -55,10 +87,13 @@ impl<'a> PathfindingMap<'a> {
 
     #[inline]
     fn find_path_points(&self, pathfinder: Pathfinder) -> Option<(Vec<CellIndex2d>, u32)> {
-        astar(&pathfinder.start,
-              |p| self.calculate_successors(p),
-              |p| p.distance(&pathfinder.end),
-              |p| *p == pathfinder.end)
+        self.find_path_points_with(pathfinder, |p| self.estimate_cost(*p, pathfinder.end))
+    }
+
+    #[inline]
+    fn find_path_points_with(&self, pathfinder: Pathfinder, heuristic: impl Fn(&CellIndex2d) -> u32)
+                             -> Option<(Vec<CellIndex2d>, u32)> {
+        navigation::find_path_on_with(self, pathfinder.start, pathfinder.end, |p| heuristic(&p))
     }
 
     fn convert_normalized_1d_to_global_points(&self, normalized_points: &Vec<usize>) -> Vec<CellIndex2d> {
@@ -132,18 +167,19 @@ impl<'a> PathfindingMap<'a> {
     pub fn calculate_successors(&self, cell_index2d: &CellIndex2d) -> SmallVec<[(CellIndex2d, u32); 10]> {
         let mut successors = SmallVec::<[(CellIndex2d, u32); 10]>::new();
 
-        for direction in [Direction::North.as_vector(), Direction::East.as_vector(),
-            Direction::South.as_vector(), Direction::West.as_vector()]
+        for direction in Connectivity::Four.offsets()
         {
-            let outer_cell = *cell_index2d + direction;
             // Boundary check
+            let Some(outer_cell) = cell_index2d.checked_offset(*direction) else {
+                continue;
+            };
             if !self.is_valid_index(&outer_cell) {
                 continue;
             }
             if self[outer_cell].occupation_state != Occupation::Free {
                 continue;
             }
-            successors.push((outer_cell, 1));
+            successors.push((outer_cell, STRAIGHT_STEP_COST));
         }
 
         successors
@@ -232,12 +268,12 @@ impl<'a> BaseMap for PathfindingMap<'a> {
 
         let mut exits = SmallVec::<[(usize, f32); 10]>::new();
 
-        for direction in [Direction::North.as_vector(), Direction::East.as_vector(),
-            Direction::South.as_vector(), Direction::West.as_vector()]
+        for direction in Connectivity::Four.offsets()
         {
-            let outer_cell = central_cell + direction;
-
             // Boundary check
+            let Some(outer_cell) = central_cell.checked_offset(*direction) else {
+                continue;
+            };
             if !self.is_valid_index(&outer_cell) {
                 continue;
             }
@@ -251,5 +287,68 @@ impl<'a> BaseMap for PathfindingMap<'a> {
     }
 }
 
+/// Cells are local to the map area.
+impl NavGrid for PathfindingMap<'_> {
+    type Cell = CellIndex2d;
 
+    #[inline]
+    fn contains(&self, cell: CellIndex2d) -> bool {
+        self.is_valid_index(&cell)
+    }
+}
 
+impl NavGridNeighbors for PathfindingMap<'_> {
+    fn neighbors(&self, cell: CellIndex2d) -> SmallVec<[CellIndex2d; 8]> {
+        Connectivity::Four.offsets().iter()
+            .filter_map(|offset| cell.checked_offset(*offset))
+            .filter(|neighbor| self.is_valid_index(neighbor))
+            .collect()
+    }
+}
+
+impl NavGridCosts for PathfindingMap<'_> {
+    #[inline]
+    fn is_passable(&self, cell: CellIndex2d) -> bool {
+        self[cell].occupation_state == Occupation::Free
+    }
+
+    #[inline]
+    fn step_cost(&self, _from: CellIndex2d, _to: CellIndex2d) -> u32 {
+        STRAIGHT_STEP_COST
+    }
+
+    #[inline]
+    fn estimate_cost(&self, from: CellIndex2d, to: CellIndex2d) -> u32 {
+        from.distance(&to) * STRAIGHT_STEP_COST
+    }
+}
+
+impl NavGridStorage for PathfindingMap<'_> {
+    #[inline]
+    fn storage_shape(&self) -> (usize, usize) {
+        self.get_data_shape()
+    }
+
+    #[inline]
+    fn storage_index(&self, cell: CellIndex2d) -> (usize, usize) {
+        (cell.x as usize, cell.y as usize)
+    }
+
+    #[inline]
+    fn cell_at_storage(&self, storage_index: (usize, usize)) -> CellIndex2d {
+        CellIndex2d::new(storage_index.0, storage_index.1)
+    }
+}
+
+impl NavGridLinkSpace for PathfindingMap<'_> {
+    #[inline]
+    fn to_grid_cell(&self, cell: CellIndex2d) -> CellIndex2d {
+        self.grid_segment.local_to_global_index(cell)
+    }
+
+    #[inline]
+    fn from_grid_cell(&self, grid_cell: CellIndex2d) -> Option<CellIndex2d> {
+        let local_cell = self.grid_segment.global_to_local_index(grid_cell);
+        self.is_valid_index(&local_cell).then_some(local_cell)
+    }
+}
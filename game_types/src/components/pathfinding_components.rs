@@ -1,13 +1,14 @@
 use std::{
     borrow::Borrow,
+    collections::HashMap,
     ops::Index,
 };
 
-use bevy::prelude::{Component, URect};
+use bevy::prelude::{Component, Entity, Resource, URect};
 use colored::{ColoredString, Colorize};
 use derive_more::Constructor;
 use ndarray::{
-    ArrayView2,
+    CowArray,
     Ix2,
     iter::IndexedIter,
 };
@@ -36,7 +37,8 @@ impl Pathfinder {
 #[derive(Component, Constructor)]
 pub struct PathfindingMap<'a> {
     pub(super) grid_segment: GridSegment,
-    grid_segment_data: ArrayView2<'a, GridCellData>,
+    // Either a view into the grid data, or a gathered copy of it for areas crossing wrapped edges
+    grid_segment_data: CowArray<'a, GridCellData, Ix2>,
     pub(super) area: URect,
     pub(super) area_normalized: URect,
 }
@@ -56,6 +58,11 @@ impl<'a> PathfindingMap<'a> {
     pub fn is_valid_index(&self, cell_index2d: &CellIndex2d) -> bool {
         self.grid_segment_data.get(cell_index2d).is_some()
     }
+
+    #[inline]
+    pub fn get_data_shape(&self) -> (usize, usize) {
+        self.grid_segment_data.dim()
+    }
 }
 
 #[derive(Component, Constructor, Clone, Default)]
@@ -63,3 +70,40 @@ pub struct MovementSpeed {
     pub value: f32,
 }
 
+
+/// Parameters of a search through time, see `space_time_search`. All values are in seconds.
+#[derive(Clone, Copy, Debug, Constructor)]
+pub struct TimedSearchParameters {
+    pub departure_time: f32,
+    // Time every move between neighbouring cells or wait in place takes
+    pub step_duration: f32,
+    // Routes arriving later than `departure_time + horizon` are not considered
+    pub horizon: f32,
+}
+
+/// Cell of a path through time together with the moment the agent gets there.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TimedWaypoint {
+    pub cell: CellIndex2d,
+    pub arrival_time: f32,
+}
+
+/// Cells and moves between them claimed by agents at discrete time steps, so that cooperative searches
+/// could plan around each other.
+#[derive(Resource)]
+pub struct ReservationTable {
+    // Duration of a single step of the table in seconds, see `calculate_steps_per_cell`
+    pub step_duration: f32,
+    pub(crate) cells: HashMap<(CellIndex2d, u32), Entity>,
+    // Keyed by both ends of the move and the step the agent arrives at
+    pub(crate) moves: HashMap<(CellIndex2d, CellIndex2d, u32), Entity>,
+    // Reserved paths with the step they start at, so that reservations could be released
+    pub(crate) paths: HashMap<Entity, (u32, Vec<CellIndex2d>)>,
+}
+
+/// Start and goal of an agent of a group solved together, see `cooperative_pathfinding::solve_conflicts_on`.
+#[derive(Clone, Copy, Debug, Constructor)]
+pub struct AgentTask {
+    pub start: CellIndex2d,
+    pub goal: CellIndex2d,
+}
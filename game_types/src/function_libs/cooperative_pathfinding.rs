@@ -0,0 +1,375 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+    iter,
+};
+
+use bevy::prelude::Entity;
+use ndarray::Array2;
+use pathfinding::prelude::astar;
+
+use crate::{
+    components::{
+        grid_components::{
+            definitions::{CellIndex2d, Grid2D, GridResized},
+            nav_grid_traits::{NavGrid, NavGridCosts, NavGridNeighbors, NavGridStorage},
+        },
+        pathfinding_components::{AgentTask, ReservationTable},
+    },
+    function_libs::{
+        flow_field::{STRAIGHT_STEP_COST, UNREACHABLE_COST},
+        grid_calculations::remap_cell_after_resize,
+        navigation::calculate_integration_field_on,
+    },
+};
+
+// Cells worth of time the last cell of a reserved path stays reserved for
+const GOAL_HOLD_CELLS: u32 = 4;
+// Resolution of the table, agents take as many steps per cell as their speed needs
+const DEFAULT_STEP_DURATION: f32 = 0.05;
+// Keeps agents that barely move from reserving cells for ages
+const MAX_STEPS_PER_CELL: u32 = 200;
+
+impl ReservationTable {
+    pub fn new(step_duration: f32) -> Self {
+        ReservationTable { step_duration, cells: HashMap::new(), moves: HashMap::new(), paths: HashMap::new() }
+    }
+
+    #[inline]
+    pub fn calculate_step_at(&self, time: f32) -> u32 {
+        (time / self.step_duration) as u32
+    }
+
+    /// Steps an agent moving at the speed, in surface coordinate units per second, needs to cross a cell.
+    /// The larger side of the cell is taken, so that slow agents would not be expected anywhere too early.
+    pub fn calculate_steps_per_cell(&self, grid: &Grid2D, speed: f32) -> u32 {
+        let cells_per_unit = grid.max_column_index.min(grid.max_row_index).max(1) as f32;
+        let cell_duration = 1.0 / (cells_per_unit * speed.max(f32::EPSILON));
+        (cell_duration / self.step_duration).ceil().clamp(1.0, MAX_STEPS_PER_CELL as f32) as u32
+    }
+
+    /// Whether the agent could step from one cell into another, arriving at the given step. Waiting is a move
+    /// into the same cell. Reservations of the agent itself are not in the way.
+    pub fn can_move(&self, agent: Entity, from: CellIndex2d, to: CellIndex2d, arrival_step: u32) -> bool {
+        let is_reserved_by_other = |owner: Option<&Entity>| owner.is_some_and(|owner| *owner != agent);
+        // Agents swapping their cells would pass through each other
+        !is_reserved_by_other(self.cells.get(&(to, arrival_step)))
+            && !is_reserved_by_other(self.moves.get(&(to, from, arrival_step)))
+    }
+
+    /// Whether the agent could stay in the cell for the steps it needs to cross it and then move on, arriving
+    /// at `departure_step + steps_per_cell`.
+    pub fn can_move_over(&self, agent: Entity, from: CellIndex2d, to: CellIndex2d, departure_step: u32,
+                         steps_per_cell: u32) -> bool {
+        (departure_step + 1..departure_step + steps_per_cell).all(|step| self.can_move(agent, from, from, step))
+            && self.can_move(agent, from, to, departure_step + steps_per_cell)
+    }
+
+    /// Index of the first waypoint of the path colliding with reservations of other agents. Every waypoint
+    /// of the path, including the repeated ones of waits, takes the agent `steps_per_cell` steps.
+    pub fn find_conflict(&self, agent: Entity, path: &[CellIndex2d], start_step: u32,
+                         steps_per_cell: u32) -> Option<usize> {
+        let timed_path = expand_to_steps(path, steps_per_cell);
+        (0..timed_path.len())
+            .find(|step| {
+                let from = timed_path[step.saturating_sub(1)];
+                !self.can_move(agent, from, timed_path[*step], start_step + *step as u32)
+            })
+            .map(|step| step / steps_per_cell.max(1) as usize)
+    }
+
+    /// Reserves the path instead of the previous one of the agent, see `find_conflict` for its timing.
+    /// Cells already reserved by other agents are left to them.
+    pub fn reserve_path(&mut self, agent: Entity, path: &[CellIndex2d], start_step: u32, steps_per_cell: u32) {
+        self.release_agent(agent);
+        let mut timed_path = expand_to_steps(path, steps_per_cell);
+        // The agent stands at the end for a while, so that agents planning later would not walk into it
+        if let Some(last_cell) = path.last() {
+            timed_path.extend(iter::repeat(*last_cell).take((GOAL_HOLD_CELLS * steps_per_cell) as usize));
+        }
+        for (step_offset, cell) in timed_path.iter().enumerate() {
+            let step = start_step + step_offset as u32;
+            self.cells.entry((*cell, step)).or_insert(agent);
+            if step_offset > 0 && timed_path[step_offset - 1] != *cell {
+                self.moves.entry((timed_path[step_offset - 1], *cell, step)).or_insert(agent);
+            }
+        }
+        self.paths.insert(agent, (start_step, timed_path));
+    }
+
+    /// Reserved cells of the agent, one per step, together with the step they start at.
+    pub fn get_reserved_path(&self, agent: Entity) -> Option<(u32, &[CellIndex2d])> {
+        self.paths.get(&agent).map(|(start_step, path)| (*start_step, path.as_slice()))
+    }
+
+    /// Removes the reservations of the agent, looking up only the steps of its reserved path.
+    pub fn release_agent(&mut self, agent: Entity) {
+        let Some((start_step, timed_path)) = self.paths.remove(&agent) else {
+            return;
+        };
+        for (step_offset, cell) in timed_path.iter().enumerate() {
+            let step = start_step + step_offset as u32;
+            if self.cells.get(&(*cell, step)) == Some(&agent) {
+                self.cells.remove(&(*cell, step));
+            }
+            let Some(previous_cell) = step_offset.checked_sub(1).map(|previous| timed_path[previous]) else {
+                continue;
+            };
+            if self.moves.get(&(previous_cell, *cell, step)) == Some(&agent) {
+                self.moves.remove(&(previous_cell, *cell, step));
+            }
+        }
+    }
+
+    /// Moves the reservations along with the grid after it was resized. Agents with some reserved cell cut off
+    /// lose all of their reservations, they have to plan anew anyway.
+    pub fn resize_to(&mut self, grid_resized: &GridResized) {
+        let cut_off_agents: Vec<Entity> = self.paths.iter()
+            .filter(|(_, (_, path))| path.iter().any(|cell| remap_cell_after_resize(*cell, grid_resized).is_none()))
+            .map(|(agent, _)| *agent)
+            .collect();
+        for agent in cut_off_agents {
+            self.release_agent(agent);
+        }
+
+        let remap = |cell: &CellIndex2d| remap_cell_after_resize(*cell, grid_resized);
+        self.cells = self.cells.drain()
+            .filter_map(|((cell, step), agent)| Some(((remap(&cell)?, step), agent)))
+            .collect();
+        self.moves = self.moves.drain()
+            .filter_map(|((from, to, step), agent)| Some(((remap(&from)?, remap(&to)?, step), agent)))
+            .collect();
+        for (_, path) in self.paths.values_mut() {
+            *path = path.iter().filter_map(remap).collect();
+        }
+    }
+
+    /// Forgets reservations of the steps that have already passed.
+    pub fn release_before(&mut self, step: u32) {
+        self.cells.retain(|(_, reserved_step), _| *reserved_step >= step);
+        self.moves.retain(|(_, _, reserved_step), _| *reserved_step >= step);
+        self.paths.retain(|_, (start_step, path)| *start_step + path.len() as u32 >= step);
+    }
+}
+
+impl Default for ReservationTable {
+    fn default() -> Self {
+        ReservationTable::new(DEFAULT_STEP_DURATION)
+    }
+}
+
+/// Cells of the path for every step, each waypoint lasting `steps_per_cell` steps.
+pub fn expand_to_steps(path: &[CellIndex2d], steps_per_cell: u32) -> Vec<CellIndex2d> {
+    let lingering_steps = steps_per_cell.saturating_sub(1) as usize;
+    path.first().copied().into_iter()
+        .chain(path.windows(2).flat_map(|pair| iter::repeat(pair[0]).take(lingering_steps).chain(iter::once(pair[1]))))
+        .collect()
+}
+
+/// Merges waits, the repeated cells of cooperative paths, into the waypoint before them. Returns the
+/// waypoints together with the amount of waits at each one.
+pub fn split_waits(path: &[CellIndex2d]) -> (Vec<CellIndex2d>, Vec<u32>) {
+    let mut waypoints: Vec<CellIndex2d> = Vec::with_capacity(path.len());
+    let mut waits: Vec<u32> = Vec::with_capacity(path.len());
+    for cell in path {
+        match waypoints.last() {
+            Some(last_cell) if last_cell == cell => *waits.last_mut().unwrap() += 1,
+            _ => {
+                waypoints.push(*cell);
+                waits.push(0);
+            }
+        }
+    }
+    (waypoints, waits)
+}
+
+/// Windowed hierarchical cooperative A* (WHCA*). The first `window` waypoints avoid cells and moves reserved
+/// by other agents, waiting in place if needed, while the rest of the path just follows the shortest route.
+/// Every waypoint takes the agent `steps_per_cell` steps, so waits show up as repeated cells.
+pub fn find_cooperative_path_on<G>(nav_grid: &G, reservations: &ReservationTable, agent: Entity,
+                                   start: CellIndex2d, goal: CellIndex2d, start_step: u32, window: u32,
+                                   steps_per_cell: u32) -> Option<Vec<CellIndex2d>>
+    where G: NavGrid<Cell=CellIndex2d> + NavGridNeighbors + NavGridCosts + NavGridStorage {
+    // True distances to the goal ignoring other agents are the heuristic, the hierarchical part of HCA*
+    let distances = calculate_integration_field_on(nav_grid, &[goal]);
+    let distance_at = |cell: CellIndex2d| distances[nav_grid.storage_index(cell)];
+    if !nav_grid.contains(start) || distance_at(start) == UNREACHABLE_COST {
+        return None;
+    }
+
+    let steps_per_cell = steps_per_cell.max(1);
+    let window_end = start_step + window * steps_per_cell;
+    let (states, _) = astar(&(start, start_step),
+                            |&(cell, step)| {
+                                iter_moves(nav_grid, cell)
+                                    .filter(|next| distance_at(*next) != UNREACHABLE_COST
+                                        && reservations.can_move_over(agent, cell, *next, step, steps_per_cell))
+                                    .map(|next| {
+                                        ((next, step + steps_per_cell), calculate_move_cost(nav_grid, cell, next))
+                                    })
+                                    .collect::<Vec<_>>()
+                            },
+                            |&(cell, _)| distance_at(cell),
+                            |&(cell, step)| cell == goal || step >= window_end)?;
+
+    let mut path: Vec<CellIndex2d> = states.into_iter().map(|(cell, _)| cell).collect();
+    let mut last_cell = *path.last().unwrap();
+    while last_cell != goal {
+        last_cell = nav_grid.neighbors(last_cell).into_iter()
+            .filter(|neighbor| nav_grid.is_passable(*neighbor) && distance_at(*neighbor) < distance_at(last_cell))
+            .min_by_key(|neighbor| distance_at(*neighbor))?;
+        path.push(last_cell);
+    }
+    Some(path)
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+enum Constraint {
+    // The agent should not be in the cell at the step
+    Vertex { cell: CellIndex2d, step: u32 },
+    // The agent should not move between the cells, arriving at the step
+    Move { from: CellIndex2d, to: CellIndex2d, step: u32 },
+}
+
+struct ConstraintNode {
+    constraints: Vec<HashSet<Constraint>>,
+    paths: Vec<Vec<CellIndex2d>>,
+    costs: Vec<u32>,
+}
+
+/// Conflict-Based Search: collision-free paths with the lowest total cost for a small group, e.g. a squad
+/// passing a chokepoint. Paths have one waypoint per step and agents stay at their goals after arriving.
+/// Returns `None` if some path needs more than `max_steps`, or the solution was not found within
+/// `max_expansions` of the constraint tree.
+pub fn solve_conflicts_on<G>(nav_grid: &G, tasks: &[AgentTask], max_steps: u32, max_expansions: usize)
+                             -> Option<Vec<Vec<CellIndex2d>>>
+    where G: NavGrid<Cell=CellIndex2d> + NavGridNeighbors + NavGridCosts + NavGridStorage {
+    let distance_fields: Vec<Array2<u32>> = tasks.iter()
+        .map(|task| calculate_integration_field_on(nav_grid, &[task.goal]))
+        .collect();
+
+    let mut root = ConstraintNode {
+        constraints: vec![HashSet::new(); tasks.len()],
+        paths: Vec::with_capacity(tasks.len()),
+        costs: Vec::with_capacity(tasks.len()),
+    };
+    for (agent, task) in tasks.iter().enumerate() {
+        let (path, cost) = find_constrained_path(nav_grid, &distance_fields[agent], *task,
+                                                 &root.constraints[agent], max_steps)?;
+        root.paths.push(path);
+        root.costs.push(cost);
+    }
+
+    let mut open = BinaryHeap::from([Reverse((root.costs.iter().sum::<u32>(), 0))]);
+    let mut nodes = vec![root];
+    let mut expansions = 0;
+    while let Some(Reverse((_, node_index))) = open.pop() {
+        let Some(conflict) = find_first_conflict(&nodes[node_index].paths) else {
+            return Some(std::mem::take(&mut nodes[node_index].paths));
+        };
+        expansions += 1;
+        if expansions > max_expansions {
+            return None;
+        }
+
+        // Either of the agents gives way
+        for (agent, constraint) in conflict {
+            let mut constraints = nodes[node_index].constraints.clone();
+            constraints[agent].insert(constraint);
+            let Some((path, cost)) = find_constrained_path(nav_grid, &distance_fields[agent], tasks[agent],
+                                                           &constraints[agent], max_steps) else {
+                continue;
+            };
+
+            let mut paths = nodes[node_index].paths.clone();
+            let mut costs = nodes[node_index].costs.clone();
+            paths[agent] = path;
+            costs[agent] = cost;
+            open.push(Reverse((costs.iter().sum(), nodes.len())));
+            nodes.push(ConstraintNode { constraints, paths, costs });
+        }
+    }
+    None
+}
+
+// Space-time A* for a single agent of the group, respecting its constraints
+fn find_constrained_path<G>(nav_grid: &G, distances: &Array2<u32>, task: AgentTask,
+                            constraints: &HashSet<Constraint>, max_steps: u32) -> Option<(Vec<CellIndex2d>, u32)>
+    where G: NavGrid<Cell=CellIndex2d> + NavGridNeighbors + NavGridCosts + NavGridStorage {
+    let distance_at = |cell: CellIndex2d| distances[nav_grid.storage_index(cell)];
+    if !nav_grid.contains(task.start) || distance_at(task.start) == UNREACHABLE_COST {
+        return None;
+    }
+    // Agents stay at their goals, so the goal could only be taken after other agents have passed it
+    let goal_free_from = constraints.iter()
+        .filter_map(|constraint| match constraint {
+            Constraint::Vertex { cell, step } if *cell == task.goal => Some(step + 1),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+    let is_allowed = |from: CellIndex2d, to: CellIndex2d, step: u32| {
+        !constraints.contains(&Constraint::Vertex { cell: to, step })
+            && !constraints.contains(&Constraint::Move { from, to, step })
+    };
+
+    let (states, cost) = astar(&(task.start, 0u32),
+                               |&(cell, step)| {
+                                   if step >= max_steps {
+                                       return Vec::new();
+                                   }
+                                   iter_moves(nav_grid, cell)
+                                       .filter(|next| distance_at(*next) != UNREACHABLE_COST
+                                           && is_allowed(cell, *next, step + 1))
+                                       .map(|next| ((next, step + 1), calculate_move_cost(nav_grid, cell, next)))
+                                       .collect::<Vec<_>>()
+                               },
+                               |&(cell, _)| distance_at(cell),
+                               |&(cell, step)| cell == task.goal && step >= goal_free_from)?;
+    Some((states.into_iter().map(|(cell, _)| cell).collect(), cost))
+}
+
+// Both agents of the first conflict found, each with the constraint that would resolve it.
+// Agents stay at the last waypoint of their paths.
+fn find_first_conflict(paths: &[Vec<CellIndex2d>]) -> Option<[(usize, Constraint); 2]> {
+    let position_at = |agent: usize, step: usize| paths[agent][step.min(paths[agent].len() - 1)];
+    let steps_number = paths.iter().map(Vec::len).max().unwrap_or(0);
+
+    for step in 0..steps_number {
+        for first in 0..paths.len() {
+            for second in first + 1..paths.len() {
+                let cell = position_at(first, step);
+                if cell == position_at(second, step) {
+                    let constraint = Constraint::Vertex { cell, step: step as u32 };
+                    return Some([(first, constraint), (second, constraint)]);
+                }
+
+                if step == 0 {
+                    continue;
+                }
+                let previous_cell = position_at(first, step - 1);
+                if previous_cell == position_at(second, step) && cell == position_at(second, step - 1) {
+                    return Some([
+                        (first, Constraint::Move { from: previous_cell, to: cell, step: step as u32 }),
+                        (second, Constraint::Move { from: cell, to: previous_cell, step: step as u32 }),
+                    ]);
+                }
+            }
+        }
+    }
+    None
+}
+
+// Passable neighbours plus the cell itself, for waiting
+fn iter_moves<'a, G>(nav_grid: &'a G, cell: CellIndex2d) -> impl Iterator<Item=CellIndex2d> + 'a
+    where G: NavGrid<Cell=CellIndex2d> + NavGridNeighbors + NavGridCosts {
+    nav_grid.neighbors(cell).into_iter()
+        .filter(move |neighbor| nav_grid.is_passable(*neighbor))
+        .chain(iter::once(cell))
+}
+
+#[inline]
+fn calculate_move_cost<G>(nav_grid: &G, from: CellIndex2d, to: CellIndex2d) -> u32
+    where G: NavGrid<Cell=CellIndex2d> + NavGridCosts {
+    if from == to { STRAIGHT_STEP_COST } else { nav_grid.step_cost(from, to) }
+}
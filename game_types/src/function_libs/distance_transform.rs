@@ -0,0 +1,178 @@
+use bevy::math::{URect, UVec2};
+use ndarray::{Array2, ArrayViewMut1, Axis};
+
+use crate::{
+    components::grid_components::definitions::{
+        CellIndex2d,
+        ClearanceLayer,
+        Grid2D,
+        GridRelatedData,
+        GridResized,
+        Occupation,
+    },
+    function_libs::grid_calculations::slice_2d_array_mut,
+};
+
+impl ClearanceLayer {
+    pub fn new(grid_parameters: &Grid2D, max_distance: f32) -> Self {
+        ClearanceLayer {
+            distances: Array2::from_elem((grid_parameters.column_number as usize,
+                                          grid_parameters.row_number as usize), max_distance),
+            max_distance,
+            dirty_area: Some(grid_parameters.indexes_rect),
+        }
+    }
+
+    /// Re-allocates the layer after the grid was resized. Everything gets recomputed on the next update.
+    pub fn resize_to(&mut self, grid_resized: &GridResized) {
+        let new_size = grid_resized.new_size;
+        self.distances = Array2::from_elem((new_size.x as usize, new_size.y as usize), self.max_distance);
+        self.dirty_area = Some(URect::from_corners(UVec2::ZERO, new_size - UVec2::ONE));
+    }
+
+    #[inline]
+    pub fn clearance_at(&self, cell_index: &CellIndex2d) -> f32 {
+        self.distances[cell_index]
+    }
+
+    /// Whether a unit with the given radius (in cells) can stand in the center of the cell without
+    /// overlapping an occupied cell.
+    #[inline]
+    pub fn has_clearance_for(&self, cell_index: &CellIndex2d, unit_radius: f32) -> bool {
+        self.clearance_at(cell_index) - 0.5 >= unit_radius
+    }
+
+    /// Inverse of the distance to the closest obstacle, zero outside of the influence radius.
+    #[inline]
+    pub fn detraction_factor_at(&self, cell_index: &CellIndex2d, influence_radius: f32) -> f32 {
+        let clearance = self.clearance_at(cell_index);
+        if clearance < f32::EPSILON || clearance > influence_radius {
+            0.0
+        } else {
+            1.0 / clearance
+        }
+    }
+
+    pub fn mark_dirty(&mut self, area: URect) {
+        self.dirty_area = Some(match self.dirty_area {
+            None => area,
+            Some(dirty_area) => dirty_area.union(area),
+        });
+    }
+
+    #[inline]
+    pub fn is_dirty(&self) -> bool {
+        self.dirty_area.is_some()
+    }
+
+    pub fn recompute_all(&mut self, grid_parameters: &Grid2D, grid_related_data: &GridRelatedData) -> URect {
+        self.mark_dirty(grid_parameters.indexes_rect);
+        self.update(grid_parameters, grid_related_data).unwrap_or(grid_parameters.indexes_rect)
+    }
+
+    /// Recomputes clearance around the areas marked as dirty and returns the area that received new values.
+    ///
+    /// Since values are capped at `max_distance`, a cell can only be affected by obstacles within that
+    /// distance. So it is enough to refresh the dirty area inflated by `max_distance`, computing the
+    /// transform over the dirty area inflated by twice of it.
+    pub fn update(&mut self, grid_parameters: &Grid2D, grid_related_data: &GridRelatedData) -> Option<URect> {
+        let dirty_area = self.dirty_area.take()?;
+        let reach = self.max_distance.ceil() as u32;
+
+        let affected_area = grid_parameters.inflate_area_clamped(dirty_area, reach);
+        let sampled_area = grid_parameters.inflate_area_clamped(affected_area, reach);
+
+        let obstacles = grid_related_data.get_segment_view_of(sampled_area)
+            .mapv(|cell_data| cell_data.occupation_state == Occupation::Occupied);
+        let squared_distances = calculate_squared_distance_transform(&obstacles);
+
+        let offset: UVec2 = affected_area.min - sampled_area.min;
+        let max_distance = self.max_distance;
+        let mut affected_view = slice_2d_array_mut(&mut self.distances, affected_area);
+        for ((x, y), distance) in affected_view.indexed_iter_mut() {
+            let squared_distance = squared_distances[[x + offset.x as usize, y + offset.y as usize]];
+            *distance = (squared_distance.sqrt() as f32).min(max_distance);
+        }
+
+        Some(affected_area)
+    }
+}
+
+/// Exact squared euclidean distance transform (Felzenszwalb & Huttenlocher), done as two separable
+/// passes of the 1D lower envelope algorithm. Cells marked with `true` are the sources.
+///
+/// If there are no sources at all, every value is bigger than any distance possible inside of the array.
+pub fn calculate_squared_distance_transform(sources: &Array2<bool>) -> Array2<f64> {
+    let (width, height) = sources.dim();
+    let unreachable = (width * width + height * height) as f64 + 1.0;
+
+    let mut distances = sources.mapv(|is_source| if is_source { 0.0 } else { unreachable });
+    let mut buffers = LowerEnvelopeBuffers::new(width.max(height));
+
+    for lane in distances.lanes_mut(Axis(1)) {
+        buffers.transform_lane(lane);
+    }
+    for lane in distances.lanes_mut(Axis(0)) {
+        buffers.transform_lane(lane);
+    }
+
+    distances
+}
+
+struct LowerEnvelopeBuffers {
+    sampled: Vec<f64>,
+    parabolas: Vec<usize>,
+    boundaries: Vec<f64>,
+}
+
+impl LowerEnvelopeBuffers {
+    fn new(length: usize) -> Self {
+        LowerEnvelopeBuffers {
+            sampled: vec![0.0; length],
+            parabolas: vec![0; length],
+            boundaries: vec![0.0; length + 1],
+        }
+    }
+
+    fn transform_lane(&mut self, mut lane: ArrayViewMut1<f64>) {
+        let length = lane.len();
+        if length == 0 {
+            return;
+        }
+        for (sampled, value) in self.sampled.iter_mut().zip(lane.iter()) {
+            *sampled = *value;
+        }
+
+        let f = &self.sampled;
+        let intersection = |q: usize, p: usize| -> f64 {
+            ((f[q] + (q * q) as f64) - (f[p] + (p * p) as f64)) / (2.0 * q as f64 - 2.0 * p as f64)
+        };
+
+        let mut k = 0;
+        self.parabolas[0] = 0;
+        self.boundaries[0] = f64::NEG_INFINITY;
+        self.boundaries[1] = f64::INFINITY;
+
+        for q in 1..length {
+            let mut s = intersection(q, self.parabolas[k]);
+            while s <= self.boundaries[k] {
+                k -= 1;
+                s = intersection(q, self.parabolas[k]);
+            }
+            k += 1;
+            self.parabolas[k] = q;
+            self.boundaries[k] = s;
+            self.boundaries[k + 1] = f64::INFINITY;
+        }
+
+        k = 0;
+        for (q, value) in lane.iter_mut().enumerate() {
+            while self.boundaries[k + 1] < q as f64 {
+                k += 1;
+            }
+            let p = self.parabolas[k];
+            let delta = q as f64 - p as f64;
+            *value = delta * delta + f[p];
+        }
+    }
+}
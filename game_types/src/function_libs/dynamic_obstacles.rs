@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+
+use bevy::{
+    ecs::entity::Entity,
+    math::URect,
+};
+use ndarray::Array2;
+
+use crate::{
+    components::grid_components::definitions::{
+        CellIndex2d,
+        DynamicOccupancy,
+        Grid2D,
+        GridRelatedData,
+        GridResized,
+    },
+    function_libs::grid_calculations::extend_inclusive_rect,
+};
+
+impl DynamicOccupancy {
+    pub fn new(grid_parameters: &Grid2D) -> Self {
+        let shape = (grid_parameters.column_number as usize, grid_parameters.row_number as usize);
+        DynamicOccupancy {
+            stamps: Array2::zeros(shape),
+            footprints: HashMap::new(),
+        }
+    }
+
+    /// Forgets every footprint after the grid was resized, `obstacle_stamping_system` stamps all of them anew.
+    /// Cells closed by them are opened before the grid data is resized.
+    pub fn resize_to(&mut self, grid_data: &mut GridRelatedData, grid_resized: &GridResized) {
+        let entities: Vec<Entity> = self.footprints.keys().copied().collect();
+        for entity in entities {
+            self.clear(grid_data, entity);
+        }
+        let shape = (grid_resized.new_size.x as usize, grid_resized.new_size.y as usize);
+        self.stamps = Array2::zeros(shape);
+    }
+
+    #[inline]
+    pub fn is_stamped_at(&self, cell_index: &CellIndex2d) -> bool {
+        self.stamps[cell_index] > 0
+    }
+
+    /// Replaces the previous footprint of the obstacle with the cells. Returns the area in which the occupation
+    /// of the grid was changed, if it was.
+    pub fn stamp(&mut self, grid_data: &mut GridRelatedData, entity: Entity,
+                 cells: Vec<CellIndex2d>) -> Option<URect> {
+        let mut changed_area: Option<URect> = None;
+        // New cells are covered before the previous ones are uncovered, so that cells in both stay occupied
+        for cell in cells.iter() {
+            self.stamps[cell] += 1;
+            if grid_data.close_at(cell) {
+                changed_area = Some(extend_inclusive_rect(changed_area, *cell));
+            }
+        }
+        for cell in self.footprints.remove(&entity).unwrap_or_default() {
+            self.stamps[&cell] -= 1;
+            if grid_data.open_at(&cell) {
+                changed_area = Some(extend_inclusive_rect(changed_area, cell));
+            }
+        }
+        if !cells.is_empty() {
+            self.footprints.insert(entity, cells);
+        }
+        changed_area
+    }
+
+    /// Takes back the closures of the footprint of the obstacle, so that it would not get in its own way while
+    /// planning. Cells closed by something else as well stay occupied. Returns the footprint, which has to be
+    /// closed again with `put_back` once the obstacle is done.
+    pub fn lift(&self, grid_data: &mut GridRelatedData, entity: Entity) -> Vec<CellIndex2d> {
+        let Some(cells) = self.footprints.get(&entity) else {
+            return Vec::new();
+        };
+        for cell in cells.iter() {
+            grid_data.open_at(cell);
+        }
+        cells.clone()
+    }
+
+    pub fn put_back(&self, grid_data: &mut GridRelatedData, lifted_cells: &[CellIndex2d]) {
+        for cell in lifted_cells {
+            grid_data.close_at(cell);
+        }
+    }
+
+    /// Frees the cells closed by the footprint of an obstacle which is gone.
+    pub fn clear(&mut self, grid_data: &mut GridRelatedData, entity: Entity) -> Option<URect> {
+        self.stamp(grid_data, entity, Vec::new())
+    }
+}
@@ -1,20 +1,41 @@
-use std::cmp::{max, min};
+use std::{
+    cmp::{max, min},
+    f32::consts::TAU,
+};
 
-use bevy::math::{UVec2, Vec2};
+use bevy::math::{FloatExt, IVec2, UVec2, Vec2};
 use ndarray::prelude::*;
 use rand::Rng;
 
-use crate::components::{
-    flow_field_components::{ExplosionParameters, FlowField},
-    grid_components::definitions::{CellIndex2d, Grid2D},
+use crate::{
+    components::{
+        flow_field_components::{Falloff, FlowBrush, FlowBrushes, FlowBrushKind, FlowField, LayeredFlowField,
+                                VortexDirection},
+        grid_components::{
+            definitions::{
+                CellIndex2d,
+                Grid2D,
+                GridRelatedData,
+                GridResized,
+                LayeredCellIndex,
+                LayeredGrid,
+                LinkedNavGrid,
+                OffMeshLinks,
+                SquareNavGrid,
+            },
+            nav_grid_traits::{NavGridNeighbors, NavGridStorage},
+        },
+        movement_components::{AgentClassMask, SurfaceTopology},
+    },
+    function_libs::{grid_calculations, navigation},
 };
 
-impl ExplosionParameters {
-    pub fn new(impact_center_cell_index: CellIndex2d, impact_radius: f32) -> Self {
-        Self { impact_center_cell_index, impact_radius }
-    }
-}
+pub const STRAIGHT_STEP_COST: u32 = 10;
+pub const DIAGONAL_STEP_COST: u32 = 14;
+pub const UNREACHABLE_COST: u32 = u32::MAX;
 
+// How steep the inverse square falloff is, in units of the brush radius
+const INVERSE_SQUARE_SHARPNESS: f32 = 16.0;
 
 impl FlowField {
     fn from_array(array: Array2<Vec2>) -> FlowField
@@ -55,6 +76,36 @@ impl FlowField {
         FlowField::from_array(field_init)
     }
 
+    /// Re-allocates the field after the grid was resized. New cells continue the closest existing flow.
+    pub fn resize_to(&mut self, grid_resized: &GridResized) {
+        let new_shape = (grid_resized.new_size.x as usize, grid_resized.new_size.y as usize);
+        self.field = grid_calculations::extend_2d_array_clamped(&self.field, new_shape, grid_resized.offset);
+    }
+
+    /// Integrates costs from the goals and points every cell to its cheapest neighbour.
+    /// Along wrapped axes of the topology the flow continues over the grid edges.
+    pub fn integrate_towards(&mut self, grid_parameters: &Grid2D, grid_related_data: &GridRelatedData,
+                             goals: &[CellIndex2d], topology: SurfaceTopology) {
+        let integration_field = calculate_integration_field(grid_parameters, grid_related_data, goals, topology);
+        self.apply_integration_field(grid_parameters, grid_related_data, &integration_field, topology);
+    }
+
+    /// Same as `integrate_towards`, but also going over the off-mesh links usable by all of the agent classes.
+    pub fn integrate_towards_with_links(&mut self, grid_parameters: &Grid2D, grid_related_data: &GridRelatedData,
+                                        goals: &[CellIndex2d], topology: SurfaceTopology,
+                                        links: &OffMeshLinks, agent_classes: AgentClassMask) {
+        let square_grid = SquareNavGrid::new(grid_parameters, grid_related_data, topology);
+        let nav_grid = LinkedNavGrid::new(&square_grid, links, agent_classes);
+        let integration_field = navigation::calculate_integration_field_on(&nav_grid, goals);
+        self.field = navigation::calculate_flow_field_on(&nav_grid, &integration_field);
+    }
+
+    pub fn apply_integration_field(&mut self, grid_parameters: &Grid2D, grid_related_data: &GridRelatedData,
+                                   integration_field: &Array2<u32>, topology: SurfaceTopology) {
+        let nav_grid = SquareNavGrid::new(grid_parameters, grid_related_data, topology);
+        self.field = navigation::calculate_flow_field_on(&nav_grid, integration_field);
+    }
+
     pub fn get_field_at(&self, cell_index: &CellIndex2d) -> Vec2 {
         self.field[cell_index]
     }
@@ -64,39 +115,194 @@ impl FlowField {
         let field_at_index: Vec2 = self.get_field_at(cell_index);
         field_at_index.x.atan2(field_at_index.y)
     }
+}
 
-    pub fn apply_smooth_explosion(&mut self, grid_parameters: &Grid2D, explosion_parameters: ExplosionParameters) {
-        let impact_full_radius = explosion_parameters.impact_radius;
-        let (min_limit, max_limit) = grid_parameters.calculate_indexes_limits_in_rang(explosion_parameters.impact_center_cell_index,
-                                                                                      impact_full_radius as u32);
+impl Falloff {
+    /// Weight at the distance from the center, given as a part of the radius.
+    pub fn sample(&self, distance: f32) -> f32 {
+        if distance > 1.0 {
+            return 0.0;
+        }
+        match self {
+            Falloff::Linear => 1.0 - distance,
+            Falloff::Smoothstep => 1.0 - distance * distance * (3.0 - 2.0 * distance),
+            Falloff::InverseSquare => {
+                // Shifted and scaled down to reach zero at the radius, so that brushes do not end with a step
+                let edge_weight = 1.0 / (1.0 + INVERSE_SQUARE_SHARPNESS);
+                (1.0 / (1.0 + INVERSE_SQUARE_SHARPNESS * distance * distance) - edge_weight) / (1.0 - edge_weight)
+            }
+        }
+    }
+}
+
+impl FlowBrush {
+    pub fn new(kind: FlowBrushKind, center: Vec2, radius: f32, falloff: Falloff, strength: f32,
+               duration: Option<f32>) -> Self {
+        FlowBrush { kind, center, radius, falloff, strength, duration, age: 0.0 }
+    }
+
+    #[inline]
+    pub fn is_expired(&self) -> bool {
+        self.duration.is_some_and(|duration| self.age >= duration)
+    }
 
-        let mut cell_index_2d = CellIndex2d::default();
-        for x in u32::from(min_limit.x)..=u32::from(max_limit.x) {
-            cell_index_2d.x = x.into();
-            for y in u32::from(min_limit.y)..=u32::from(max_limit.y) {
-                cell_index_2d.y = y.into();
+    /// Flow the brush adds at the world position, already weighted by the falloff and the decay.
+    pub fn calculate_flow_at(&self, position: Vec2) -> Vec2 {
+        let offset = position - self.center;
+        let weight = self.falloff.sample(offset.length() / self.radius) * self.strength
+            * self.duration.map_or(1.0, |duration| (1.0 - self.age / duration).max(0.0));
+        let direction = match self.kind {
+            FlowBrushKind::Repulsor => offset.normalize_or_zero(),
+            FlowBrushKind::Attractor => -offset.normalize_or_zero(),
+            FlowBrushKind::Vortex(VortexDirection::CounterClockwise) => offset.perp().normalize_or_zero(),
+            FlowBrushKind::Vortex(VortexDirection::Clockwise) => -offset.perp().normalize_or_zero(),
+            FlowBrushKind::Wind(direction) => direction.normalize_or_zero(),
+            FlowBrushKind::Noise { scale, seed } => Vec2::from_angle(TAU * sample_value_noise(position / scale, seed)),
+        };
+        direction * weight
+    }
+}
+
+impl FlowBrushes {
+    pub fn add(&mut self, brush: FlowBrush) {
+        self.brushes.push(brush);
+    }
+
+    /// Removes every brush, the base field is restored on the next `apply_to`.
+    pub fn clear(&mut self) {
+        self.brushes.clear();
+    }
 
-                self.field[&cell_index_2d] = apply_explosion_to_flow_vector(self.field[&cell_index_2d],
-                                                                            cell_index_2d.into(), explosion_parameters.impact_center_cell_index,
-                                                                            grid_parameters.cell_size, 0.9);
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.brushes.is_empty()
+    }
+
+    /// Whether the flow field has to be written, either to apply brushes or to restore the base after them.
+    #[inline]
+    pub fn is_shaping(&self) -> bool {
+        !self.brushes.is_empty() || self.base_field.is_some()
+    }
+
+    /// Decays the brushes and drops the ones which expired.
+    pub fn advance(&mut self, delta_seconds: f32) {
+        for brush in self.brushes.iter_mut() {
+            brush.age += delta_seconds;
+        }
+        self.brushes.retain(|brush| !brush.is_expired());
+    }
+
+    /// Takes the field as the new base, after it was integrated or resized.
+    pub fn rebase_on(&mut self, flow_field: &FlowField) {
+        if self.base_field.is_some() {
+            self.base_field = Some(flow_field.field.clone());
+        }
+    }
+
+    /// Writes the base field with all of the brushes on top into the flow field. Every vector is renormalized,
+    /// so that brushes change only the direction of the flow. Cells without a base flow, like goals and
+    /// obstacles, are left without it.
+    pub fn apply_to(&mut self, flow_field: &mut FlowField, grid_parameters: &Grid2D) {
+        if self.brushes.is_empty() {
+            if let Some(base_field) = self.base_field.take() {
+                flow_field.field = base_field;
             }
+            return;
         }
+
+        let base_field = self.base_field.get_or_insert_with(|| flow_field.field.clone());
+        flow_field.field = Array2::from_shape_fn(base_field.dim(), |(x, y)| {
+            let base_flow = base_field[[x, y]];
+            if base_flow == Vec2::ZERO {
+                return Vec2::ZERO;
+            }
+            let position = grid_parameters.calculate_cell_position(CellIndex2d::new(x, y));
+            let brushes_flow: Vec2 = self.brushes.iter().map(|brush| brush.calculate_flow_at(position)).sum();
+            (base_flow + brushes_flow).normalize_or_zero()
+        });
     }
 }
 
-pub fn apply_explosion_to_flow_vector(current_flow_vector: Vec2, cell_index: CellIndex2d, impact_center_cell_index: CellIndex2d,
-                                      cell_size: Vec2, effect_magnitude: f32) -> Vec2 {
-    if impact_center_cell_index == cell_index {
-        return current_flow_vector;
+impl LayeredFlowField {
+    /// Integrates costs over all layers and portals. On every layer cells point to their cheapest neighbour,
+    /// unless the cheapest way continues through a portal.
+    pub fn integrate_towards(&mut self, layered_grid: &LayeredGrid, goals: &[LayeredCellIndex]) {
+        let integration_field = navigation::calculate_integration_field_on(layered_grid, goals);
+
+        self.fields = (0..layered_grid.get_layers_number())
+            .map(|layer| {
+                let grid = &layered_grid.get_layer(layer).grid;
+                FlowField { field: Array2::from_elem((grid.column_number as usize, grid.row_number as usize), Vec2::ZERO) }
+            })
+            .collect();
+        self.portals_to_take.clear();
+
+        for (storage_index, cost) in integration_field.indexed_iter() {
+            let cell = layered_grid.cell_at_storage(storage_index);
+            let mut lowest_cost = *cost;
+            let mut lowest_neighbor = None;
+            for neighbor in layered_grid.neighbors(cell) {
+                let neighbor_cost = integration_field[layered_grid.storage_index(neighbor)];
+                if neighbor_cost < lowest_cost {
+                    lowest_cost = neighbor_cost;
+                    lowest_neighbor = Some(neighbor);
+                }
+            }
+
+            let Some(neighbor) = lowest_neighbor else {
+                continue;
+            };
+            match layered_grid.find_portal_between(cell, neighbor) {
+                Some(portal_index) => {
+                    self.portals_to_take.insert(cell, portal_index);
+                }
+                None => {
+                    let offset = IVec2::from(neighbor.cell) - IVec2::from(cell.cell);
+                    self.fields[cell.layer as usize].field[&cell.cell] = offset.as_vec2().normalize_or_zero();
+                }
+            }
+        }
     }
 
-    let world_center = impact_center_cell_index * cell_size;
-    let world_position = cell_index * cell_size;
+    #[inline]
+    pub fn get_field_at(&self, cell: LayeredCellIndex) -> Vec2 {
+        self.fields[cell.layer as usize].get_field_at(&cell.cell)
+    }
 
-    let direction = (world_position - world_center).normalize();
-    let explosion_vector = direction * effect_magnitude;
+    /// Portal the agent standing in the cell should take to get to the goal.
+    #[inline]
+    pub fn get_portal_at(&self, cell: LayeredCellIndex) -> Option<usize> {
+        self.portals_to_take.get(&cell).copied()
+    }
+}
+
+/// Integration over free cells of the square grid, starting from the goals. Entering a cell costs more the higher
+/// its detraction factor is. Occupied and unreachable cells are left with `UNREACHABLE_COST`.
+#[inline]
+pub fn calculate_integration_field(grid_parameters: &Grid2D, grid_related_data: &GridRelatedData,
+                                   goals: &[CellIndex2d], topology: SurfaceTopology) -> Array2<u32> {
+    let nav_grid = SquareNavGrid::new(grid_parameters, grid_related_data, topology);
+    navigation::calculate_integration_field_on(&nav_grid, goals)
+}
+
+// Smoothly interpolated hashes of the surrounding lattice points, in [0, 1)
+fn sample_value_noise(position: Vec2, seed: u32) -> f32 {
+    let lattice = position.floor();
+    let fraction = position - lattice;
+    let weight = fraction * fraction * (Vec2::splat(3.0) - 2.0 * fraction);
+    let (x, y) = (lattice.x as i32, lattice.y as i32);
+    let bottom = hash_lattice_point(x, y, seed).lerp(hash_lattice_point(x + 1, y, seed), weight.x);
+    let top = hash_lattice_point(x, y + 1, seed).lerp(hash_lattice_point(x + 1, y + 1, seed), weight.x);
+    bottom.lerp(top, weight.y)
+}
 
-    return current_flow_vector + explosion_vector;
+fn hash_lattice_point(x: i32, y: i32, seed: u32) -> f32 {
+    let mut hash = (x as u32).wrapping_mul(0x8da6_b343) ^ (y as u32).wrapping_mul(0xd816_3841)
+        ^ seed.wrapping_mul(0xcb1a_b31f);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0x5bd1_e995);
+    hash ^= hash >> 15;
+    (hash >> 8) as f32 / (1u32 << 24) as f32
 }
 
 pub fn calculate_max_index(grid_parameters: &Grid2D, impact_center_cell_index: UVec2, impact_radius: f32)
@@ -0,0 +1,222 @@
+use std::collections::{HashSet, VecDeque};
+
+use bevy::{
+    math::{IVec2, Vec2},
+    prelude::Entity,
+};
+
+use crate::{
+    components::{
+        directions::Connectivity,
+        grid_components::{
+            definitions::{CellIndex2d, Grid2D, GridRelatedData},
+            nav_grid_traits::{NavGrid, NavGridCosts, NavGridNeighbors, NavGridStorage},
+        },
+        movement_components::{Formation, FormationMember, FormationShape, SurfaceTopology},
+        pathfinding_components::AgentTask,
+    },
+    function_libs::cooperative_pathfinding,
+};
+
+// How far obstacles could push a slot away from its place in the formation, in cells
+const MAX_SLOT_DISPLACEMENT: u32 = 4;
+// How far the slot could move away from the end of the route of its member before the way is planned again
+const MAX_ROUTE_DRIFT: u32 = 2;
+// Conflict-based search gets expensive quickly, larger formations just head for their slots
+const MAX_COOPERATIVE_MEMBERS: usize = 8;
+const MAX_ROUTE_STEPS: u32 = 64;
+const MAX_ROUTE_EXPANSIONS: usize = 256;
+
+impl FormationShape {
+    /// Offset of the slot from the leader in cells, the y axis pointing forward and the x axis to the right.
+    pub fn calculate_slot_offset(&self, slot: usize, slots_number: usize, spacing: f32) -> Vec2 {
+        // Slots alternate between the right and the left side, getting further away every second slot
+        let rank = (slot / 2 + 1) as f32;
+        let side = if slot % 2 == 0 { 1.0 } else { -1.0 };
+        match self {
+            FormationShape::Line => Vec2::new(side * rank, 0.0) * spacing,
+            FormationShape::Wedge => Vec2::new(side * rank, -rank) * spacing,
+            FormationShape::Column => Vec2::new(0.0, -((slot + 1) as f32)) * spacing,
+            FormationShape::Box => {
+                let width = (slots_number as f32).sqrt().ceil().max(1.0) as usize;
+                let (column, row) = (slot % width, slot / width);
+                Vec2::new(column as f32 - (width - 1) as f32 / 2.0, -((row + 1) as f32)) * spacing
+            }
+        }
+    }
+}
+
+impl Formation {
+    pub fn new(shape: FormationShape, spacing: f32, members: Vec<Entity>) -> Self {
+        Formation { shape, spacing, members, slot_cells: Vec::new(), heading: Vec2::Y, assigned_shape: shape }
+    }
+
+    #[inline]
+    pub fn get_slot_cell(&self, slot: usize) -> Option<CellIndex2d> {
+        self.slot_cells.get(slot).copied()
+    }
+
+    #[inline]
+    pub fn get_slots_number(&self) -> usize {
+        self.slot_cells.len()
+    }
+
+    #[inline]
+    pub fn get_heading(&self) -> Vec2 {
+        self.heading
+    }
+
+    /// Turns the formation towards the direction, unless it is zero.
+    pub fn set_heading(&mut self, direction: Vec2) {
+        if let Some(heading) = direction.try_normalize() {
+            self.heading = heading;
+        }
+    }
+
+    #[inline]
+    pub(crate) fn set_slot_cells(&mut self, slot_cells: Vec<CellIndex2d>) {
+        self.slot_cells = slot_cells;
+    }
+
+    /// Drops members that are gone, e.g. died, and reports whether there were any.
+    pub fn retain_members(&mut self, is_alive: impl Fn(Entity) -> bool) -> bool {
+        let members_number = self.members.len();
+        self.members.retain(|member| is_alive(*member));
+        self.members.len() != members_number
+    }
+
+    /// Whether members should be handed out slots again. Only gone members, a new shape or a different number
+    /// of slots count, members keep their slots while obstacles deform the formation.
+    pub fn needs_slot_assignment(&self, members_changed: bool, slots_number: usize) -> bool {
+        members_changed || self.shape != self.assigned_shape || self.get_slots_number() != slots_number
+    }
+
+    /// Places a slot for every member around the leader. Slots that would end up outside of the grid,
+    /// on obstacles or on other slots are moved to the nearest free cell. Reports whether any slot was moved.
+    pub fn calculate_slot_cells(&self, grid: &Grid2D, grid_data: &GridRelatedData, topology: SurfaceTopology,
+                                leader_cell: CellIndex2d) -> (Vec<CellIndex2d>, bool) {
+        let right = Vec2::new(self.heading.y, -self.heading.x);
+        let slots_number = self.members.len();
+        let mut taken_cells = HashSet::from([leader_cell]);
+        let mut slot_cells = Vec::with_capacity(slots_number);
+        let mut is_deformed = false;
+
+        for slot in 0..slots_number {
+            let local_offset = self.shape.calculate_slot_offset(slot, slots_number, self.spacing);
+            let offset = (right * local_offset.x + self.heading * local_offset.y).round().as_ivec2();
+            let desired_cell = match topology.offset_cell_in(leader_cell, offset, grid) {
+                Some(cell) => cell,
+                None => {
+                    is_deformed = true;
+                    let cell = leader_cell.saturating_offset(offset);
+                    grid.form_grid_bound_cell_index(cell.x, cell.y)
+                }
+            };
+
+            let slot_cell = find_nearest_free_cell(grid, grid_data, topology, desired_cell, &taken_cells)
+                .unwrap_or(desired_cell);
+            is_deformed |= slot_cell != desired_cell;
+            taken_cells.insert(slot_cell);
+            slot_cells.push(slot_cell);
+        }
+        (slot_cells, is_deformed)
+    }
+
+    /// Gives every member a slot, taking the closest member and slot pairs first, so that members would not
+    /// cross each other's way much. Returns slots in the order of `member_cells`.
+    pub fn assign_slots(member_cells: &[CellIndex2d], slot_cells: &[CellIndex2d]) -> Vec<usize> {
+        let mut pairs: Vec<(usize, usize)> = (0..member_cells.len())
+            .flat_map(|member| (0..slot_cells.len()).map(move |slot| (member, slot)))
+            .collect();
+        pairs.sort_by_key(|(member, slot)| {
+            (IVec2::from(member_cells[*member]) - IVec2::from(slot_cells[*slot])).length_squared()
+        });
+
+        let mut member_slots = vec![usize::MAX; member_cells.len()];
+        let mut is_slot_taken = vec![false; slot_cells.len()];
+        for (member, slot) in pairs {
+            if member_slots[member] == usize::MAX && !is_slot_taken[slot] {
+                member_slots[member] = slot;
+                is_slot_taken[slot] = true;
+            }
+        }
+        member_slots
+    }
+
+    /// Routes of the members to their slots that do not run into each other, one cell per step, in the order
+    /// of `member_cells`. Only small formations are planned together, `None` is returned for larger ones
+    /// and when no routes were found.
+    pub fn plan_member_routes<G>(nav_grid: &G, member_cells: &[CellIndex2d], slot_cells: &[CellIndex2d],
+                                 member_slots: &[usize]) -> Option<Vec<Vec<CellIndex2d>>>
+        where G: NavGrid<Cell=CellIndex2d> + NavGridNeighbors + NavGridCosts + NavGridStorage {
+        if member_cells.len() > MAX_COOPERATIVE_MEMBERS {
+            return None;
+        }
+        let tasks: Vec<AgentTask> = member_cells.iter().zip(member_slots)
+            .map(|(member_cell, slot)| AgentTask::new(*member_cell, *slot_cells.get(*slot)?))
+            .collect::<Option<_>>()?;
+        cooperative_pathfinding::solve_conflicts_on(nav_grid, &tasks, MAX_ROUTE_STEPS, MAX_ROUTE_EXPANSIONS)
+    }
+}
+
+impl FormationMember {
+    pub fn new(leader: Entity, slot: usize) -> Self {
+        FormationMember { leader, slot, route: Vec::new(), route_progress: 0.0 }
+    }
+
+    /// Starts following the route from its first cell.
+    pub fn set_route(&mut self, route: Vec<CellIndex2d>) {
+        self.route = route;
+        self.route_progress = 0.0;
+    }
+
+    #[inline]
+    pub fn is_following_route(&self) -> bool {
+        !self.route.is_empty()
+    }
+
+    /// Whether the way to the slot should be planned again: the member is neither in the slot nor following
+    /// a route that ends close to it.
+    pub fn needs_route_to(&self, cell: CellIndex2d, slot_cell: CellIndex2d) -> bool {
+        match self.route.last() {
+            Some(route_end) => route_end.distance(&slot_cell) > MAX_ROUTE_DRIFT,
+            None => cell != slot_cell,
+        }
+    }
+
+    /// Moves along the route by the distance in cells and returns the flat surface coordinate the member should
+    /// be at. The route is dropped once its end is reached.
+    pub fn advance_route(&mut self, grid: &Grid2D, cells: f32) -> Option<Vec2> {
+        let last_step = self.route.len().checked_sub(1)?;
+        self.route_progress = (self.route_progress + cells).min(last_step as f32);
+        let step = self.route_progress.floor() as usize;
+        let position_at = |step: usize| Vec2::from(grid.calculate_flat_surface_coordinate_from_2d(self.route[step]));
+        if step == last_step {
+            let position = position_at(last_step);
+            self.route.clear();
+            return Some(position);
+        }
+        Some(position_at(step).lerp(position_at(step + 1), self.route_progress.fract()))
+    }
+}
+
+// Breadth-first search in rings around the cell
+fn find_nearest_free_cell(grid: &Grid2D, grid_data: &GridRelatedData, topology: SurfaceTopology,
+                          cell: CellIndex2d, taken_cells: &HashSet<CellIndex2d>) -> Option<CellIndex2d> {
+    let mut visited = HashSet::from([cell]);
+    let mut frontier = VecDeque::from([(cell, 0)]);
+    while let Some((current, distance)) = frontier.pop_front() {
+        if !grid_data.is_occupied_at(&current) && !taken_cells.contains(&current) {
+            return Some(current);
+        }
+        if distance == MAX_SLOT_DISPLACEMENT {
+            continue;
+        }
+        for neighbor in current.neighbors_on(grid, Connectivity::Eight, topology) {
+            if visited.insert(neighbor) {
+                frontier.push_back((neighbor, distance + 1));
+            }
+        }
+    }
+    None
+}
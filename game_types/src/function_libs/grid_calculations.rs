@@ -5,6 +5,7 @@ use std::{
 
 use bevy::math::{
     FloatExt,
+    IVec2,
     URect,
     UVec2,
     Vec2,
@@ -14,7 +15,7 @@ use num_traits::AsPrimitive;
 
 use crate::components::{
     directions::Direction,
-    grid_components::definitions::{CellIndex1d, CellIndex2d, Grid2D},
+    grid_components::definitions::{CellIndex1d, CellIndex2d, Grid2D, GridResized},
 };
 
 #[inline]
@@ -81,6 +82,58 @@ pub fn slice_2d_array_mut<T>(data: &mut Array2<T>, inclusive_rect: URect) -> Arr
     slice
 }
 
+/// Offset along one axis that keeps existing cells attached to the anchored side after resizing.
+#[inline]
+pub fn calculate_anchored_offset(previous_length: u32, new_length: u32, anchor_axis: i32) -> i32 {
+    let difference = new_length as i32 - previous_length as i32;
+    match anchor_axis.signum() {
+        -1 => 0,
+        0 => difference / 2,
+        _ => difference,
+    }
+}
+
+/// Copies the source into an array of a new shape, shifting every element by `offset`.
+/// Elements that have no source counterpart are produced by `fill`, which receives their new index.
+pub fn resize_2d_array<T: Clone>(source: &Array2<T>, new_shape: (usize, usize), offset: IVec2,
+                                 mut fill: impl FnMut(CellIndex2d) -> T) -> Array2<T> {
+    let (source_width, source_height) = source.dim();
+    Array2::from_shape_fn(new_shape, |(x, y)| {
+        let source_x = x as i64 - offset.x as i64;
+        let source_y = y as i64 - offset.y as i64;
+        if source_x >= 0 && source_y >= 0 && (source_x as usize) < source_width && (source_y as usize) < source_height {
+            source[[source_x as usize, source_y as usize]].clone()
+        } else {
+            fill(CellIndex2d::new(x, y))
+        }
+    })
+}
+
+/// Same as `resize_2d_array`, but new elements repeat the closest element of the source.
+pub fn extend_2d_array_clamped<T: Clone>(source: &Array2<T>, new_shape: (usize, usize), offset: IVec2) -> Array2<T> {
+    let (source_width, source_height) = source.dim();
+    resize_2d_array(source, new_shape, offset, |new_index| {
+        let source_x = (new_index.x as i64 - offset.x as i64).clamp(0, source_width as i64 - 1);
+        let source_y = (new_index.y as i64 - offset.y as i64).clamp(0, source_height as i64 - 1);
+        source[[source_x as usize, source_y as usize]].clone()
+    })
+}
+
+/// Index of the cell after the grid was resized, `None` if the cell was cut off.
+#[inline]
+pub fn remap_cell_after_resize(cell: CellIndex2d, grid_resized: &GridResized) -> Option<CellIndex2d> {
+    cell.checked_offset(grid_resized.offset)
+        .filter(|cell| cell.x < grid_resized.new_size.x && cell.y < grid_resized.new_size.y)
+}
+
+/// Inclusive rect after the grid was resized, clipped to the resized grid. `None` if all of it was cut off.
+pub fn remap_inclusive_rect_after_resize(inclusive_rect: URect, grid_resized: &GridResized) -> Option<URect> {
+    let last_index = grid_resized.new_size.as_ivec2() - IVec2::ONE;
+    let min = (inclusive_rect.min.as_ivec2() + grid_resized.offset).max(IVec2::ZERO);
+    let max = (inclusive_rect.max.as_ivec2() + grid_resized.offset).min(last_index);
+    min.cmple(max).all().then(|| URect::from_corners(min.as_uvec2(), max.as_uvec2()))
+}
+
 #[inline]
 pub fn global_to_local(global_index: CellIndex2d, segment: URect) -> CellIndex2d {
     let local_x = global_index.x.sub(segment.min.x);
@@ -107,6 +160,12 @@ pub fn normalize_rect(rect: URect) -> URect {
     }
 }
 
+/// Smallest inclusive rect holding both the area, if there is one, and the cell.
+pub fn extend_inclusive_rect(area: Option<URect>, cell: CellIndex2d) -> URect {
+    let cell: UVec2 = cell.into();
+    area.map_or(URect::from_corners(cell, cell), |area| area.union_point(cell))
+}
+
 /// Splits a grid into eight compass directions: north, northeast, east, southeast, south, southwest, west, and northwest.
 ///
 /// # Arguments
@@ -0,0 +1,72 @@
+use std::f32::consts::{PI, TAU};
+
+use bevy::math::{Quat, Vec2};
+
+use crate::components::movement_components::{Heading, Kinematics};
+
+// Below that speed the agent is considered standing, so it could start moving in any direction
+const STANDING_SPEED: f32 = 1e-6;
+
+impl Kinematics {
+    pub fn new(max_speed: f32, max_acceleration: f32, max_turn_rate: f32) -> Self {
+        Kinematics { velocity: Vec2::ZERO, max_speed, max_acceleration, max_turn_rate }
+    }
+
+    /// Semi-implicit Euler step: the velocity is steered towards the desired one within the turn rate and
+    /// acceleration limits first, then the displacement for the step is calculated with the new velocity.
+    pub fn integrate_towards(&mut self, desired_velocity: Vec2, delta_seconds: f32) -> Vec2 {
+        let desired_velocity = self.limit_turn(desired_velocity.clamp_length_max(self.max_speed), delta_seconds);
+        let velocity_change = (desired_velocity - self.velocity)
+            .clamp_length_max(self.max_acceleration * delta_seconds);
+        self.velocity = (self.velocity + velocity_change).clamp_length_max(self.max_speed);
+        self.velocity * delta_seconds
+    }
+
+    #[inline]
+    pub fn get_speed(&self) -> f32 {
+        self.velocity.length()
+    }
+
+    // Rotates the desired direction towards the current one, so the agent could only turn that much in a step.
+    // Braking is not limited, so the desired velocity keeps its length.
+    fn limit_turn(&self, desired_velocity: Vec2, delta_seconds: f32) -> Vec2 {
+        let desired_speed = desired_velocity.length();
+        let Some(current_direction) = self.velocity.try_normalize().filter(|_| self.get_speed() > STANDING_SPEED) else {
+            return desired_velocity;
+        };
+        let Some(desired_direction) = desired_velocity.try_normalize() else {
+            return desired_velocity;
+        };
+
+        let angle = current_direction.angle_between(desired_direction);
+        let max_angle = self.max_turn_rate * delta_seconds;
+        if angle.abs() <= max_angle {
+            return desired_velocity;
+        }
+        Vec2::from_angle(max_angle.copysign(angle)).rotate(current_direction) * desired_speed
+    }
+}
+
+impl Heading {
+    #[inline]
+    pub fn new(angle: f32, turn_speed: f32) -> Self {
+        Heading { angle, turn_speed }
+    }
+
+    /// Turns along the shorter arc towards the direction, covering the part of the remaining angle the turn
+    /// speed allows within the step. Zero direction keeps the heading.
+    pub fn turn_towards(&mut self, direction: Vec2, delta_seconds: f32) {
+        if direction.length_squared() <= STANDING_SPEED * STANDING_SPEED {
+            return;
+        }
+        let target_angle = direction.y.atan2(direction.x);
+        let remaining_angle = (target_angle - self.angle + PI).rem_euclid(TAU) - PI;
+        let turned_part = 1.0 - (-self.turn_speed * delta_seconds).exp();
+        self.angle = (self.angle + remaining_angle * turned_part).rem_euclid(TAU);
+    }
+
+    #[inline]
+    pub fn calculate_rotation(&self) -> Quat {
+        Quat::from_rotation_z(self.angle)
+    }
+}
@@ -1,49 +1,441 @@
 use bevy::{
-    math::{Quat, Vec3},
+    math::{Quat, URect, Vec3},
     prelude::{Transform, Vec2},
 };
 
-use crate::components::{
-    grid_components::definitions::{CellIndex2d, Grid2D},
-    movement_components::{Maneuver, SurfaceCoordinate},
+use crate::{
+    components::{
+        grid_components::definitions::{CellIndex2d, Grid2D, GridResized},
+        movement_components::{CurveKind, CurveSample, Maneuver, ManeuverLinkSegment, PlaybackMode,
+                              SurfaceCoordinate},
+    },
+    function_libs::maneuver_curves::TANGENT_PROGRESS_DELTA,
 };
 
+// Samples per section between two waypoints the arc length table is built from
+const ARC_LENGTH_SAMPLES_PER_SECTION: usize = 16;
+// Fractions of a sample of the arc length table below it are rounding errors
+const SAMPLE_SNAP_TOLERANCE: f32 = 1e-3;
+// Waypoints closer than that are taken as reached, so that the maneuver would not stand at them twice
+const DWELL_DISTANCE_TOLERANCE: f32 = 1e-5;
+// Knot intervals are kept above it, so repeated waypoints do not divide by zero
+const MIN_KNOT_INTERVAL: f32 = 1e-4;
+
 impl Maneuver {
     pub fn new(surface_coordinates: Vec<SurfaceCoordinate>) -> Self {
-        Self {
+        let mut maneuver = Self {
             path_points: surface_coordinates,
             progress: 0.0,
             last_destination: Default::default(),
-        }
+            link_segments: Vec::new(),
+            started_links: Vec::new(),
+            curve_kind: CurveKind::default(),
+            arc_lengths: Vec::new(),
+            playback_mode: PlaybackMode::default(),
+            time_scale: 1.0,
+            paused: false,
+            backwards: false,
+            started: false,
+            passed_waypoints: Vec::new(),
+            dwell_times: Vec::new(),
+            dwell_remaining: 0.0,
+        };
+        maneuver.rebuild_arc_lengths();
+        maneuver
     }
 
     pub fn set_coordinates(&mut self, maneuver_coordinates: Vec<SurfaceCoordinate>)
     {
+        self.set_coordinates_with_links(maneuver_coordinates, Vec::new());
+    }
+
+    pub fn set_coordinates_with_links(&mut self, maneuver_coordinates: Vec<SurfaceCoordinate>,
+                                      mut link_segments: Vec<ManeuverLinkSegment>) {
+        link_segments.sort_by_key(|segment| segment.start_waypoint);
         self.path_points = maneuver_coordinates;
-        self.progress = 0.0;
+        self.link_segments = link_segments;
+        self.dwell_times.clear();
+        self.rebuild_arc_lengths();
+        self.restart();
+    }
+
+    /// Makes the maneuver stand at the waypoints for the seconds, indexed by the waypoint. Cleared whenever
+    /// the path points are set.
+    pub fn set_dwell_times(&mut self, dwell_times: Vec<f32>) {
+        self.dwell_times = dwell_times;
+        if !self.started {
+            self.dwell_remaining = self.get_dwell_time(self.get_starting_waypoint());
+        }
+    }
+
+    #[inline]
+    pub fn is_dwelling(&self) -> bool {
+        self.dwell_remaining > 0.0
+    }
+
+    /// Goes back to the end the playback mode starts from.
+    pub fn restart(&mut self) {
+        self.backwards = self.playback_mode == PlaybackMode::Reverse;
+        self.progress = if self.backwards { 1.0 } else { 0.0 };
+        self.started_links.clear();
+        self.started = false;
+        self.passed_waypoints.clear();
+        self.dwell_remaining = self.get_dwell_time(self.get_starting_waypoint());
+    }
+
+    pub fn set_playback_mode(&mut self, playback_mode: PlaybackMode) {
+        self.playback_mode = playback_mode;
+        self.restart();
+    }
+
+    #[inline]
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    #[inline]
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    #[inline]
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Whether the maneuver has been advanced since it was set or restarted.
+    #[inline]
+    pub fn has_started(&self) -> bool {
+        self.started
+    }
+
+    pub fn set_curve_kind(&mut self, curve_kind: CurveKind) {
+        self.curve_kind = curve_kind;
+        self.rebuild_arc_lengths();
+    }
+
+    /// Measures the curve so it could be walked at a constant speed. Called whenever the path points change.
+    pub fn rebuild_arc_lengths(&mut self) {
+        self.arc_lengths.clear();
+        if self.path_points.is_empty() {
+            return;
+        }
+        let samples_number = self.path_points.len().saturating_sub(1) * ARC_LENGTH_SAMPLES_PER_SECTION;
+        self.arc_lengths.reserve(samples_number + 1);
+        self.arc_lengths.push(0.0);
+
+        let mut previous_point = self.sample_at_progress(0.0);
+        let mut length = 0.0;
+        for sample in 1..=samples_number {
+            let point = self.sample_at_progress(sample as f32 / samples_number as f32);
+            length += (point - previous_point).length();
+            self.arc_lengths.push(length);
+            previous_point = point;
+        }
+    }
+
+    /// Length of the whole curve, in surface coordinate units.
+    #[inline]
+    pub fn calculate_length(&self) -> f32 {
+        self.arc_lengths.last().copied().unwrap_or(0.0)
+    }
+
+    /// Distance along the curve from the start to the current progress.
+    #[inline]
+    pub fn calculate_travelled_distance(&self) -> f32 {
+        self.calculate_distance_at_progress(self.progress)
+    }
+
+    /// Distance along the curve from its start to the progress, read from the arc length table.
+    pub fn calculate_distance_at_progress(&self, progress: f32) -> f32 {
+        let Some(last_sample) = self.arc_lengths.len().checked_sub(1) else {
+            return 0.0;
+        };
+        let sample = progress.clamp(0.0, 1.0) * last_sample as f32;
+        let index = (sample.floor() as usize).min(last_sample.saturating_sub(1));
+        let next_index = (index + 1).min(last_sample);
+        let fraction = sample - index as f32;
+        self.arc_lengths[index] + (self.arc_lengths[next_index] - self.arc_lengths[index]) * fraction
+    }
+
+    /// Progress at which the curve reaches the given distance from its start.
+    pub fn calculate_progress_at_distance(&self, distance: f32) -> f32 {
+        let length = self.calculate_length();
+        if length <= 0.0 || distance >= length {
+            return 1.0;
+        }
+        if distance <= 0.0 {
+            return 0.0;
+        }
+        let last_sample = self.arc_lengths.len() - 1;
+        let next_index = self.arc_lengths.partition_point(|sample_length| *sample_length < distance).max(1);
+        let (from, to) = (self.arc_lengths[next_index - 1], self.arc_lengths[next_index]);
+        let fraction = if to > from { (distance - from) / (to - from) } else { 0.0 };
+        ((next_index - 1) as f32 + fraction) / last_sample as f32
+    }
+
+    /// Plays the maneuver for the time at the speed, scaled by the time scale, standing at the waypoints
+    /// with dwell times for as long as they say. Paused maneuvers stay put.
+    pub fn advance_by_time(&mut self, speed: f32, delta_seconds: f32) -> SurfaceCoordinate {
+        if self.paused {
+            return self.sample_at_progress(self.progress).into();
+        }
+        let mut remaining_time = delta_seconds * self.time_scale;
+        if self.dwell_times.is_empty() || speed <= 0.0 {
+            return self.advance_by_distance(speed * remaining_time);
+        }
+        self.started = true;
+        loop {
+            let dwelt_time = remaining_time.min(self.dwell_remaining);
+            self.dwell_remaining -= dwelt_time;
+            remaining_time -= dwelt_time;
+            if remaining_time <= 0.0 || self.is_done() {
+                break;
+            }
+
+            // Walks up to the next waypoint to stand at within the reach of this frame
+            let distance = speed * remaining_time;
+            let travelled_distance = self.calculate_travelled_distance();
+            let Some((waypoint, stop_distance)) = self.find_next_dwell(travelled_distance, distance) else {
+                self.advance_by_distance(distance);
+                break;
+            };
+            self.advance_by_distance(stop_distance);
+            remaining_time -= stop_distance / speed;
+            self.dwell_remaining = self.get_dwell_time(waypoint);
+        }
+        self.sample_at_progress(self.progress).into()
+    }
+
+    // Closest waypoint with a dwell time ahead, within the distance and before the end, with the distance to it
+    fn find_next_dwell(&self, travelled_distance: f32, distance: f32) -> Option<(usize, f32)> {
+        (0..self.path_points.len())
+            .filter(|waypoint| self.get_dwell_time(*waypoint) > 0.0)
+            .map(|waypoint| (waypoint, self.calculate_waypoint_distance(waypoint) - travelled_distance))
+            .map(|(waypoint, offset)| (waypoint, if self.backwards { -offset } else { offset }))
+            .filter(|(_, ahead)| *ahead > DWELL_DISTANCE_TOLERANCE && *ahead <= distance)
+            .min_by(|(_, first), (_, second)| first.total_cmp(second))
+    }
+
+    #[inline]
+    fn get_dwell_time(&self, waypoint: usize) -> f32 {
+        self.dwell_times.get(waypoint).copied().unwrap_or(0.0)
+    }
+
+    // Waypoint the playback starts from
+    #[inline]
+    fn get_starting_waypoint(&self) -> usize {
+        if self.backwards { self.path_points.len().saturating_sub(1) } else { 0 }
+    }
+
+    // Distance along the curve from its start to the waypoint, or to where it comes closest to it
+    #[inline]
+    fn calculate_waypoint_distance(&self, waypoint: usize) -> f32 {
+        let progress = self.calculate_waypoint_progress(waypoint);
+        // Waypoints mostly fall on samples of the table, those are read as they are, so that the ends match exactly
+        let sample = progress * self.arc_lengths.len().saturating_sub(1) as f32;
+        match self.arc_lengths.get(sample.round() as usize) {
+            Some(distance) if (sample - sample.round()).abs() < SAMPLE_SNAP_TOLERANCE => *distance,
+            _ => self.calculate_distance_at_progress(progress),
+        }
     }
 
+    /// Progress at which the curve reaches the waypoint, see `CurveKind::calculate_waypoint_progress`.
+    #[inline]
+    pub fn calculate_waypoint_progress(&self, waypoint: usize) -> f32 {
+        self.curve_kind.calculate_waypoint_progress(waypoint, self.path_points.len())
+    }
+
+    /// Moves along the curve by the distance in surface coordinate units, regardless of how long
+    /// the sections between waypoints are. What happens at the ends depends on the playback mode.
+    pub fn advance_by_distance(&mut self, distance: f32) -> SurfaceCoordinate {
+        self.started = true;
+        let length = self.calculate_length();
+        if length <= 0.0 {
+            if matches!(self.playback_mode, PlaybackMode::Once | PlaybackMode::Reverse) {
+                self.progress = if self.backwards { 0.0 } else { 1.0 };
+            }
+            return self.sample_at_progress(self.progress).into();
+        }
+
+        let mut travelled_distance = self.calculate_travelled_distance();
+        let mut remaining_distance = distance.max(0.0);
+        // Whole cycles end where they started, so all but one of them are skipped: one cycle and the rest
+        // of the distance are walked and reported
+        let cycle_length = match self.playback_mode {
+            PlaybackMode::Loop => length,
+            PlaybackMode::PingPong => 2.0 * length,
+            PlaybackMode::Once | PlaybackMode::Reverse => f32::INFINITY,
+        };
+        if remaining_distance > cycle_length {
+            remaining_distance = cycle_length + remaining_distance % cycle_length;
+        }
+        loop {
+            let end_distance = if self.backwards { 0.0 } else { length };
+            let distance_to_end = (end_distance - travelled_distance).abs();
+            if remaining_distance < distance_to_end {
+                let target_distance = if self.backwards {
+                    travelled_distance - remaining_distance
+                } else {
+                    travelled_distance + remaining_distance
+                };
+                self.collect_passed_waypoints(travelled_distance, target_distance);
+                travelled_distance = target_distance;
+                break;
+            }
+            self.collect_passed_waypoints(travelled_distance, end_distance);
+            travelled_distance = end_distance;
+            remaining_distance -= distance_to_end;
+
+            match self.playback_mode {
+                PlaybackMode::Once | PlaybackMode::Reverse => break,
+                PlaybackMode::Loop => {
+                    travelled_distance = 0.0;
+                    self.passed_waypoints.push(0);
+                }
+                PlaybackMode::PingPong => self.backwards = !self.backwards,
+            }
+            if remaining_distance <= 0.0 {
+                break;
+            }
+        }
+        self.progress = self.calculate_progress_at_distance(travelled_distance);
+        self.sample_at_progress(self.progress).into()
+    }
+
+    /// Remembers the waypoints after the `from` distance up to the `to` one, in the order they are passed,
+    /// together with the links started on the way. Only waypoints the curve goes through are reported.
+    fn collect_passed_waypoints(&mut self, from: f32, to: f32) {
+        let waypoint_distance = |waypoint: usize| self.calculate_waypoint_distance(waypoint);
+        let waypoints_number = self.path_points.len();
+        let is_reached = |waypoint: &usize| self.curve_kind.interpolates_waypoint(*waypoint, waypoints_number);
+        let (passed_waypoints, started_links): (Vec<usize>, Vec<usize>) = if to > from {
+            ((0..waypoints_number)
+                 .filter(|waypoint| from < waypoint_distance(*waypoint) && waypoint_distance(*waypoint) <= to)
+                 .filter(is_reached)
+                 .collect(),
+             // Links forwards start when leaving their first waypoint
+             self.link_segments.iter()
+                 .filter(|segment| (from..to).contains(&waypoint_distance(segment.start_waypoint)))
+                 .map(|segment| segment.link)
+                 .collect())
+        } else {
+            ((0..waypoints_number).rev()
+                 .filter(|waypoint| to <= waypoint_distance(*waypoint) && waypoint_distance(*waypoint) < from)
+                 .filter(is_reached)
+                 .collect(),
+             // Links backwards start when leaving the waypoint after them
+             self.link_segments.iter().rev()
+                 .filter(|segment| {
+                     let end_distance = waypoint_distance(segment.start_waypoint + 1);
+                     to < end_distance && end_distance <= from
+                 })
+                 .map(|segment| segment.link)
+                 .collect())
+        };
+        self.passed_waypoints.extend(passed_waypoints);
+        self.started_links.extend(started_links);
+    }
+
+    /// Waypoints passed since the last call.
+    #[inline]
+    pub fn take_passed_waypoints(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.passed_waypoints)
+    }
+
+    /// Position and direction of the curve at the progress, whatever its kind.
+    #[inline]
+    pub fn sample_curve_at(&self, progress: f32) -> CurveSample {
+        self.curve_kind.sample(progress, &self.path_points)
+    }
+
+    #[inline]
+    pub fn sample_curve(&self) -> CurveSample {
+        self.sample_curve_at(self.progress)
+    }
+
+    /// Unit direction of the curve at the current progress. Where the curve stops for a moment, e.g. at
+    /// the clamped ends of a B-spline, it is measured over a short stretch around. Zero if the curve has no length.
+    pub fn sample_tangent(&self) -> Vec2 {
+        let tangent = self.sample_curve().tangent;
+        if tangent != Vec2::ZERO || self.path_points.len() < 2 {
+            return tangent;
+        }
+        let from = (self.progress - TANGENT_PROGRESS_DELTA).max(0.0);
+        let to = (self.progress + TANGENT_PROGRESS_DELTA).min(1.0);
+        (self.sample_at_progress(to) - self.sample_at_progress(from)).normalize_or_zero()
+    }
+
+    /// Direction the agent should face: the way it goes, except where a vehicle drives backwards.
+    pub fn calculate_facing_direction(&self) -> Vec2 {
+        let tangent = self.sample_tangent();
+        if self.sample_curve().backwards != self.backwards { -tangent } else { tangent }
+    }
+
+    #[inline]
+    fn sample_at_progress(&self, progress: f32) -> Vec2 {
+        self.sample_curve_at(progress).position
+    }
+
+    /// Waypoint the maneuver has passed last, going by where the curve kind reaches every waypoint.
+    pub fn calculate_current_waypoint(&self) -> usize {
+        (1..self.path_points.len())
+            .take_while(|waypoint| self.calculate_waypoint_progress(*waypoint) <= self.progress)
+            .count()
+    }
+
+    /// Links the maneuver started going over since the last call, in the order they were started. Links are
+    /// reported whichever way they are gone over and every time they are.
+    #[inline]
+    pub fn take_started_links(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.started_links)
+    }
+
+    /// Direction from the current waypoint to the next one, in cells. Zero once the maneuver is done.
+    pub fn calculate_current_direction(&self, grid_parameters: &Grid2D) -> Vec2 {
+        let current_waypoint = self.calculate_current_waypoint();
+        match (self.path_points.get(current_waypoint), self.path_points.get(current_waypoint + 1)) {
+            (Some(from), Some(to)) => {
+                let cells_per_unit = Vec2::new(grid_parameters.max_column_index as f32,
+                                               grid_parameters.max_row_index as f32);
+                (Vec2::from(*to) - Vec2::from(*from)) * cells_per_unit
+            }
+            _ => Vec2::ZERO,
+        }
+    }
+
+    /// Whether the part of the path that is not passed yet goes through the area.
+    pub fn crosses_area(&self, grid_parameters: &Grid2D, area: URect) -> bool {
+        self.path_points[self.calculate_current_waypoint()..]
+            .iter()
+            .any(|path_point| area.contains(path_point.calculate_cell_index_on_flat_surface(grid_parameters).into()))
+    }
+
+    pub fn remap_after_resize(&mut self, grid_resized: &GridResized) {
+        for path_point in self.path_points.iter_mut() {
+            path_point.remap_after_resize(grid_resized);
+        }
+        self.rebuild_arc_lengths();
+    }
+
+    /// Goes up and down every column of the grid. See `lawnmower` for an area avoiding obstacles.
     pub fn zigzag(grid_parameters: &Grid2D) -> Self {
         let mut maneuver_points = vec![];
 
-        for i in 0..25 {
+        for i in 0..grid_parameters.column_number {
             if i % 2 == 0 {
-                for j in 0..25 {
+                for j in 0..grid_parameters.row_number {
                     maneuver_points.push(grid_parameters.calculate_flat_surface_coordinate_from_2d(CellIndex2d::new(i, j)));
                 }
             } else {
-                for j in (0..25).rev() {
+                for j in (0..grid_parameters.row_number).rev() {
                     maneuver_points.push(grid_parameters.calculate_flat_surface_coordinate_from_2d(CellIndex2d::new(i, j)));
                 }
             }
         }
         maneuver_points.shrink_to_fit();
 
-        Maneuver {
-            path_points: maneuver_points,
-            progress: 0.0,
-            last_destination: Default::default(),
-        }
+        Maneuver::new(maneuver_points)
     }
 
     pub fn spiral(grid_parameters: &Grid2D) -> Self {
@@ -79,11 +471,7 @@ impl Maneuver {
             }
         }
 
-        Maneuver {
-            path_points: maneuver_points,
-            progress: 0.0,
-            last_destination: Default::default(),
-        }
+        Maneuver::new(maneuver_points)
     }
 
     /*    pub fn straight_interpolate_along_path(&self, progress: f32) -> Transform {
@@ -92,16 +480,22 @@ impl Maneuver {
 
     pub fn catmull_rom_interpolate_along_path_ping_pong(&mut self, progress: f32) -> SurfaceCoordinate {
         self.progress = ping_pong(progress, 1.0);
-        catmull_rom_interpolate_coordinates(self.progress, &self.path_points)
+        self.sample_at_progress(self.progress).into()
     }
 
+    /// Advances the curve parameter directly. Sections are passed in equal time whatever their length,
+    /// use `advance_by_distance` for a constant speed.
     pub fn catmull_rom_interpolate_along_path(&mut self, progress: f32) -> SurfaceCoordinate {
         self.progress = (self.progress + progress).clamp(0.0, 1.0);
-        catmull_rom_interpolate_coordinates(self.progress, &self.path_points)
+        self.sample_at_progress(self.progress).into()
     }
 
     pub fn is_done(&self) -> bool {
-        1.0 - self.progress < 0.01
+        match self.playback_mode {
+            PlaybackMode::Once => self.progress >= 1.0,
+            PlaybackMode::Reverse => self.progress <= 0.0,
+            PlaybackMode::Loop | PlaybackMode::PingPong => false,
+        }
     }
 }
 
@@ -272,6 +666,42 @@ pub fn catmull_rom_interpolate_coordinates(progress: f32, points: &[SurfaceCoord
     }
 }
 
+/// Interpolates along the centripetal Catmull-Rom spline through the points. Unlike the uniform one it
+/// neither forms cusps nor loops when the waypoints are unevenly spaced. The ends are extended
+/// with mirrored phantom points, so the curve leaves the first and reaches the last point along the path.
+pub fn centripetal_catmull_rom_interpolate(progress: f32, points: &[SurfaceCoordinate]) -> Vec2 {
+    let (_t_sec, t, t0, t1, t2, t3) = calculate_interpolation_parameters(progress.clamp(0.0, 1.0), points.len());
+
+    let p1: Vec2 = points[t1].into();
+    let p2: Vec2 = points[t2].into();
+    if t1 == t2 || p1 == p2 {
+        return p1;
+    }
+    let p0: Vec2 = if t0 == t1 { 2.0 * p1 - p2 } else { points[t0].into() };
+    let p3: Vec2 = if t3 == t2 { 2.0 * p2 - p1 } else { points[t3].into() };
+
+    centripetal_catmull_rom_interp_vec2(p0, p1, p2, p3, t)
+}
+
+/// Barry and Goldman's pyramidal evaluation with the knots spaced by the square root of the chord lengths.
+#[inline]
+fn centripetal_catmull_rom_interp_vec2(p0: Vec2, p1: Vec2, p2: Vec2, p3: Vec2, t: f32) -> Vec2 {
+    let knot_interval = |from: Vec2, to: Vec2| from.distance(to).sqrt().max(MIN_KNOT_INTERVAL);
+    let k0 = 0.0;
+    let k1 = k0 + knot_interval(p0, p1);
+    let k2 = k1 + knot_interval(p1, p2);
+    let k3 = k2 + knot_interval(p2, p3);
+    let k = k1 + (k2 - k1) * t;
+
+    let blend = |a: Vec2, b: Vec2, from: f32, to: f32| (a * (to - k) + b * (k - from)) / (to - from);
+    let a1 = blend(p0, p1, k0, k1);
+    let a2 = blend(p1, p2, k1, k2);
+    let a3 = blend(p2, p3, k2, k3);
+    let b1 = blend(a1, a2, k0, k2);
+    let b2 = blend(a2, a3, k1, k3);
+    blend(b1, b2, k1, k2)
+}
+
 #[inline]
 fn interpolate_rotation(t_sec: usize, t: f32, t1: usize, q0: Quat, q1: Quat, q2: Quat) -> Quat {
     if t_sec == t1 {
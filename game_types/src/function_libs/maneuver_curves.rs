@@ -0,0 +1,281 @@
+use std::f32::consts::{PI, TAU};
+
+use bevy::math::Vec2;
+
+use crate::{
+    components::movement_components::{CurveKind, CurveSample, SurfaceCoordinate},
+    function_libs::maneuver_animation_calculations::centripetal_catmull_rom_interpolate,
+};
+
+// Progress step tangents are measured over where the curve does not give them directly
+pub(crate) const TANGENT_PROGRESS_DELTA: f32 = 1e-3;
+// Turn radius is kept above it, so the Dubins path is not scaled by an infinite factor
+const MIN_TURN_RADIUS: f32 = 1e-6;
+
+impl CurveKind {
+    /// Position and direction of the curve through the points at the progress from 0 to 1.
+    pub fn sample(&self, progress: f32, points: &[SurfaceCoordinate]) -> CurveSample {
+        let Some(first_point) = points.first() else {
+            return CurveSample::default();
+        };
+        if points.len() == 1 {
+            return CurveSample { position: (*first_point).into(), ..Default::default() };
+        }
+        let progress = progress.clamp(0.0, 1.0);
+
+        match self {
+            CurveKind::CatmullRom => sample_catmull_rom(progress, &points),
+            CurveKind::BezierChain => sample_bezier_chain(progress, &points),
+            CurveKind::BSpline => sample_b_spline(progress, &points),
+            CurveKind::Hermite { tangents } => sample_hermite(progress, &points, tangents),
+            CurveKind::Dubins { turn_radius, reverse_sections } =>
+                sample_dubins(progress, &points, turn_radius.max(MIN_TURN_RADIUS), *reverse_sections),
+        }
+    }
+
+    /// Progress at which the curve is at the waypoint. Points the curve is only pulled by get the progress
+    /// it comes closest to them at: handles of a Bezier section lie within it, B-spline points
+    /// at the start of the section they weigh the most in.
+    pub fn calculate_waypoint_progress(&self, waypoint: usize, points_number: usize) -> f32 {
+        let Some(last_index) = points_number.checked_sub(1).filter(|last_index| *last_index > 0) else {
+            return 0.0;
+        };
+        let waypoint = waypoint.min(last_index);
+        match self {
+            CurveKind::BezierChain => {
+                let sections_number = last_index.div_ceil(3);
+                let section = (waypoint / 3).min(sections_number - 1);
+                let last_control = (section * 3 + 3).min(last_index);
+                let within = (waypoint - section * 3) as f32 / (last_control - section * 3) as f32;
+                (section as f32 + within) / sections_number as f32
+            }
+            CurveKind::BSpline if waypoint == 0 => 0.0,
+            CurveKind::BSpline if waypoint == last_index => 1.0,
+            CurveKind::BSpline => (waypoint + 1) as f32 / (points_number + 1) as f32,
+            CurveKind::CatmullRom | CurveKind::Hermite { .. } | CurveKind::Dubins { .. } =>
+                waypoint as f32 / last_index as f32,
+        }
+    }
+
+    /// Whether the curve goes through the waypoint, rather than only being pulled by it.
+    pub fn interpolates_waypoint(&self, waypoint: usize, points_number: usize) -> bool {
+        let last_index = points_number.saturating_sub(1);
+        match self {
+            CurveKind::BezierChain => waypoint % 3 == 0 || waypoint == last_index,
+            CurveKind::BSpline => waypoint == 0 || waypoint == last_index,
+            CurveKind::CatmullRom | CurveKind::Hermite { .. } | CurveKind::Dubins { .. } => waypoint <= last_index,
+        }
+    }
+}
+
+/// Section the progress falls into and the progress within it, both sections sharing the whole curve equally.
+#[inline]
+fn locate_section(progress: f32, sections_number: usize) -> (usize, f32) {
+    let scaled_progress = progress * sections_number as f32;
+    let section = (scaled_progress.floor() as usize).min(sections_number - 1);
+    (section, scaled_progress - section as f32)
+}
+
+fn sample_catmull_rom(progress: f32, points: &[SurfaceCoordinate]) -> CurveSample {
+    let before = centripetal_catmull_rom_interpolate((progress - TANGENT_PROGRESS_DELTA).max(0.0), points);
+    let after = centripetal_catmull_rom_interpolate((progress + TANGENT_PROGRESS_DELTA).min(1.0), points);
+    CurveSample {
+        position: centripetal_catmull_rom_interpolate(progress, points),
+        tangent: (after - before).normalize_or_zero(),
+        backwards: false,
+    }
+}
+
+fn sample_bezier_chain(progress: f32, points: &[SurfaceCoordinate]) -> CurveSample {
+    let sections_number = (points.len() - 1).div_ceil(3);
+    let (section, t) = locate_section(progress, sections_number);
+    let last_control = (section * 3 + 3).min(points.len() - 1);
+    evaluate_bezier(&points[section * 3..=last_control], t)
+}
+
+/// De Casteljau evaluation of a Bezier curve of up to the third degree.
+fn evaluate_bezier(control_points: &[SurfaceCoordinate], t: f32) -> CurveSample {
+    let mut levels = [Vec2::ZERO; 4];
+    let mut points_number = control_points.len();
+    for (level, control_point) in levels.iter_mut().zip(control_points) {
+        *level = Vec2::from(*control_point);
+    }
+    while points_number > 2 {
+        for index in 0..points_number - 1 {
+            levels[index] = levels[index].lerp(levels[index + 1], t);
+        }
+        points_number -= 1;
+    }
+    CurveSample {
+        position: levels[0].lerp(levels[1], t),
+        tangent: (levels[1] - levels[0]).normalize_or_zero(),
+        backwards: false,
+    }
+}
+
+fn sample_b_spline(progress: f32, points: &[SurfaceCoordinate]) -> CurveSample {
+    // Both end points are tripled, so the curve is clamped to them
+    let last_index = points.len() - 1;
+    let control_point = |index: usize| Vec2::from(points[index.saturating_sub(2).min(last_index)]);
+    let (section, t) = locate_section(progress, points.len() + 1);
+    let [c0, c1, c2, c3] = [0, 1, 2, 3].map(|offset| control_point(section + offset));
+
+    let t2 = t * t;
+    let t3 = t2 * t;
+    let inverse_t = 1.0 - t;
+    let position = (c0 * inverse_t.powi(3) + c1 * (3.0 * t3 - 6.0 * t2 + 4.0)
+        + c2 * (-3.0 * t3 + 3.0 * t2 + 3.0 * t + 1.0) + c3 * t3) / 6.0;
+    let derivative = (-c0 * inverse_t * inverse_t + c1 * (3.0 * t2 - 4.0 * t)
+        + c2 * (-3.0 * t2 + 2.0 * t + 1.0) + c3 * t2) / 2.0;
+    CurveSample { position, tangent: derivative.normalize_or_zero(), backwards: false }
+}
+
+fn sample_hermite(progress: f32, points: &[SurfaceCoordinate], tangents: &[Vec2]) -> CurveSample {
+    let last_index = points.len() - 1;
+    let point = |index: usize| Vec2::from(points[index]);
+    let tangent_at = |index: usize| tangents.get(index).copied().unwrap_or_else(|| {
+        let (previous, next) = (index.saturating_sub(1), (index + 1).min(last_index));
+        (point(next) - point(previous)) / (next - previous) as f32
+    });
+    let (section, t) = locate_section(progress, last_index);
+    let (p0, p1) = (point(section), point(section + 1));
+    let (m0, m1) = (tangent_at(section), tangent_at(section + 1));
+
+    let t2 = t * t;
+    let t3 = t2 * t;
+    let position = p0 * (2.0 * t3 - 3.0 * t2 + 1.0) + m0 * (t3 - 2.0 * t2 + t)
+        + p1 * (-2.0 * t3 + 3.0 * t2) + m1 * (t3 - t2);
+    let derivative = p0 * (6.0 * t2 - 6.0 * t) + m0 * (3.0 * t2 - 4.0 * t + 1.0)
+        + p1 * (-6.0 * t2 + 6.0 * t) + m1 * (3.0 * t2 - 2.0 * t);
+    CurveSample { position, tangent: derivative.normalize_or_zero(), backwards: false }
+}
+
+fn sample_dubins(progress: f32, points: &[SurfaceCoordinate], turn_radius: f32,
+                 reverse_sections: bool) -> CurveSample {
+    let last_index = points.len() - 1;
+    let point = |index: usize| Vec2::from(points[index]);
+    // Heading at a point goes from the previous point to the next one
+    let heading_at = |index: usize| {
+        let direction = point((index + 1).min(last_index)) - point(index.saturating_sub(1));
+        direction.y.atan2(direction.x)
+    };
+    let (section, t) = locate_section(progress, last_index);
+    let (from, to) = (point(section), point(section + 1));
+    let (from_heading, to_heading) = (heading_at(section), heading_at(section + 1));
+
+    let mut path = DubinsPath::find_shortest(from, from_heading, to, to_heading, turn_radius);
+    // Gears only change at the waypoints, a section is driven either forwards or backwards as a whole
+    if reverse_sections {
+        let backwards_path = DubinsPath::find_shortest(from, from_heading + PI, to, to_heading + PI, turn_radius);
+        if backwards_path.calculate_length() < path.calculate_length() {
+            path = DubinsPath { backwards: true, ..backwards_path };
+        }
+    }
+    path.sample(t * path.calculate_length())
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum DubinsTurn {
+    Left,
+    Straight,
+    Right,
+}
+
+/// Three pieces of a Dubins path, their lengths measured in turn radii.
+#[derive(Clone, Copy, Debug)]
+struct DubinsPath {
+    start: Vec2,
+    heading: f32,
+    turn_radius: f32,
+    pieces: [(DubinsTurn, f32); 3],
+    backwards: bool,
+}
+
+impl DubinsPath {
+    /// Picks the shortest of the six Dubins words from one pose to the other.
+    fn find_shortest(from: Vec2, from_heading: f32, to: Vec2, to_heading: f32, turn_radius: f32) -> Self {
+        use DubinsTurn::{Left, Right, Straight};
+
+        let offset = to - from;
+        let distance = offset.length() / turn_radius;
+        let offset_angle = offset.y.atan2(offset.x);
+        let alpha = (from_heading - offset_angle).rem_euclid(TAU);
+        let beta = (to_heading - offset_angle).rem_euclid(TAU);
+        let (alpha_sin, alpha_cos) = alpha.sin_cos();
+        let (beta_sin, beta_cos) = beta.sin_cos();
+        let cos_difference = (alpha - beta).cos();
+        let wrap = |angle: f32| angle.rem_euclid(TAU);
+
+        let mut candidates: Vec<[(DubinsTurn, f32); 3]> = Vec::with_capacity(6);
+        let squared = 2.0 + distance * distance - 2.0 * cos_difference + 2.0 * distance * (alpha_sin - beta_sin);
+        if squared >= 0.0 {
+            let angle = (beta_cos - alpha_cos).atan2(distance + alpha_sin - beta_sin);
+            candidates.push([(Left, wrap(angle - alpha)), (Straight, squared.sqrt()), (Left, wrap(beta - angle))]);
+        }
+        let squared = 2.0 + distance * distance - 2.0 * cos_difference + 2.0 * distance * (beta_sin - alpha_sin);
+        if squared >= 0.0 {
+            let angle = (alpha_cos - beta_cos).atan2(distance - alpha_sin + beta_sin);
+            candidates.push([(Right, wrap(alpha - angle)), (Straight, squared.sqrt()), (Right, wrap(angle - beta))]);
+        }
+        let squared = -2.0 + distance * distance + 2.0 * cos_difference + 2.0 * distance * (alpha_sin + beta_sin);
+        if squared >= 0.0 {
+            let straight = squared.sqrt();
+            let angle = (-alpha_cos - beta_cos).atan2(distance + alpha_sin + beta_sin) - (-2.0f32).atan2(straight);
+            candidates.push([(Left, wrap(angle - alpha)), (Straight, straight), (Right, wrap(angle - beta))]);
+        }
+        let squared = -2.0 + distance * distance + 2.0 * cos_difference - 2.0 * distance * (alpha_sin + beta_sin);
+        if squared >= 0.0 {
+            let straight = squared.sqrt();
+            let angle = (alpha_cos + beta_cos).atan2(distance - alpha_sin - beta_sin) - 2.0f32.atan2(straight);
+            candidates.push([(Right, wrap(alpha - angle)), (Straight, straight), (Left, wrap(beta - angle))]);
+        }
+        let middle_cos = (6.0 - distance * distance + 2.0 * cos_difference + 2.0 * distance * (alpha_sin - beta_sin)) / 8.0;
+        if middle_cos.abs() <= 1.0 {
+            let middle = wrap(TAU - middle_cos.acos());
+            let first = wrap(alpha - (alpha_cos - beta_cos).atan2(distance - alpha_sin + beta_sin) + middle / 2.0);
+            candidates.push([(Right, first), (Left, middle), (Right, wrap(alpha - beta - first + middle))]);
+        }
+        let middle_cos = (6.0 - distance * distance + 2.0 * cos_difference + 2.0 * distance * (beta_sin - alpha_sin)) / 8.0;
+        if middle_cos.abs() <= 1.0 {
+            let middle = wrap(TAU - middle_cos.acos());
+            let first = wrap(-alpha - (alpha_cos - beta_cos).atan2(distance + alpha_sin - beta_sin) + middle / 2.0);
+            candidates.push([(Left, first), (Right, middle), (Left, wrap(beta - alpha - first + middle))]);
+        }
+
+        // The straight words always exist, so there is at least one candidate
+        let pieces = candidates.into_iter()
+            .min_by(|a, b| a.iter().map(|piece| piece.1).sum::<f32>()
+                .total_cmp(&b.iter().map(|piece| piece.1).sum::<f32>()))
+            .unwrap_or([(Straight, distance), (Straight, 0.0), (Straight, 0.0)]);
+        DubinsPath { start: from, heading: from_heading, turn_radius, pieces, backwards: false }
+    }
+
+    #[inline]
+    fn calculate_length(&self) -> f32 {
+        self.pieces.iter().map(|(_, length)| length).sum::<f32>() * self.turn_radius
+    }
+
+    fn sample(&self, distance: f32) -> CurveSample {
+        let mut position = self.start;
+        let mut heading = self.heading;
+        let mut remaining = (distance / self.turn_radius).max(0.0);
+        for (turn, length) in self.pieces {
+            let piece = remaining.min(length);
+            match turn {
+                DubinsTurn::Left => {
+                    position += Vec2::new((heading + piece).sin() - heading.sin(),
+                                          heading.cos() - (heading + piece).cos()) * self.turn_radius;
+                    heading += piece;
+                }
+                DubinsTurn::Straight => position += Vec2::from_angle(heading) * piece * self.turn_radius,
+                DubinsTurn::Right => {
+                    position += Vec2::new(heading.sin() - (heading - piece).sin(),
+                                          (heading - piece).cos() - heading.cos()) * self.turn_radius;
+                    heading -= piece;
+                }
+            }
+            remaining -= piece;
+        }
+        CurveSample { position, tangent: Vec2::from_angle(heading), backwards: self.backwards }
+    }
+}
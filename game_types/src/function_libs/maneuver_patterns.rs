@@ -0,0 +1,171 @@
+use std::f32::consts::TAU;
+
+use bevy::math::{IVec2, URect, UVec2, Vec2};
+use rand::Rng;
+
+use crate::{
+    components::{
+        directions::Connectivity,
+        grid_components::{
+            definitions::{CellIndex2d, Grid2D, GridRelatedData, SquareNavGrid},
+            nav_grid_traits::NavGridNeighbors,
+        },
+        movement_components::{Maneuver, PlaybackMode, SpiralDirection, SurfaceTopology, SweepAxis},
+    },
+    function_libs::navigation,
+};
+
+impl Maneuver {
+    /// Covers the inclusive area lane by lane, turning around at its edges. Lanes are `lane_spacing` cells apart.
+    pub fn lawnmower(grid: &Grid2D, grid_data: &GridRelatedData, area: URect, lane_spacing: u32,
+                     axis: SweepAxis) -> Self {
+        let area = clamp_area_to(grid, area);
+        let (lanes, lane_cells) = match axis {
+            SweepAxis::Vertical => (area.min.x..=area.max.x, area.min.y..=area.max.y),
+            SweepAxis::Horizontal => (area.min.y..=area.max.y, area.min.x..=area.max.x),
+        };
+
+        let mut cells = Vec::new();
+        for (lane_number, lane) in lanes.step_by(lane_spacing.max(1) as usize).enumerate() {
+            let along_lane: Vec<u32> = if lane_number % 2 == 0 {
+                lane_cells.clone().collect()
+            } else {
+                lane_cells.clone().rev().collect()
+            };
+            cells.extend(along_lane.into_iter().map(|position| match axis {
+                SweepAxis::Vertical => CellIndex2d::new(lane, position),
+                SweepAxis::Horizontal => CellIndex2d::new(position, lane),
+            }));
+        }
+        Self::from_routed_cells(grid, grid_data, cells, false)
+    }
+
+    /// Square spiral around the center, ring after ring up to the radius.
+    pub fn spiral_around(grid: &Grid2D, grid_data: &GridRelatedData, center: CellIndex2d, radius: u32,
+                         direction: SpiralDirection) -> Self {
+        let mut cells = vec![center];
+        for ring in 1..=radius as i32 {
+            // Starts right above the last cell of the previous ring and goes clockwise
+            let ring_offsets = (-ring + 1..=ring).map(|x| IVec2::new(x, ring))
+                .chain((-ring..ring).rev().map(|y| IVec2::new(ring, y)))
+                .chain((-ring..ring).rev().map(|x| IVec2::new(x, -ring)))
+                .chain((-ring + 1..=ring).map(|y| IVec2::new(-ring, y)));
+            cells.extend(ring_offsets.filter_map(|offset| center.checked_offset_in(offset, grid)));
+        }
+        if direction == SpiralDirection::Inwards {
+            cells.reverse();
+        }
+        Self::from_routed_cells(grid, grid_data, cells, false)
+    }
+
+    /// Loop through the waypoints and back to the first one, going around obstacles.
+    /// None if some waypoint can not be reached from the previous one.
+    pub fn patrol(grid: &Grid2D, grid_data: &GridRelatedData, topology: SurfaceTopology,
+                  waypoints: &[CellIndex2d]) -> Option<Self> {
+        let nav_grid = SquareNavGrid::new(grid, grid_data, topology);
+        let mut cells: Vec<CellIndex2d> = Vec::new();
+        for (index, from) in waypoints.iter().enumerate() {
+            let to = waypoints[(index + 1) % waypoints.len()];
+            let (leg, _) = navigation::find_path_on(&nav_grid, *from, to)?;
+            // Every leg starts where the previous one ended
+            let leg_start = usize::from(!cells.is_empty());
+            cells.extend_from_slice(&leg[leg_start..]);
+        }
+        // The last leg ends at the first waypoint, where the loop starts over
+        cells.pop();
+
+        let mut path_points = grid.calculate_surface_coordinates_for_2d(&cells);
+        topology.unwrap_coordinates(&mut path_points);
+        let mut maneuver = Maneuver::new(path_points);
+        maneuver.set_playback_mode(PlaybackMode::Loop);
+        Some(maneuver)
+    }
+
+    /// Loop around the center at the radius, in cells, going counterclockwise.
+    pub fn orbit(grid: &Grid2D, grid_data: &GridRelatedData, center: CellIndex2d, radius: u32) -> Self {
+        // Enough samples for neighbouring ones to land in the same or adjacent cells
+        let samples_number = ((TAU * radius as f32).ceil() as usize * 2).max(4);
+        let mut cells: Vec<CellIndex2d> = Vec::with_capacity(samples_number);
+        for sample in 0..samples_number {
+            let offset = (Vec2::from_angle(TAU * sample as f32 / samples_number as f32) * radius as f32)
+                .round().as_ivec2();
+            let Some(cell) = center.checked_offset_in(offset, grid) else {
+                continue;
+            };
+            if cells.last() != Some(&cell) {
+                cells.push(cell);
+            }
+        }
+        if cells.len() > 1 && cells.first() == cells.last() {
+            cells.pop();
+        }
+
+        let mut maneuver = Self::from_routed_cells(grid, grid_data, cells, true);
+        maneuver.set_playback_mode(PlaybackMode::Loop);
+        maneuver
+    }
+
+    /// Wanders from the start over free cells, a random neighbour every step. It avoids stepping
+    /// straight back unless there is no other way.
+    pub fn random_walk(grid: &Grid2D, grid_data: &GridRelatedData, start: CellIndex2d, steps_number: usize,
+                       rng: &mut impl Rng) -> Self {
+        let mut cells = vec![start];
+        let mut previous_cell: Option<CellIndex2d> = None;
+        let mut current_cell = start;
+        for _ in 0..steps_number {
+            let free_neighbors: Vec<CellIndex2d> = current_cell.neighbors(grid, Connectivity::Eight)
+                .filter(|neighbor| !grid_data.is_occupied_at(neighbor))
+                .collect();
+            let forward_neighbors: Vec<CellIndex2d> = free_neighbors.iter()
+                .copied()
+                .filter(|neighbor| Some(*neighbor) != previous_cell)
+                .collect();
+            let candidates = if forward_neighbors.is_empty() { free_neighbors } else { forward_neighbors };
+            if candidates.is_empty() {
+                break;
+            }
+            let next_cell = candidates[rng.gen_range(0..candidates.len())];
+            previous_cell = Some(current_cell);
+            current_cell = next_cell;
+            cells.push(next_cell);
+        }
+        Maneuver::new(grid.calculate_surface_coordinates_for_2d(&cells))
+    }
+
+    // Goes through the free cells of the pattern in order, walking around obstacles between the ones that are
+    // not neighbours, as `patrol` does. Cells that could not be reached are left out. Closed patterns also
+    // find their way from the last cell back to the first one. Patterns stay within the grid, so its edges
+    // are not wrapped.
+    fn from_routed_cells(grid: &Grid2D, grid_data: &GridRelatedData, cells: Vec<CellIndex2d>,
+                         is_closed: bool) -> Self {
+        let nav_grid = SquareNavGrid::new(grid, grid_data, SurfaceTopology::Bounded);
+        let mut routed_cells: Vec<CellIndex2d> = Vec::with_capacity(cells.len());
+        for cell in cells.into_iter().filter(|cell| !grid_data.is_occupied_at(cell)) {
+            match routed_cells.last().copied() {
+                None => routed_cells.push(cell),
+                Some(last_cell) if nav_grid.neighbors(last_cell).contains(&cell) => routed_cells.push(cell),
+                Some(last_cell) => {
+                    if let Some((leg, _)) = navigation::find_path_on(&nav_grid, last_cell, cell) {
+                        routed_cells.extend_from_slice(&leg[1..]);
+                    }
+                }
+            }
+        }
+        if is_closed && routed_cells.len() > 1 {
+            let (first_cell, last_cell) = (routed_cells[0], routed_cells[routed_cells.len() - 1]);
+            if first_cell != last_cell && !nav_grid.neighbors(last_cell).contains(&first_cell) {
+                // The loop starts over at the first cell, which is not repeated
+                if let Some((leg, _)) = navigation::find_path_on(&nav_grid, last_cell, first_cell) {
+                    routed_cells.extend_from_slice(&leg[1..leg.len() - 1]);
+                }
+            }
+        }
+        Maneuver::new(grid.calculate_surface_coordinates_for_2d(&routed_cells))
+    }
+}
+
+#[inline]
+fn clamp_area_to(grid: &Grid2D, area: URect) -> URect {
+    let max_index = UVec2::new(grid.max_column_index, grid.max_row_index);
+    URect::from_corners(area.min.min(max_index), area.max.min(max_index))
+}
@@ -3,3 +3,15 @@ pub mod flow_field;
 pub mod surface_calculations;
 pub mod coordinates_calculations;
 pub mod maneuver_animation_calculations;
+pub mod distance_transform;
+pub mod navigation;
+pub mod space_time_search;
+pub mod cooperative_pathfinding;
+pub mod formations;
+pub mod steering;
+pub mod kinematics;
+pub mod maneuver_curves;
+pub mod maneuver_patterns;
+pub mod path_following;
+pub mod rasterization;
+pub mod dynamic_obstacles;
@@ -0,0 +1,126 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use bevy::math::Vec2;
+use ndarray::Array2;
+use pathfinding::prelude::astar;
+
+use crate::{
+    components::{
+        flow_field_components::HexFlowField,
+        grid_components::{
+            definitions::{HexCellIndex, HexGrid2D, PassabilityOverlay},
+            nav_grid_traits::{NavGrid, NavGridCosts, NavGridNeighbors, NavGridStorage, NavGridWorld},
+        },
+    },
+    function_libs::flow_field::UNREACHABLE_COST,
+};
+
+/// Dijkstra's algorithm from the goals over passable cells.
+/// Cells that can't be reached are left with `UNREACHABLE_COST`.
+pub fn calculate_integration_field_on<G>(nav_grid: &G, goals: &[G::Cell]) -> Array2<u32>
+    where G: NavGridNeighbors + NavGridCosts + NavGridStorage {
+    let mut integration_field = Array2::from_elem(nav_grid.storage_shape(), UNREACHABLE_COST);
+    // Storage indexes are kept in the queue, since cells are not required to be ordered
+    let mut frontier = BinaryHeap::new();
+
+    for goal in goals {
+        if !nav_grid.contains(*goal) || !nav_grid.is_passable(*goal) {
+            continue;
+        }
+        let goal_storage_index = nav_grid.storage_index(*goal);
+        integration_field[goal_storage_index] = 0;
+        frontier.push(Reverse((0, goal_storage_index)));
+    }
+
+    while let Some(Reverse((cost, storage_index))) = frontier.pop() {
+        if cost > integration_field[storage_index] {
+            continue;
+        }
+
+        let cell = nav_grid.cell_at_storage(storage_index);
+        for neighbor in nav_grid.neighbors(cell) {
+            if !nav_grid.is_passable(neighbor) {
+                continue;
+            }
+            let neighbor_cost = cost + nav_grid.step_cost(cell, neighbor);
+            let neighbor_storage_index = nav_grid.storage_index(neighbor);
+            if neighbor_cost < integration_field[neighbor_storage_index] {
+                integration_field[neighbor_storage_index] = neighbor_cost;
+                frontier.push(Reverse((neighbor_cost, neighbor_storage_index)));
+            }
+        }
+    }
+
+    integration_field
+}
+
+/// Direction from the cell towards its neighbour with the lowest integrated cost,
+/// or zero if there is no cheaper neighbour.
+pub fn calculate_flow_direction_on<G>(nav_grid: &G, integration_field: &Array2<u32>, cell: G::Cell) -> Vec2
+    where G: NavGridNeighbors + NavGridStorage + NavGridWorld {
+    let mut lowest_cost = integration_field[nav_grid.storage_index(cell)];
+    let mut lowest_neighbor = None;
+
+    for neighbor in nav_grid.neighbors(cell) {
+        let neighbor_cost = integration_field[nav_grid.storage_index(neighbor)];
+        if neighbor_cost < lowest_cost {
+            lowest_cost = neighbor_cost;
+            lowest_neighbor = Some(neighbor);
+        }
+    }
+
+    lowest_neighbor.map_or(Vec2::ZERO, |neighbor| nav_grid.calculate_step_direction(cell, neighbor))
+}
+
+pub fn calculate_flow_field_on<G>(nav_grid: &G, integration_field: &Array2<u32>) -> Array2<Vec2>
+    where G: NavGridNeighbors + NavGridStorage + NavGridWorld {
+    Array2::from_shape_fn(nav_grid.storage_shape(), |storage_index| {
+        calculate_flow_direction_on(nav_grid, integration_field, nav_grid.cell_at_storage(storage_index))
+    })
+}
+
+/// A* over passable cells. Returns the path including both ends together with its cost.
+#[inline]
+pub fn find_path_on<G>(nav_grid: &G, start: G::Cell, goal: G::Cell) -> Option<(Vec<G::Cell>, u32)>
+    where G: NavGridNeighbors + NavGridCosts {
+    find_path_on_with(nav_grid, start, goal, |cell| nav_grid.estimate_cost(cell, goal))
+}
+
+/// Same as `find_path_on`, but with a custom heuristic. It should never overestimate the cost.
+pub fn find_path_on_with<G>(nav_grid: &G, start: G::Cell, goal: G::Cell,
+                            heuristic: impl Fn(G::Cell) -> u32) -> Option<(Vec<G::Cell>, u32)>
+    where G: NavGridNeighbors + NavGridCosts {
+    astar(&start,
+          |cell| {
+              nav_grid.neighbors(*cell).into_iter()
+                  .filter(|neighbor| nav_grid.is_passable(*neighbor))
+                  .map(|neighbor| (neighbor, nav_grid.step_cost(*cell, neighbor)))
+                  .collect::<Vec<_>>()
+          },
+          |cell| heuristic(*cell),
+          |cell| *cell == goal)
+}
+
+impl HexFlowField {
+    pub fn new(hex_grid: &HexGrid2D) -> Self {
+        HexFlowField { field: Array2::from_elem(hex_grid.storage_shape(), Vec2::ZERO) }
+    }
+
+    pub fn integrate_towards(&mut self, hex_grid: &HexGrid2D, goals: &[HexCellIndex],
+                             is_passable: impl Fn(HexCellIndex) -> bool) {
+        let nav_grid = PassabilityOverlay::new(hex_grid, is_passable);
+        let integration_field = calculate_integration_field_on(&nav_grid, goals);
+        self.field = calculate_flow_field_on(&nav_grid, &integration_field);
+    }
+
+    #[inline]
+    pub fn get_field_at(&self, hex_grid: &HexGrid2D, cell_index: HexCellIndex) -> Vec2 {
+        self.field[hex_grid.storage_index(cell_index)]
+    }
+
+    #[inline]
+    pub fn get_rotation_angle_at(&self, hex_grid: &HexGrid2D, cell_index: HexCellIndex) -> f32 {
+        let field_at_index = self.get_field_at(hex_grid, cell_index);
+        field_at_index.x.atan2(field_at_index.y)
+    }
+}
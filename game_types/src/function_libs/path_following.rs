@@ -0,0 +1,166 @@
+use bevy::math::{IVec2, Vec2};
+
+use crate::{
+    components::{
+        grid_components::{
+            definitions::{CellIndex2d, Grid2D, PassabilityOverlay, SquareNavGrid},
+            nav_grid_traits::NavGridCosts,
+        },
+        movement_components::{AvoidanceSettings, Maneuver, ManeuverLinkSegment, StuckDetector, StuckStage,
+                              SurfaceCoordinate, SurfaceTopology},
+    },
+    function_libs::navigation,
+};
+
+impl Maneuver {
+    #[inline]
+    pub fn calculate_waypoint_cell(&self, grid: &Grid2D, topology: SurfaceTopology, waypoint: usize) -> CellIndex2d {
+        topology.constrain_coordinate(self.path_points[waypoint]).calculate_cell_index_on_flat_surface(grid)
+    }
+
+    /// First of the `look_ahead` waypoints after the current one that is blocked.
+    pub fn find_blocked_waypoint_ahead(&self, grid: &Grid2D, topology: SurfaceTopology, look_ahead: u32,
+                                       is_blocked: impl Fn(CellIndex2d) -> bool) -> Option<usize> {
+        let first_ahead = self.calculate_current_waypoint() + 1;
+        let last_ahead = (first_ahead + look_ahead as usize).min(self.path_points.len());
+        (first_ahead..last_ahead).find(|waypoint| is_blocked(self.calculate_waypoint_cell(grid, topology, *waypoint)))
+    }
+
+    /// First waypoint after the blocked one a detour could lead back to.
+    pub fn find_rejoin_waypoint(&self, grid: &Grid2D, topology: SurfaceTopology, blocked_waypoint: usize,
+                                is_blocked: impl Fn(CellIndex2d) -> bool) -> Option<usize> {
+        (blocked_waypoint + 1..self.path_points.len())
+            .find(|waypoint| !is_blocked(self.calculate_waypoint_cell(grid, topology, *waypoint)))
+    }
+
+    /// Replaces the way from the current position up to the rejoin waypoint with the detour, which starts
+    /// in the cell of the agent. The part already passed is kept, so loops still go all the way round, and
+    /// playback goes on from the current position the way it went before.
+    pub fn splice_detour(&mut self, grid: &Grid2D, topology: SurfaceTopology, current: SurfaceCoordinate,
+                         detour: &[CellIndex2d], rejoin_waypoint: usize) {
+        let current_waypoint = self.calculate_current_waypoint().min(rejoin_waypoint);
+        let mut path_points: Vec<SurfaceCoordinate> = self.path_points[..=current_waypoint].iter()
+            .map(|path_point| topology.constrain_coordinate(*path_point))
+            .collect();
+        // The agent could be standing right at the current waypoint, which is not repeated then
+        let current = topology.constrain_coordinate(current);
+        if topology.calculate_offset(path_points[current_waypoint], current).length() > f32::EPSILON {
+            path_points.push(current);
+        }
+        let current_point = path_points.len() - 1;
+        path_points.extend(detour.iter().skip(1).map(|cell| grid.calculate_flat_surface_coordinate_from_2d(*cell)));
+        path_points.extend(self.path_points[rejoin_waypoint + 1..].iter()
+            .map(|path_point| topology.constrain_coordinate(*path_point)));
+        topology.unwrap_coordinates(&mut path_points);
+
+        // Links up to the current waypoint stay where they were, the ones ahead of it up to the rejoin
+        // waypoint are skipped by the detour
+        let shift = (current_point + detour.len().saturating_sub(1)) as isize - rejoin_waypoint as isize;
+        let shift_waypoint = |waypoint: usize| match waypoint {
+            waypoint if waypoint < current_waypoint => Some(waypoint),
+            waypoint if waypoint >= rejoin_waypoint => Some((waypoint as isize + shift) as usize),
+            _ => None,
+        };
+        self.link_segments = self.link_segments.iter()
+            .filter_map(|segment| shift_waypoint(segment.start_waypoint).map(|start_waypoint| ManeuverLinkSegment {
+                start_waypoint,
+                link: segment.link,
+            }))
+            .collect();
+        if !self.dwell_times.is_empty() {
+            let mut dwell_times = vec![0.0; path_points.len()];
+            for (waypoint, dwell_time) in self.dwell_times.iter().enumerate() {
+                if let Some(shifted) = shift_waypoint(waypoint).and_then(|waypoint| dwell_times.get_mut(waypoint)) {
+                    *shifted = *dwell_time;
+                }
+            }
+            self.dwell_times = dwell_times;
+        }
+
+        self.path_points = path_points;
+        self.rebuild_arc_lengths();
+        self.progress = self.calculate_waypoint_progress(current_point);
+    }
+}
+
+/// Path around blocked cells that stays within `look_ahead` cells of the start.
+pub fn find_bounded_detour(nav_grid: &SquareNavGrid, from: CellIndex2d, to: CellIndex2d, look_ahead: u32,
+                           is_blocked: impl Fn(CellIndex2d) -> bool) -> Option<Vec<CellIndex2d>> {
+    let bound = look_ahead as i32;
+    let bounded_grid = PassabilityOverlay::new(nav_grid, |cell: CellIndex2d| {
+        let within_bound = nav_grid.calculate_wrapped_offset(from, cell).abs().max_element() <= bound;
+        within_bound && (cell == from || !is_blocked(cell))
+    });
+    navigation::find_path_on(&bounded_grid, from, to).map(|(path, _)| path)
+}
+
+/// Path to the closest cell around the goal that could be reached, looking up to `search_radius` cells away.
+pub fn find_alternative_goal(nav_grid: &SquareNavGrid, from: CellIndex2d, goal: CellIndex2d, search_radius: u32,
+                             is_blocked: impl Fn(CellIndex2d) -> bool) -> Option<Vec<CellIndex2d>> {
+    let passable_grid = PassabilityOverlay::new(nav_grid, |cell: CellIndex2d| cell == from || !is_blocked(cell));
+    for radius in 1..=search_radius as i32 {
+        let mut ring: Vec<CellIndex2d> = (-radius..=radius)
+            .flat_map(|x| (-radius..=radius).map(move |y| IVec2::new(x, y)))
+            .filter(|offset| offset.abs().max_element() == radius)
+            .filter_map(|offset| goal.checked_offset_in(offset, nav_grid.grid))
+            .filter(|cell| passable_grid.is_passable(*cell))
+            .collect();
+        ring.sort_by(|a, b| a.euclidean_distance(&goal).total_cmp(&b.euclidean_distance(&goal)));
+        if let Some(path) = ring.into_iter()
+            .find_map(|candidate| navigation::find_path_on(&passable_grid, from, candidate)) {
+            return Some(path.0);
+        }
+    }
+    None
+}
+
+impl StuckDetector {
+    #[inline]
+    pub fn get_stage(&self) -> StuckStage {
+        self.stage
+    }
+
+    pub fn reset(&mut self, position: Vec2) {
+        self.stage = StuckStage::Moving;
+        self.anchor = position;
+        self.stalled_for = 0.0;
+    }
+
+    /// Tracks the agent position. Returns the next stage once the agent has not made progress for long enough.
+    pub fn observe(&mut self, position: Vec2, delta_seconds: f32) -> Option<StuckStage> {
+        if position.distance(self.anchor) > self.progress_distance {
+            self.reset(position);
+            return None;
+        }
+        self.stalled_for += delta_seconds;
+        let stage_duration = if self.stage == StuckStage::Waiting { self.wait_duration } else { self.patience };
+        if self.stalled_for < stage_duration {
+            return None;
+        }
+
+        self.stalled_for = 0.0;
+        self.stage = match self.stage {
+            StuckStage::Moving => StuckStage::Replanning,
+            StuckStage::Replanning => StuckStage::Waiting,
+            StuckStage::Waiting => StuckStage::Rerouting,
+            StuckStage::Rerouting | StuckStage::GivenUp => StuckStage::GivenUp,
+        };
+        Some(self.stage)
+    }
+}
+
+impl AvoidanceSettings {
+    /// Cells in front of an agent moving with the velocity, given in surface coordinate units per second,
+    /// that are checked for obstacles.
+    pub fn calculate_look_ahead_cells(&self, grid: &Grid2D, velocity: Vec2) -> u32 {
+        let cells_per_unit = Vec2::new(grid.max_column_index as f32, grid.max_row_index as f32);
+        let cells_covered = (velocity * cells_per_unit).length() * self.look_ahead_time;
+        (self.min_look_ahead + cells_covered.ceil() as u32).min(self.max_look_ahead.max(self.min_look_ahead))
+    }
+
+    /// Steps of a detour checked against the reservations of other agents.
+    #[inline]
+    pub fn calculate_cooperative_window(&self) -> u32 {
+        self.search_window.max_element() * 2
+    }
+}
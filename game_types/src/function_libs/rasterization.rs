@@ -0,0 +1,187 @@
+use bevy::math::{Rect, URect, Vec2};
+
+use crate::{
+    components::{
+        grid_components::definitions::{CellIndex2d, Grid2D, GridRelatedData, Occupation, StampMode},
+        movement_components::ObstacleFootprint,
+    },
+    function_libs::grid_calculations::extend_inclusive_rect,
+};
+
+// Part of the cell size by which cells are shrunk for conservative stamping, so that shapes which only
+// touch a cell along its border would not cover it
+const TOUCH_TOLERANCE: f32 = 1e-3;
+
+impl ObstacleFootprint {
+    /// Cells which centers are covered by the footprint placed at the world position and turned by the angle.
+    pub fn rasterize(&self, grid: &Grid2D, position: Vec2, angle: f32) -> Vec<CellIndex2d> {
+        match self {
+            ObstacleFootprint::Circle { radius } => rasterize_circle(grid, position, *radius, StampMode::CellCenter),
+            ObstacleFootprint::Rect { half_size } => {
+                let corners = [Vec2::new(-1.0, -1.0), Vec2::new(1.0, -1.0),
+                               Vec2::new(1.0, 1.0), Vec2::new(-1.0, 1.0)];
+                let rotation = Vec2::from_angle(angle);
+                let vertices: Vec<Vec2> = corners.iter()
+                    .map(|corner| position + rotation.rotate(*corner * *half_size))
+                    .collect();
+                rasterize_polygon(grid, &vertices, StampMode::CellCenter)
+            }
+            ObstacleFootprint::Polygon { vertices } => {
+                let rotation = Vec2::from_angle(angle);
+                let vertices: Vec<Vec2> = vertices.iter().map(|vertex| position + rotation.rotate(*vertex)).collect();
+                rasterize_polygon(grid, &vertices, StampMode::CellCenter)
+            }
+        }
+    }
+}
+
+/// Shapes are given in world units. Every stamp returns the area in which the occupation was changed, if it was.
+impl GridRelatedData {
+    pub fn stamp_polygon(&mut self, grid: &Grid2D, vertices: &[Vec2], occupation: Occupation,
+                         mode: StampMode) -> Option<URect> {
+        self.stamp_cells(rasterize_polygon(grid, vertices, mode), occupation)
+    }
+
+    pub fn stamp_circle(&mut self, grid: &Grid2D, center: Vec2, radius: f32, occupation: Occupation,
+                        mode: StampMode) -> Option<URect> {
+        self.stamp_cells(rasterize_circle(grid, center, radius, mode), occupation)
+    }
+
+    /// Stamps every point within `radius` of the segment.
+    pub fn stamp_capsule(&mut self, grid: &Grid2D, from: Vec2, to: Vec2, radius: f32, occupation: Occupation,
+                         mode: StampMode) -> Option<URect> {
+        self.stamp_cells(rasterize_capsule(grid, from, to, radius, mode), occupation)
+    }
+
+    /// Stamps a line with flat ends. Lines without thickness only cover cells with the conservative mode.
+    pub fn stamp_line(&mut self, grid: &Grid2D, from: Vec2, to: Vec2, thickness: f32, occupation: Occupation,
+                      mode: StampMode) -> Option<URect> {
+        let half_width = (to - from).normalize_or_zero().perp() * thickness / 2.0;
+        let vertices = [from - half_width, to - half_width, to + half_width, from + half_width];
+        self.stamp_polygon(grid, &vertices, occupation, mode)
+    }
+
+    fn stamp_cells(&mut self, cells: Vec<CellIndex2d>, occupation: Occupation) -> Option<URect> {
+        let mut changed_area: Option<URect> = None;
+        for cell in cells {
+            if self.set_occupation_at(&cell, occupation) {
+                changed_area = Some(extend_inclusive_rect(changed_area, cell));
+            }
+        }
+        changed_area
+    }
+}
+
+pub fn rasterize_circle(grid: &Grid2D, center: Vec2, radius: f32, mode: StampMode) -> Vec<CellIndex2d> {
+    rasterize_capsule(grid, center, center, radius, mode)
+}
+
+pub fn rasterize_capsule(grid: &Grid2D, from: Vec2, to: Vec2, radius: f32, mode: StampMode) -> Vec<CellIndex2d> {
+    let extent = Vec2::splat(radius);
+    cells_within_bounds(grid, from.min(to) - extent, from.max(to) + extent)
+        .filter(|cell| match mode {
+            StampMode::CellCenter =>
+                calculate_point_segment_distance(grid.calculate_cell_position(*cell), from, to) <= radius,
+            StampMode::Conservative =>
+                calculate_segment_rect_distance(from, to, calculate_cell_rect(grid, *cell)) <= radius,
+        })
+        .collect()
+}
+
+pub fn rasterize_polygon(grid: &Grid2D, vertices: &[Vec2], mode: StampMode) -> Vec<CellIndex2d> {
+    if vertices.is_empty() {
+        return Vec::new();
+    }
+    let min = vertices.iter().copied().reduce(Vec2::min).unwrap();
+    let max = vertices.iter().copied().reduce(Vec2::max).unwrap();
+    cells_within_bounds(grid, min, max)
+        .filter(|cell| match mode {
+            StampMode::CellCenter => is_point_in_polygon(grid.calculate_cell_position(*cell), vertices),
+            StampMode::Conservative => {
+                // Either the cell is inside of the polygon, or one of the edges goes through the cell
+                let cell_rect = calculate_cell_rect(grid, *cell);
+                is_point_in_polygon(cell_rect.center(), vertices)
+                    || polygon_edges(vertices).any(|(start, end)| is_segment_intersecting_rect(start, end, cell_rect))
+            }
+        })
+        .collect()
+}
+
+fn calculate_cell_rect(grid: &Grid2D, cell: CellIndex2d) -> Rect {
+    Rect::from_center_half_size(grid.calculate_cell_position(cell), grid.cell_size * (0.5 - TOUCH_TOLERANCE))
+}
+
+fn polygon_edges(vertices: &[Vec2]) -> impl Iterator<Item=(Vec2, Vec2)> + '_ {
+    vertices.iter().copied().zip(vertices.iter().copied().cycle().skip(1))
+}
+
+// Even-odd rule, counts crossings of a ray going along x from the point
+fn is_point_in_polygon(point: Vec2, vertices: &[Vec2]) -> bool {
+    let mut is_inside = false;
+    for (start, end) in polygon_edges(vertices) {
+        if (start.y > point.y) != (end.y > point.y) {
+            let crossing_x = start.x + (point.y - start.y) * (end.x - start.x) / (end.y - start.y);
+            if point.x < crossing_x {
+                is_inside = !is_inside;
+            }
+        }
+    }
+    is_inside
+}
+
+fn calculate_point_segment_distance(point: Vec2, start: Vec2, end: Vec2) -> f32 {
+    let segment = end - start;
+    let length_squared = segment.length_squared();
+    if length_squared < f32::EPSILON {
+        return point.distance(start);
+    }
+    let along = ((point - start).dot(segment) / length_squared).clamp(0.0, 1.0);
+    point.distance(start + segment * along)
+}
+
+// Liang-Barsky clipping of the segment against the rect
+fn is_segment_intersecting_rect(start: Vec2, end: Vec2, rect: Rect) -> bool {
+    let delta = end - start;
+    let (mut entering, mut leaving) = (0.0f32, 1.0f32);
+    for (direction, distance) in [(-delta.x, start.x - rect.min.x), (delta.x, rect.max.x - start.x),
+                                  (-delta.y, start.y - rect.min.y), (delta.y, rect.max.y - start.y)] {
+        if direction == 0.0 {
+            if distance < 0.0 {
+                return false;
+            }
+            continue;
+        }
+        let crossing = distance / direction;
+        if direction < 0.0 {
+            entering = entering.max(crossing);
+        } else {
+            leaving = leaving.min(crossing);
+        }
+        if entering > leaving {
+            return false;
+        }
+    }
+    true
+}
+
+// Closest points of a segment and a rect that do not intersect are at an end of the segment or a rect corner
+fn calculate_segment_rect_distance(start: Vec2, end: Vec2, rect: Rect) -> f32 {
+    if is_segment_intersecting_rect(start, end, rect) {
+        return 0.0;
+    }
+    let corners = [rect.min, Vec2::new(rect.max.x, rect.min.y), rect.max, Vec2::new(rect.min.x, rect.max.y)];
+    let corner_distance = corners.iter()
+        .map(|corner| calculate_point_segment_distance(*corner, start, end))
+        .fold(f32::INFINITY, f32::min);
+    let end_distance = [start, end].iter()
+        .map(|point| point.distance(point.clamp(rect.min, rect.max)))
+        .fold(f32::INFINITY, f32::min);
+    corner_distance.min(end_distance)
+}
+
+// Cells overlapping the world rectangle, clamped to the grid
+fn cells_within_bounds(grid: &Grid2D, min: Vec2, max: Vec2) -> impl Iterator<Item=CellIndex2d> {
+    let from = grid.calculate_cell_index_from_position(min);
+    let to = grid.calculate_cell_index_from_position(max);
+    (from.y..=to.y).flat_map(move |y| (from.x..=to.x).map(move |x| CellIndex2d::new(x, y)))
+}
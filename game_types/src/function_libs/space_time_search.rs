@@ -0,0 +1,62 @@
+use pathfinding::prelude::astar;
+
+use crate::{
+    components::{
+        grid_components::{
+            definitions::{CellIndex2d, CostSchedule},
+            nav_grid_traits::{NavGrid, NavGridCosts, NavGridNeighbors},
+        },
+        pathfinding_components::{TimedSearchParameters, TimedWaypoint},
+    },
+    function_libs::flow_field::STRAIGHT_STEP_COST,
+};
+
+/// Time-expanded A*: every cell of the route has to be open at the moment the agent gets there, and
+/// scheduled costs are taken at that moment as well. Every move and every wait in place, e.g. in front of
+/// a closed gate, takes a single step duration, while costs only decide which route is the cheapest.
+/// Returns the route including both ends together with its cost.
+///
+/// The grid is expected to have the schedule effects applied at some other moment, e.g. by
+/// `CostSchedule::apply_at`, so they are replaced with the ones at the arrival time.
+pub fn find_timed_path_on<G>(nav_grid: &G, schedule: &CostSchedule, start: CellIndex2d, goal: CellIndex2d,
+                             parameters: TimedSearchParameters) -> Option<(Vec<TimedWaypoint>, u32)>
+    where G: NavGrid<Cell=CellIndex2d> + NavGridNeighbors + NavGridCosts {
+    // Time is counted in steps, so that states could be hashed
+    let max_tick = (parameters.horizon / parameters.step_duration) as u32;
+    let calculate_time = |tick: u32| parameters.departure_time + tick as f32 * parameters.step_duration;
+    let is_open_at = |cell: CellIndex2d, tick: u32| {
+        (nav_grid.is_passable(cell) || schedule.is_closed_by_schedule(cell))
+            && !schedule.is_closed_at(cell, calculate_time(tick))
+    };
+
+    let (states, cost) = astar(&(start, 0u32),
+                               |&(cell, tick)| {
+                                   let mut successors = Vec::new();
+                                   let next_tick = tick + 1;
+                                   if next_tick > max_tick {
+                                       return successors;
+                                   }
+                                   if is_open_at(cell, next_tick) {
+                                       successors.push(((cell, next_tick), STRAIGHT_STEP_COST));
+                                   }
+
+                                   for neighbor in nav_grid.neighbors(cell) {
+                                       if !is_open_at(neighbor, next_tick) {
+                                           continue;
+                                       }
+                                       let step_cost = nav_grid.step_cost(cell, neighbor)
+                                           .saturating_sub(schedule.calculate_applied_extra_cost(neighbor));
+                                       let extra_cost = schedule.calculate_extra_cost_at(neighbor,
+                                                                                         calculate_time(next_tick));
+                                       successors.push(((neighbor, next_tick), step_cost + extra_cost));
+                                   }
+                                   successors
+                               },
+                               |&(cell, _)| nav_grid.estimate_cost(cell, goal),
+                               |&(cell, _)| cell == goal)?;
+
+    let waypoints = states.into_iter()
+        .map(|(cell, tick)| TimedWaypoint { cell, arrival_time: calculate_time(tick) })
+        .collect();
+    Some((waypoints, cost))
+}
@@ -0,0 +1,90 @@
+use std::f32::consts::TAU;
+
+use bevy::math::{IVec2, URect, UVec2, Vec2};
+
+use crate::components::{
+    grid_components::definitions::{CellIndex2d, Grid2D, GridRelatedData},
+    movement_components::Wander,
+};
+
+// Closer than that the agent is considered to be at the target, in surface coordinate units
+const ARRIVAL_TOLERANCE: f32 = 1e-4;
+
+#[inline]
+pub fn seek(position: Vec2, target: Vec2, max_speed: f32) -> Vec2 {
+    (target - position).normalize_or_zero() * max_speed
+}
+
+/// Runs away from the threat while it is closer than `panic_distance`.
+pub fn flee(position: Vec2, threat: Vec2, max_speed: f32, panic_distance: f32) -> Vec2 {
+    if position.distance(threat) > panic_distance {
+        return Vec2::ZERO;
+    }
+    (position - threat).normalize_or_zero() * max_speed
+}
+
+pub fn arrive(position: Vec2, target: Vec2, max_speed: f32, slowing_distance: f32) -> Vec2 {
+    let distance = position.distance(target);
+    if distance < ARRIVAL_TOLERANCE {
+        return Vec2::ZERO;
+    }
+    let speed = if distance < slowing_distance { max_speed * distance / slowing_distance } else { max_speed };
+    (target - position) / distance * speed
+}
+
+/// Seeks the place the target would reach in the time it takes to get to it.
+pub fn pursue(position: Vec2, target: Vec2, target_velocity: Vec2, max_speed: f32) -> Vec2 {
+    seek(position, predict_position(position, target, target_velocity, max_speed), max_speed)
+}
+
+pub fn evade(position: Vec2, threat: Vec2, threat_velocity: Vec2, max_speed: f32, panic_distance: f32) -> Vec2 {
+    flee(position, predict_position(position, threat, threat_velocity, max_speed), max_speed, panic_distance)
+}
+
+#[inline]
+fn predict_position(position: Vec2, other: Vec2, other_velocity: Vec2, max_speed: f32) -> Vec2 {
+    let look_ahead_time = if max_speed > 0.0 { position.distance(other) / max_speed } else { 0.0 };
+    other + other_velocity * look_ahead_time
+}
+
+/// Arrives at the offset from the leader, rotated so that its y axis points the way the leader moves.
+pub fn follow_leader(position: Vec2, leader: Vec2, leader_velocity: Vec2, offset: Vec2, max_speed: f32,
+                     slowing_distance: f32) -> Vec2 {
+    let forward = leader_velocity.try_normalize().unwrap_or(Vec2::Y);
+    let right = Vec2::new(forward.y, -forward.x);
+    arrive(position, leader + right * offset.x + forward * offset.y, max_speed, slowing_distance)
+}
+
+impl Wander {
+    pub fn new(radius: f32, distance: f32, jitter: f32) -> Self {
+        Wander { radius, distance, jitter, angle: 0.0 }
+    }
+
+    /// Moves the point along the circle by `displacement`, a random value in the -1..=1 range scaled by
+    /// the jitter, and seeks it.
+    pub fn steer(&mut self, position: Vec2, heading: Vec2, max_speed: f32, displacement: f32, delta_seconds: f32)
+                 -> Vec2 {
+        self.angle = (self.angle + displacement.clamp(-1.0, 1.0) * self.jitter * delta_seconds).rem_euclid(TAU);
+        let forward = heading.try_normalize().unwrap_or(Vec2::Y);
+        let circle_center = position + forward * self.distance;
+        seek(position, circle_center + Vec2::from_angle(self.angle).rotate(forward) * self.radius, max_speed)
+    }
+}
+
+/// Free cell right behind the obstacle closest to the agent, as seen from the threat.
+pub fn find_hiding_cell(grid: &Grid2D, grid_data: &GridRelatedData, agent_cell: CellIndex2d,
+                        threat_cell: CellIndex2d, search_radius: u32) -> Option<CellIndex2d> {
+    let area_min = UVec2::from(agent_cell).saturating_sub(UVec2::splat(search_radius));
+    let area_max = (UVec2::from(agent_cell) + search_radius)
+        .min(UVec2::new(grid.max_column_index, grid.max_row_index));
+
+    grid.iter_coordinates_in_area(URect::from_corners(area_min, area_max))
+        .filter(|cell| grid_data.is_occupied_at(cell))
+        .filter_map(|obstacle_cell| {
+            let away_from_threat = (IVec2::from(obstacle_cell) - IVec2::from(threat_cell)).as_vec2();
+            let offset = away_from_threat.try_normalize()?.round().as_ivec2();
+            obstacle_cell.checked_offset_in(offset, grid)
+                .filter(|hiding_cell| !grid_data.is_occupied_at(hiding_cell))
+        })
+        .min_by_key(|hiding_cell| hiding_cell.distance(&agent_cell))
+}
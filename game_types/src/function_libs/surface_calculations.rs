@@ -1,13 +1,23 @@
-use std::fmt::{Display, Formatter};
+use std::{
+    f64::consts::{PI, TAU},
+    fmt::{Display, Formatter},
+};
 
 use bevy::{
-    math::{DVec2, Quat, Vec2, Vec3},
+    math::{BVec2, DVec2, IVec2, Quat, Vec2, Vec3},
     prelude::Transform,
 };
 use crate::{
     components::{
-        grid_components::definitions::{CellIndex2d, Grid2D},
-        movement_components::{Coordinate, SurfaceCoordinate},
+        grid_components::definitions::{CellIndex2d, Grid2D, GridResized},
+        movement_components::{
+            Coordinate,
+            SphericalSurface,
+            SurfaceCoordinate,
+            SurfaceTopology,
+            ZLayering,
+            ZOrderPolicy,
+        },
     }
 };
 
@@ -27,18 +37,26 @@ impl SurfaceCoordinate {
     pub fn new(latitude: Coordinate, longitude: Coordinate) -> Self {
         SurfaceCoordinate { latitude, longitude }
     }
-    pub fn set_latitude(&mut self, value: Coordinate) {
-        self.latitude = value.clamp(0.0, 1.0);
+    pub fn set_latitude(&mut self, value: Coordinate, topology: SurfaceTopology) {
+        self.latitude = constrain_value_normalized(value, topology.wraps_x());
     }
 
-    pub fn set_longitude(&mut self, value: Coordinate) {
-        self.longitude = value.clamp(0.0, 1.0);
+    pub fn set_longitude(&mut self, value: Coordinate, topology: SurfaceTopology) {
+        self.longitude = constrain_value_normalized(value, topology.wraps_y());
     }
 
     #[inline]
-    pub fn adjust_coordinate(&mut self, offset: DVec2) {
-        self.latitude = wrap_value_normalized(self.latitude + offset.x as Coordinate);
-        self.longitude = wrap_value_normalized(self.longitude + offset.y as Coordinate);
+    pub fn adjust_coordinate(&mut self, offset: DVec2, topology: SurfaceTopology) {
+        self.set_latitude(self.latitude + offset.x as Coordinate, topology);
+        self.set_longitude(self.longitude + offset.y as Coordinate, topology);
+    }
+
+    /// Moves the coordinate so it stays over the same cell after the grid was resized.
+    pub fn remap_after_resize(&mut self, grid_resized: &GridResized) {
+        self.latitude = remap_after_resize(self.latitude, grid_resized.previous_size.x, grid_resized.new_size.x,
+                                           grid_resized.offset.x);
+        self.longitude = remap_after_resize(self.longitude, grid_resized.previous_size.y, grid_resized.new_size.y,
+                                            grid_resized.offset.y);
     }
 
     #[inline]
@@ -54,6 +72,7 @@ impl SurfaceCoordinate {
         return grid_parameters.calculate_flat_surface_coordinate_from_2d(hovered_cell_index);
     }
 
+    /// Position over the grid at zero depth, see `ZLayering` for the depth agents are drawn at.
     #[inline]
     pub fn project_surface_coordinate_on_grid(&self, grid: &Grid2D) -> Transform {
         let proportional_latitude = self.latitude * (grid.shape_rect.width() as Coordinate - grid.cell_size.x as Coordinate)
@@ -64,13 +83,174 @@ impl SurfaceCoordinate {
         let position = Vec2::new(proportional_latitude as f32, proportional_longitude as f32);
 
         let transform = Transform {
-            translation: position.extend(10.0),
+            translation: position.extend(0.0),
             rotation: Quat::IDENTITY,  // No rotation
             scale: Vec3::ONE,  // No scaling
         };
 
         transform
     }
+
+    /// Azimuth in `[0, TAU)` along the x axis and elevation in `[-PI / 2, PI / 2]` along the y axis.
+    /// Columns go around the equator, so the last one is a single column away from the first.
+    #[inline]
+    pub fn calculate_spherical_angles(&self, grid_parameters: &Grid2D) -> DVec2 {
+        let column = self.latitude as f64 * grid_parameters.max_column_index as f64;
+        DVec2::new(column / grid_parameters.column_number as f64 * TAU, (self.longitude as f64 - 0.5) * PI)
+    }
+
+    /// Central angle between two coordinates on a sphere, calculated with the haversine formula.
+    pub fn calculate_great_circle_angle(&self, other: &SurfaceCoordinate, grid_parameters: &Grid2D) -> f64 {
+        let from = self.calculate_spherical_angles(grid_parameters);
+        let to = other.calculate_spherical_angles(grid_parameters);
+
+        let half_elevation_delta = ((to.y - from.y) / 2.0).sin();
+        let half_azimuth_delta = ((to.x - from.x) / 2.0).sin();
+        let haversine = half_elevation_delta.powi(2)
+            + from.y.cos() * to.y.cos() * half_azimuth_delta.powi(2);
+
+        2.0 * haversine.sqrt().clamp(0.0, 1.0).asin()
+    }
+}
+
+impl ZLayering {
+    #[inline]
+    pub fn calculate_agent_depth(&self, coordinate: &SurfaceCoordinate) -> f32 {
+        match self.policy {
+            ZOrderPolicy::Flat => self.agents,
+            ZOrderPolicy::SortByRow { range } => self.agents + (1.0 - coordinate.longitude.clamp(0.0, 1.0)) * range,
+        }
+    }
+}
+
+impl SphericalSurface {
+    #[inline]
+    pub fn calculate_normal(&self, coordinate: &SurfaceCoordinate, grid_parameters: &Grid2D) -> Vec3 {
+        let angles = coordinate.calculate_spherical_angles(grid_parameters);
+        let (azimuth_sin, azimuth_cos) = angles.x.sin_cos();
+        let (elevation_sin, elevation_cos) = angles.y.sin_cos();
+        Vec3::new((elevation_cos * azimuth_sin) as f32, elevation_sin as f32, (elevation_cos * azimuth_cos) as f32)
+    }
+
+    /// Places the coordinate on the sphere, turning the local z axis along the surface normal.
+    pub fn project_surface_coordinate(&self, coordinate: &SurfaceCoordinate, grid_parameters: &Grid2D) -> Transform {
+        let normal = self.calculate_normal(coordinate, grid_parameters);
+        Transform {
+            translation: self.center + normal * self.radius,
+            rotation: Quat::from_rotation_arc(Vec3::Z, normal),
+            scale: Vec3::ONE,
+        }
+    }
+
+    #[inline]
+    pub fn calculate_great_circle_distance(&self, from: &SurfaceCoordinate, to: &SurfaceCoordinate,
+                                           grid_parameters: &Grid2D) -> f32 {
+        (from.calculate_great_circle_angle(to, grid_parameters) * self.radius as f64) as f32
+    }
+
+    /// Lower bound of grid steps between two cells laid over the sphere. A single step can't cover more
+    /// than the widest cell arc, so dividing the great circle angle by it never overestimates.
+    pub fn calculate_cell_heuristic(grid: &Grid2D, from: CellIndex2d, to: CellIndex2d) -> u32 {
+        let from_coordinate = grid.calculate_flat_surface_coordinate_from_2d(from);
+        let to_coordinate = grid.calculate_flat_surface_coordinate_from_2d(to);
+
+        let azimuth_step = TAU / grid.column_number.max(1) as f64;
+        let elevation_step = PI / grid.max_row_index.max(1) as f64;
+        let widest_step = azimuth_step.max(elevation_step);
+
+        (from_coordinate.calculate_great_circle_angle(&to_coordinate, grid) / widest_step).floor() as u32
+    }
+}
+
+impl SurfaceTopology {
+    #[inline]
+    pub fn wraps_x(&self) -> bool {
+        matches!(self, SurfaceTopology::Torus | SurfaceTopology::CylinderX | SurfaceTopology::Sphere)
+    }
+
+    #[inline]
+    pub fn wraps_y(&self) -> bool {
+        matches!(self, SurfaceTopology::Torus | SurfaceTopology::CylinderY)
+    }
+
+    #[inline]
+    pub fn wrap_flags(&self) -> BVec2 {
+        BVec2::new(self.wraps_x(), self.wraps_y())
+    }
+
+    /// Offsets the cell, wrapping around the grid along wrapped axes.
+    /// Returns `None` if the cell leaves the grid along a bounded axis.
+    #[inline]
+    pub fn offset_cell_in(&self, cell_index: CellIndex2d, offset: IVec2, grid: &Grid2D) -> Option<CellIndex2d> {
+        let x = offset_axis(cell_index.x, offset.x, grid.column_number, self.wraps_x())?;
+        let y = offset_axis(cell_index.y, offset.y, grid.row_number, self.wraps_y())?;
+        Some(CellIndex2d { x, y })
+    }
+
+    /// Brings a coordinate that might be outside of the normalized range back on the surface.
+    #[inline]
+    pub fn constrain_coordinate(&self, coordinate: SurfaceCoordinate) -> SurfaceCoordinate {
+        SurfaceCoordinate {
+            latitude: constrain_value_normalized(coordinate.latitude, self.wraps_x()),
+            longitude: constrain_value_normalized(coordinate.longitude, self.wraps_y()),
+        }
+    }
+
+    /// Offset from one coordinate to another, taking the shorter way across wrapped edges.
+    pub fn calculate_offset(&self, from: SurfaceCoordinate, to: SurfaceCoordinate) -> Vec2 {
+        let mut coordinates = [from, to];
+        self.unwrap_coordinates(&mut coordinates);
+        Vec2::from(coordinates[1]) - Vec2::from(coordinates[0])
+    }
+
+    /// Shifts coordinates by whole surface lengths, so that a path crossing a wrapped edge stays continuous
+    /// and could be interpolated. Results should go through `constrain_coordinate` before being applied.
+    pub fn unwrap_coordinates(&self, coordinates: &mut [SurfaceCoordinate]) {
+        for index in 1..coordinates.len() {
+            let previous = coordinates[index - 1];
+            let current = &mut coordinates[index];
+            if self.wraps_x() {
+                current.latitude = unwrap_value_normalized(current.latitude, previous.latitude);
+            }
+            if self.wraps_y() {
+                current.longitude = unwrap_value_normalized(current.longitude, previous.longitude);
+            }
+        }
+    }
+}
+
+#[inline]
+fn offset_axis(value: u32, offset: i32, cells_number: u32, wraps: bool) -> Option<u32> {
+    let moved = value as i64 + offset as i64;
+    if wraps {
+        Some(moved.rem_euclid(cells_number as i64) as u32)
+    } else if moved >= 0 && moved < cells_number as i64 {
+        Some(moved as u32)
+    } else {
+        None
+    }
+}
+
+#[inline]
+fn constrain_value_normalized(value: Coordinate, wraps: bool) -> Coordinate {
+    if wraps {
+        wrap_value_normalized(value)
+    } else {
+        value.clamp(0.0, 1.0)
+    }
+}
+
+// Picks the representation of the value that is the closest to the previous one
+#[inline]
+fn unwrap_value_normalized(value: Coordinate, previous: Coordinate) -> Coordinate {
+    value - (value - previous).round()
+}
+
+#[inline]
+fn remap_after_resize(value: Coordinate, previous_cells_number: u32, new_cells_number: u32, offset: i32) -> Coordinate {
+    let previous_max_index = previous_cells_number.saturating_sub(1).max(1) as Coordinate;
+    let new_max_index = new_cells_number.saturating_sub(1).max(1) as Coordinate;
+    ((value * previous_max_index + offset as Coordinate) / new_max_index).clamp(0.0, 1.0)
 }
 
 #[inline]
@@ -1,4 +1,4 @@
-use std::borrow::Borrow;
+use std::{borrow::Borrow, collections::HashMap};
 
 use bevy::{
     log::info,
@@ -7,6 +7,9 @@ use bevy::{
         Color,
         Commands,
         Entity,
+        EventReader,
+        EventWriter,
+        Or,
         Query,
         Res,
         ResMut,
@@ -17,6 +20,7 @@ use bevy::{
     },
 };
 use bevy::prelude::Without;
+use rand::Rng;
 
 use crate::{
     components::{
@@ -24,122 +28,662 @@ use crate::{
         grid_components::definitions::{
             CellIndex,
             CellIndex2d,
+            DynamicOccupancy,
             Grid2D,
             GridRelatedData,
             Occupation,
+            OffMeshLinks,
+            ScheduleStateChanged,
+            SquareNavGrid,
         },
         movement_components::{
+            AgentClass,
+            ALL_AGENT_CLASSES,
+            Arrive,
+            AvoidanceSettings,
+            DesiredVelocity,
+            Evade,
+            Flee,
+            FollowLeader,
+            Formation,
+            FormationMember,
+            Heading,
+            Hide,
+            Kinematics,
             Maneuver,
+            ManeuverFinished,
+            ManeuverStarted,
             MoveTag,
+            OffMeshLinkTraversalStarted,
+            PathValidation,
             PerformManeuver,
+            PlaybackMode,
+            Pursue,
+            Seek,
+            SphericalSurface,
+            SteeringBlend,
+            StuckDetector,
+            StuckStage,
             SurfaceCoordinate,
+            SurfaceTopology,
+            Velocity,
+            Wander,
+            WaypointPassed,
+            ZLayering,
         },
         pathfinding_components::{
             MovementSpeed,
             PathfindingMap,
+            ReservationTable,
         },
         directions::Direction,
         pathfinding_components::Pathfinder,
     },
-    systems::{CELLS_IN_FRONT, PATHFINDING_RECT},
+    function_libs::{cooperative_pathfinding, flow_field::STRAIGHT_STEP_COST, navigation, path_following, steering},
 };
 
-pub fn calculate_coordination_data(grid_parameters: &Res<Grid2D>, cell_index: CellIndex2d) -> (SurfaceCoordinate, Transform, Vec2) {
+// How much faster than their speed formation members may move to keep up with their routes
+const ROUTE_CATCH_UP_FACTOR: f32 = 2.0;
+
+pub fn calculate_coordination_data(grid_parameters: &Res<Grid2D>, z_layering: &ZLayering,
+                                   cell_index: CellIndex2d) -> (SurfaceCoordinate, Transform, Vec2) {
     let coordinate = grid_parameters.calculate_flat_surface_coordinate_from_2d(cell_index);
     let mut coordinate_world_transform = coordinate.project_surface_coordinate_on_grid(&grid_parameters);
-    coordinate_world_transform.translation.z = 10.0;
+    coordinate_world_transform.translation.z = z_layering.calculate_agent_depth(&coordinate);
     let actor_size: Vec2 = grid_parameters.cell_size / 2.0;
     (coordinate, coordinate_world_transform, actor_size)
 }
 
-pub fn adjust_coordinate_system(time: Res<Time>, flow_field: Res<FlowField>,
-                                mut query: Query<(&mut SurfaceCoordinate, &CellIndex, &MovementSpeed),
-                                    With<MoveTag>>)
+pub fn adjust_coordinate_system(time: Res<Time>, flow_field: Res<FlowField>, topology: Res<SurfaceTopology>,
+                                mut query: Query<(&mut SurfaceCoordinate, &CellIndex, &MovementSpeed,
+                                                  Option<&mut Kinematics>, Option<&mut Velocity>),
+                                    (With<MoveTag>, Without<DesiredVelocity>)>)
 {
-    for (mut surface_calculations, cell_index, speed)
+    for (mut surface_calculations, cell_index, speed, kinematics, velocity)
     in query.iter_mut() {
+        if let Some(mut kinematics) = kinematics {
+            let desired_velocity = flow_field.get_field_at(cell_index.as_ref()) * kinematics.max_speed;
+            let displacement = kinematics.integrate_towards(desired_velocity, time.delta_seconds());
+            surface_calculations.adjust_coordinate(displacement.as_dvec2(), *topology);
+            if let Some(mut velocity) = velocity {
+                velocity.value = kinematics.velocity;
+            }
+            continue;
+        }
         let direction: DVec2 = DVec2::from(flow_field.get_field_at(cell_index.as_ref()));
 
         let speed_mul = (speed.value * time.delta_seconds()) as f64;
-        surface_calculations.adjust_coordinate(direction * speed_mul);
+        surface_calculations.adjust_coordinate(direction * speed_mul, *topology);
+        if let Some(mut velocity) = velocity {
+            velocity.value = direction.as_vec2() * speed.value;
+        }
+    }
+}
+
+/// Sums up velocities the steering behaviours of every agent ask for.
+pub fn steering_behaviors_system(time: Res<Time>, grid: Res<Grid2D>, grid_data: Res<GridRelatedData>,
+                                 mut query: Query<(&SurfaceCoordinate, &CellIndex, &MovementSpeed,
+                                                   &mut DesiredVelocity, Option<&Velocity>,
+                                                   (Option<&Seek>, Option<&Flee>, Option<&Arrive>),
+                                                   (Option<&Pursue>, Option<&Evade>, Option<&FollowLeader>),
+                                                   (Option<&mut Wander>, Option<&Hide>))>,
+                                 targets_query: Query<(&SurfaceCoordinate, Option<&Velocity>)>) {
+    let mut rng = rand::thread_rng();
+    let locate = |entity: Entity| {
+        targets_query.get(entity).ok()
+            .map(|(coordinate, velocity)| (Vec2::from(*coordinate), velocity.map_or(Vec2::ZERO, |velocity| velocity.value)))
+    };
+
+    for (coordinate, cell_index, speed, mut desired_velocity, velocity,
+        (seek, flee, arrive), (pursue, evade, follow_leader), (wander, hide)) in query.iter_mut() {
+        let position = Vec2::from(*coordinate);
+        let max_speed = speed.value;
+        let mut steering_velocity = Vec2::ZERO;
+
+        if let Some(seek) = seek {
+            steering_velocity += steering::seek(position, seek.target, max_speed);
+        }
+        if let Some(flee) = flee {
+            steering_velocity += steering::flee(position, flee.threat, max_speed, flee.panic_distance);
+        }
+        if let Some(arrive) = arrive {
+            steering_velocity += steering::arrive(position, arrive.target, max_speed, arrive.slowing_distance);
+        }
+        if let Some((target, target_velocity)) = pursue.and_then(|pursue| locate(pursue.target)) {
+            steering_velocity += steering::pursue(position, target, target_velocity, max_speed);
+        }
+        if let Some(evade) = evade {
+            if let Some((threat, threat_velocity)) = locate(evade.threat) {
+                steering_velocity += steering::evade(position, threat, threat_velocity, max_speed,
+                                                     evade.panic_distance);
+            }
+        }
+        if let Some(follow_leader) = follow_leader {
+            if let Some((leader, leader_velocity)) = locate(follow_leader.leader) {
+                steering_velocity += steering::follow_leader(position, leader, leader_velocity, follow_leader.offset,
+                                                             max_speed, follow_leader.slowing_distance);
+            }
+        }
+        if let Some(mut wander) = wander {
+            let heading = velocity.map_or(Vec2::ZERO, |velocity| velocity.value);
+            steering_velocity += wander.steer(position, heading, max_speed, rng.gen_range(-1.0..=1.0),
+                                              time.delta_seconds());
+        }
+        if let Some(hide) = hide {
+            if let Some((threat, _)) = locate(hide.threat) {
+                let threat_cell = SurfaceCoordinate::new(threat.x, threat.y).calculate_cell_index_on_flat_surface(&grid);
+                steering_velocity += match steering::find_hiding_cell(&grid, &grid_data, cell_index.index, threat_cell,
+                                                                      hide.search_radius) {
+                    Some(hiding_cell) => {
+                        let hiding_spot = Vec2::from(grid.calculate_flat_surface_coordinate_from_2d(hiding_cell));
+                        steering::arrive(position, hiding_spot, max_speed, 1.0 / grid.max_column_index as f32)
+                    }
+                    // Nowhere to hide, so just run
+                    None => steering::flee(position, threat, max_speed, f32::INFINITY),
+                };
+            }
+        }
+
+        desired_velocity.value = steering_velocity;
+    }
+}
+
+/// Moves agents with steering behaviours along the blend of the flow field direction and their desired velocity.
+pub fn steering_movement_system(time: Res<Time>, grid: Res<Grid2D>, flow_field: Res<FlowField>,
+                                topology: Res<SurfaceTopology>,
+                                mut query: Query<(&mut SurfaceCoordinate, &mut CellIndex, &MovementSpeed,
+                                                  &DesiredVelocity, Option<&SteeringBlend>, Option<&mut Velocity>,
+                                                  Option<&mut Kinematics>),
+                                    Without<PerformManeuver>>) {
+    for (mut coordinate, mut cell_index, speed, desired_velocity, blend, velocity, kinematics) in query.iter_mut() {
+        let max_speed = kinematics.as_ref().map_or(speed.value, |kinematics| kinematics.max_speed);
+        let blend = blend.copied().unwrap_or_default();
+        let flow_velocity = flow_field.get_field_at(cell_index.as_ref()) * max_speed;
+        let blended_velocity = (flow_velocity * blend.flow_weight + desired_velocity.value * blend.steering_weight)
+            .clamp_length_max(max_speed);
+
+        // With inertia the agent only gets there gradually
+        let (displacement, new_velocity) = match kinematics {
+            Some(mut kinematics) => {
+                let displacement = kinematics.integrate_towards(blended_velocity, time.delta_seconds());
+                (displacement, kinematics.velocity)
+            }
+            None => (blended_velocity * time.delta_seconds(), blended_velocity),
+        };
+        coordinate.adjust_coordinate(displacement.as_dvec2(), *topology);
+        cell_index.index = coordinate.calculate_cell_index_on_flat_surface(&grid);
+        if let Some(mut velocity) = velocity {
+            velocity.value = new_velocity;
+        }
     }
 }
 
-pub fn apply_surface_coordinate_system(grid_parameters: Res<Grid2D>,
-                                       mut query: Query<(&mut Transform,
-                                                         &SurfaceCoordinate), With<MoveTag>>) {
-    for (mut transform, coordinate) in query.iter_mut() {
+/// Turns agents towards the way they move: along the maneuver they perform, their velocity if they have one,
+/// otherwise the flow direction.
+pub fn heading_update_system(time: Res<Time>, flow_field: Res<FlowField>,
+                             mut query: Query<(&mut Heading, &CellIndex, Option<&Kinematics>, Option<&Velocity>,
+                                               Option<&Maneuver>)>) {
+    for (mut heading, cell_index, kinematics, velocity, maneuver) in query.iter_mut() {
+        let direction = match (maneuver.filter(|maneuver| !maneuver.is_done()), kinematics, velocity) {
+            (Some(maneuver), _, _) => maneuver.calculate_facing_direction(),
+            (None, Some(kinematics), _) => kinematics.velocity,
+            (None, None, Some(velocity)) => velocity.value,
+            (None, None, None) => flow_field.get_field_at(cell_index.as_ref()),
+        };
+        heading.turn_towards(direction, time.delta_seconds());
+    }
+}
+
+pub fn apply_surface_coordinate_system(grid_parameters: Res<Grid2D>, z_layering: Res<ZLayering>,
+                                       mut query: Query<(&mut Transform, &SurfaceCoordinate, Option<&Heading>),
+                                           Or<(With<MoveTag>, With<FormationMember>)>>) {
+    for (mut transform, coordinate, heading) in query.iter_mut() {
         *transform = coordinate.project_surface_coordinate_on_grid(&grid_parameters);
+        transform.translation.z = z_layering.calculate_agent_depth(coordinate);
+        if let Some(heading) = heading {
+            transform.rotation = heading.calculate_rotation();
+        }
+    }
+}
+
+pub fn apply_spherical_surface_coordinate_system(grid_parameters: Res<Grid2D>,
+                                                 spherical_surface: Res<SphericalSurface>,
+                                                 mut query: Query<(&mut Transform,
+                                                                   &SurfaceCoordinate, Option<&Heading>),
+                                                     Or<(With<MoveTag>, With<FormationMember>)>>) {
+    for (mut transform, coordinate, heading) in query.iter_mut() {
+        *transform = spherical_surface.project_surface_coordinate(coordinate, &grid_parameters);
+        // Heading turns the agent around the surface normal
+        if let Some(heading) = heading {
+            transform.rotation *= heading.calculate_rotation();
+        }
+    }
+}
+
+/// Way the agent moves, in surface coordinate units per second: its velocity if it has one,
+/// otherwise the flow direction at its movement speed.
+fn calculate_agent_velocity(flow_field: &FlowField, cell_index: &CellIndex, speed: Option<&MovementSpeed>,
+                            kinematics: Option<&Kinematics>, velocity: Option<&Velocity>) -> Vec2 {
+    match (kinematics, velocity) {
+        (Some(kinematics), _) => kinematics.velocity,
+        (None, Some(velocity)) => velocity.value,
+        (None, None) => flow_field.get_field_at(cell_index.as_ref()).normalize_or_zero()
+            * speed.map_or(0.0, |speed| speed.value),
     }
 }
 
-pub fn avoidance_maneuver_system(mut _commands: Commands, grid: Res<Grid2D>,
+/// Agents without `AvoidanceSettings` use the default ones. Agents standing still look in the main movement
+/// direction.
+pub fn avoidance_maneuver_system(mut _commands: Commands, time: Res<Time>, grid: Res<Grid2D>,
                                  mut grid_related_data: ResMut<GridRelatedData>,
+                                 dynamic_occupancy: Res<DynamicOccupancy>,
+                                 mut reservations: ResMut<ReservationTable>,
                                  main_move_direction: Res<Direction>,
-                                 mut query: Query<(Entity, &CellIndex, &mut Maneuver),
+                                 flow_field: Res<FlowField>,
+                                 topology: Res<SurfaceTopology>,
+                                 off_mesh_links: Res<OffMeshLinks>,
+                                 mut query: Query<(Entity, &CellIndex, &mut Maneuver, Option<&AgentClass>,
+                                                   Option<&AvoidanceSettings>, Option<&MovementSpeed>,
+                                                   Option<&Kinematics>, Option<&Velocity>),
                                      (With<MoveTag>, Without<PerformManeuver>)>) {
-    for (entity, cell_index, mut _maneuver) in query.iter_mut() {
+    for (entity, cell_index, mut _maneuver, agent_class, settings, speed, kinematics, velocity) in query.iter_mut() {
+        let agent_classes = agent_class.map_or(ALL_AGENT_CLASSES, |agent_class| agent_class.mask);
+        let settings = settings.copied().unwrap_or_default();
+        let agent_velocity = calculate_agent_velocity(&flow_field, cell_index, speed, kinematics, velocity);
+        let move_direction = Direction::from_vector(agent_velocity).unwrap_or(*main_move_direction);
         let straight_path_area = grid.calculate_line_infront_from(cell_index.index,
-                                                                  main_move_direction.as_vector(),
-                                                                  CELLS_IN_FRONT);
+                                                                  move_direction.as_vector(),
+                                                                  settings.calculate_look_ahead_cells(&grid,
+                                                                                                      agent_velocity));
 
-        if grid_related_data.has_obstacle_in(straight_path_area) {
-            let area = grid.calculate_square_area_wrapped_from(cell_index.index, PATHFINDING_RECT);
+        // Moving obstacles look past their own footprint
+        let lifted_cells = dynamic_occupancy.lift(&mut grid_related_data, entity);
+        'avoidance: {
+            if !grid_related_data.has_obstacle_in(straight_path_area) {
+                break 'avoidance;
+            }
+            let area = grid.calculate_square_area_wrapped_from(cell_index.index, settings.search_window, *topology);
             // grid_related_data.set_color_for_area(area, Color::GRAY);
 
-            let pathfinding_map: PathfindingMap = grid_related_data.create_pathfinding_map_on(&grid, area);
+            let pathfinding_map: PathfindingMap =
+                grid_related_data.create_wrapped_pathfinding_map_on(&grid, area, *topology);
             let path_description_local: Option<Pathfinder> =
-                pathfinding_map.find_destination_in_direction(cell_index.index, *main_move_direction);
+                pathfinding_map.find_destination_in_direction(cell_index.index, move_direction);
 
             if path_description_local.is_none() {
                 // info!("No valid destination found");
-                continue;
+                break 'avoidance;
             }
             let path_description_local = path_description_local.unwrap();
             if path_description_local == _maneuver.last_destination {
-                continue;
+                break 'avoidance;
             }
             _maneuver.last_destination = path_description_local.clone();
             let path_description_global = pathfinding_map.convert_to_global(path_description_local);
 
-            let nav_path: Option<Vec<CellIndex2d>> =
-                pathfinding_map.calculate_path_coordinates_global(path_description_local);
+            let nav_path: Option<Vec<CellIndex2d>> = if *topology == SurfaceTopology::Sphere {
+                pathfinding_map.calculate_path_coordinates_global_with(path_description_local, &off_mesh_links,
+                                                                       agent_classes, |from, to| {
+                    SphericalSurface::calculate_cell_heuristic(&grid, from, to) * STRAIGHT_STEP_COST
+                })
+            } else {
+                pathfinding_map.calculate_path_coordinates_global_with_links(path_description_local,
+                                                                             &off_mesh_links, agent_classes)
+            };
 
             if nav_path.is_none() {
                 info!("No valid path found");
                 pathfinding_map.visualize_key_points_on_grid(&grid, &path_description_global, &grid_related_data);
-                continue;
+                break 'avoidance;
+            }
+            let mut path_points_global = nav_path.unwrap();
+
+            // Detours of other agents are reserved, so several agents would not pick the same free cells
+            let current_step = reservations.calculate_step_at(time.elapsed_seconds());
+            let steps_per_cell = speed.map_or(1, |speed| reservations.calculate_steps_per_cell(&grid, speed.value));
+            if reservations.find_conflict(entity, &path_points_global, current_step, steps_per_cell).is_some() {
+                let nav_grid = SquareNavGrid::new(&grid, &grid_related_data, *topology);
+                let goal = *path_points_global.last().unwrap();
+                let Some(cooperative_path) = cooperative_pathfinding::find_cooperative_path_on(
+                    &nav_grid, &reservations, entity, path_points_global[0], goal, current_step,
+                    settings.calculate_cooperative_window(), steps_per_cell)
+                else {
+                    info!("No cooperative path found");
+                    break 'avoidance;
+                };
+                path_points_global = cooperative_path;
             }
-            let path_points_global = nav_path.unwrap();
+            reservations.reserve_path(entity, &path_points_global, current_step, steps_per_cell);
+            // Waits become stops at the waypoints, lasting as long as the reservations expect
+            let (path_points_global, wait_steps) = cooperative_pathfinding::split_waits(&path_points_global);
+            let step_seconds = reservations.step_duration * steps_per_cell as f32;
             /* pathfinding_map.visualize_path_on_grid(&grid, &path_description_global,
                                                     &grid_related_data, &path_points_global);*/
 
-            let global_path_points =
+            let mut global_path_points =
                 grid.calculate_surface_coordinates_for_2d(&path_points_global);
+            topology.unwrap_coordinates(&mut global_path_points);
 
             grid_related_data.set_color_for_index(&path_description_local.start, Color::RED);
             grid_related_data.set_color_for_index(&path_description_local.end, Color::MIDNIGHT_BLUE);
 
-            _maneuver.set_coordinates(global_path_points);
+            let link_segments = off_mesh_links.find_links_along(&path_points_global, agent_classes);
+            _maneuver.set_coordinates_with_links(global_path_points, link_segments);
+            if wait_steps.iter().any(|steps| *steps > 0) {
+                _maneuver.set_dwell_times(wait_steps.iter().map(|steps| *steps as f32 * step_seconds).collect());
+            }
             _commands.entity(entity).insert(PerformManeuver::default());
         }
+        dynamic_occupancy.put_back(&mut grid_related_data, &lifted_cells);
     }
 }
 
 pub fn path_movement_system(mut _commands: Commands,
                             time: Res<Time>,
-                            mut query: Query<(Entity, &mut SurfaceCoordinate, &mut Maneuver),
-                                (With<MoveTag>, With<PerformManeuver>)>) {
-    for (_entity, mut coordinate, mut maneuver) in query.iter_mut() {
-        *coordinate = maneuver.catmull_rom_interpolate_along_path(0.005);
+                            topology: Res<SurfaceTopology>,
+                            mut reservations: ResMut<ReservationTable>,
+                            mut query: Query<(Entity, &mut SurfaceCoordinate, &mut Maneuver, &MovementSpeed,
+                                              Option<&mut Velocity>), (With<MoveTag>, With<PerformManeuver>)>,
+                            mut started_events: EventWriter<ManeuverStarted>,
+                            mut waypoint_events: EventWriter<WaypointPassed>,
+                            mut finished_events: EventWriter<ManeuverFinished>) {
+    for (_entity, mut coordinate, mut maneuver, speed, velocity) in query.iter_mut() {
+        if maneuver.path_points.is_empty() {
+            _commands.entity(_entity).remove::<PerformManeuver>();
+            continue;
+        }
+        let was_started = maneuver.has_started();
+        let previous_coordinate = *coordinate;
+        *coordinate = topology.constrain_coordinate(maneuver.advance_by_time(speed.value, time.delta_seconds()));
+        if let Some(mut velocity) = velocity {
+            velocity.value = topology.calculate_offset(previous_coordinate, *coordinate)
+                / time.delta_seconds().max(f32::EPSILON);
+        }
+        if !was_started && maneuver.has_started() {
+            started_events.send(ManeuverStarted { entity: _entity });
+        }
+        // Reservations only cover a single pass, maneuvers going on and on give them up at the ends
+        let is_cyclic = matches!(maneuver.playback_mode, PlaybackMode::Loop | PlaybackMode::PingPong);
+        let last_waypoint = maneuver.path_points.len() - 1;
+        for waypoint in maneuver.take_passed_waypoints() {
+            if is_cyclic && (waypoint == 0 || waypoint == last_waypoint) {
+                reservations.release_agent(_entity);
+            }
+            waypoint_events.send(WaypointPassed { entity: _entity, waypoint });
+        }
         if maneuver.is_done() {
+            finished_events.send(ManeuverFinished { entity: _entity });
             _commands.entity(_entity).remove::<PerformManeuver>();
+            reservations.release_agent(_entity);
+        }
+    }
+}
+
+pub fn reservation_cleanup_system(time: Res<Time>, mut reservations: ResMut<ReservationTable>) {
+    let current_step = reservations.calculate_step_at(time.elapsed_seconds());
+    reservations.release_before(current_step);
+}
+
+pub fn off_mesh_link_traversal_system(mut query: Query<(Entity, &mut Maneuver), With<PerformManeuver>>,
+                                      mut traversal_events: EventWriter<OffMeshLinkTraversalStarted>) {
+    for (entity, mut maneuver) in query.iter_mut() {
+        for link in maneuver.take_started_links() {
+            traversal_events.send(OffMeshLinkTraversalStarted { entity, link });
+        }
+    }
+}
+
+/// Forgets cached destinations of the maneuvers going through areas whose schedule has changed, so they would
+/// be planned again. Maneuvers running into a closed area are stopped.
+pub fn scheduled_path_invalidation_system(mut commands: Commands, grid_parameters: Res<Grid2D>,
+                                          mut schedule_state_changes: EventReader<ScheduleStateChanged>,
+                                          mut query: Query<(Entity, &mut Maneuver, Option<&PerformManeuver>),
+                                              With<MoveTag>>) {
+    for state_change in schedule_state_changes.read() {
+        for (entity, mut maneuver, perform_maneuver) in query.iter_mut() {
+            if !maneuver.crosses_area(&grid_parameters, state_change.area) {
+                continue;
+            }
+            maneuver.last_destination = Pathfinder::ZERO;
+            if state_change.occupation_changed && perform_maneuver.is_some() {
+                commands.entity(entity).remove::<PerformManeuver>();
+            }
+        }
+    }
+}
+
+/// Checks the way ahead of agents performing maneuvers every now and then. Once a cell ahead gets occupied
+/// or crowded, the agent plans a detour within its look-ahead back to the first free waypoint behind it.
+/// Moving obstacles plan past their own footprint.
+pub fn path_validation_system(time: Res<Time>, grid: Res<Grid2D>, mut grid_data: ResMut<GridRelatedData>,
+                              dynamic_occupancy: Res<DynamicOccupancy>, topology: Res<SurfaceTopology>,
+                              mut reservations: ResMut<ReservationTable>,
+                              mut query: Query<(Entity, &CellIndex, &SurfaceCoordinate, &mut Maneuver,
+                                                &mut PathValidation, Option<&MovementSpeed>),
+                                  (With<MoveTag>, With<PerformManeuver>)>,
+                              agents_query: Query<&CellIndex, With<MoveTag>>) {
+    let agents_per_cell = count_agents_per_cell(agents_query.iter());
+    for (entity, cell_index, coordinate, mut maneuver, mut validation, speed) in query.iter_mut() {
+        validation.since_last_check += time.delta_seconds();
+        if validation.since_last_check < validation.interval {
+            continue;
+        }
+        validation.since_last_check = 0.0;
+
+        let lifted_cells = dynamic_occupancy.lift(&mut grid_data, entity);
+        'validation: {
+            let nav_grid = SquareNavGrid::new(&grid, &grid_data, *topology);
+            let (look_ahead, crowd_limit) = (validation.look_ahead, validation.crowd_limit);
+            let is_blocked = |cell: CellIndex2d| {
+                is_cell_blocked(&grid_data, &agents_per_cell, cell, cell_index.index, crowd_limit)
+            };
+            let Some(blocked_waypoint) = maneuver.find_blocked_waypoint_ahead(&grid, *topology, look_ahead,
+                                                                              &is_blocked)
+            else {
+                break 'validation;
+            };
+            // With the goal blocked there is nothing to rejoin, the stuck detector takes care of that
+            let Some(rejoin_waypoint) = maneuver.find_rejoin_waypoint(&grid, *topology, blocked_waypoint,
+                                                                      &is_blocked)
+            else {
+                break 'validation;
+            };
+            let rejoin_cell = maneuver.calculate_waypoint_cell(&grid, *topology, rejoin_waypoint);
+            let Some(detour) = path_following::find_bounded_detour(&nav_grid, cell_index.index, rejoin_cell,
+                                                                    look_ahead, &is_blocked)
+            else {
+                info!("No detour found within the look-ahead");
+                break 'validation;
+            };
+            maneuver.splice_detour(&grid, *topology, *coordinate, &detour, rejoin_waypoint);
+            let current_step = reservations.calculate_step_at(time.elapsed_seconds());
+            let steps_per_cell = speed.map_or(1, |speed| reservations.calculate_steps_per_cell(&grid, speed.value));
+            reservations.reserve_path(entity, &detour, current_step, steps_per_cell);
+        }
+        dynamic_occupancy.put_back(&mut grid_data, &lifted_cells);
+    }
+}
+
+/// Escalates for agents performing maneuvers that stopped making progress: plans the whole way again, then
+/// waits for it to clear, then heads for the goal or a free cell near it and finally gives up. Moving
+/// obstacles plan past their own footprint.
+pub fn stuck_detection_system(mut commands: Commands, time: Res<Time>, grid: Res<Grid2D>,
+                              mut grid_data: ResMut<GridRelatedData>, dynamic_occupancy: Res<DynamicOccupancy>,
+                              topology: Res<SurfaceTopology>, mut reservations: ResMut<ReservationTable>,
+                              mut query: Query<(Entity, &CellIndex, &SurfaceCoordinate, &mut Maneuver,
+                                                &mut StuckDetector, Option<&PathValidation>, Option<&MovementSpeed>),
+                                  (With<MoveTag>, With<PerformManeuver>)>,
+                              agents_query: Query<&CellIndex, With<MoveTag>>) {
+    let agents_per_cell = count_agents_per_cell(agents_query.iter());
+    let whole_grid_bound = grid.column_number.max(grid.row_number);
+    for (entity, cell_index, coordinate, mut maneuver, mut detector, validation, speed) in query.iter_mut() {
+        let stage = detector.observe(Vec2::from(*coordinate), time.delta_seconds());
+        if stage.is_none() && detector.get_stage() != StuckStage::Waiting {
+            continue;
+        }
+
+        let lifted_cells = dynamic_occupancy.lift(&mut grid_data, entity);
+        'escalation: {
+            let nav_grid = SquareNavGrid::new(&grid, &grid_data, *topology);
+            let validation = validation.copied().unwrap_or_default();
+            let is_blocked = |cell: CellIndex2d| {
+                is_cell_blocked(&grid_data, &agents_per_cell, cell, cell_index.index, validation.crowd_limit)
+            };
+            if detector.get_stage() == StuckStage::Waiting {
+                // Stands only while the next waypoint is blocked. Once the agent gets going again the detector
+                // goes back to moving, otherwise it reroutes when the wait is over.
+                if maneuver.find_blocked_waypoint_ahead(&grid, *topology, 1, &is_blocked).is_some() {
+                    maneuver.pause();
+                } else {
+                    maneuver.resume();
+                }
+                break 'escalation;
+            }
+            let (Some(stage), Some(goal_waypoint)) = (stage, maneuver.path_points.len().checked_sub(1)) else {
+                break 'escalation;
+            };
+            let goal = maneuver.calculate_waypoint_cell(&grid, *topology, goal_waypoint);
+
+            let new_path = match stage {
+                StuckStage::Replanning => path_following::find_bounded_detour(&nav_grid, cell_index.index, goal,
+                                                                              whole_grid_bound, &is_blocked),
+                StuckStage::Rerouting => {
+                    maneuver.resume();
+                    path_following::find_bounded_detour(&nav_grid, cell_index.index, goal, whole_grid_bound,
+                                                        &is_blocked)
+                        .or_else(|| path_following::find_alternative_goal(&nav_grid, cell_index.index, goal,
+                                                                          validation.look_ahead, &is_blocked))
+                }
+                // Waiting agents are taken care of above
+                StuckStage::Waiting => break 'escalation,
+                StuckStage::Moving | StuckStage::GivenUp => {
+                    info!("Agent gave up its maneuver");
+                    maneuver.resume();
+                    detector.reset(Vec2::from(*coordinate));
+                    commands.entity(entity).remove::<PerformManeuver>();
+                    reservations.release_agent(entity);
+                    break 'escalation;
+                }
+            };
+            let Some(new_path) = new_path else {
+                break 'escalation;
+            };
+            maneuver.splice_detour(&grid, *topology, *coordinate, &new_path, goal_waypoint);
+            let current_step = reservations.calculate_step_at(time.elapsed_seconds());
+            let steps_per_cell = speed.map_or(1, |speed| reservations.calculate_steps_per_cell(&grid, speed.value));
+            reservations.reserve_path(entity, &new_path, current_step, steps_per_cell);
+        }
+        dynamic_occupancy.put_back(&mut grid_data, &lifted_cells);
+    }
+}
+
+fn count_agents_per_cell<'a>(cells: impl Iterator<Item=&'a CellIndex>) -> HashMap<CellIndex2d, u32> {
+    let mut agents_per_cell = HashMap::new();
+    for cell_index in cells {
+        *agents_per_cell.entry(cell_index.index).or_insert(0) += 1;
+    }
+    agents_per_cell
+}
+
+/// Whether the cell is occupied or holds at least `crowd_limit` agents besides the one standing in `own_cell`.
+#[inline]
+fn is_cell_blocked(grid_data: &GridRelatedData, agents_per_cell: &HashMap<CellIndex2d, u32>, cell: CellIndex2d,
+                   own_cell: CellIndex2d, crowd_limit: u32) -> bool {
+    let other_agents = agents_per_cell.get(&cell).copied().unwrap_or(0).saturating_sub(u32::from(cell == own_cell));
+    grid_data.is_occupied_at(&cell) || other_agents >= crowd_limit
+}
+
+/// Lays out slots of every formation around its leader and hands them out to the members again whenever
+/// members are gone or the shape changed. Members plan their way to the slots over the grid.
+pub fn formation_slot_system(grid: Res<Grid2D>, grid_data: Res<GridRelatedData>, flow_field: Res<FlowField>,
+                             topology: Res<SurfaceTopology>,
+                             mut leaders_query: Query<(&mut Formation, &CellIndex, Option<&Maneuver>,
+                                                       Option<&PerformManeuver>)>,
+                             mut members_query: Query<(&CellIndex, &mut FormationMember)>) {
+    for (mut formation, leader_cell, maneuver, perform_maneuver) in leaders_query.iter_mut() {
+        let members_changed = formation.retain_members(|member| members_query.contains(member));
+        let direction = match maneuver {
+            Some(maneuver) if perform_maneuver.is_some() => maneuver.calculate_current_direction(&grid),
+            _ => flow_field.get_field_at(leader_cell.as_ref()),
+        };
+        formation.set_heading(direction);
+
+        let (slot_cells, _) = formation.calculate_slot_cells(&grid, &grid_data, *topology, leader_cell.index);
+        let nav_grid = SquareNavGrid::new(&grid, &grid_data, *topology);
+        if formation.needs_slot_assignment(members_changed, slot_cells.len()) {
+            formation.assigned_shape = formation.shape;
+            let member_cells: Vec<CellIndex2d> = formation.members.iter()
+                .filter_map(|member| members_query.get(*member).ok())
+                .map(|(cell_index, _)| cell_index.index)
+                .collect();
+            let member_slots = Formation::assign_slots(&member_cells, &slot_cells);
+            let mut routes = Formation::plan_member_routes(&nav_grid, &member_cells, &slot_cells, &member_slots)
+                .unwrap_or_default()
+                .into_iter();
+            for (member, slot) in formation.members.iter().zip(member_slots) {
+                if let Ok((_, mut formation_member)) = members_query.get_mut(*member) {
+                    formation_member.slot = slot;
+                    formation_member.set_route(routes.next().unwrap_or_default());
+                }
+            }
+        }
+        formation.set_slot_cells(slot_cells);
+
+        // Slots move along with the leader, members that fell behind them plan their way again
+        for member in formation.members.iter() {
+            let Ok((cell_index, mut formation_member)) = members_query.get_mut(*member) else {
+                continue;
+            };
+            let Some(slot_cell) = formation.get_slot_cell(formation_member.slot) else {
+                continue;
+            };
+            if formation_member.needs_route_to(cell_index.index, slot_cell) {
+                let route = navigation::find_path_on(&nav_grid, cell_index.index, slot_cell)
+                    .map(|(path, _)| path)
+                    .unwrap_or_default();
+                formation_member.set_route(route);
+            }
+        }
+    }
+}
+
+/// Moves formation members along their routes and then into their slots. Members are not driven by the flow
+/// field, so they should not have the `MoveTag`.
+pub fn formation_following_system(time: Res<Time>, grid: Res<Grid2D>, topology: Res<SurfaceTopology>,
+                                  leaders_query: Query<&Formation>,
+                                  mut members_query: Query<(&mut FormationMember, &mut SurfaceCoordinate,
+                                                            &mut CellIndex, &MovementSpeed, Option<&mut Velocity>),
+                                      Without<MoveTag>>) {
+    // Routes are timed in cells, the larger side of the cell is taken as for reservations
+    let cells_per_unit = grid.max_column_index.min(grid.max_row_index).max(1) as f32;
+    for (mut formation_member, mut coordinate, mut cell_index, speed, velocity) in members_query.iter_mut() {
+        let distance = speed.value * time.delta_seconds();
+        let (target, max_distance) = match formation_member.advance_route(&grid, distance * cells_per_unit) {
+            // Members that lag behind the timing of their route catch up with it
+            Some(target) => (target, distance * ROUTE_CATCH_UP_FACTOR),
+            // Without a route the member only settles within its slot and otherwise stands, it does not cut
+            // through obstacles
+            None => {
+                let target = leaders_query.get(formation_member.leader).ok()
+                    .and_then(|formation| formation.get_slot_cell(formation_member.slot))
+                    .filter(|slot_cell| *slot_cell == cell_index.index)
+                    .map_or(Vec2::from(*coordinate),
+                            |slot_cell| grid.calculate_flat_surface_coordinate_from_2d(slot_cell).into());
+                (target, distance)
+            }
+        };
+        let offset = (target - Vec2::from(*coordinate)).clamp_length_max(max_distance);
+        coordinate.adjust_coordinate(offset.as_dvec2(), *topology);
+        cell_index.index = coordinate.calculate_cell_index_on_flat_surface(&grid);
+        if let Some(mut velocity) = velocity {
+            velocity.value = offset / time.delta_seconds().max(f32::EPSILON);
         }
     }
 }
 
 pub fn grid_relation_system(grid_parameters: Res<Grid2D>,
-                            mut query: Query<(&mut CellIndex, &SurfaceCoordinate), With<MoveTag>>)
+                            mut query: Query<(&mut CellIndex, &SurfaceCoordinate),
+                                Or<(With<MoveTag>, With<FormationMember>)>>)
 {
     for (mut cell_index, surface_calculations) in query.iter_mut() {
         cell_index.index = surface_calculations.calculate_cell_index_on_flat_surface(&grid_parameters);
@@ -149,12 +693,18 @@ pub fn grid_relation_system(grid_parameters: Res<Grid2D>,
 pub fn cell_occupation_highlight_system(mut grid_cell_data: ResMut<GridRelatedData>,
                                         grid_parameters: Res<Grid2D>,
                                         main_movement_direction: Res<Direction>,
-                                        query: Query<&CellIndex, With<MoveTag>>)
+                                        flow_field: Res<FlowField>,
+                                        query: Query<(&CellIndex, Option<&AvoidanceSettings>, Option<&MovementSpeed>,
+                                                      Option<&Kinematics>, Option<&Velocity>), With<MoveTag>>)
 {
-    for cell_index in query.iter() {
+    for (cell_index, settings, speed, kinematics, velocity) in query.iter() {
+        let settings = settings.copied().unwrap_or_default();
+        let agent_velocity = calculate_agent_velocity(&flow_field, cell_index, speed, kinematics, velocity);
+        let move_direction = Direction::from_vector(agent_velocity).unwrap_or(*main_movement_direction);
         let segment_area = grid_parameters.calculate_line_from(cell_index.index.into(),
-                                                               main_movement_direction.as_vector(),
-                                                               CELLS_IN_FRONT);
+                                                               move_direction.as_vector(),
+                                                               settings.calculate_look_ahead_cells(&grid_parameters,
+                                                                                                   agent_velocity));
         let segment_view = grid_parameters.get_indexes_segment(segment_area);
 
         for segment_cell_index in segment_view {
@@ -3,10 +3,12 @@ use bevy::{
     math::Quat,
     prelude::{
         ButtonInput,
+        EventReader,
         MouseButton,
         Query,
         Res,
         ResMut,
+        Time,
         Transform,
         With,
     },
@@ -15,20 +17,26 @@ use bevy::math::{URect, UVec2};
 
 use crate::{
     components::{
-        flow_field_components::{Arrow, ExplosionParameters, FlowField},
+        flow_field_components::{Arrow, Falloff, FlowBrush, FlowBrushes, FlowBrushKind, FlowField, FlowFieldGoals},
         grid_components::{
             definitions::{
                 CellIndex,
                 Grid2D,
                 GridRelatedData,
             },
-            definitions::{GridSegment, ObstaclesParameters},
+            definitions::{
+                ClearanceLayer,
+                GridSegment,
+                ObstaclesParameters,
+                OccupationChanged,
+                OffMeshLinks,
+                ScheduleStateChanged,
+            },
         },
-        movement_components::{MoveTag, ObstacleTag},
+        movement_components::{ALL_AGENT_CLASSES, MoveTag, ObstacleTag, SurfaceTopology},
         world_manipulation_components::CursorWorldPosition,
     }
 };
-use crate::components::grid_components::definitions::Occupation;
 
 pub fn rotate_flow_arrows_system(mut shapes_transform_query: Query<(&mut Transform, &CellIndex), With<Arrow>>,
                                  flow_field: Res<FlowField>) {
@@ -38,34 +46,67 @@ pub fn rotate_flow_arrows_system(mut shapes_transform_query: Query<(&mut Transfo
 }
 
 pub fn flow_explosion_system(input: Res<ButtonInput<MouseButton>>, cursor_world_position: Res<CursorWorldPosition>,
-                             grid_parameters: Res<Grid2D>, mut flow_field: ResMut<FlowField>) {
+                             grid_parameters: Res<Grid2D>, mut flow_brushes: ResMut<FlowBrushes>) {
     if input.just_pressed(MouseButton::Left) {
         info!("LMB was pressed!");
 
         let world_pos = cursor_world_position.position;
-        let hovered_cell_index = grid_parameters.calculate_cell_index_from_position(world_pos);
-        flow_field.apply_smooth_explosion(&grid_parameters, ExplosionParameters::new(hovered_cell_index,
-                                                                                     4.0));
+        flow_brushes.add(FlowBrush::new(FlowBrushKind::Repulsor, world_pos, 4.0 * grid_parameters.cell_size.x,
+                                        Falloff::Smoothstep, 2.0, Some(3.0)));
+    }
+}
+
+/// Decays the flow brushes and shapes the flow field with them. Fields integrated meanwhile become the new base.
+pub fn flow_brush_system(time: Res<Time>, grid_parameters: Res<Grid2D>, mut flow_brushes: ResMut<FlowBrushes>,
+                         mut flow_field: ResMut<FlowField>) {
+    if flow_field.is_changed() {
+        flow_brushes.rebase_on(&flow_field);
+    }
+    flow_brushes.advance(time.delta_seconds());
+    if flow_brushes.is_shaping() {
+        flow_brushes.apply_to(&mut flow_field, &grid_parameters);
     }
 }
 
 pub fn detraction_factor_calculation_system(mut grid_data: ResMut<GridRelatedData>,
                                             grid: Res<Grid2D>,
                                             obstacles_parameters: Res<ObstaclesParameters>,
-                                            query: Query<&CellIndex>) {
-    for obstacle_index in query.iter() {
-        let cell_index = &obstacle_index.index;
+                                            mut clearance_layer: ResMut<ClearanceLayer>) {
+    let updated_area = clearance_layer.recompute_all(&grid, &grid_data);
+    grid_data.apply_detraction_from_clearance(&clearance_layer, updated_area,
+                                              obstacles_parameters.influence_radius());
+}
 
-        if grid_data.get_data_at(cell_index).occupation_state != Occupation::Occupied {
-            continue;
-        }
+pub fn clearance_update_system(mut occupation_changes: EventReader<OccupationChanged>,
+                               mut grid_data: ResMut<GridRelatedData>,
+                               grid: Res<Grid2D>,
+                               obstacles_parameters: Res<ObstaclesParameters>,
+                               mut clearance_layer: ResMut<ClearanceLayer>) {
+    for occupation_change in occupation_changes.read() {
+        clearance_layer.mark_dirty(occupation_change.area);
+    }
 
-        let segment_rect = grid.calculate_area_clamped_from_center(cell_index,
-                                                                   obstacles_parameters.influence_area);
+    if let Some(updated_area) = clearance_layer.update(&grid, &grid_data) {
+        grid_data.apply_detraction_from_clearance(&clearance_layer, updated_area,
+                                                  obstacles_parameters.influence_radius());
+    }
+}
 
-        for cell_in_segment in grid.iter_coordinates_in_area(segment_rect) {
-            let detraction_factor = cell_index.inverse_chebyshev_distance(&cell_in_segment);
-            grid_data.set_increased_detraction_factor(&cell_in_segment, detraction_factor);
-        }
+pub fn flow_field_integration_system(grid: Res<Grid2D>, grid_data: Res<GridRelatedData>,
+                                     topology: Res<SurfaceTopology>, goals: Res<FlowFieldGoals>,
+                                     off_mesh_links: Res<OffMeshLinks>,
+                                     mut occupation_changes: EventReader<OccupationChanged>,
+                                     mut schedule_state_changes: EventReader<ScheduleStateChanged>,
+                                     mut flow_field: ResMut<FlowField>) {
+    // Both readers are drained, so that old events would not trigger the integration later
+    let occupation_changed = occupation_changes.read().count() > 0;
+    let schedule_changed = schedule_state_changes.read().count() > 0;
+    let is_outdated = goals.is_changed() || topology.is_changed() || off_mesh_links.is_changed()
+        || occupation_changed || schedule_changed;
+    if goals.cells.is_empty() || !is_outdated {
+        return;
     }
+    // The field is shared, so only links every agent could use are taken into account
+    flow_field.integrate_towards_with_links(&grid, &grid_data, &goals.cells, *topology, &off_mesh_links,
+                                            ALL_AGENT_CLASSES);
 }
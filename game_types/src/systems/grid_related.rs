@@ -1,12 +1,24 @@
+use std::collections::HashSet;
+
 use bevy::{
-    prelude::{Color, Query, Res, ResMut, With},
+    math::EulerRot,
+    prelude::{Color, Entity, EventReader, EventWriter, GlobalTransform, Query, Ref, RemovedComponents, Res, ResMut,
+              With},
     sprite::Sprite,
 };
 use bevy::prelude::Time;
 use crate::components::directions::Direction;
 
-use crate::components::grid_components::definitions::{CellIndex, ElapsedTimeTracker, Grid2D, GridCellTag,
-                                                      GridRelatedData};
+use crate::components::{
+    flow_field_components::FlowField,
+    grid_components::definitions::{CellIndex, ChunkedGridRelatedData, ChunkLoaded, ChunkStreamingAnchor,
+                                   ChunkUnloaded, ClearanceLayer, CostSchedule, DynamicOccupancy,
+                                   ElapsedTimeTracker, Grid2D,
+                                   GridCellTag, GridRelatedData, GridResized, OccupationChanged, OffMeshLinks,
+                                   ResizeGrid, ScheduleStateChanged},
+    movement_components::{Maneuver, ObstacleFootprint, ObstacleTag, SurfaceCoordinate},
+    pathfinding_components::ReservationTable,
+};
 
 pub fn reset_cells_colorization(grid: Res<Grid2D>, mut grid_cell_data: ResMut<GridRelatedData>) {
     let mut color1 = Color::YELLOW_GREEN;
@@ -43,6 +55,107 @@ pub fn visualize_grid_data_in_log(time: Res<Time>, mut elapsed_time_tracker: Res
     }
 }
 
+pub fn grid_resize_system(mut resize_requests: EventReader<ResizeGrid>, mut grid: ResMut<Grid2D>,
+                          mut grid_resized_events: EventWriter<GridResized>) {
+    for resize_request in resize_requests.read() {
+        let grid_resized = grid.resize(resize_request.column_number, resize_request.row_number,
+                                       resize_request.anchor);
+        grid_resized_events.send(grid_resized);
+    }
+}
+
+pub fn grid_layers_resize_system(mut grid_resized_events: EventReader<GridResized>,
+                                 mut grid_cell_data: ResMut<GridRelatedData>,
+                                 mut flow_field: ResMut<FlowField>,
+                                 mut clearance_layer: ResMut<ClearanceLayer>,
+                                 mut dynamic_occupancy: ResMut<DynamicOccupancy>,
+                                 mut cost_schedule: ResMut<CostSchedule>,
+                                 mut off_mesh_links: ResMut<OffMeshLinks>,
+                                 mut reservations: ResMut<ReservationTable>,
+                                 mut coordinates_query: Query<&mut SurfaceCoordinate>,
+                                 mut maneuvers_query: Query<&mut Maneuver>) {
+    for grid_resized in grid_resized_events.read() {
+        // Stamped cells are freed while the grid data still has its previous size
+        dynamic_occupancy.resize_to(&mut grid_cell_data, grid_resized);
+        grid_cell_data.resize_to(grid_resized);
+        flow_field.resize_to(grid_resized);
+        clearance_layer.resize_to(grid_resized);
+        cost_schedule.resize_to(grid_resized);
+        off_mesh_links.resize_to(grid_resized);
+        reservations.resize_to(grid_resized);
+
+        for mut coordinate in coordinates_query.iter_mut() {
+            coordinate.remap_after_resize(grid_resized);
+        }
+        for mut maneuver in maneuvers_query.iter_mut() {
+            maneuver.remap_after_resize(grid_resized);
+        }
+    }
+}
+
+pub fn chunk_streaming_system(grid_parameters: Res<Grid2D>,
+                              mut chunked_grid_data: ResMut<ChunkedGridRelatedData>,
+                              anchors_query: Query<(&GlobalTransform, &ChunkStreamingAnchor)>,
+                              mut chunk_loaded_events: EventWriter<ChunkLoaded>,
+                              mut chunk_unloaded_events: EventWriter<ChunkUnloaded>) {
+    let mut required_chunks = HashSet::new();
+    for (transform, anchor) in anchors_query.iter() {
+        let anchor_cell = ChunkedGridRelatedData::calculate_cell_from_position(&grid_parameters,
+                                                                               transform.translation().truncate());
+        required_chunks.extend(chunked_grid_data.calculate_chunks_around(anchor_cell, anchor.radius_in_chunks));
+    }
+
+    let (loaded_chunks, unloaded_chunks) = chunked_grid_data.stream_chunks(&required_chunks);
+    for chunk in loaded_chunks {
+        chunk_loaded_events.send(ChunkLoaded { chunk });
+    }
+    for (chunk, data) in unloaded_chunks {
+        chunk_unloaded_events.send(ChunkUnloaded { chunk, data });
+    }
+}
+
+pub fn cost_schedule_system(time: Res<Time>, mut cost_schedule: ResMut<CostSchedule>,
+                            mut grid_data: ResMut<GridRelatedData>,
+                            mut occupation_changes: EventWriter<OccupationChanged>,
+                            mut schedule_state_changes: EventWriter<ScheduleStateChanged>) {
+    for state_change in cost_schedule.apply_at(time.elapsed_seconds(), &mut grid_data) {
+        if state_change.occupation_changed {
+            occupation_changes.send(OccupationChanged { area: state_change.area });
+        }
+        schedule_state_changes.send(state_change);
+    }
+}
+
+/// Stamps footprints of moving obstacles into the grid whenever they moved or the grid was resized, and frees
+/// the cells of obstacles which lost their footprint.
+pub fn obstacle_stamping_system(grid: Res<Grid2D>, mut grid_data: ResMut<GridRelatedData>,
+                                mut dynamic_occupancy: ResMut<DynamicOccupancy>,
+                                obstacles_query: Query<(Entity, Ref<GlobalTransform>, Ref<ObstacleFootprint>),
+                                    With<ObstacleTag>>,
+                                mut removed_footprints: RemovedComponents<ObstacleFootprint>,
+                                mut grid_resized_events: EventReader<GridResized>,
+                                mut occupation_changes: EventWriter<OccupationChanged>) {
+    for entity in removed_footprints.read() {
+        if let Some(area) = dynamic_occupancy.clear(&mut grid_data, entity) {
+            occupation_changes.send(OccupationChanged { area });
+        }
+    }
+
+    // Footprints were forgotten on resize, see `DynamicOccupancy::resize_to`
+    let grid_resized = grid_resized_events.read().count() > 0;
+    for (entity, transform, footprint) in obstacles_query.iter() {
+        if !grid_resized && !transform.is_changed() && !footprint.is_changed() {
+            continue;
+        }
+        let (_, rotation, translation) = transform.to_scale_rotation_translation();
+        let (angle, _, _) = rotation.to_euler(EulerRot::ZYX);
+        let cells = footprint.rasterize(&grid, translation.truncate(), angle);
+        if let Some(area) = dynamic_occupancy.stamp(&mut grid_data, entity, cells) {
+            occupation_changes.send(OccupationChanged { area });
+        }
+    }
+}
+
 pub fn visualize_grid_in_log(grid2d: Res<Grid2D>)
 {
     grid2d.visualize_indexes_in_log();
@@ -1,23 +1,41 @@
+use std::collections::HashSet;
+
 use bevy::{
     math::{Rect, UVec2},
     prelude::*,
 };
 
+use rand::{rngs::StdRng, SeedableRng};
+
 use crate::{
     components::{
         grid_components::definitions::{
             CellIndex2d,
+            ChunkedGridRelatedData,
+            DynamicOccupancy,
             Grid2D,
+            GridRelatedData,
             GridSegment,
+            HexCellIndex,
+            HexGrid2D,
+            Occupation,
+            SquareNavGrid,
+            StampMode,
         },
-        movement_components::Maneuver,
+        flow_field_components::{Falloff, FlowBrush, FlowBrushes, FlowBrushKind, FlowField, HexFlowField,
+                                VortexDirection},
+        grid_components::nav_grid_traits::{NavGridCosts, NavGridStorage},
+        movement_components::{CurveKind, Formation, FormationMember, FormationShape, Heading, Kinematics,
+                              Maneuver, ObstacleFootprint, PlaybackMode, SpiralDirection, SphericalSurface,
+                              SurfaceCoordinate, SurfaceTopology, SweepAxis, Wander, ZLayering, ZOrderPolicy},
     },
-    function_libs::grid_calculations,
+    function_libs::{grid_calculations, navigation, steering},
     tests::common,
 };
 
 use test_case::test_case;
 use crate::components::directions;
+use crate::components::directions::Connectivity;
 use crate::components::grid_components::grid_related_iterators::{AreaFullIterator, AreaLineIterator};
 
 #[test]
@@ -151,6 +169,88 @@ fn test_local_to_global_index(local_index: CellIndex2d, expected_global_index: C
 }
 
 
+#[test_case(directions::Direction::SouthWest, IVec2::new(0, 0); "south west anchor")]
+#[test_case(directions::Direction::NorthEast, IVec2::new(5, 3); "north east anchor")]
+#[test_case(directions::Direction::North, IVec2::new(2, 3); "north anchor")]
+fn test_grid_resize_preserves_data(anchor: directions::Direction, expected_offset: IVec2) {
+    let mut grid: Grid2D = common::construct_default_grid();
+    let mut grid_related_data = GridRelatedData::new(&grid);
+    let marked_cell = CellIndex2d::new(3, 4);
+    grid_related_data.set_occupation_at(&marked_cell, Occupation::Occupied);
+    let marked_cell_position = grid.calculate_cell_position(marked_cell);
+
+    let grid_resized = grid.resize(grid.column_number + 5, grid.row_number + 3, anchor);
+    grid_related_data.resize_to(&grid_resized);
+
+    assert_eq!(grid_resized.offset, expected_offset);
+    assert_eq!(grid.column_number, 20);
+    assert_eq!(grid.row_number, 18);
+
+    let moved_cell = marked_cell + grid_resized.offset;
+    assert!(grid_related_data.is_occupied_at(&moved_cell), "Data of {marked_cell} was not moved to {moved_cell}");
+    assert_eq!(grid.calculate_cell_position(moved_cell), marked_cell_position, "Cell has changed its world position");
+}
+
+#[test]
+fn test_chunked_grid_data_signed_coordinates() {
+    let mut chunked_grid_data = ChunkedGridRelatedData::new(UVec2::new(8, 8));
+
+    let cell = IVec2::new(-1, -9);
+    assert_eq!(chunked_grid_data.chunk_of(cell), IVec2::new(-1, -2));
+    assert!(chunked_grid_data[cell].occupation_state == Occupation::Occupied, "Unloaded cells should be occupied");
+
+    chunked_grid_data[cell].occupation_state = Occupation::Free;
+    chunked_grid_data[IVec2::new(7, 0)].occupation_state = Occupation::Occupied;
+    assert!(chunked_grid_data.is_chunk_loaded(IVec2::new(-1, -2)));
+    assert!(chunked_grid_data[cell].occupation_state == Occupation::Free);
+    assert!(chunked_grid_data[CellIndex2d::new(7, 0)].occupation_state == Occupation::Occupied);
+    assert!(chunked_grid_data[CellIndex2d::new(6, 0)].occupation_state == Occupation::Free);
+
+    let required_chunks = HashSet::from([IVec2::new(0, 0)]);
+    let (loaded, unloaded) = chunked_grid_data.stream_chunks(&required_chunks);
+    assert!(loaded.is_empty());
+    assert_eq!(unloaded.len(), 1);
+    assert_eq!(unloaded[0].0, IVec2::new(-1, -2));
+}
+
+#[test_case(CellIndex2d { x: 0, y: 5 }, IVec2::new(-1, 0), None; "west of the first column")]
+#[test_case(CellIndex2d { x: 14, y: 5 }, IVec2::new(1, 1), None; "north east of the last column")]
+#[test_case(CellIndex2d { x: 3, y: 5 }, IVec2::new(-1, -1), Some(CellIndex2d { x: 2, y: 4 }); "inside of the grid")]
+fn test_checked_cell_index_offset(cell_index: CellIndex2d, offset: IVec2, expected: Option<CellIndex2d>) {
+    let grid: Grid2D = common::construct_default_grid();
+
+    assert_eq!(cell_index.checked_offset_in(offset, &grid), expected);
+}
+
+#[test]
+fn test_saturating_and_wrapping_cell_index_offsets() {
+    let grid: Grid2D = common::construct_default_grid();
+
+    assert_eq!(CellIndex2d::new(0, 0).saturating_offset(IVec2::new(-3, 2)), CellIndex2d::new(0, 2));
+    assert_eq!(CellIndex2d::new(1, 0) + IVec2::new(-1, 2), CellIndex2d::new(0, 2));
+    assert_eq!(CellIndex2d::new(2, 0) - IVec2::new(2, -1), CellIndex2d::new(0, 1));
+    assert_eq!(CellIndex2d::new(0, 0).wrapping_offset_in(IVec2::new(-1, -1), &grid),
+               CellIndex2d::new(grid.max_column_index, grid.max_row_index));
+}
+
+#[test]
+fn test_neighbors_stay_in_grid_bounds() {
+    let grid: Grid2D = common::construct_default_grid();
+
+    for cell_index in grid.iter_coordinates() {
+        let cardinal_neighbors: Vec<CellIndex2d> = cell_index.neighbors(&grid, Connectivity::Four).collect();
+        let all_neighbors: Vec<CellIndex2d> = cell_index.neighbors(&grid, Connectivity::Eight).collect();
+
+        for neighbor in all_neighbors.iter() {
+            assert!(grid.is_cell_index_in_grid_bounds(*neighbor), "neighbor: {neighbor} of {cell_index} is out of the grid");
+        }
+        assert!(cardinal_neighbors.len() >= 2 && cardinal_neighbors.len() <= 4);
+        assert!(all_neighbors.len() >= 3 && all_neighbors.len() <= 8);
+    }
+    assert_eq!(CellIndex2d::ZERO.neighbors(&grid, Connectivity::Eight).count(), 3);
+    assert_eq!(CellIndex2d::ZERO.normalize(), CellIndex2d::ZERO);
+}
+
 // Helper function to compute the expected outcome
 fn compute_expected_outcome(start_point: CellIndex2d, direction: Vec2, num_steps: u32) -> Vec<CellIndex2d> {
     (1..=num_steps).map(|i| start_point + CellIndex2d::from(direction * Vec2::new(i as f32, i as f32))).collect()
@@ -293,4 +393,616 @@ fn test_rect_intersection() {
         let result = grid_calculations::are_intersecting_exclusive(rect, rect2);
         assert!(result, "result was {result}")
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_great_circle_heuristic_is_admissible() {
+    let grid = Grid2D::new(9, 7, Vec2::new(50f32, 50f32));
+    let planet = SphericalSurface::new(10.0, Vec3::ZERO);
+
+    // Half of the columns around the equator, the last column sits next to the first one
+    let equator_point = SurfaceCoordinate::new(0.0, 0.5);
+    let antipodal_point = SurfaceCoordinate::new(grid.column_number as f32 / (2.0 * grid.max_column_index as f32), 0.5);
+    let distance = planet.calculate_great_circle_distance(&equator_point, &antipodal_point, &grid);
+    assert!((distance - std::f32::consts::PI * planet.radius).abs() < 1e-4, "distance: {distance}");
+    let seam_distance = planet.calculate_great_circle_distance(&equator_point, &SurfaceCoordinate::new(1.0, 0.5), &grid);
+    let column_arc = std::f32::consts::TAU * planet.radius / grid.column_number as f32;
+    assert!((seam_distance - column_arc).abs() < 1e-4, "seam distance: {seam_distance}");
+
+    let south_pole = planet.project_surface_coordinate(&SurfaceCoordinate::new(0.3, 0.0), &grid);
+    assert!((south_pole.translation - Vec3::new(0.0, -planet.radius, 0.0)).length() < 1e-4);
+
+    // Shortest amount of 4-connected steps with the x axis wrapping around
+    for from in grid.iter_coordinates() {
+        for to in grid.iter_coordinates() {
+            let x_steps = from.x.abs_diff(to.x).min(grid.column_number - from.x.abs_diff(to.x));
+            let steps = x_steps + from.y.abs_diff(to.y);
+            let heuristic = SphericalSurface::calculate_cell_heuristic(&grid, from, to);
+            assert!(heuristic <= steps, "from: {from} :: to: {to} :: heuristic: {heuristic} :: steps: {steps}");
+        }
+    }
+}
+
+#[test]
+fn test_segment_and_area_iteration_over_hex_layout() {
+    let hex_grid = HexGrid2D::new(7, 6, 20.0);
+    let child = URect::from_corners(UVec2::new(2, 1), UVec2::new(5, 4));
+    let segment = GridSegment::new(URect::from_corners(UVec2::ZERO, UVec2::new(6, 5)), child);
+
+    let area_cells: Vec<HexCellIndex> = hex_grid.iter_cells_in_area(child).collect();
+    assert_eq!(area_cells.len(), 16);
+    for cell_index in area_cells {
+        assert!(segment.contains_global_cell(&hex_grid, cell_index));
+        let local_cell = segment.global_to_local_cell(&hex_grid, cell_index);
+        assert_eq!(segment.local_to_global_cell(&hex_grid, local_cell), cell_index);
+    }
+    let corner = HexGrid2D::offset_to_axial(IVec2::new(2, 1));
+    assert_eq!(segment.global_to_local_cell(&hex_grid, corner), HexGrid2D::offset_to_axial(IVec2::ZERO));
+    assert!(!segment.contains_global_cell(&hex_grid, HexGrid2D::offset_to_axial(IVec2::new(6, 1))));
+
+    // Directions step along the storage axes, whatever the layout of the cells is
+    let column: Vec<HexCellIndex> = hex_grid.iter_cells_in_line(corner, IVec2::new(0, 1), child).collect();
+    assert_eq!(column, (1..4).map(|row| HexGrid2D::offset_to_axial(IVec2::new(2, row))).collect::<Vec<_>>());
+    assert_eq!(hex_grid.iter_cells_in_area_from(corner, IVec2::new(1, 1), child).count(), 15);
+}
+
+#[test]
+fn test_hex_grid_layout_and_flow() {
+    let hex_grid = HexGrid2D::new(7, 6, 20.0);
+
+    for cell_index in hex_grid.iter_cells() {
+        assert!(hex_grid.contains(cell_index));
+        let position = hex_grid.calculate_cell_position(cell_index);
+        assert_eq!(hex_grid.calculate_cell_index_from_position(position), cell_index);
+        // Near the corner of the hexagon, but still inside of it
+        let inner_position = position + Vec2::new(0.0, hex_grid.cell_radius * 0.9);
+        assert_eq!(hex_grid.calculate_cell_index_from_position(inner_position), cell_index);
+    }
+
+    let start = HexGrid2D::offset_to_axial(IVec2::new(0, 0));
+    let goal = HexGrid2D::offset_to_axial(IVec2::new(6, 5));
+    let (path, cost) = navigation::find_path_on(&hex_grid, start, goal)
+        .expect("Open map should always have a path");
+    assert_eq!(path.len() as u32 - 1, start.distance(&goal));
+    assert_eq!(cost, hex_grid.estimate_cost(start, goal));
+
+    let mut flow_field = HexFlowField::new(&hex_grid);
+    flow_field.integrate_towards(&hex_grid, &[goal], |_| true);
+    for cell_index in hex_grid.iter_cells().filter(|cell_index| *cell_index != goal) {
+        let flow = flow_field.get_field_at(&hex_grid, cell_index);
+        let next_cell = hex_grid.calculate_cell_index_from_position(
+            hex_grid.calculate_cell_position(cell_index) + flow * hex_grid.cell_radius * 1.7);
+        assert_eq!(next_cell.distance(&goal) + 1, cell_index.distance(&goal), "cell_index: {cell_index}");
+    }
+    assert_eq!(flow_field.get_field_at(&hex_grid, goal), Vec2::ZERO);
+    assert_eq!(HexCellIndex::new(0, 0).distance(&HexCellIndex::new(2, -3)), 3);
+}
+
+#[test]
+fn test_pathfinding_over_chunked_grid() {
+    let mut chunked_data = ChunkedGridRelatedData::new(UVec2::new(4, 4));
+    for chunk in [IVec2::new(-1, 0), IVec2::new(0, 0), IVec2::new(-1, -1), IVec2::new(0, -1)] {
+        chunked_data.load_chunk(chunk);
+    }
+    // Wall along x = 0, blocking the whole height of the upper chunks
+    for y in 0..4 {
+        chunked_data[IVec2::new(0, y)].occupation_state = Occupation::Occupied;
+    }
+
+    let start = IVec2::new(-2, 2);
+    let goal = IVec2::new(2, 2);
+    let (path, _) = navigation::find_path_on(&chunked_data, start, goal)
+        .expect("Path should go around the wall through the chunks below");
+    assert!(path.iter().all(|cell| chunked_data.is_passable(*cell)), "Path goes through an obstacle");
+    assert!(path.iter().any(|cell| cell.y < 0), "Path should leave the wall's chunks");
+
+    // Without the chunk below the wall it can't be passed
+    chunked_data.unload_chunk(IVec2::new(0, -1));
+    assert!(navigation::find_path_on(&chunked_data, start, goal).is_none());
+}
+
+#[test]
+fn test_formation_slots_deform_around_obstacles() {
+    let grid_parameters = common::construct_default_grid();
+    let mut grid_data = GridRelatedData::new(&grid_parameters);
+    let members: Vec<Entity> = (1..=4).map(Entity::from_raw).collect();
+    let mut formation = Formation::new(FormationShape::Wedge, 1.0, members.clone());
+    let leader_cell = CellIndex2d::new(7, 7);
+
+    let (slot_cells, is_deformed) = formation.calculate_slot_cells(&grid_parameters, &grid_data,
+                                                                   SurfaceTopology::Bounded, leader_cell);
+    assert!(!is_deformed);
+    assert_eq!(slot_cells, vec![CellIndex2d::new(8, 6), CellIndex2d::new(6, 6),
+                                CellIndex2d::new(9, 5), CellIndex2d::new(5, 5)]);
+
+    // Members standing mirrored take the slots closest to them
+    let member_cells = [CellIndex2d::new(5, 5), CellIndex2d::new(9, 5), CellIndex2d::new(6, 6), CellIndex2d::new(8, 6)];
+    assert_eq!(Formation::assign_slots(&member_cells, &slot_cells), vec![3, 2, 1, 0]);
+
+    grid_data.set_occupation_at(&CellIndex2d::new(8, 6), Occupation::Occupied);
+    let (slot_cells, is_deformed) = formation.calculate_slot_cells(&grid_parameters, &grid_data,
+                                                                   SurfaceTopology::Bounded, leader_cell);
+    assert!(is_deformed);
+    assert_eq!(slot_cells.iter().collect::<HashSet<_>>().len(), slot_cells.len(), "Slots should not overlap");
+    assert!(slot_cells.iter().all(|cell| !grid_data.is_occupied_at(cell) && *cell != leader_cell));
+    assert!(slot_cells[0].distance(&CellIndex2d::new(8, 6)) <= 2);
+    formation.set_slot_cells(slot_cells.clone());
+    assert!(!formation.needs_slot_assignment(false, slot_cells.len()), "Deformed formations should keep their slots");
+    assert!(formation.needs_slot_assignment(true, slot_cells.len()));
+
+    assert!(formation.retain_members(|member| member != members[1]));
+    assert!(!formation.retain_members(|_| true));
+    formation.set_heading(Vec2::X);
+    formation.shape = FormationShape::Line;
+    assert!(formation.needs_slot_assignment(false, formation.get_slots_number()));
+    let (slot_cells, _) = formation.calculate_slot_cells(&grid_parameters, &grid_data,
+                                                         SurfaceTopology::Bounded, leader_cell);
+    assert_eq!(slot_cells.len(), 3);
+    assert_eq!(slot_cells[0], CellIndex2d::new(7, 6), "First slot should be to the right of the leader");
+
+    // Members walking to their slots do not run into each other
+    let member_cells = [CellIndex2d::new(7, 4), CellIndex2d::new(7, 9), CellIndex2d::new(6, 8)];
+    let member_slots = Formation::assign_slots(&member_cells, &slot_cells);
+    let nav_grid = SquareNavGrid::new(&grid_parameters, &grid_data, SurfaceTopology::Bounded);
+    let routes = Formation::plan_member_routes(&nav_grid, &member_cells, &slot_cells, &member_slots)
+        .expect("Routes to the slots should exist");
+    for ((route, member_cell), slot) in routes.iter().zip(member_cells).zip(member_slots) {
+        assert_eq!(route.first(), Some(&member_cell));
+        assert_eq!(route.last(), Some(&slot_cells[slot]));
+    }
+    let steps_number = routes.iter().map(Vec::len).max().unwrap();
+    let cells_at = |step: usize| routes.iter().map(|route| route[step.min(route.len() - 1)]).collect::<HashSet<_>>();
+    assert!((0..steps_number).all(|step| cells_at(step).len() == routes.len()), "Members should not collide");
+
+    let mut formation_member = FormationMember::new(Entity::from_raw(0), 0);
+    let (from, to) = (CellIndex2d::new(2, 2), CellIndex2d::new(3, 2));
+    assert!(!formation_member.needs_route_to(from, from));
+    assert!(formation_member.needs_route_to(from, to));
+    formation_member.set_route(vec![from, to, to]);
+    assert!(!formation_member.needs_route_to(from, CellIndex2d::new(4, 2)), "Slot is still close to the route");
+    assert!(formation_member.needs_route_to(from, CellIndex2d::new(8, 2)));
+    let middle = (Vec2::from(grid_parameters.calculate_flat_surface_coordinate_from_2d(from))
+        + Vec2::from(grid_parameters.calculate_flat_surface_coordinate_from_2d(to))) / 2.0;
+    assert!(formation_member.advance_route(&grid_parameters, 0.5).unwrap().distance(middle) < 1e-5);
+    formation_member.advance_route(&grid_parameters, 2.0);
+    assert!(!formation_member.is_following_route(), "Route should be dropped at its end");
+    assert_eq!(formation_member.advance_route(&grid_parameters, 1.0), None);
+}
+
+#[test]
+fn test_steering_behaviors() {
+    let position = Vec2::new(0.5, 0.5);
+    assert_eq!(steering::seek(position, Vec2::new(0.5, 0.9), 0.2), Vec2::new(0.0, 0.2));
+    assert_eq!(steering::flee(position, Vec2::new(0.5, 0.9), 0.2, 0.1), Vec2::ZERO, "Threat is too far");
+    assert_eq!(steering::flee(position, Vec2::new(0.5, 0.55), 0.2, 0.1), Vec2::new(0.0, -0.2));
+
+    let slowed_down = steering::arrive(position, Vec2::new(0.5, 0.55), 0.2, 0.1);
+    assert!((slowed_down.length() - 0.1).abs() < 1e-5);
+    assert_eq!(steering::arrive(position, position, 0.2, 0.1), Vec2::ZERO);
+
+    // Target moving to the right is intercepted ahead of it
+    let pursuit = steering::pursue(position, Vec2::new(0.5, 0.7), Vec2::new(0.1, 0.0), 0.2);
+    assert!(pursuit.x > 0.0 && pursuit.y > 0.0);
+
+    // Velocities of targets crossing a wrapped edge follow the short way over it
+    let (before_edge, after_edge) = (SurfaceCoordinate::new(0.98, 0.5), SurfaceCoordinate::new(0.01, 0.5));
+    assert!((SurfaceTopology::Torus.calculate_offset(before_edge, after_edge) - Vec2::new(0.03, 0.0)).length() < 1e-5);
+    assert!((SurfaceTopology::Bounded.calculate_offset(before_edge, after_edge) - Vec2::new(-0.97, 0.0)).length() < 1e-5);
+
+    // Leader moving to the right has its left side above it
+    let following = steering::follow_leader(position, Vec2::new(0.5, 0.7), Vec2::X, Vec2::new(-0.1, 0.0), 0.2, 0.01);
+    assert!((following - Vec2::new(0.0, 0.2)).length() < 1e-5);
+
+    let mut wander = Wander::new(0.05, 0.1, 1.0);
+    for _ in 0..10 {
+        let wandering = wander.steer(position, Vec2::X, 0.2, 1.0, 0.5);
+        assert!((wandering.length() - 0.2).abs() < 1e-5);
+        assert!(wandering.x > 0.0, "Wandering should keep going roughly forward");
+    }
+
+    let grid_parameters = common::construct_default_grid();
+    let mut grid_data = GridRelatedData::new(&grid_parameters);
+    grid_data.set_occupation_at(&CellIndex2d::new(7, 7), Occupation::Occupied);
+    let hiding_cell = steering::find_hiding_cell(&grid_parameters, &grid_data, CellIndex2d::new(5, 7),
+                                                 CellIndex2d::new(2, 7), 3);
+    assert_eq!(hiding_cell, Some(CellIndex2d::new(8, 7)));
+    assert_eq!(steering::find_hiding_cell(&grid_parameters, &grid_data, CellIndex2d::new(1, 1),
+                                          CellIndex2d::new(2, 7), 3), None);
+}
+
+#[test]
+fn test_kinematics_accelerate_turn_and_brake() {
+    let mut kinematics = Kinematics::new(1.0, 2.0, std::f32::consts::FRAC_PI_2);
+
+    // Accelerating from standing still takes half a second
+    let displacement = kinematics.integrate_towards(Vec2::X * 5.0, 0.25);
+    assert!((kinematics.velocity - Vec2::new(0.5, 0.0)).length() < 1e-5);
+    assert!((displacement - Vec2::new(0.125, 0.0)).length() < 1e-5, "Position should use the new velocity");
+    kinematics.integrate_towards(Vec2::X, 0.5);
+    assert!((kinematics.get_speed() - 1.0).abs() < 1e-5);
+
+    // Turning around is limited to a quarter turn per second
+    kinematics.max_acceleration = 100.0;
+    kinematics.integrate_towards(-Vec2::X, 0.5);
+    let turned_angle = Vec2::X.angle_between(kinematics.velocity).abs();
+    assert!((turned_angle - std::f32::consts::FRAC_PI_4).abs() < 1e-4);
+
+    kinematics.max_acceleration = 2.0;
+    for _ in 0..10 {
+        kinematics.integrate_towards(Vec2::ZERO, 0.1);
+    }
+    assert!(kinematics.get_speed() < 1e-5, "Agent should come to a stop");
+}
+
+#[test]
+fn test_heading_turns_smoothly_along_shorter_arc() {
+    let mut heading = Heading::new(0.1, 8.0);
+    let (target_sin, target_cos) = (-0.3f32).sin_cos();
+    let direction = Vec2::new(target_cos, target_sin);
+
+    // One step covers only a part of the turn and goes clockwise through zero, not around the other way
+    heading.turn_towards(direction, 0.1);
+    assert!(heading.angle > std::f32::consts::TAU - 0.3, "Heading went the long way: {}", heading.angle);
+
+    for _ in 0..60 {
+        heading.turn_towards(direction, 0.1);
+    }
+    assert!((heading.angle - (std::f32::consts::TAU - 0.3)).abs() < 1e-3);
+
+    // Standing still keeps the last heading
+    let settled_angle = heading.angle;
+    heading.turn_towards(Vec2::ZERO, 0.1);
+    assert_eq!(heading.angle, settled_angle);
+}
+
+#[test]
+fn test_z_layering_sorts_agents_by_row() {
+    let flat = ZLayering::default();
+    assert_eq!(flat.calculate_agent_depth(&SurfaceCoordinate::new(0.5, 0.2)), flat.agents);
+
+    let sorted = ZLayering { policy: ZOrderPolicy::SortByRow { range: 5.0 }, ..ZLayering::default() };
+    let lower_depth = sorted.calculate_agent_depth(&SurfaceCoordinate::new(0.5, 0.2));
+    let upper_depth = sorted.calculate_agent_depth(&SurfaceCoordinate::new(0.5, 0.8));
+    assert!(lower_depth > upper_depth, "Agents lower on the grid should be drawn over the upper ones");
+    assert!(upper_depth >= sorted.agents && lower_depth <= sorted.agents + 5.0);
+    assert!(upper_depth > sorted.flow_arrows);
+}
+
+#[test]
+fn test_maneuver_moves_with_constant_speed_along_uneven_sections() {
+    // Long, short and medium sections along one line
+    let mut maneuver = Maneuver::new(vec![SurfaceCoordinate::new(0.1, 0.5), SurfaceCoordinate::new(0.5, 0.5),
+                                          SurfaceCoordinate::new(0.55, 0.5), SurfaceCoordinate::new(0.9, 0.5)]);
+    assert!((maneuver.calculate_length() - 0.8).abs() < 1e-3, "Length: {}", maneuver.calculate_length());
+
+    let step = 0.05;
+    let mut previous_position = Vec2::new(0.1, 0.5);
+    while !maneuver.is_done() {
+        let position = Vec2::from(maneuver.advance_by_distance(step));
+        let travelled = position.distance(previous_position);
+        assert!(maneuver.is_done() || (travelled - step).abs() < 2e-3,
+                "Step of {travelled} at progress {}", maneuver.progress);
+        assert!((position.y - 0.5).abs() < 1e-4);
+        assert!(maneuver.is_done() || maneuver.sample_tangent().dot(Vec2::X) > 0.999);
+        previous_position = position;
+    }
+    assert!(previous_position.distance(Vec2::new(0.9, 0.5)) < 1e-4);
+}
+
+#[test]
+fn test_maneuver_curve_kinds() {
+    let points = vec![SurfaceCoordinate::new(0.2, 0.2), SurfaceCoordinate::new(0.4, 0.2),
+                      SurfaceCoordinate::new(0.4, 0.4), SurfaceCoordinate::new(0.6, 0.4)];
+    let first = Vec2::from(points[0]);
+    let last = Vec2::from(points[3]);
+
+    let bezier = CurveKind::BezierChain.sample(0.5, &points);
+    let expected_middle = (Vec2::from(points[0]) + 3.0 * Vec2::from(points[1]) + 3.0 * Vec2::from(points[2])
+        + Vec2::from(points[3])) / 8.0;
+    assert!(bezier.position.distance(expected_middle) < 1e-5);
+
+    for kind in [CurveKind::BSpline, CurveKind::CatmullRom, CurveKind::BezierChain] {
+        assert!(kind.sample(0.0, &points).position.distance(first) < 1e-5, "{kind:?} should start at the first point");
+        assert!(kind.sample(1.0, &points).position.distance(last) < 1e-5, "{kind:?} should end at the last point");
+    }
+
+    // Waypoints are reached where the curve kind puts them, not after equal shares of the progress
+    let mut chain_points = points.clone();
+    chain_points.push(SurfaceCoordinate::new(0.8, 0.4));
+    let mut maneuver = Maneuver::new(chain_points.clone());
+    maneuver.set_curve_kind(CurveKind::BezierChain);
+    assert_eq!(maneuver.calculate_waypoint_progress(3), 0.5);
+    assert!(maneuver.sample_curve_at(0.5).position.distance(Vec2::from(chain_points[3])) < 1e-5);
+    maneuver.progress = 0.6;
+    assert_eq!(maneuver.calculate_current_waypoint(), 3);
+    maneuver.set_curve_kind(CurveKind::BSpline);
+    assert_eq!(maneuver.calculate_waypoint_progress(4), 1.0);
+    assert_eq!(maneuver.calculate_current_waypoint(), 2);
+    // Only points the curve goes through are reported as passed
+    maneuver.restart();
+    maneuver.advance_by_distance(maneuver.calculate_length());
+    assert_eq!(maneuver.take_passed_waypoints(), vec![4]);
+    maneuver.set_curve_kind(CurveKind::BezierChain);
+    maneuver.restart();
+    maneuver.advance_by_distance(maneuver.calculate_length());
+    assert_eq!(maneuver.take_passed_waypoints(), vec![3, 4]);
+
+    let hermite = CurveKind::Hermite { tangents: vec![Vec2::Y, Vec2::X, Vec2::X, Vec2::Y] };
+    let at_second_point = hermite.sample(1.0 / 3.0, &points);
+    assert!(at_second_point.position.distance(Vec2::from(points[1])) < 1e-5);
+    assert!(at_second_point.tangent.distance(Vec2::X) < 1e-4);
+
+    // Vehicles never turn tighter than the radius and still reach every waypoint
+    let turn_radius = 0.05;
+    let mut maneuver = Maneuver::new(points.clone());
+    maneuver.set_curve_kind(CurveKind::Dubins { turn_radius, reverse_sections: false });
+    let mut previous = maneuver.sample_curve();
+    while !maneuver.is_done() {
+        maneuver.advance_by_distance(0.005);
+        let sample = maneuver.sample_curve();
+        let travelled = sample.position.distance(previous.position);
+        if travelled > 1e-4 {
+            let turned = previous.tangent.angle_between(sample.tangent).abs();
+            assert!(turned <= travelled / turn_radius + 1e-2, "Turned {turned} over {travelled}");
+        }
+        previous = sample;
+    }
+    assert!(previous.position.distance(last) < 1e-4);
+
+    let forward_length = maneuver.calculate_length();
+    maneuver.set_curve_kind(CurveKind::Dubins { turn_radius, reverse_sections: true });
+    assert!(maneuver.calculate_length() <= forward_length + 1e-5);
+}
+
+#[test]
+fn test_maneuver_playback_modes() {
+    let mut maneuver = Maneuver::new(vec![SurfaceCoordinate::new(0.1, 0.5), SurfaceCoordinate::new(0.5, 0.5),
+                                          SurfaceCoordinate::new(0.9, 0.5)]);
+    assert!(!maneuver.has_started());
+    maneuver.advance_by_distance(0.5);
+    assert!(maneuver.has_started());
+    assert_eq!(maneuver.take_passed_waypoints(), vec![1]);
+    maneuver.advance_by_distance(1.0);
+    assert!(maneuver.is_done());
+    assert_eq!(maneuver.take_passed_waypoints(), vec![2]);
+
+    // Turns around at the end and comes back
+    maneuver.set_playback_mode(PlaybackMode::PingPong);
+    maneuver.advance_by_distance(1.0);
+    assert_eq!(maneuver.take_passed_waypoints(), vec![1, 2]);
+    let position = Vec2::from(maneuver.advance_by_distance(0.3));
+    assert!((position.x - 0.4).abs() < 1e-3, "Position after turning around: {position}");
+    assert_eq!(maneuver.take_passed_waypoints(), vec![1]);
+    assert!(!maneuver.is_done());
+
+    maneuver.set_playback_mode(PlaybackMode::Loop);
+    let position = Vec2::from(maneuver.advance_by_distance(0.9));
+    assert!((position.x - 0.2).abs() < 1e-3, "Position after looping: {position}");
+    assert_eq!(maneuver.take_passed_waypoints(), vec![1, 2, 0]);
+    assert!(!maneuver.is_done());
+
+    maneuver.set_playback_mode(PlaybackMode::Reverse);
+    let position = Vec2::from(maneuver.advance_by_distance(0.5));
+    assert!((position.x - 0.4).abs() < 1e-3, "Position going in reverse: {position}");
+    assert_eq!(maneuver.take_passed_waypoints(), vec![1]);
+    maneuver.advance_by_distance(1.0);
+    assert!(maneuver.is_done());
+    assert_eq!(maneuver.take_passed_waypoints(), vec![0]);
+
+    // Paused maneuvers stay put, time scale slows the rest down
+    maneuver.set_playback_mode(PlaybackMode::Once);
+    maneuver.pause();
+    let position = Vec2::from(maneuver.advance_by_time(0.2, 1.0));
+    assert!((position.x - 0.1).abs() < 1e-5);
+    assert!(!maneuver.has_started());
+    maneuver.resume();
+    maneuver.time_scale = 0.5;
+    let position = Vec2::from(maneuver.advance_by_time(0.2, 1.0));
+    assert!((position.x - 0.2).abs() < 1e-3, "Position with half time scale: {position}");
+    // Stands at the waypoint for its dwell time before moving on
+    maneuver.time_scale = 1.0;
+    maneuver.set_dwell_times(vec![0.0, 1.0, 0.0]);
+    maneuver.restart();
+    let position = Vec2::from(maneuver.advance_by_time(0.2, 2.5));
+    assert!((position.x - 0.5).abs() < 1e-3, "Position while dwelling: {position}");
+    assert!(maneuver.is_dwelling());
+    let position = Vec2::from(maneuver.advance_by_time(0.2, 1.0));
+    assert!((position.x - 0.6).abs() < 1e-3, "Position after dwelling: {position}");
+    assert!(!maneuver.is_dwelling());
+}
+
+#[test]
+fn test_moving_obstacles_stamp_their_footprint() {
+    let grid = Grid2D::new(10, 10, Vec2::new(50f32, 50f32));
+    let mut grid_data = GridRelatedData::new(&grid);
+    let mut dynamic_occupancy = DynamicOccupancy::new(&grid);
+    let static_cell = CellIndex2d::new(2, 2);
+    grid_data.set_occupation_at(&static_cell, Occupation::Occupied);
+
+    let first = Entity::from_raw(1);
+    let second = Entity::from_raw(2);
+    let circle = ObstacleFootprint::Circle { radius: 60.0 };
+    let cells = circle.rasterize(&grid, grid.calculate_cell_position(static_cell), 0.0);
+    assert_eq!(cells.len(), 5);
+    let changed_area = dynamic_occupancy.stamp(&mut grid_data, first, cells).unwrap();
+    assert_eq!(changed_area, URect::new(1, 1, 3, 3));
+    assert!(grid_data.is_occupied_at(&CellIndex2d::new(1, 2)));
+
+    // Moving away frees the stamped cells, but not the ones that were occupied before
+    let moved_center = CellIndex2d::new(6, 6);
+    let cells = circle.rasterize(&grid, grid.calculate_cell_position(moved_center), 0.0);
+    dynamic_occupancy.stamp(&mut grid_data, first, cells);
+    assert!(grid_data.is_occupied_at(&static_cell));
+    assert!(!grid_data.is_occupied_at(&CellIndex2d::new(1, 2)));
+    assert!(grid_data.is_occupied_at(&CellIndex2d::new(6, 7)));
+
+    // Turned by a quarter, the long side of the rectangle goes along the column
+    let rect = ObstacleFootprint::Rect { half_size: Vec2::new(80.0, 10.0) };
+    let cells: HashSet<CellIndex2d> = rect.rasterize(&grid, grid.calculate_cell_position(moved_center),
+                                                     std::f32::consts::FRAC_PI_2).into_iter().collect();
+    assert_eq!(cells, HashSet::from([CellIndex2d::new(6, 5), moved_center, CellIndex2d::new(6, 7)]));
+    dynamic_occupancy.stamp(&mut grid_data, second, cells.into_iter().collect());
+
+    // Obstacles planning their way free only the cells no one else covers
+    let lifted_cells = dynamic_occupancy.lift(&mut grid_data, first);
+    assert_eq!(lifted_cells.len(), 5);
+    assert!(!grid_data.is_occupied_at(&CellIndex2d::new(5, 6)) && grid_data.is_occupied_at(&moved_center));
+    let second_lifted_cells = dynamic_occupancy.lift(&mut grid_data, second);
+    assert!(!grid_data.is_occupied_at(&moved_center));
+    dynamic_occupancy.put_back(&mut grid_data, &second_lifted_cells);
+    dynamic_occupancy.put_back(&mut grid_data, &lifted_cells);
+    assert!(grid_data.is_occupied_at(&CellIndex2d::new(5, 6)) && grid_data.is_occupied_at(&moved_center));
+
+    // Cells covered by both obstacles stay occupied until both are gone
+    dynamic_occupancy.clear(&mut grid_data, first);
+    assert!(grid_data.is_occupied_at(&moved_center));
+    assert!(!grid_data.is_occupied_at(&CellIndex2d::new(5, 6)));
+    dynamic_occupancy.clear(&mut grid_data, second);
+    assert!(!grid_data.is_occupied_at(&moved_center));
+    assert!(!dynamic_occupancy.is_stamped_at(&moved_center));
+}
+
+#[test]
+fn test_shapes_stamp_with_center_sampling_or_conservatively() {
+    let grid = Grid2D::new(10, 10, Vec2::new(50f32, 50f32));
+    let mut grid_data = GridRelatedData::new(&grid);
+    let count_occupied = |grid_data: &GridRelatedData| {
+        grid.iter_coordinates().filter(|cell| grid_data.is_occupied_at(cell)).count()
+    };
+
+    // Neighbours are only touched by the circle, their centers are out of it
+    let center = grid.calculate_cell_position(CellIndex2d::new(4, 4));
+    grid_data.stamp_circle(&grid, center, 30.0, Occupation::Occupied, StampMode::CellCenter);
+    assert_eq!(count_occupied(&grid_data), 1);
+    let changed_area = grid_data.stamp_circle(&grid, center, 30.0, Occupation::Occupied, StampMode::Conservative);
+    assert_eq!(changed_area, Some(URect::new(3, 3, 5, 5)));
+    assert_eq!(count_occupied(&grid_data), 5);
+    grid_data.stamp_capsule(&grid, center, center, 30.0, Occupation::Free, StampMode::Conservative);
+    assert_eq!(count_occupied(&grid_data), 0);
+
+    // Shapes along cell borders do not spill over into the neighbouring cells
+    let min_corner = grid.calculate_cell_position(CellIndex2d::new(0, 0)) - grid.cell_size / 2.0;
+    let max_corner = grid.calculate_cell_position(CellIndex2d::new(1, 1)) + grid.cell_size / 2.0;
+    let square = [min_corner, Vec2::new(max_corner.x, min_corner.y), max_corner, Vec2::new(min_corner.x, max_corner.y)];
+    let changed_area = grid_data.stamp_polygon(&grid, &square, Occupation::Occupied, StampMode::Conservative);
+    assert_eq!(changed_area, Some(URect::new(0, 0, 1, 1)));
+    assert_eq!(count_occupied(&grid_data), 4);
+    assert_eq!(grid_data.stamp_polygon(&grid, &square, Occupation::Occupied, StampMode::CellCenter), None);
+
+    // Lines without thickness cover only the cells they go through
+    let from = grid.calculate_cell_position(CellIndex2d::new(1, 7)) - Vec2::new(10.0, 0.0);
+    let to = grid.calculate_cell_position(CellIndex2d::new(5, 7)) + Vec2::new(10.0, 0.0);
+    assert_eq!(grid_data.stamp_line(&grid, from, to, 0.0, Occupation::Occupied, StampMode::CellCenter), None);
+    let changed_area = grid_data.stamp_line(&grid, from, to, 0.0, Occupation::Occupied, StampMode::Conservative);
+    assert_eq!(changed_area, Some(URect::new(1, 7, 5, 7)));
+    grid_data.stamp_line(&grid, from, to, 10.0, Occupation::Free, StampMode::CellCenter);
+    assert_eq!(count_occupied(&grid_data), 4);
+}
+
+#[test]
+fn test_flow_brushes_stack_decay_and_restore_the_base_field() {
+    let grid = Grid2D::new(10, 10, Vec2::new(50f32, 50f32));
+    let mut flow_field = FlowField::form_field(10, 10);
+    // Neither the goal nor an obstacle has a flow to shape
+    let (goal, obstacle) = (CellIndex2d::new(9, 9), CellIndex2d::new(6, 5));
+    flow_field.field[&goal] = Vec2::ZERO;
+    flow_field.field[&obstacle] = Vec2::ZERO;
+    let base_field = flow_field.field.clone();
+    let mut flow_brushes = FlowBrushes::default();
+
+    let center = grid.calculate_cell_position(CellIndex2d::new(5, 5));
+    flow_brushes.add(FlowBrush::new(FlowBrushKind::Vortex(VortexDirection::CounterClockwise), center, 200.0,
+                                    Falloff::Linear, 10.0, Some(2.0)));
+    flow_brushes.add(FlowBrush::new(FlowBrushKind::Wind(Vec2::Y), Vec2::ZERO, f32::INFINITY,
+                                    Falloff::Smoothstep, 0.5, None));
+    flow_brushes.apply_to(&mut flow_field, &grid);
+
+    // East of the center the vortex turns the flow northwards, the rest of the vectors stay normalized
+    let east_flow = flow_field.get_field_at(&CellIndex2d::new(7, 5));
+    assert!(east_flow.y > 0.9, "Flow east of the vortex: {east_flow}");
+    let west_flow = flow_field.get_field_at(&CellIndex2d::new(3, 5));
+    assert!(west_flow.y < -0.9, "Flow west of the vortex: {west_flow}");
+    assert_eq!(flow_field.get_field_at(&goal), Vec2::ZERO);
+    assert_eq!(flow_field.get_field_at(&obstacle), Vec2::ZERO);
+    assert!(flow_field.field.iter().filter(|flow| **flow != Vec2::ZERO).all(|flow| (flow.length() - 1.0).abs() < 1e-4));
+
+    // Once the vortex is gone, only the wind is left on top of the base field
+    flow_brushes.advance(2.5);
+    flow_brushes.apply_to(&mut flow_field, &grid);
+    let corner = CellIndex2d::new(0, 0);
+    let expected_flow = (base_field[[0, 0]] + Vec2::Y * 0.5).normalize();
+    assert!(flow_field.get_field_at(&corner).distance(expected_flow) < 1e-4);
+
+    flow_brushes.clear();
+    assert!(flow_brushes.is_shaping());
+    flow_brushes.apply_to(&mut flow_field, &grid);
+    assert_eq!(flow_field.field, base_field);
+    assert!(!flow_brushes.is_shaping());
+
+    // Every falloff fades out completely at the radius
+    for falloff in [Falloff::Linear, Falloff::Smoothstep, Falloff::InverseSquare] {
+        assert!((falloff.sample(0.0) - 1.0).abs() < 1e-6 && falloff.sample(1.0).abs() < 1e-6, "{falloff:?}");
+    }
+}
+
+#[test]
+fn test_maneuver_patterns_skip_obstacles() {
+    let grid: Grid2D = common::construct_default_grid();
+    let mut grid_related_data = GridRelatedData::new(&grid);
+    for y in 3..=11 {
+        grid_related_data.set_occupation_at(&CellIndex2d::new(7, y), Occupation::Occupied);
+    }
+    let cells_of = |maneuver: &Maneuver| -> Vec<CellIndex2d> {
+        maneuver.path_points.iter().map(|point| point.calculate_cell_index_on_flat_surface(&grid)).collect()
+    };
+    let assert_free = |cells: &[CellIndex2d]| {
+        assert!(cells.iter().all(|cell| !grid_related_data.is_occupied_at(cell)), "Pattern goes through an obstacle");
+    };
+    let assert_step_by_step = |cells: &[CellIndex2d]| {
+        assert!(cells.windows(2).all(|pair| (IVec2::from(pair[0]) - IVec2::from(pair[1])).abs().max_element() == 1),
+                "Pattern should go cell by cell");
+    };
+
+    let lawnmower = cells_of(&Maneuver::lawnmower(&grid, &grid_related_data,
+                                                  URect::new(5, 2, 9, 12), 2, SweepAxis::Vertical));
+    assert_free(&lawnmower);
+    assert_step_by_step(&lawnmower);
+    assert_eq!(lawnmower.first(), Some(&CellIndex2d::new(5, 2)));
+    assert_eq!(lawnmower.last(), Some(&CellIndex2d::new(9, 12)));
+    // The middle lane is walled off, so only its ends past the wall are visited
+    assert!(lawnmower.contains(&CellIndex2d::new(7, 12)) && lawnmower.contains(&CellIndex2d::new(7, 2)));
+
+    let spiral = cells_of(&Maneuver::spiral_around(&grid, &grid_related_data, CellIndex2d::new(3, 3), 2,
+                                                   SpiralDirection::Outwards));
+    assert_eq!(spiral.len(), 25);
+    assert_eq!(spiral[0], CellIndex2d::new(3, 3));
+    assert!(spiral.windows(2).all(|pair| pair[0].distance(&pair[1]) == 1), "Spiral should go cell by cell");
+    let inwards = cells_of(&Maneuver::spiral_around(&grid, &grid_related_data, CellIndex2d::new(3, 3), 2,
+                                                    SpiralDirection::Inwards));
+    assert_eq!(inwards.last(), Some(&CellIndex2d::new(3, 3)));
+
+    let (first_post, second_post) = (CellIndex2d::new(4, 7), CellIndex2d::new(10, 7));
+    let patrol = Maneuver::patrol(&grid, &grid_related_data, SurfaceTopology::Bounded, &[first_post, second_post])
+        .expect("Both posts should be reachable around the wall");
+    assert_eq!(patrol.playback_mode, PlaybackMode::Loop);
+    let patrol_cells = cells_of(&patrol);
+    assert_free(&patrol_cells);
+    assert_eq!(patrol_cells[0], first_post);
+    assert!(patrol_cells.contains(&second_post));
+    assert!(patrol_cells.windows(2).all(|pair| pair[0] != pair[1]), "Legs should not repeat the posts");
+
+    let center = CellIndex2d::new(7, 7);
+    let orbit = cells_of(&Maneuver::orbit(&grid, &grid_related_data, center, 3));
+    assert_free(&orbit);
+    assert_step_by_step(&orbit);
+    // Only the detours around the ends of the wall leave the ring
+    assert!(orbit.iter().filter(|cell| (4..=10).contains(&cell.y))
+        .all(|cell| (cell.euclidean_distance(&center) - 3.0).abs() < 1.0));
+    assert!(!orbit.contains(&CellIndex2d::new(7, 10)) && orbit.contains(&CellIndex2d::new(10, 7)));
+
+    let mut rng = StdRng::seed_from_u64(7);
+    let walk = cells_of(&Maneuver::random_walk(&grid, &grid_related_data, CellIndex2d::new(6, 7), 40, &mut rng));
+    assert_eq!(walk.len(), 41);
+    assert_free(&walk);
+    assert_step_by_step(&walk);
+}
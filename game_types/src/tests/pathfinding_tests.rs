@@ -17,7 +17,8 @@ use std::{
 
 use bevy::{
     asset::{AsyncReadExt, AsyncWriteExt},
-    math::{URect, UVec2},
+    ecs::entity::Entity,
+    math::{IVec2, URect, UVec2, Vec2},
 };
 
 use serde::{Deserialize, Serialize};
@@ -26,11 +27,31 @@ use serde::de::DeserializeOwned;
 use crate::{
     components::{
         grid_components::definitions::{
+            CellIndex2d,
+            ClearanceLayer,
+            CostSchedule,
+            DynamicOccupancy,
             Grid2D,
             GridRelatedData,
+            LayeredCellIndex,
+            LayeredGrid,
+            LinkedNavGrid,
             Occupation,
+            OffMeshLink,
+            OffMeshLinkKind,
+            OffMeshLinks,
+            PortalKind,
+            PortalLink,
+            ScheduleCurve,
+            ScheduledArea,
+            ScheduledEffect,
+            SquareNavGrid,
         },
         directions::Direction,
+        flow_field_components::{FlowField, LayeredFlowField},
+        grid_components::nav_grid_traits::NavGridCosts,
+        movement_components::{AvoidanceSettings, Maneuver, PlaybackMode, StuckDetector, StuckStage, SurfaceTopology},
+        pathfinding_components::{AgentTask, ReservationTable, TimedSearchParameters},
     },
     tests::{
         common::{
@@ -38,7 +59,7 @@ use crate::{
             construct_default_grid,
         }
     },
-    function_libs::grid_calculations,
+    function_libs::{cooperative_pathfinding, grid_calculations, navigation, path_following, space_time_search},
 };
 
 const PATHFINDING_RECT: UVec2 = UVec2::new(10, 10);
@@ -118,6 +139,366 @@ fn test_obstacles_identification() {
     grid_related_data.visualize_on_grid(&grid)
 }
 
+#[test]
+fn test_clearance_layer_matches_brute_force() {
+    let grid: Grid2D = common::construct_default_grid();
+    let mut grid_related_data = GridRelatedData::new(&grid);
+    grid_related_data.fill_with_random_obstacle_pattern(&grid);
+
+    let max_distance = 4.0;
+    let mut clearance_layer = ClearanceLayer::new(&grid, max_distance);
+    clearance_layer.recompute_all(&grid, &grid_related_data);
+
+    // Edit a single cell and let the layer update incrementally
+    let edited_cell = grid.get_central_cell();
+    let occupation = if grid_related_data.is_occupied_at(&edited_cell) { Occupation::Free } else { Occupation::Occupied };
+    grid_related_data.set_occupation_at(&edited_cell, occupation);
+    clearance_layer.mark_dirty(URect::from_corners(edited_cell.into(), edited_cell.into()));
+    clearance_layer.update(&grid, &grid_related_data);
+
+    let obstacles: Vec<CellIndex2d> = grid.iter_coordinates()
+        .filter(|cell_index| grid_related_data.is_occupied_at(cell_index))
+        .collect();
+
+    for cell_index in grid.iter_coordinates() {
+        let expected = obstacles.iter()
+            .map(|obstacle| obstacle.euclidean_distance(&cell_index))
+            .fold(max_distance, f32::min);
+        let actual = clearance_layer.clearance_at(&cell_index);
+        assert!((expected - actual).abs() < 1e-4, "cell_index: {cell_index} :: expected: {expected} :: actual: {actual}");
+    }
+}
+
+#[test]
+fn test_flow_field_integration_crosses_wrapped_edges() {
+    let grid: Grid2D = common::construct_default_grid();
+    let grid_related_data = GridRelatedData::new(&grid);
+    let goal = CellIndex2d::new(0, grid.max_row_index / 2);
+    let cell_at_opposite_edge = CellIndex2d::new(grid.max_column_index, goal.y);
+
+    let mut flow_field = FlowField::form_field(grid.column_number as usize, grid.row_number as usize);
+
+    flow_field.integrate_towards(&grid, &grid_related_data, &[goal], SurfaceTopology::Torus);
+    assert!(flow_field.get_field_at(&cell_at_opposite_edge).x > 0.0, "Flow should go over the wrapped edge");
+
+    flow_field.integrate_towards(&grid, &grid_related_data, &[goal], SurfaceTopology::Bounded);
+    assert!(flow_field.get_field_at(&cell_at_opposite_edge).x < 0.0, "Flow should go across the whole grid");
+}
+
+#[test]
+fn test_pathfinding_across_layers() {
+    let mut layered_grid = LayeredGrid::new();
+    for _ in 0..2 {
+        let grid = Grid2D::new(6, 6, Vec2::new(50f32, 50f32));
+        let data = GridRelatedData::new(&grid);
+        layered_grid.add_layer(grid, data);
+    }
+    let stairs_bottom = LayeredCellIndex::new(0, CellIndex2d::new(5, 5));
+    let stairs_top = LayeredCellIndex::new(1, CellIndex2d::new(0, 0));
+
+    // Portals are loaded the way a level description would provide them
+    let portals = vec![PortalLink { from: stairs_bottom, to: stairs_top, kind: PortalKind::Stairs, cost: 30,
+                                    bidirectional: true }];
+    let serialized = serde_json::to_string(&portals).expect("Portals should be serializable");
+    layered_grid.set_portals(serde_json::from_str(&serialized).expect("Portals should be deserializable"));
+
+    let start = LayeredCellIndex::new(0, CellIndex2d::new(0, 0));
+    let goal = LayeredCellIndex::new(1, CellIndex2d::new(3, 3));
+    let (path, cost) = navigation::find_path_on(&layered_grid, start, goal).expect("Stairs should connect the layers");
+    let stairs_position = path.iter().position(|cell| *cell == stairs_bottom).expect("Path should use the stairs");
+    assert_eq!(path[stairs_position + 1], stairs_top);
+    assert_eq!(cost, 5 * 14 + 30 + 3 * 14);
+    assert_eq!(layered_grid.estimate_cost(start, goal), cost, "Straight walks to and from the stairs are exact");
+    for cell in (0..2).flat_map(|layer| Grid2D::new(6, 6, Vec2::new(50f32, 50f32)).iter_coordinates()
+        .map(move |cell| LayeredCellIndex::new(layer, cell))) {
+        let (_, cost) = navigation::find_path_on(&layered_grid, start, cell).unwrap();
+        let estimate = layered_grid.estimate_cost(start, cell);
+        assert!(estimate <= cost, "Estimate {estimate} of reaching {cell} is above the cost {cost}");
+    }
+
+    let mut flow_field = LayeredFlowField::default();
+    flow_field.integrate_towards(&layered_grid, &[goal]);
+    assert_eq!(flow_field.get_portal_at(stairs_bottom), Some(0));
+    assert!(flow_field.get_field_at(start).dot(Vec2::ONE) > 0.0, "Flow should lead to the stairs");
+    assert_eq!(flow_field.get_field_at(stairs_top), Vec2::ONE.normalize());
+}
+
+#[test]
+fn test_off_mesh_links_cross_walls() {
+    let grid: Grid2D = common::construct_default_grid();
+    let mut grid_related_data = GridRelatedData::new(&grid);
+    for y in 0..grid.row_number {
+        grid_related_data.set_occupation_at(&CellIndex2d::new(7, y), Occupation::Occupied);
+    }
+    let infantry = 0b01;
+    let vehicles = 0b10;
+
+    let mut links = OffMeshLinks::default();
+    let jump = links.add_link(OffMeshLink {
+        from: CellIndex2d::new(6, 7),
+        to: CellIndex2d::new(8, 7),
+        kind: OffMeshLinkKind::Jump,
+        cost: 30,
+        bidirectional: false,
+        agent_classes: infantry,
+        enabled: true,
+    });
+
+    let start = CellIndex2d::new(2, 7);
+    let goal = CellIndex2d::new(12, 7);
+    let square_grid = SquareNavGrid::new(&grid, &grid_related_data, SurfaceTopology::Bounded);
+    let (path, _) = navigation::find_path_on(&LinkedNavGrid::new(&square_grid, &links, infantry), start, goal)
+        .expect("Infantry should jump over the wall");
+    let link_segments = links.find_links_along(&path, infantry);
+    assert_eq!(link_segments.len(), 1);
+    assert_eq!(link_segments[0].link, jump);
+    assert_eq!(path[link_segments[0].start_waypoint], CellIndex2d::new(6, 7));
+
+    assert!(navigation::find_path_on(&LinkedNavGrid::new(&square_grid, &links, vehicles), start, goal).is_none());
+    assert!(navigation::find_path_on(&LinkedNavGrid::new(&square_grid, &links, infantry), goal, start).is_none(),
+            "One-way link should not be used backwards");
+
+    let mut flow_field = FlowField::form_field(grid.column_number as usize, grid.row_number as usize);
+    flow_field.integrate_towards_with_links(&grid, &grid_related_data, &[goal], SurfaceTopology::Bounded,
+                                            &links, infantry);
+    assert!(flow_field.get_field_at(&CellIndex2d::new(6, 7)).x > 0.0, "Flow should lead into the jump");
+
+    links.set_enabled(jump, false);
+    assert!(navigation::find_path_on(&LinkedNavGrid::new(&square_grid, &links, infantry), start, goal).is_none());
+
+    let mut maneuver = Maneuver::default();
+    maneuver.set_coordinates_with_links(grid.calculate_surface_coordinates_for_2d(&path), link_segments);
+    assert!(maneuver.take_started_links().is_empty());
+    maneuver.advance_by_distance(maneuver.calculate_length());
+    assert_eq!(maneuver.take_started_links(), vec![jump]);
+    assert!(maneuver.take_started_links().is_empty(), "Traversal should be reported once");
+    // Links are gone over backwards too
+    maneuver.set_playback_mode(PlaybackMode::PingPong);
+    maneuver.advance_by_distance(2.0 * maneuver.calculate_length());
+    assert_eq!(maneuver.take_started_links(), vec![jump, jump]);
+    maneuver.set_playback_mode(PlaybackMode::Reverse);
+    maneuver.advance_by_distance(maneuver.calculate_length());
+    assert_eq!(maneuver.take_started_links(), vec![jump]);
+
+    // Diagonal neighbours are adjacent, a link more expensive than the diagonal step there is just walked
+    let diagonal_link = |from: CellIndex2d, cost: u32| OffMeshLink {
+        from,
+        to: CellIndex2d::new(from.x + 1, from.y + 1),
+        kind: OffMeshLinkKind::Ladder,
+        cost,
+        bidirectional: false,
+        agent_classes: infantry,
+        enabled: true,
+    };
+    links.add_link(diagonal_link(CellIndex2d::new(2, 2), 20));
+    let cheap_ladder = links.add_link(diagonal_link(CellIndex2d::new(4, 4), 10));
+    let diagonal_walk = [CellIndex2d::new(2, 2), CellIndex2d::new(3, 3), CellIndex2d::new(4, 4), CellIndex2d::new(5, 5)];
+    let diagonal_links = links.find_links_along(&diagonal_walk, infantry);
+    assert_eq!(diagonal_links.len(), 1);
+    assert_eq!((diagonal_links[0].start_waypoint, diagonal_links[0].link), (2, cheap_ladder));
+}
+
+#[test]
+fn test_timed_gate_is_passed_when_open() {
+    let grid: Grid2D = common::construct_default_grid();
+    let mut grid_related_data = GridRelatedData::new(&grid);
+    let gate = CellIndex2d::new(7, 7);
+    for y in (0..grid.row_number).filter(|y| *y != gate.y) {
+        grid_related_data.set_occupation_at(&CellIndex2d::new(7, y), Occupation::Occupied);
+    }
+
+    let mut cost_schedule = CostSchedule::default();
+    // Closed for the first half of every 10 seconds
+    cost_schedule.add_area(ScheduledArea::new(URect::from_corners(gate.into(), gate.into()),
+                                              ScheduleCurve::Periodic { period: 10.0, phase: 0.0, active_duration: 5.0 },
+                                              ScheduledEffect::Occupation));
+    let hazard = cost_schedule.add_area(ScheduledArea::new(URect::new(9, 0, 9, 14),
+                                                           ScheduleCurve::Keyframes { period: 10.0, keys: vec![(0.0, 0.0), (5.0, 1.0)] },
+                                                           ScheduledEffect::ExtraCost(20)));
+    assert_eq!(cost_schedule.get_area(hazard).calculate_extra_cost_at(7.5), 10);
+
+    let state_changes = cost_schedule.apply_at(0.0, &mut grid_related_data);
+    assert_eq!(state_changes.len(), 1, "Hazard has no extra cost at the start");
+    assert!(state_changes[0].occupation_changed);
+    assert!(grid_related_data.is_occupied_at(&gate));
+
+    let start = CellIndex2d::new(5, 7);
+    let goal = CellIndex2d::new(9, 7);
+    let square_grid = SquareNavGrid::new(&grid, &grid_related_data, SurfaceTopology::Bounded);
+    assert!(navigation::find_path_on(&square_grid, start, goal).is_none());
+
+    let parameters = TimedSearchParameters::new(0.0, 1.0, 30.0);
+    let (path, _) = space_time_search::find_timed_path_on(&square_grid, &cost_schedule, start, goal, parameters)
+        .expect("Agent should wait for the gate to open");
+    let gate_waypoint = path.iter().find(|waypoint| waypoint.cell == gate).unwrap();
+    assert!((5.0..10.0).contains(&gate_waypoint.arrival_time));
+    assert_eq!(path.last().unwrap().cell, goal);
+    assert!(path.windows(2).all(|step| (step[1].arrival_time - step[0].arrival_time - 1.0).abs() < 1e-5),
+            "Every move or wait should take a single step duration");
+
+    let short_horizon = TimedSearchParameters::new(0.0, 1.0, 4.0);
+    assert!(space_time_search::find_timed_path_on(&square_grid, &cost_schedule, start, goal, short_horizon).is_none());
+
+    let state_changes = cost_schedule.apply_at(6.0, &mut grid_related_data);
+    assert_eq!(state_changes.len(), 2);
+    assert!(!grid_related_data.is_occupied_at(&gate));
+    assert_eq!(grid_related_data.get_data_at(&goal).scheduled_cost, 16);
+    assert!(cost_schedule.apply_at(6.01, &mut grid_related_data).is_empty());
+}
+
+#[test]
+fn test_gates_and_moving_obstacles_share_closures() {
+    let grid: Grid2D = common::construct_default_grid();
+    let mut grid_related_data = GridRelatedData::new(&grid);
+    let mut dynamic_occupancy = DynamicOccupancy::new(&grid);
+    let gate = CellIndex2d::new(7, 7);
+    let obstacle = Entity::from_raw(1);
+
+    let mut cost_schedule = CostSchedule::default();
+    cost_schedule.add_area(ScheduledArea::new(URect::from_corners(gate.into(), gate.into()),
+                                              ScheduleCurve::Periodic { period: 10.0, phase: 0.0, active_duration: 5.0 },
+                                              ScheduledEffect::Occupation));
+
+    // The gate opens while the obstacle still stands in it
+    cost_schedule.apply_at(0.0, &mut grid_related_data);
+    dynamic_occupancy.stamp(&mut grid_related_data, obstacle, vec![gate]);
+    cost_schedule.apply_at(6.0, &mut grid_related_data);
+    assert!(grid_related_data.is_occupied_at(&gate), "Obstacle still stands in the open gate");
+    dynamic_occupancy.clear(&mut grid_related_data, obstacle);
+    assert!(!grid_related_data.is_occupied_at(&gate));
+
+    // The obstacle leaves while the gate is closed
+    dynamic_occupancy.stamp(&mut grid_related_data, obstacle, vec![gate]);
+    cost_schedule.apply_at(10.0, &mut grid_related_data);
+    let lifted_cells = dynamic_occupancy.lift(&mut grid_related_data, obstacle);
+    assert!(grid_related_data.is_occupied_at(&gate), "Lifting the footprint should not open the closed gate");
+    dynamic_occupancy.put_back(&mut grid_related_data, &lifted_cells);
+    dynamic_occupancy.clear(&mut grid_related_data, obstacle);
+    assert!(grid_related_data.is_occupied_at(&gate), "Gate is still closed");
+    cost_schedule.apply_at(16.0, &mut grid_related_data);
+    assert!(!grid_related_data.is_occupied_at(&gate));
+
+    // Walls are never freed by either of them
+    grid_related_data.set_occupation_at(&gate, Occupation::Occupied);
+    cost_schedule.apply_at(20.0, &mut grid_related_data);
+    dynamic_occupancy.stamp(&mut grid_related_data, obstacle, vec![gate]);
+    cost_schedule.apply_at(26.0, &mut grid_related_data);
+    dynamic_occupancy.clear(&mut grid_related_data, obstacle);
+    assert!(grid_related_data.is_occupied_at(&gate));
+}
+
+#[test]
+fn test_scheduled_areas_follow_the_resized_grid() {
+    let mut grid: Grid2D = common::construct_default_grid();
+    let mut grid_related_data = GridRelatedData::new(&grid);
+    let mut cost_schedule = CostSchedule::default();
+    let closed_first_half = ScheduleCurve::Periodic { period: 10.0, phase: 0.0, active_duration: 5.0 };
+    let clipped = cost_schedule.add_area(ScheduledArea::new(URect::new(3, 3, 8, 8), closed_first_half.clone(),
+                                                            ScheduledEffect::Occupation));
+    let cut_off = cost_schedule.add_area(ScheduledArea::new(URect::new(0, 0, 2, 2), closed_first_half,
+                                                            ScheduledEffect::Occupation));
+    cost_schedule.apply_at(0.0, &mut grid_related_data);
+
+    let grid_resized = grid.resize(grid.column_number - 5, grid.row_number - 5, Direction::NorthEast);
+    assert_eq!(grid_resized.offset, IVec2::new(-5, -5));
+    grid_related_data.resize_to(&grid_resized);
+    cost_schedule.resize_to(&grid_resized);
+
+    assert_eq!(cost_schedule.get_area(clipped).area, URect::new(0, 0, 3, 3));
+    assert_eq!(cost_schedule.iter_areas().count(), 1, "Area {cut_off} should be cut off");
+    assert!(cost_schedule.is_closed_by_schedule(CellIndex2d::new(3, 3)));
+
+    cost_schedule.apply_at(6.0, &mut grid_related_data);
+    assert!(grid.iter_coordinates().all(|cell| !grid_related_data.is_occupied_at(&cell)),
+            "Every closed cell left in the grid should be opened");
+}
+
+#[test]
+fn test_cooperative_detours_respect_reservations() {
+    let grid: Grid2D = common::construct_default_grid();
+    let grid_related_data = GridRelatedData::new(&grid);
+    let square_grid = SquareNavGrid::new(&grid, &grid_related_data, SurfaceTopology::Bounded);
+    let (first_agent, second_agent) = (Entity::from_raw(1), Entity::from_raw(2));
+
+    let mut reservations = ReservationTable::new(0.5);
+    let first_path: Vec<CellIndex2d> = (2..=8).map(|x| CellIndex2d::new(x, 5)).collect();
+    reservations.reserve_path(first_agent, &first_path, 0, 1);
+
+    let crossing_path: Vec<CellIndex2d> = (2..=8).map(|y| CellIndex2d::new(5, y)).collect();
+    assert_eq!(reservations.find_conflict(second_agent, &crossing_path, 0, 1), Some(3));
+    assert_eq!(reservations.find_conflict(first_agent, &first_path, 0, 1), None);
+
+    let cooperative_path = cooperative_pathfinding::find_cooperative_path_on(
+        &square_grid, &reservations, second_agent, crossing_path[0], *crossing_path.last().unwrap(), 0, 16, 1)
+        .expect("Detour around the reserved cells should exist");
+    assert_eq!(reservations.find_conflict(second_agent, &cooperative_path, 0, 1), None);
+    assert_eq!(cooperative_path.last(), crossing_path.last());
+
+    // A slower first agent reaches the crossing later, so only an equally slow second one runs into it
+    reservations.reserve_path(first_agent, &first_path, 0, 2);
+    assert_eq!(reservations.find_conflict(second_agent, &crossing_path, 0, 1), None);
+    assert_eq!(reservations.find_conflict(second_agent, &crossing_path, 0, 2), Some(3));
+    let slow_path = cooperative_pathfinding::find_cooperative_path_on(
+        &square_grid, &reservations, second_agent, crossing_path[0], *crossing_path.last().unwrap(), 0, 16, 2)
+        .expect("Slow detour around the reserved cells should exist");
+    assert_eq!(reservations.find_conflict(second_agent, &slow_path, 0, 2), None);
+
+    reservations.release_agent(first_agent);
+    assert_eq!(reservations.find_conflict(second_agent, &crossing_path, 0, 1), None);
+
+    // Conflicts while lingering in a waypoint are reported for that waypoint, not for the next one
+    let blocker = Entity::from_raw(3);
+    let lingering_path = [CellIndex2d::new(10, 10), CellIndex2d::new(11, 10), CellIndex2d::new(12, 10)];
+    reservations.reserve_path(blocker, &[CellIndex2d::new(11, 9), lingering_path[1], CellIndex2d::new(11, 11)], 3, 1);
+    assert_eq!(reservations.find_conflict(second_agent, &lingering_path, 0, 3), Some(1));
+    reservations.release_agent(blocker);
+    assert_eq!(reservations.find_conflict(second_agent, &lingering_path, 0, 3), None);
+
+    // Steps follow the speed of the agent, in surface coordinate units per second
+    assert_eq!(reservations.calculate_steps_per_cell(&grid, 0.1), 2);
+    assert_eq!(reservations.calculate_steps_per_cell(&grid, 0.01), 15);
+    assert_eq!(reservations.calculate_steps_per_cell(&grid, 0.0), 200);
+
+    let (a, b) = (CellIndex2d::new(1, 1), CellIndex2d::new(2, 1));
+    assert_eq!(cooperative_pathfinding::expand_to_steps(&[a, b], 3), vec![a, a, a, b]);
+    assert_eq!(cooperative_pathfinding::split_waits(&[a, a, a, b, b]), (vec![a, b], vec![2, 1]));
+}
+
+#[test]
+fn test_conflict_based_search_passes_chokepoint() {
+    let grid: Grid2D = common::construct_default_grid();
+    let mut grid_related_data = GridRelatedData::new(&grid);
+    // Single cell wide corridor with one side pocket agents could give way in
+    for x in 5..=9 {
+        for y in (0..grid.row_number).filter(|y| *y != 7) {
+            if (x, y) != (7, 8) {
+                grid_related_data.set_occupation_at(&CellIndex2d::new(x, y), Occupation::Occupied);
+            }
+        }
+    }
+    let square_grid = SquareNavGrid::new(&grid, &grid_related_data, SurfaceTopology::Bounded);
+    let tasks = [AgentTask::new(CellIndex2d::new(1, 7), CellIndex2d::new(13, 7)),
+        AgentTask::new(CellIndex2d::new(13, 7), CellIndex2d::new(1, 7))];
+
+    let paths = cooperative_pathfinding::solve_conflicts_on(&square_grid, &tasks, 64, 1000)
+        .expect("Agents should pass each other using the pocket");
+    for (path, task) in paths.iter().zip(tasks.iter()) {
+        assert_eq!(path.first(), Some(&task.start));
+        assert_eq!(path.last(), Some(&task.goal));
+    }
+
+    let position_at = |path: &Vec<CellIndex2d>, step: usize| path[step.min(path.len() - 1)];
+    let steps_number = paths.iter().map(Vec::len).max().unwrap();
+    for step in 0..steps_number {
+        assert_ne!(position_at(&paths[0], step), position_at(&paths[1], step), "Agents collide at step {step}");
+        if step > 0 {
+            let is_swap = position_at(&paths[0], step) == position_at(&paths[1], step - 1)
+                && position_at(&paths[1], step) == position_at(&paths[0], step - 1);
+            assert!(!is_swap, "Agents pass through each other at step {step}");
+        }
+    }
+}
+
 #[test]
 fn test_split_grid() {
     let grid = construct_default_grid();
@@ -278,5 +659,103 @@ fn load_dictionary_from_file(path: PathBuf) -> Result<Vec<HashMap<Direction, URe
     Ok(collection)
 }
 
+#[test]
+fn test_blocked_route_is_replanned_around() {
+    let grid: Grid2D = construct_default_grid();
+    let mut grid_related_data = GridRelatedData::new(&grid);
+    let route: Vec<CellIndex2d> = (2..=12).map(|x| CellIndex2d::new(x, 7)).collect();
+    let mut maneuver = Maneuver::new(grid.calculate_surface_coordinates_for_2d(&route));
+    let topology = SurfaceTopology::Bounded;
+
+    // A wall appears across the route after it was planned
+    for y in 6..=8 {
+        grid_related_data.set_occupation_at(&CellIndex2d::new(6, y), Occupation::Occupied);
+    }
+    let is_blocked = |cell: CellIndex2d| grid_related_data.is_occupied_at(&cell);
+    assert_eq!(maneuver.find_blocked_waypoint_ahead(&grid, topology, 2, is_blocked), None,
+               "The wall is beyond the look-ahead");
+    let blocked_waypoint = maneuver.find_blocked_waypoint_ahead(&grid, topology, 6, is_blocked)
+        .expect("The wall should be seen within the look-ahead");
+    assert_eq!(blocked_waypoint, 4);
+    let rejoin_waypoint = maneuver.find_rejoin_waypoint(&grid, topology, blocked_waypoint, is_blocked).unwrap();
+    assert_eq!(rejoin_waypoint, 5);
+
+    let nav_grid = SquareNavGrid::new(&grid, &grid_related_data, topology);
+    let rejoin_cell = maneuver.calculate_waypoint_cell(&grid, topology, rejoin_waypoint);
+    assert!(path_following::find_bounded_detour(&nav_grid, route[0], rejoin_cell, 1, is_blocked).is_none(),
+            "Going around the wall takes more room than the bound");
+    let detour = path_following::find_bounded_detour(&nav_grid, route[0], rejoin_cell, 6, is_blocked).unwrap();
+    assert!(detour.iter().all(|cell| !is_blocked(*cell)));
+
+    maneuver.splice_detour(&grid, topology, maneuver.path_points[0], &detour, rejoin_waypoint);
+    assert_eq!(maneuver.path_points.len(), detour.len() + route.len() - rejoin_waypoint - 1);
+    assert_eq!(maneuver.find_blocked_waypoint_ahead(&grid, topology, 20, is_blocked), None);
+    assert_eq!(maneuver.calculate_waypoint_cell(&grid, topology, maneuver.path_points.len() - 1), route[10]);
+
+    // Spliced in the middle of a loop, the passed part is kept and playback goes on from where the agent is
+    let mut looping = Maneuver::new(grid.calculate_surface_coordinates_for_2d(&route));
+    looping.set_playback_mode(PlaybackMode::Loop);
+    let cell_length = 1.0 / grid.max_column_index as f32;
+    let current = looping.advance_by_distance(looping.calculate_distance_at_progress(
+        looping.calculate_waypoint_progress(2)) + cell_length * 0.25);
+    let current_cell = current.calculate_cell_index_on_flat_surface(&grid);
+    let detour = path_following::find_bounded_detour(&nav_grid, current_cell, rejoin_cell, 6, is_blocked).unwrap();
+    looping.splice_detour(&grid, topology, current, &detour, rejoin_waypoint);
+    assert_eq!(looping.playback_mode, PlaybackMode::Loop);
+    assert!(looping.has_started());
+    assert_eq!(looping.calculate_waypoint_cell(&grid, topology, 0), route[0]);
+    assert_eq!(looping.calculate_waypoint_cell(&grid, topology, 2), route[2]);
+    assert!(Vec2::from(looping.path_points[3]).distance(Vec2::from(current)) < 1e-6);
+    assert_eq!(looping.calculate_current_waypoint(), 3);
+    assert!(Vec2::from(looping.advance_by_distance(0.0)).distance(Vec2::from(current)) < 1e-4);
+    assert_eq!(looping.path_points.len(), 4 + detour.len() - 1 + route.len() - rejoin_waypoint - 1);
+
+    // The goal is walled in, the closest reachable cell next to it is picked instead
+    let goal = CellIndex2d::new(12, 7);
+    for offset in [IVec2::new(1, 0), IVec2::new(0, 1), IVec2::new(0, -1), IVec2::new(-1, 0)] {
+        grid_related_data.set_occupation_at(&(goal + offset), Occupation::Occupied);
+    }
+    grid_related_data.set_occupation_at(&goal, Occupation::Occupied);
+    let nav_grid = SquareNavGrid::new(&grid, &grid_related_data, topology);
+    let is_blocked = |cell: CellIndex2d| grid_related_data.is_occupied_at(&cell);
+    let alternative = path_following::find_alternative_goal(&nav_grid, route[0], goal, 2, is_blocked).unwrap();
+    let alternative_goal = *alternative.last().unwrap();
+    let offset = IVec2::from(alternative_goal) - IVec2::from(goal);
+    assert!(offset.x.abs() == 1 && offset.y.abs() == 1, "A diagonal neighbour is closest, got {alternative_goal}");
+}
+
+#[test]
+fn test_stuck_detector_escalates() {
+    let mut detector = StuckDetector { patience: 1.0, wait_duration: 2.0, ..Default::default() };
+    let position = Vec2::new(0.5, 0.5);
+    assert_eq!(detector.observe(position, 0.0), None);
+
+    let mut stages = Vec::new();
+    for _ in 0..55 {
+        if let Some(stage) = detector.observe(position, 0.1) {
+            stages.push(stage);
+        }
+    }
+    assert_eq!(stages, vec![StuckStage::Replanning, StuckStage::Waiting, StuckStage::Rerouting, StuckStage::GivenUp]);
 
+    // Any progress starts over
+    assert_eq!(detector.observe(position + Vec2::X * 0.1, 0.1), None);
+    assert_eq!(detector.get_stage(), StuckStage::Moving);
+}
 
+#[test]
+fn test_avoidance_look_ahead_follows_velocity() {
+    let grid = Grid2D::new(11, 11, Vec2::new(50f32, 50f32));
+    let settings = AvoidanceSettings::default();
+    assert_eq!(settings.calculate_look_ahead_cells(&grid, Vec2::ZERO), settings.min_look_ahead);
+    // A tenth of the grid per second covers half a cell in the look ahead time
+    assert_eq!(settings.calculate_look_ahead_cells(&grid, Vec2::new(0.1, 0.0)), settings.min_look_ahead + 1);
+    assert_eq!(settings.calculate_look_ahead_cells(&grid, Vec2::new(2.0, 0.0)), settings.max_look_ahead);
+
+    assert_eq!(Direction::from_vector(Vec2::ZERO), None);
+    assert_eq!(Direction::from_vector(Vec2::new(0.0, 1.0)), Some(Direction::North));
+    assert_eq!(Direction::from_vector(Vec2::new(1.0, 0.9)), Some(Direction::NorthEast));
+    assert_eq!(Direction::from_vector(Vec2::new(0.1, -1.0)), Some(Direction::South));
+    assert_eq!(Direction::from_vector(Vec2::new(-1.0, 0.2)), Some(Direction::West));
+    assert_eq!(Direction::from_vector(Vec2::new(-0.1, 1.0)), Some(Direction::North));
+}
@@ -4,9 +4,11 @@ use game_types::{
     components::{
         grid_components::definitions::CellIndex,
         movement_components::{
+            Heading,
             MoveTag,
             SurfaceCoordinate,
             ObstacleTag,
+            Velocity,
         },
         pathfinding_components::MovementSpeed,
     }
@@ -20,4 +22,6 @@ pub struct SurfaceWalkerBundle {
     pub move_tag: MoveTag,
     pub obstacle_tag: ObstacleTag,
     pub movement_speed: MovementSpeed,
+    pub heading: Heading,
+    pub velocity: Velocity,
 }
\ No newline at end of file
@@ -10,17 +10,36 @@ use bevy::{
 
 use game_types::{
     components::{
-        flow_field_components::FlowField,
+        flow_field_components::{FlowBrushes, FlowField, FlowFieldGoals},
         grid_components::definitions::{
+            ClearanceLayer,
+            CostSchedule,
+            DynamicOccupancy,
             ElapsedTimeTracker,
             Grid2D,
             GridRelatedData,
+            GridResized,
             ObstaclesParameters,
+            OccupationChanged,
+            OffMeshLinks,
+            ResizeGrid,
+            ScheduleStateChanged,
         }
         ,
+        movement_components::{
+            ManeuverFinished,
+            ManeuverStarted,
+            OffMeshLinkTraversalStarted,
+            SphericalSurface,
+            SurfaceTopology,
+            WaypointPassed,
+            ZLayering,
+        },
+        pathfinding_components::ReservationTable,
         world_manipulation_components::{CursorWorldPosition, HoverCell},
     },
     systems::{
+        flow_driven_movement::apply_spherical_surface_coordinate_system,
         flow_field_manipulations::*,
         grid_related::*,
     },
@@ -43,6 +62,10 @@ fn main() {
     let mut grid_related_data = GridRelatedData::new(&grid_parameters);
     grid_related_data.fill_with_random_obstacle_pattern(&grid_parameters);
     let obstacle_parameters = ObstaclesParameters { influence_area: UVec2::new(8, 8) };
+    let clearance_layer = ClearanceLayer::new(&grid_parameters, 8.0);
+    let dynamic_occupancy = DynamicOccupancy::new(&grid_parameters);
+    // The equator is as long as the grid is wide
+    let spherical_surface = SphericalSurface::new(grid_parameters.grid_size.x / std::f32::consts::TAU, Vec3::ZERO);
 
     /*    let mut main_schedule = Schedule::new(Main);
         main_schedule.set_executor_kind(ExecutorKind::SingleThreaded);
@@ -70,16 +93,48 @@ fn main() {
                                spawn_dummy_path_driven_actor, visualize_grid_in_log).chain())
         /*        .add_systems(PreUpdate, (reset_cells_colorization, capture_cursor_position, mouse_hover_system,
                                          move_camera_system, avoidance_maneuver_system, path_movement_system,
-                                         adjust_coordinate_system, apply_surface_coordinate_system,
+                                         off_mesh_link_traversal_system, scheduled_path_invalidation_system,
+                                         path_validation_system, stuck_detection_system,
+                                         reservation_cleanup_system, formation_slot_system,
+                                         formation_following_system,
+                                         steering_behaviors_system, steering_movement_system,
+                                         adjust_coordinate_system, heading_update_system,
+                                         apply_surface_coordinate_system,
                                          grid_relation_system).chain())
                 .add_systems(Update, (cell_occupation_highlight_system, colorize_obstacles_system, apply_color_to_cell
                                       , visualize_grid_data_in_log).chain())*/
-        .add_systems(Update, (flow_explosion_system, rotate_flow_arrows_system).chain())
+        .add_systems(Update, (flow_explosion_system, flow_brush_system.after(flow_field_integration_system),
+                              rotate_flow_arrows_system).chain())
         .add_systems(Update, (reset_cells_colorization, apply_color_to_cell).chain())
+        .add_systems(Update, (grid_resize_system, grid_layers_resize_system,
+                              respawn_colorized_cells_on_resize_system).chain())
+        .add_systems(Update, (cost_schedule_system, obstacle_stamping_system.after(grid_layers_resize_system),
+                              (clearance_update_system, flow_field_integration_system)).chain())
+        .add_systems(Update, apply_spherical_surface_coordinate_system
+            .run_if(resource_equals(SurfaceTopology::Sphere)))
+        .add_event::<OccupationChanged>()
+        .add_event::<OffMeshLinkTraversalStarted>()
+        .add_event::<ManeuverStarted>()
+        .add_event::<WaypointPassed>()
+        .add_event::<ManeuverFinished>()
+        .add_event::<ScheduleStateChanged>()
+        .add_event::<ResizeGrid>()
+        .add_event::<GridResized>()
         .insert_resource(grid_parameters)
         .insert_resource(grid_related_data)
         .insert_resource(obstacle_parameters)
+        .insert_resource(clearance_layer)
+        .insert_resource(dynamic_occupancy)
         .insert_resource(flow_field)
+        .insert_resource(FlowFieldGoals::default())
+        .insert_resource(FlowBrushes::default())
+        // Edges stop movement and pathfinding by default, insert `SurfaceTopology::Torus` or a cylinder to wrap
+        .insert_resource(SurfaceTopology::default())
+        .insert_resource(spherical_surface)
+        .insert_resource(OffMeshLinks::default())
+        .insert_resource(CostSchedule::default())
+        .insert_resource(ReservationTable::default())
+        .insert_resource(ZLayering::default())
         .insert_resource(ElapsedTimeTracker::default())
         .insert_resource(HoverCell::default())
         .insert_resource(CursorWorldPosition::default())
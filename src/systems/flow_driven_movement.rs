@@ -3,14 +3,17 @@ use bevy::{
     prelude::{Color, Commands, Res},
 };
 use game_types::{
-    components::grid_components::definitions::{CellIndex2d, Grid2D},
+    components::{
+        grid_components::definitions::{CellIndex2d, Grid2D},
+        movement_components::ZLayering,
+    },
     systems::flow_driven_movement::calculate_coordination_data,
 };
 
 
 use crate::systems::flow_field_related;
 
-pub fn spawn_moving_cubes(mut commands: Commands, grid_parameters: Res<Grid2D>)
+pub fn spawn_moving_cubes(mut commands: Commands, grid_parameters: Res<Grid2D>, z_layering: Res<ZLayering>)
 {
     let columns_num = grid_parameters.column_number;
     let rows_num = grid_parameters.row_number;
@@ -21,15 +24,15 @@ pub fn spawn_moving_cubes(mut commands: Commands, grid_parameters: Res<Grid2D>)
     for y in 0..rows_num {
         cell_index.y = y;
 
-        spawn_movable_actor_on_grid(&mut commands, &grid_parameters, cell_index.into(), color);
+        spawn_movable_actor_on_grid(&mut commands, &grid_parameters, &z_layering, cell_index.into(), color);
     }
 }
 
-fn spawn_movable_actor_on_grid(mut commands: &mut Commands, grid_parameters: &Res<Grid2D>,
+fn spawn_movable_actor_on_grid(mut commands: &mut Commands, grid_parameters: &Res<Grid2D>, z_layering: &ZLayering,
                                cell_index: CellIndex2d, color: Color)
 {
     let (coordinate, coordinate_world_transform, actor_size) =
-        calculate_coordination_data(&grid_parameters, cell_index);
+        calculate_coordination_data(&grid_parameters, z_layering, cell_index);
 
     flow_field_related::spawn_movable_actor(&mut commands, cell_index, color, actor_size, coordinate,
                                             coordinate_world_transform);
@@ -18,7 +18,7 @@ use game_types::{
     components::{
         flow_field_components::FlowField,
         grid_components::definitions::{CellIndex, CellIndex2d, Grid2D},
-        movement_components::{Maneuver, SurfaceCoordinate},
+        movement_components::{Maneuver, SurfaceCoordinate, ZLayering},
         pathfinding_components::MovementSpeed,
     },
     systems::flow_driven_movement,
@@ -26,10 +26,11 @@ use game_types::{
 
 use crate::bundles::movables::SurfaceWalkerBundle;
 
-pub fn visualize_flow_system(mut _commands: Commands, grid_parameter: Res<Grid2D>, flow_field: Res<FlowField>) {
+pub fn visualize_flow_system(mut _commands: Commands, grid_parameter: Res<Grid2D>, flow_field: Res<FlowField>,
+                             z_layering: Res<ZLayering>) {
     // Create a new PathBuilder for the arrow shape
     for coordinate in grid_parameter.iter_coordinates() {
-        let cell_position = grid_parameter.calculate_cell_position(coordinate).extend(0.0);
+        let cell_position = grid_parameter.calculate_cell_position(coordinate).extend(z_layering.flow_arrows);
         let mut new_transform = Transform::from_xyz(cell_position.x, cell_position.y, cell_position.z);
 
         new_transform.rotation = Quat::from_rotation_z(flow_field.get_rotation_angle_at(&coordinate));
@@ -51,11 +52,12 @@ pub fn visualize_flow_system(mut _commands: Commands, grid_parameter: Res<Grid2D
 
 const MOVEMENT_SPEED: f32 = 0.05;
 
-pub fn spawn_dummy_path_driven_actor(mut commands: Commands, grid_parameters: Res<Grid2D>) {
+pub fn spawn_dummy_path_driven_actor(mut commands: Commands, grid_parameters: Res<Grid2D>,
+                                     z_layering: Res<ZLayering>) {
     let cell_index: UVec2 = UVec2::new(10, 10);
 
     let (coordinate, coordinate_world_transform, actor_size) =
-        flow_driven_movement::calculate_coordination_data(&grid_parameters, cell_index.into());
+        flow_driven_movement::calculate_coordination_data(&grid_parameters, &z_layering, cell_index.into());
 
     /*    let maneuver_points =
             vec![grid_parameters.calculate_flat_surface_coordinate_from(UVec2::new(0, 0)),
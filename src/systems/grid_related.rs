@@ -2,18 +2,44 @@ use bevy::{
     prelude::{
         Color,
         Commands,
+        Entity,
+        EventReader,
+        Query,
         Res,
         ResMut,
         Transform,
         Vec2,
+        With,
     },
     sprite::{Sprite, SpriteBundle},
 };
 
-use game_types::components::grid_components::definitions::{CellIndex, Grid2D, GridCellTag,
-                                                           GridRelatedData, Occupation};
+use game_types::components::{
+    grid_components::definitions::{CellIndex, Grid2D, GridCellTag, GridRelatedData, GridResized, Occupation},
+    movement_components::ZLayering,
+};
+
+pub fn spawned_colorized_cells_system(mut commands: Commands, grid: Res<Grid2D>, z_layering: Res<ZLayering>)
+{
+    spawn_colorized_cells(&mut commands, &grid, z_layering.cells);
+}
+
+pub fn respawn_colorized_cells_on_resize_system(mut commands: Commands, grid: Res<Grid2D>,
+                                                z_layering: Res<ZLayering>,
+                                                mut grid_resized_events: EventReader<GridResized>,
+                                                cells_query: Query<Entity, With<GridCellTag>>)
+{
+    if grid_resized_events.read().last().is_none() {
+        return;
+    }
+
+    for cell_entity in cells_query.iter() {
+        commands.entity(cell_entity).despawn();
+    }
+    spawn_colorized_cells(&mut commands, &grid, z_layering.cells);
+}
 
-pub fn spawned_colorized_cells_system(mut commands: Commands, grid: Res<Grid2D>)
+fn spawn_colorized_cells(commands: &mut Commands, grid: &Grid2D, depth: f32)
 {
     let columns_num = grid.column_number;
     let rows_num = grid.row_number;
@@ -34,11 +60,11 @@ pub fn spawned_colorized_cells_system(mut commands: Commands, grid: Res<Grid2D>)
         let mut color = if (cell_index.x + cell_index.y) % 2 == 0 { color1 } else { color2 };
 
 
-        // Adjust the cell's position so the grid is centered at (0, 0)
+        // Adjust the cell's position so the grid is centered at the grid's shape center
         let position = Vec2::new((cell_index.x as f32 * (cell_size.x + cell_spacing))
                                      - grid_size_x / 2.0 + cell_size.x / 2.0,
                                  (cell_index.y as f32 * (cell_size.y + cell_spacing))
-                                     - grid_size_y / 2.0 + cell_size.y / 2.0);
+                                     - grid_size_y / 2.0 + cell_size.y / 2.0) + grid.shape_rect.center();
 
         commands.spawn(SpriteBundle {
             sprite: Sprite {
@@ -46,7 +72,7 @@ pub fn spawned_colorized_cells_system(mut commands: Commands, grid: Res<Grid2D>)
                 custom_size: Some(cell_size),
                 ..Default::default()
             },
-            transform: Transform::from_translation(position.extend(0.0)),
+            transform: Transform::from_translation(position.extend(depth)),
             ..Default::default()
         }).insert(GridCellTag).insert(CellIndex::new(cell_index));
     }